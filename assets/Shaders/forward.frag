@@ -2,19 +2,30 @@
 
 // TODO: Inject into shader
 #define MAX_NR_OF_POINT_LIGHTS 10
+#define MAX_NR_OF_DIRECTIONAL_LIGHTS 4
 
 const float PI = 3.14159265359;
+// How much light reaches everything, so what no light hits isn't pitch black
+const vec4 AMBIENT = vec4(vec3(0.2), 1.0);
 
+// The direction is the way the light travels, in world space
 struct DirectionalLight {
     vec4 direction;
-    vec4 ambient;
     vec4 color;
 };
 
+#define LIGHT_KIND_POINT 0
+#define LIGHT_KIND_SPOT 1
+
 struct PointLight {
     float radius;
+    uint kind;
+    // Cosines of the spot light cone angles
+    float inner_cutoff;
+    float outer_cutoff;
     vec4 position;
     vec4 color;
+    vec4 direction;
 };
 
 struct Material {
@@ -27,20 +38,51 @@ layout(location = 0) in vec2 v_TexCoord;
 layout(location = 1) in vec3 v_Color;
 layout(location = 2) in vec4 v_FragPos;
 layout(location = 3) in vec4 v_Normal;
+layout(location = 4) in vec4 v_Tangent;
+// Comes from the vertex shader so instanced models can each have their own
+layout(location = 5) in vec4 v_Tint;
 
 layout(location = 0) out vec4 o_Target;
 
 layout(set = 0, binding = 0) uniform Globals {
     mat4 u_ViewProj;
     vec4 u_Eye_Position;
+    vec4 u_FogColor;
+    float u_FogStart;
+    float u_FogEnd;
+    float u_FogDensity;
 };
 
-layout(set = 0, binding = 1) uniform texture2D t_Diffuse;
 layout(set = 0, binding = 2) uniform sampler s_Diffuse;
 
+layout(set = 0, binding = 3) uniform Lights {
+    DirectionalLight u_DirectionalLights[MAX_NR_OF_DIRECTIONAL_LIGHTS];
+    uint u_NrOfPointLights;
+    uint u_NrOfDirectionalLights;
+    PointLight u_PointLights[MAX_NR_OF_POINT_LIGHTS];
+};
+
+layout(set = 0, binding = 4) uniform texture2D t_Normal;
+
+// Shadow map of the first directional light
+layout(set = 0, binding = 5) uniform Shadows {
+    mat4 u_LightViewProj;
+    uint u_ShadowsEnabled;
+    float u_ShadowTexelSize;
+};
+layout(set = 0, binding = 6) uniform texture2D t_ShadowMap;
+layout(set = 0, binding = 7) uniform samplerShadow s_Shadow;
+
 layout(set = 1, binding = 0) uniform Locals {
     mat4 u_ModelMatrix;
     Material material;
+    vec4 u_Tint;
+    uint u_NormalMapped;
+};
+
+layout(set = 2, binding = 0) uniform texture2D t_Diffuse;
+layout(set = 2, binding = 1) uniform MeshMaterial {
+    vec4 u_MeshTint;
 };
 
 
@@ -168,12 +210,10 @@ float contrast(float a, float x) {
     return clamp(a * (cos(PI * (x + 1)) + 1) / 2.0 + (1-a)*x, 0.0, 1.0);
 }
 
-vec4 fLightFactor(vec3 normal, float distance, float radius, vec4 color, vec3 light_dir, vec3 view_dir, vec4 F_0, Material mat) {
+// Cook-Torrance BRDF for light of the given radiance arriving from light_dir
+vec4 fShade(vec3 normal, vec4 radiance, float specular_scale, vec3 light_dir, vec3 view_dir, vec4 F_0, Material mat) {
     vec3 halfway = normalize(light_dir + view_dir);
 
-    float attenuation = fLightFalloff(distance, radius, 3.0);
-    vec4 radiance = 1.0 * color * attenuation;
-
     float NDF = fDistributionGGX(normal, halfway, mat.roughness);
     float G = fGeometrySmith(normal, view_dir, light_dir, mat.roughness);
     vec4 F = fFresnelSchlick(max(dot(halfway, view_dir), 0.0), F_0);
@@ -186,77 +226,124 @@ vec4 fLightFactor(vec3 normal, float distance, float radius, vec4 color, vec3 li
     float denominator = 4.0 * max(dot(normal, view_dir), 0.0) * max(dot(normal, light_dir), 0.0);
     vec4 specular = numerator / max(denominator, 0.001);
 
-    float specular_falloff = fLightFalloff(distance, radius, 4.0);
     float lambert = fLambert(normal, light_dir);
 
-    return (kD * mat.albedo / PI + specular_falloff * specular) * radiance * lambert;
+    return (kD * mat.albedo / PI + specular_scale * specular) * radiance * lambert;
 }
 
-void main() {
-    vec4 diffuse = texture(sampler2D(t_Diffuse, s_Diffuse), v_TexCoord);
-    vec3 normal = normalize(v_Normal.xyz);
-    vec3 view_dir = normalize(u_Eye_Position.xyz - v_FragPos.xyz);
+vec4 fLightFactor(vec3 normal, float distance, float radius, vec4 color, vec3 light_dir, vec3 view_dir, vec4 F_0, Material mat) {
+    float attenuation = fLightFalloff(distance, radius, 3.0);
+    float specular_falloff = fLightFalloff(distance, radius, 4.0);
 
-    DirectionalLight directional_light = {
-        vec4(0.1, 0.2, 0.3, 1.0),
-        vec4(vec3(0.2), 1.0),
-        vec4(vec3(0.8), 1.0),
-    };
+    return fShade(normal, color * attenuation, specular_falloff, light_dir, view_dir, F_0, mat);
+}
 
-    Material mat = { diffuse, 0.0, 2.0 };
+// Smoothly fades a spot light out between its inner and outer cone
+float fSpotFactor(PointLight light, vec3 light_dir) {
+    float cos_theta = dot(-light_dir, normalize(light.direction.xyz));
+    return smoothstep(light.outer_cutoff, light.inner_cutoff, cos_theta);
+}
 
-    vec4 F_0 = vec4(vec3(0.03), 1.0);
-    F_0 = mix(F_0, mat.albedo, mat.metallic);
+// Perturbs the geometric normal by the tangent space normal map,
+// falls back to the geometric normal if the vertex has no tangent
+vec3 fMappedNormal(vec3 normal) {
+    if (u_NormalMapped == 0 || dot(v_Tangent.xyz, v_Tangent.xyz) < Epsilon) {
+        return normal;
+    }
 
-    vec4 Lo = vec4(0.0);
+    vec3 tangent = normalize(v_Tangent.xyz - normal * dot(normal, v_Tangent.xyz));
+    vec3 bitangent = cross(normal, tangent) * v_Tangent.w;
+    vec3 sampled = texture(sampler2D(t_Normal, s_Diffuse), v_TexCoord).xyz * 2.0 - 1.0;
 
-    //for(int i = 0; i < MAX_NR_OF_POINT_LIGHTS; i++) {
-    //    PointLight light = uPointLights[i];
-    //    vec3 to_light = light.position.xyz - v_FragPos.xyz;
-    //    vec3 light_dir = normalize(to_light);
-
-    //    Lo += fLightFactor(
-    //        normal,
-    //        length(to_light),
-    //        light.radius,
-    //        light.color,
-    //        light_dir,
-    //        view_dir,
-    //        F_0,
-    //        mat
-    //    );
-    //}
-
-    // Directional Light
-    vec4 ambient = directional_light.ambient * mat.albedo;
-    vec4 color = ambient + Lo;
+    return normalize(mat3(tangent, bitangent, normal) * sampled);
+}
 
-    vec3 light_dir = normalize(directional_light.direction.xyz);
-    vec3 halfway = normalize(light_dir + view_dir);
+// How lit the fragment is by the first directional light, 3x3 PCF over the shadow map.
+// Fragments outside of the map are lit.
+float fShadow(vec4 frag_pos) {
+    if (u_ShadowsEnabled == 0) {
+        return 1.0;
+    }
 
-    float NDF = fDistributionGGX(normal, halfway, mat.roughness);
-    float G = fGeometrySmith(normal, view_dir, light_dir, mat.roughness);
-    vec4 F = fFresnelSchlick(max(dot(halfway, view_dir), 0.0), F_0);
+    vec4 light_space = u_LightViewProj * frag_pos;
+    vec3 ndc = light_space.xyz / light_space.w;
+    // Texture coordinates grow downwards
+    vec2 uv = vec2(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))) || ndc.z > 1.0) {
+        return 1.0;
+    }
 
-    float lambert = fLambert(normal, light_dir);
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec2 offset = vec2(x, y) * u_ShadowTexelSize;
+            lit += texture(sampler2DShadow(t_ShadowMap, s_Shadow), vec3(uv + offset, ndc.z));
+        }
+    }
 
-    vec4 numerator = NDF * G * F;
-    float denominator = 4.0 * max(dot(normal, view_dir), 0.0) * lambert;
-    vec4 specular = numerator / max(denominator, 0.001);
+    return lit / 9.0;
+}
 
-    vec4 kS = F;
-    vec4 kD = vec4(1.0) - kS;
-    kD *= 1.0 - mat.metallic;
+// How much of the fog color covers a fragment this far from the camera, none without fog
+float fFog(float distance) {
+    if (u_FogDensity <= 0.0) {
+        return 0.0;
+    }
 
+    return u_FogDensity * smoothstep(u_FogStart, max(u_FogEnd, u_FogStart + 0.001), distance);
+}
 
-    color += (kD * mat.albedo + specular) * lambert * directional_light.color;
+void main() {
+    vec4 diffuse = texture(sampler2D(t_Diffuse, s_Diffuse), v_TexCoord) * v_Tint * u_MeshTint;
+    vec3 normal = fMappedNormal(normalize(v_Normal.xyz));
+    vec3 view_dir = normalize(u_Eye_Position.xyz - v_FragPos.xyz);
+
+    Material mat = { diffuse, 0.0, 2.0 };
+
+    vec4 F_0 = vec4(vec3(0.03), 1.0);
+    F_0 = mix(F_0, mat.albedo, mat.metallic);
+
+    vec4 Lo = vec4(0.0);
+
+    for(uint i = 0; i < u_NrOfPointLights; i++) {
+        PointLight light = u_PointLights[i];
+        vec3 to_light = light.position.xyz - v_FragPos.xyz;
+        vec3 light_dir = normalize(to_light);
+
+        vec4 light_factor = fLightFactor(
+            normal,
+            length(to_light),
+            light.radius,
+            light.color,
+            light_dir,
+            view_dir,
+            F_0,
+            mat
+        );
+
+        if (light.kind == LIGHT_KIND_SPOT) {
+            light_factor *= fSpotFactor(light, light_dir);
+        }
+
+        Lo += light_factor;
+    }
+
+    // Directional lights are not attenuated
+    for(uint i = 0; i < u_NrOfDirectionalLights; i++) {
+        DirectionalLight light = u_DirectionalLights[i];
+        vec3 light_dir = normalize(-light.direction.xyz);
 
-    color = specular;
-    color = G * mat.albedo;
+        vec4 light_factor = fShade(normal, light.color, 1.0, light_dir, view_dir, F_0, mat);
+        if (i == 0) {
+            light_factor *= fShadow(v_FragPos);
+        }
 
-    // Gamma correction
-    color = color / (color + vec4(1.0));
-    color = pow(color, vec4(1.0/2.2));
+        Lo += light_factor;
+    }
+
+    // Tonemapping and gamma correction happen in post.frag
+    vec4 ambient = AMBIENT * mat.albedo;
+    vec4 color = ambient + Lo;
 
     //color = RGBtoHCY(color);
 
@@ -268,5 +355,6 @@ void main() {
     //color = HCYtoRGB(color);
 
     //o_Target = vec4(color.rgb, 1.0);
-    o_Target = diffuse;
+    float fog = fFog(length(u_Eye_Position.xyz - v_FragPos.xyz));
+    o_Target = vec4(mix(color.rgb, u_FogColor.rgb, fog), diffuse.a);
 }
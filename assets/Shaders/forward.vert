@@ -3,30 +3,62 @@
 layout(location = 0) in vec3 a_Pos;
 layout(location = 1) in vec3 a_Normal;
 layout(location = 2) in vec2 a_TexCoord;
+layout(location = 3) in vec4 a_Tangent;
+layout(location = 4) in uvec4 a_Joints;
+layout(location = 5) in vec4 a_Weights;
 
 layout(location = 0) out vec2 v_TexCoord;
 layout(location = 1) out vec3 v_Color;
 layout(location = 2) out vec4 v_FragPos;
 layout(location = 3) out vec4 v_Normal;
+layout(location = 4) out vec4 v_Tangent;
+layout(location = 5) out vec4 v_Tint;
 
 layout(set = 0, binding = 0) uniform Globals {
     mat4 u_ViewProj;
     vec4 u_Eye_Position;
 };
 
+struct Material {
+    vec4  albedo;
+    float metallic;
+    float roughness;
+};
+
 layout(set = 1, binding = 0) uniform Locals {
     mat4 u_ModelMatrix;
-    vec3 u_Color;
+    Material material;
+    vec4 u_Tint;
+};
+
+#define MAX_NR_OF_JOINTS 64
+
+layout(set = 3, binding = 0) uniform Joints {
+    mat4 u_Joints[MAX_NR_OF_JOINTS];
 };
 
 void main() {
+    // Linear blend skinning, vertices without weights aren't attached to the skeleton
+    mat4 skin = mat4(1.0);
+    if (dot(a_Weights, vec4(1.0)) > 0.0) {
+        skin = a_Weights.x * u_Joints[a_Joints.x]
+             + a_Weights.y * u_Joints[a_Joints.y]
+             + a_Weights.z * u_Joints[a_Joints.z]
+             + a_Weights.w * u_Joints[a_Joints.w];
+    }
+    mat4 model = u_ModelMatrix * skin;
+
     vec4 position = vec4(a_Pos, 1.0);
 
-    v_FragPos = u_ModelMatrix * position;
-    v_Normal = normalize(u_ModelMatrix * vec4(a_Normal, 0.0));
+    v_FragPos = model * position;
+    // Scaling unevenly skews normals unless they go through the inverse transpose
+    mat3 normal_matrix = transpose(inverse(mat3(model)));
+    v_Normal = vec4(normalize(normal_matrix * a_Normal), 0.0);
+    v_Tangent = vec4((model * vec4(a_Tangent.xyz, 0.0)).xyz, a_Tangent.w);
 
-    v_Color = u_Color;
+    v_Color = material.albedo.rgb;
+    v_Tint = u_Tint;
     v_TexCoord = a_TexCoord;
 
-    gl_Position = u_ViewProj * u_ModelMatrix * position;
+    gl_Position = u_ViewProj * model * position;
 }
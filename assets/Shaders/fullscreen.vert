@@ -0,0 +1,12 @@
+#version 450
+
+// One triangle big enough to cover the screen, its corners come from the vertex index
+layout(location = 0) out vec2 v_TexCoord;
+
+void main() {
+    vec2 position = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+    // Textures are sampled from the top left
+    v_TexCoord = vec2(position.x * 0.5 + 0.5, 0.5 - position.y * 0.5);
+
+    gl_Position = vec4(position, 0.0, 1.0);
+}
@@ -0,0 +1,44 @@
+#version 450
+
+layout(location = 0) in vec3 a_Pos;
+layout(location = 1) in vec3 a_Normal;
+layout(location = 2) in vec2 a_TexCoord;
+layout(location = 3) in vec4 a_Tangent;
+
+// Read out of each instance's LocalUniforms
+layout(location = 6) in vec4 i_ModelMatrix0;
+layout(location = 7) in vec4 i_ModelMatrix1;
+layout(location = 8) in vec4 i_ModelMatrix2;
+layout(location = 9) in vec4 i_ModelMatrix3;
+layout(location = 10) in vec4 i_Albedo;
+layout(location = 11) in vec4 i_Tint;
+
+layout(location = 0) out vec2 v_TexCoord;
+layout(location = 1) out vec3 v_Color;
+layout(location = 2) out vec4 v_FragPos;
+layout(location = 3) out vec4 v_Normal;
+layout(location = 4) out vec4 v_Tangent;
+layout(location = 5) out vec4 v_Tint;
+
+layout(set = 0, binding = 0) uniform Globals {
+    mat4 u_ViewProj;
+    vec4 u_Eye_Position;
+};
+
+void main() {
+    mat4 model = mat4(i_ModelMatrix0, i_ModelMatrix1, i_ModelMatrix2, i_ModelMatrix3);
+
+    vec4 position = vec4(a_Pos, 1.0);
+
+    v_FragPos = model * position;
+    // Scaling unevenly skews normals unless they go through the inverse transpose
+    mat3 normal_matrix = transpose(inverse(mat3(model)));
+    v_Normal = vec4(normalize(normal_matrix * a_Normal), 0.0);
+    v_Tangent = vec4((model * vec4(a_Tangent.xyz, 0.0)).xyz, a_Tangent.w);
+
+    v_Color = i_Albedo.rgb;
+    v_Tint = i_Tint;
+    v_TexCoord = a_TexCoord;
+
+    gl_Position = u_ViewProj * model * position;
+}
@@ -0,0 +1,12 @@
+#version 450
+
+layout(location = 0) out uint o_Id;
+
+// Which entity the model belongs to, 0 is left for no entity
+layout(set = 2, binding = 0) uniform Pick {
+    uint u_Id;
+};
+
+void main() {
+    o_Id = u_Id;
+}
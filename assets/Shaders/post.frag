@@ -0,0 +1,43 @@
+#version 450
+
+#define TONEMAP_NONE 0
+#define TONEMAP_REINHARD 1
+#define TONEMAP_ACES 2
+
+layout(location = 0) in vec2 v_TexCoord;
+
+layout(location = 0) out vec4 o_Target;
+
+layout(set = 0, binding = 0) uniform texture2D t_Scene;
+layout(set = 0, binding = 1) uniform sampler s_Scene;
+
+layout(set = 1, binding = 0) uniform PostProcess {
+    float u_Exposure;
+    uint u_Tonemap;
+    float u_Gamma;
+};
+
+// https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+vec3 fAces(vec3 x) {
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+
+void main() {
+    vec4 scene = texture(sampler2D(t_Scene, s_Scene), v_TexCoord);
+    vec3 color = scene.rgb * u_Exposure;
+
+    if (u_Tonemap == TONEMAP_REINHARD) {
+        color = color / (color + vec3(1.0));
+    } else if (u_Tonemap == TONEMAP_ACES) {
+        color = fAces(color);
+    }
+
+    color = pow(clamp(color, 0.0, 1.0), vec3(1.0 / u_Gamma));
+
+    o_Target = vec4(color, 1.0);
+}
@@ -0,0 +1,13 @@
+#version 450
+
+layout(location = 0) in vec2 v_TexCoord;
+layout(location = 1) in vec4 v_Tint;
+
+layout(location = 0) out vec4 o_Target;
+
+layout(set = 1, binding = 0) uniform texture2D t_Sprite;
+layout(set = 1, binding = 1) uniform sampler s_Sprite;
+
+void main() {
+    o_Target = texture(sampler2D(t_Sprite, s_Sprite), v_TexCoord) * v_Tint;
+}
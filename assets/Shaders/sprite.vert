@@ -0,0 +1,26 @@
+#version 450
+
+// Corner of the unit quad, (0, 0) is the top left
+layout(location = 0) in vec2 a_Corner;
+
+layout(location = 1) in vec2 i_Position;
+layout(location = 2) in vec2 i_Size;
+layout(location = 3) in vec4 i_UvRect;
+layout(location = 4) in vec4 i_Tint;
+
+layout(location = 0) out vec2 v_TexCoord;
+layout(location = 1) out vec4 v_Tint;
+
+layout(set = 0, binding = 0) uniform Globals {
+    mat4 u_ViewProj;
+    vec4 u_Eye_Position;
+};
+
+void main() {
+    vec2 uv = mix(i_UvRect.xy, i_UvRect.zw, a_Corner);
+    // Textures are uploaded bottom row first
+    v_TexCoord = vec2(uv.x, 1.0 - uv.y);
+    v_Tint = i_Tint;
+
+    gl_Position = u_ViewProj * vec4(i_Position + a_Corner * i_Size, 0.0, 1.0);
+}
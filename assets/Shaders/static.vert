@@ -3,20 +3,30 @@
 layout(location = 0) in vec3 a_Pos;
 layout(location = 1) in vec3 a_Normal;
 layout(location = 2) in vec2 a_TexCoord;
+layout(location = 3) in vec4 a_Tangent;
 
 layout(location = 0) out vec2 v_TexCoord;
 layout(location = 1) out vec3 v_Color;
 layout(location = 2) out vec4 v_FragPos;
 layout(location = 3) out vec4 v_Normal;
+layout(location = 4) out vec4 v_Tangent;
+layout(location = 5) out vec4 v_Tint;
 
 layout(set = 0, binding = 0) uniform Globals {
     mat4 u_ViewProj;
     vec4 u_Eye_Position;
 };
 
+struct Material {
+    vec4  albedo;
+    float metallic;
+    float roughness;
+};
+
 layout(set = 1, binding = 0) uniform Locals {
     mat4 u_ModelMatrix;
-    vec3 u_Color;
+    Material material;
+    vec4 u_Tint;
 };
 
 void main() {
@@ -24,8 +34,10 @@ void main() {
 
     v_FragPos = position;
     v_Normal = vec4(a_Normal, 0.0);
+    v_Tangent = a_Tangent;
 
-    v_Color = u_Color;
+    v_Color = material.albedo.rgb;
+    v_Tint = u_Tint;
     v_TexCoord = a_TexCoord;
 
     gl_Position = u_ViewProj * position;
@@ -14,6 +14,28 @@ impl StaticModelRequest {
     }
 }
 
+/// Copies of static models baked into a single model, e.g. every floor tile of a dungeon.
+/// Each part is a model and the matrix moving it into place, the whole is drawn with
+/// `uniforms`. Becomes a `StaticModel` on the same entity once every part has loaded,
+/// replacing the one it had. Without parts the entity stops being drawn.
+pub struct MergedModelRequest {
+    pub parts: Vec<(String, [[f32; 4]; 4])>,
+    pub uniforms: LocalUniforms,
+}
+
+impl MergedModelRequest {
+    pub fn new(uniforms: LocalUniforms) -> Self {
+        Self {
+            parts: vec![],
+            uniforms,
+        }
+    }
+
+    pub fn push(&mut self, label: &str, model_matrix: [[f32; 4]; 4]) {
+        self.parts.push((label.to_string(), model_matrix));
+    }
+}
+
 pub struct DynamicModelRequest {
     pub label: String,
 }
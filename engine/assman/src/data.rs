@@ -3,7 +3,12 @@
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
+use graphics::fullscreen::Fullscreen;
+use graphics::GraphicsContext;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use winit::dpi::PhysicalSize;
+use winit::window::Window;
 
 #[derive(Serialize, Deserialize)]
 pub struct PathSettings {
@@ -13,6 +18,7 @@ pub struct PathSettings {
     pub models_path: PathBuf,
     pub textures_path: PathBuf,
     pub shader_path: PathBuf,
+    pub entities_path: PathBuf,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -26,11 +32,18 @@ impl Extensions {
     pub fn new() -> Self { Self::default() }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct DisplaySettings {
     pub screen_width: i32,
     pub screen_height: i32,
     pub fps: u32,
+    pub fullscreen: Fullscreen,
+    /// Multisample anti-aliasing of the 3D scene, 1 turns it off
+    pub sample_count: u32,
+    /// Width and height of the directional light's shadow map, 0 turns shadows off
+    pub shadow_map_size: u32,
+    /// Waits for the display to refresh before showing a frame, which caps the frame rate
+    pub vsync: bool,
 }
 
 impl Default for DisplaySettings {
@@ -39,10 +52,202 @@ impl Default for DisplaySettings {
             screen_width: 1024,
             screen_height: 768,
             fps: 60,
+            fullscreen: Fullscreen::Windowed,
+            sample_count: 4,
+            shadow_map_size: 2048,
+            vsync: false,
         }
     }
 }
 
+/// Every field is kept as a raw value so one bad field doesn't fail the whole file.
+/// RON values lose enum variant names, so `fullscreen` is parsed again on its own.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct RawDisplaySettings {
+    #[serde(deserialize_with = "present")]
+    screen_width: Option<ron::Value>,
+    #[serde(deserialize_with = "present")]
+    screen_height: Option<ron::Value>,
+    #[serde(deserialize_with = "present")]
+    fps: Option<ron::Value>,
+    #[serde(deserialize_with = "present")]
+    fullscreen: Option<ron::Value>,
+    #[serde(deserialize_with = "present")]
+    sample_count: Option<ron::Value>,
+    #[serde(deserialize_with = "present")]
+    shadow_map_size: Option<ron::Value>,
+    #[serde(deserialize_with = "present")]
+    vsync: Option<ron::Value>,
+}
+
+/// RON wants `Some(..)` around optional fields, we only care whether the field is there
+fn present<'de, D: serde::Deserializer<'de>, T: Deserialize<'de>>(
+    d: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(d).map(Some)
+}
+
+/// Just the fullscreen mode of a settings file, every other field is skipped
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct RawFullscreen {
+    #[serde(deserialize_with = "present")]
+    fullscreen: Option<Fullscreen>,
+}
+
+fn fullscreen_field(src: &str, fallback: Fullscreen, warnings: &mut Vec<String>) -> Fullscreen {
+    match ron::de::from_str::<RawFullscreen>(src) {
+        Ok(raw) => raw.fullscreen.unwrap_or(fallback),
+        Err(e) => {
+            warnings.push(format!(
+                "fullscreen is malformed ({}), using {:?}",
+                e, fallback
+            ));
+            fallback
+        }
+    }
+}
+
+fn raw_field<T: DeserializeOwned + std::fmt::Display>(
+    name: &str,
+    value: Option<ron::Value>,
+    fallback: T,
+    warnings: &mut Vec<String>,
+) -> T {
+    match value {
+        None => fallback,
+        Some(value) => value.into_rust().unwrap_or_else(|e| {
+            warnings.push(format!("{} is malformed ({}), using {}", name, e, fallback));
+            fallback
+        }),
+    }
+}
+
+impl DisplaySettings {
+    pub const MAX_FPS: u32 = 1000;
+    /// Bigger textures aren't guaranteed to be supported
+    pub const MAX_SHADOW_MAP_SIZE: u32 = 8192;
+
+    /// Parses display settings, replacing missing, malformed or unreasonable fields
+    /// with their defaults. Returns a warning for every field that was corrected.
+    pub fn from_ron(src: &str) -> Result<(Self, Vec<String>), ron::Error> {
+        let raw: RawDisplaySettings = ron::de::from_str(src)?;
+        let default = Self::default();
+        let mut warnings = vec![];
+
+        let mut settings = Self {
+            screen_width: raw_field(
+                "screen_width",
+                raw.screen_width,
+                default.screen_width,
+                &mut warnings,
+            ),
+            screen_height: raw_field(
+                "screen_height",
+                raw.screen_height,
+                default.screen_height,
+                &mut warnings,
+            ),
+            fps: raw_field("fps", raw.fps, default.fps, &mut warnings),
+            fullscreen: match raw.fullscreen {
+                None => default.fullscreen,
+                Some(_) => fullscreen_field(src, default.fullscreen, &mut warnings),
+            },
+            sample_count: raw_field(
+                "sample_count",
+                raw.sample_count,
+                default.sample_count,
+                &mut warnings,
+            ),
+            shadow_map_size: raw_field(
+                "shadow_map_size",
+                raw.shadow_map_size,
+                default.shadow_map_size,
+                &mut warnings,
+            ),
+            vsync: raw_field("vsync", raw.vsync, default.vsync, &mut warnings),
+        };
+
+        warnings.extend(settings.validate());
+
+        Ok((settings, warnings))
+    }
+
+    /// What `from_ron` reads back, e.g. to save settings changed while playing
+    pub fn to_ron(&self) -> Result<String, ron::Error> {
+        ron::ser::to_string_pretty(self, Default::default())
+    }
+
+    pub fn window_size(&self) -> PhysicalSize<u32> {
+        PhysicalSize::new(self.screen_width as u32, self.screen_height as u32)
+    }
+
+    /// Puts the window in the fullscreen mode and resolution of the settings and reconfigures
+    /// the swap chain to match. Fullscreen modes resize the window themselves, the swap chain
+    /// follows with the `WindowEvent::Resized` that brings.
+    pub fn apply(&self, window: &Window, graphics_context: &mut GraphicsContext) {
+        if self.fullscreen.apply(window) == Fullscreen::Windowed {
+            window.set_inner_size(self.window_size());
+            graphics_context.resize(self.window_size());
+        }
+        graphics_context.set_vsync(self.vsync);
+    }
+
+    /// Corrects out of range values in place, returning a warning for each one
+    pub fn validate(&mut self) -> Vec<String> {
+        let default = Self::default();
+        let mut warnings = vec![];
+
+        if self.screen_width <= 0 || self.screen_height <= 0 {
+            warnings.push(format!(
+                "resolution {}x{} is invalid, using {}x{}",
+                self.screen_width, self.screen_height, default.screen_width, default.screen_height
+            ));
+            self.screen_width = default.screen_width;
+            self.screen_height = default.screen_height;
+        }
+
+        if self.fps == 0 {
+            warnings.push(format!("fps cap of 0 is invalid, using {}", default.fps));
+            self.fps = default.fps;
+        } else if self.fps > Self::MAX_FPS {
+            warnings.push(format!(
+                "fps cap of {} clamped to {}",
+                self.fps,
+                Self::MAX_FPS
+            ));
+            self.fps = Self::MAX_FPS;
+        }
+
+        if self.sample_count == 0 {
+            warnings.push("sample count of 0 is invalid, using 1".to_string());
+            self.sample_count = 1;
+        }
+
+        if self.shadow_map_size > Self::MAX_SHADOW_MAP_SIZE {
+            warnings.push(format!(
+                "shadow map size of {} clamped to {}",
+                self.shadow_map_size,
+                Self::MAX_SHADOW_MAP_SIZE
+            ));
+            self.shadow_map_size = Self::MAX_SHADOW_MAP_SIZE;
+        }
+
+        if let Fullscreen::Exclusive { resolution } = self.fullscreen {
+            if resolution.0 == 0 || resolution.1 == 0 {
+                warnings.push(format!(
+                    "exclusive fullscreen at {}x{} is invalid, using borderless",
+                    resolution.0, resolution.1
+                ));
+                self.fullscreen = Fullscreen::Borderless;
+            }
+        }
+
+        warnings
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct StorageInfo<T> {
     pub id: T,
@@ -72,3 +277,108 @@ pub struct Asset {
     pub path: PathBuf,
     pub storage_info: AssetStorageInfo,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let (settings, warnings) = DisplaySettings::from_ron("(fps: 144, vsync: true)").unwrap();
+
+        assert_eq!(
+            settings,
+            DisplaySettings {
+                fps: 144,
+                vsync: true,
+                ..Default::default()
+            }
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn bad_fields_are_corrected_individually() {
+        let (settings, warnings) = DisplaySettings::from_ron(
+            "(screen_width: \"wide\", screen_height: 600, fps: 100000, sample_count: 0, \
+             shadow_map_size: 100000, vsync: 1)",
+        )
+        .unwrap();
+
+        assert_eq!(
+            settings.screen_width,
+            DisplaySettings::default().screen_width
+        );
+        assert_eq!(settings.screen_height, 600);
+        assert_eq!(settings.fps, DisplaySettings::MAX_FPS);
+        assert_eq!(settings.sample_count, 1);
+        assert_eq!(
+            settings.shadow_map_size,
+            DisplaySettings::MAX_SHADOW_MAP_SIZE
+        );
+        assert!(!settings.vsync);
+        assert_eq!(warnings.len(), 5);
+    }
+
+    #[test]
+    fn bad_fullscreen_modes_fall_back_on_their_own() {
+        for src in &[
+            "(fps: 30, fullscreen: Bordeless)",
+            "(fps: 30, fullscreen: Exclusive(resolution: \"big\"))",
+        ] {
+            let (settings, warnings) = DisplaySettings::from_ron(src).unwrap();
+
+            assert_eq!(settings.fps, 30);
+            assert_eq!(settings.fullscreen, DisplaySettings::default().fullscreen);
+            assert_eq!(warnings.len(), 1, "{:?}", warnings);
+            assert!(warnings[0].starts_with("fullscreen"), "{}", warnings[0]);
+        }
+    }
+
+    #[test]
+    fn saved_settings_load_back() {
+        let settings = DisplaySettings {
+            screen_width: 1920,
+            screen_height: 1080,
+            fullscreen: Fullscreen::Exclusive {
+                resolution: (1920, 1080),
+            },
+            vsync: true,
+            ..Default::default()
+        };
+
+        let (loaded, warnings) = DisplaySettings::from_ron(&settings.to_ron().unwrap()).unwrap();
+        assert_eq!(loaded, settings);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn zero_resolution_is_rejected() {
+        let (settings, warnings) =
+            DisplaySettings::from_ron("(screen_width: 0, screen_height: 0, fps: 60)").unwrap();
+
+        assert_eq!(settings, DisplaySettings::default());
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn fullscreen_modes_parse() {
+        let (settings, _) = DisplaySettings::from_ron("(fullscreen: Borderless)").unwrap();
+        assert_eq!(settings.fullscreen, Fullscreen::Borderless);
+
+        let (settings, warnings) =
+            DisplaySettings::from_ron("(fullscreen: Exclusive(resolution: (1920, 1080)))").unwrap();
+        assert_eq!(
+            settings.fullscreen,
+            Fullscreen::Exclusive {
+                resolution: (1920, 1080)
+            }
+        );
+        assert!(warnings.is_empty());
+
+        let (settings, warnings) =
+            DisplaySettings::from_ron("(fullscreen: Exclusive(resolution: (0, 1080)))").unwrap();
+        assert_eq!(settings.fullscreen, Fullscreen::Borderless);
+        assert_eq!(warnings.len(), 1);
+    }
+}
@@ -0,0 +1,18 @@
+use entity_smith::EntitySmith;
+use graphics::data::LocalUniforms;
+
+use crate::components::{DynamicModelRequest, StaticModelRequest};
+
+pub trait AssetEntitySmith {
+    fn dynamic_model(&mut self, label: &str) -> &mut Self;
+    fn static_model(&mut self, label: &str, uniforms: LocalUniforms) -> &mut Self;
+}
+
+impl<'a> AssetEntitySmith for EntitySmith<'a> {
+    fn dynamic_model(&mut self, label: &str) -> &mut Self {
+        self.add_component(DynamicModelRequest::new(label))
+    }
+    fn static_model(&mut self, label: &str, uniforms: LocalUniforms) -> &mut Self {
+        self.add_component(StaticModelRequest::new(label, uniforms))
+    }
+}
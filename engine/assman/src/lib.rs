@@ -1,8 +1,13 @@
 pub use loader::*;
+pub use reader::read_ron;
+
+pub use crate::entity_smith::AssetEntitySmith;
 
 pub mod components;
 pub mod data;
+mod entity_smith;
 mod loader;
+mod model_loader;
 mod optimizer;
 mod reader;
 pub mod systems;
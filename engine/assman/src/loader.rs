@@ -4,8 +4,12 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::SystemTime;
 
+use itertools::Itertools;
+
 use super::data::*;
 use super::reader;
+use crate::model_loader::ModelLoader;
+use crate::optimizer::weld_vertices;
 
 //pub const DEFAULT_SETTINGS_PATH: &'static str = "settings/";
 //pub const PATHS_SETTINGS_NAME: &'static str = "paths.settings";
@@ -14,6 +18,7 @@ pub struct AssetStore {
     assets: HashMap<PathBuf, Asset>,
     paths: PathSettings,
     extensions: Extensions,
+    model_loader: ModelLoader,
 }
 
 impl AssetStore {
@@ -26,6 +31,7 @@ impl AssetStore {
             assets: Default::default(),
             paths,
             extensions,
+            model_loader: Default::default(),
         }
     }
 
@@ -71,6 +77,24 @@ impl AssetStore {
         }
     }
 
+    pub fn get_texture_index(&self, name: &str) -> Option<graphics::TextureID> {
+        match self.get_asset_storage_info(name) {
+            Some(AssetStorageInfo::Texture(Some(storage_info))) => Some(storage_info.id),
+            _ => None,
+        }
+    }
+
+    /// Applies to models loaded from now on, reload them for it to take effect on the others
+    pub fn set_normal_shading(&mut self, shading: graphics::data::NormalShading) {
+        self.model_loader.normal_shading = shading;
+    }
+
+    /// Where the prefab files live
+    pub fn entities_path(&self) -> &Path { &self.paths.entities_path }
+
+    /// How many model files are still being parsed, for showing loading progress
+    pub fn models_loading(&self) -> usize { self.model_loader.in_flight() }
+
     pub fn get_asset_storage_info(&self, file_name: &str) -> Option<AssetStorageInfo> {
         self.assets
             .values()
@@ -108,13 +132,44 @@ impl AssetStore {
     }
 
     pub fn load_display_settings(&mut self) -> DisplaySettings {
-        reader::read_ron::<DisplaySettings>(&self.paths.display_settings_path).unwrap_or({
-            println!(
-                "Failed to load DisplaySettings at path: {:?}",
-                self.paths.display_settings_path
+        let path = &self.paths.display_settings_path;
+
+        let parsed = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|src| DisplaySettings::from_ron(&src).map_err(|e| e.to_string()));
+
+        match parsed {
+            Ok((settings, warnings)) => {
+                for warning in warnings {
+                    eprintln!("[assman] (warning): {:?}: {}", path, warning);
+                }
+                settings
+            }
+            Err(e) => {
+                eprintln!(
+                    "[assman] (warning): Failed to load DisplaySettings at path {:?}: {}",
+                    path, e
+                );
+                DisplaySettings::default()
+            }
+        }
+    }
+
+    /// Writes the settings where `load_display_settings` reads them from
+    pub fn save_display_settings(&self, settings: &DisplaySettings) {
+        let path = &self.paths.display_settings_path;
+
+        let saved = settings
+            .to_ron()
+            .map_err(|e| e.to_string())
+            .and_then(|src| fs::write(path, src).map_err(|e| e.to_string()));
+
+        if let Err(e) = saved {
+            eprintln!(
+                "[assman] (warning): Failed to save DisplaySettings at path {:?}: {}",
+                path, e
             );
-            DisplaySettings::default()
-        })
+        }
     }
 }
 
@@ -174,144 +229,314 @@ impl<'a, 'b, 'c> GraphicsAssetManager<'a, 'b, 'c> {
     }
 
     fn load_shader(&mut self, path: &Path) -> Option<Asset> {
+        if let Err(e) = self.store_shader(path) {
+            eprintln!(
+                "[assman] (warning): Failed to compile shader {:?}: {}",
+                path, e
+            );
+        }
+
+        self.asset_store.assets.get(path).cloned()
+    }
+
+    /// Compiles the shader into `GraphicsResources::shaders`. A shader that doesn't compile
+    /// leaves the module from before in place, so pipelines built from it keep working.
+    fn store_shader(&mut self, path: &Path) -> Result<(), String> {
+        let shader_module = self.compile_shader(path)?;
+
+        if let Some(Asset {
+            storage_info: AssetStorageInfo::Shader(Some(storage_info)),
+            ..
+        }) = self.asset_store.assets.get_mut(path)
+        {
+            storage_info.loaded_at_time = SystemTime::now();
+            *self
+                .graphics_resources
+                .shaders
+                .get_mut(&storage_info.id)
+                .unwrap() = Arc::new(shader_module);
+        } else {
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            let id = file_name.to_string();
+            self.graphics_resources
+                .shaders
+                .insert(id.clone(), Arc::new(shader_module));
+            self.asset_store
+                .register_asset(path, AssetStorageInfo::Shader(StorageInfo::now(id)));
+        }
+
+        Ok(())
+    }
+
+    fn compile_shader(&self, path: &Path) -> Result<wgpu::ShaderModule, String> {
         let file_name = path.file_name().unwrap().to_str().unwrap();
         let ext = path.extension().unwrap().to_str().unwrap();
 
+        let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
         let mut shader_compiler = shaderc::Compiler::new().unwrap();
+        let spirv = shader_compiler
+            .compile_into_spirv(
+                &source,
+                match ext {
+                    "frag" => shaderc::ShaderKind::Fragment,
+                    "vert" => shaderc::ShaderKind::Vertex,
+                    &_ => {
+                        eprintln!("Invalid shader extension: {}", &ext);
+                        shaderc::ShaderKind::InferFromSource
+                    }
+                },
+                file_name,
+                "main",
+                None,
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(self
+            .graphics_context
+            .device
+            .create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::util::make_spirv(spirv.as_binary_u8()),
+                flags: Default::default(),
+            }))
+    }
 
-        if let Ok(spirv) = shader_compiler.compile_into_spirv(
-            &fs::read_to_string(path).unwrap(),
-            match ext {
-                "frag" => shaderc::ShaderKind::Fragment,
-                "vert" => shaderc::ShaderKind::Vertex,
-                &_ => {
-                    eprintln!("Invalid shader extension: {}", &ext);
-                    shaderc::ShaderKind::InferFromSource
-                }
-            },
-            file_name,
-            "main",
-            None,
-        ) {
-            let shader_module =
-                self.graphics_context
-                    .device
-                    .create_shader_module(&wgpu::ShaderModuleDescriptor {
-                        label: None,
-                        source: wgpu::util::make_spirv(spirv.as_binary_u8()),
-                        flags: Default::default(),
-                    });
-            if let Some(Asset {
-                storage_info: AssetStorageInfo::Shader(Some(storage_info)),
-                ..
-            }) = self.asset_store.assets.get_mut(path)
-            {
-                storage_info.loaded_at_time = SystemTime::now();
-                *self
-                    .graphics_resources
-                    .shaders
-                    .get_mut(&storage_info.id)
-                    .unwrap() = Arc::new(shader_module);
-            } else {
-                let id = file_name.to_string();
-                self.graphics_resources
-                    .shaders
-                    .insert(id.clone(), Arc::new(shader_module));
-                self.asset_store
-                    .register_asset(path, AssetStorageInfo::Shader(StorageInfo::now(id)));
+    /// Recompiles the shaders whose files changed since they were last loaded and returns
+    /// whether any of them compiled. A broken shader is reported once per change to its file.
+    pub fn reload_changed_shaders(&mut self) -> bool {
+        let changed = self
+            .asset_store
+            .assets
+            .values_mut()
+            .filter_map(
+                |Asset {
+                     path, storage_info, ..
+                 }| match storage_info {
+                    AssetStorageInfo::Shader(Some(storage_info)) => {
+                        let modified = fs::metadata(&path)
+                            .and_then(|metadata| metadata.modified())
+                            .ok()?;
+                        (modified > storage_info.loaded_at_time).then(|| {
+                            storage_info.loaded_at_time = modified;
+                            path.clone()
+                        })
+                    }
+                    _ => None,
+                },
+            )
+            .collect_vec();
+
+        let mut reloaded = false;
+        for path in changed {
+            println!("Hotloading shader {}...", path.display());
+            match self.store_shader(&path) {
+                Ok(()) => reloaded = true,
+                Err(e) => eprintln!(
+                    "[assman] (warning): Failed to compile shader {:?}, keeping the old one: {}",
+                    path, e
+                ),
             }
         }
-
-        self.asset_store.assets.get(path).cloned()
+        reloaded
     }
 
+    /// Reloaded textures keep their TextureID
     fn load_texture(&mut self, path: &Path) -> Option<Asset> {
-        let asset_entry = self.asset_store.assets.get_mut(path).cloned();
-
-        let mut exists = false;
-
-        if let Some(asset) = asset_entry {
-            if let AssetStorageInfo::Texture(Some(mut storage_info)) = asset.storage_info {
-                exists = true;
-                if let Some(image) = reader::read_image(path) {
-                    storage_info.loaded_at_time = SystemTime::now();
-                    *self
-                        .graphics_resources
-                        .textures
-                        .get_mut(storage_info.id)
-                        .unwrap() = graphics::data::Texture::new(image, self.graphics_context);
-                } else {
-                    println!("Failed to load: {}", path.display());
-                }
+        let image = match reader::read_image(path) {
+            Some(image) => image,
+            None => {
+                eprintln!("[assman] (warning): Failed to load texture {:?}", path);
+                return self.asset_store.assets.get(path).cloned();
             }
-        }
-        if !exists {
-            if let Some(image) = reader::read_image(path) {
-                let id = self
-                    .graphics_resources
-                    .textures
-                    .insert(graphics::data::Texture::new(image, self.graphics_context));
-                self.asset_store
-                    .register_asset(path, AssetStorageInfo::Texture(StorageInfo::now(id)));
-            } else {
-                println!("Failed to load: {}", path.display());
+        };
+        let texture = self.graphics_context.texture_from_image(image);
+
+        let id = match self.asset_store.assets.get(path) {
+            Some(Asset {
+                storage_info: AssetStorageInfo::Texture(Some(storage_info)),
+                ..
+            }) => {
+                self.graphics_resources.textures[storage_info.id] = texture;
+                storage_info.id
             }
-        }
+            _ => self.graphics_resources.textures.insert(texture),
+        };
 
+        self.asset_store
+            .register_asset(path, AssetStorageInfo::Texture(StorageInfo::now(id)));
         self.asset_store.assets.get(path).cloned()
     }
 
-    fn load_model(&mut self, path: &Path) -> Option<Asset> {
-        let ext = path.extension().unwrap().to_str().unwrap();
-
-        let asset_entry = self.asset_store.assets.get_mut(path).cloned();
-
-        let mut exists = false;
-
-        if let Some(mut asset) = asset_entry {
-            if let AssetStorageInfo::Model(Some(storage_info)) = asset.storage_info.clone() {
-                exists = true;
-                let model = self.get_graphics_model(path, ext);
-                self.graphics_resources.models[storage_info.id] = model;
-                asset.storage_info = AssetStorageInfo::Model(StorageInfo::now(storage_info.id));
-                self.asset_store.assets.insert(path.to_path_buf(), asset);
-            }
+    /// Reloads the textures whose files changed since they were last loaded and returns
+    /// whether any of them did. A broken image is reported once per change to its file.
+    pub fn reload_changed_textures(&mut self) -> bool {
+        let changed = self
+            .asset_store
+            .assets
+            .values_mut()
+            .filter_map(
+                |Asset {
+                     path, storage_info, ..
+                 }| match storage_info {
+                    AssetStorageInfo::Texture(Some(storage_info)) => {
+                        let modified = fs::metadata(&path)
+                            .and_then(|metadata| metadata.modified())
+                            .ok()?;
+                        (modified > storage_info.loaded_at_time).then(|| {
+                            storage_info.loaded_at_time = modified;
+                            path.clone()
+                        })
+                    }
+                    _ => None,
+                },
+            )
+            .collect_vec();
+
+        for path in &changed {
+            println!("Hotloading texture {}...", path.display());
+            self.load_texture(path);
         }
-        if !exists {
-            let model = self.get_graphics_model(path, ext);
+        !changed.is_empty()
+    }
 
-            let id = self.graphics_resources.models.insert(model);
+    /// Queues the model to be parsed on another thread, `poll_loaded` uploads it when it's done
+    fn load_model(&mut self, path: &Path) -> Option<Asset> {
+        self.asset_store.model_loader.queue(path);
+        self.asset_store.assets.get(path).cloned()
+    }
 
-            self.asset_store
-                .register_asset(path, AssetStorageInfo::Model(StorageInfo::now(id)));
+    /// Uploads the models that finished parsing since the last call and returns how many
+    /// finished, failed ones included. Reloaded models keep their ModelID.
+    pub fn poll_loaded(&mut self) -> usize {
+        let finished = self.asset_store.model_loader.finished();
+        let count = finished.len();
+
+        for parsed in finished {
+            let (vertex_lists, skin) = match parsed.result {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!(
+                        "[assman] (warning): Failed to load model {:?}: {}",
+                        parsed.path, e
+                    );
+                    continue;
+                }
+            };
+
+            let mut model = self.model_from_vertex_lists(vertex_lists);
+            model.skin = skin.map(Arc::new);
+
+            let id = match self.asset_store.assets.get(&parsed.path) {
+                Some(Asset {
+                    storage_info: AssetStorageInfo::Model(Some(storage_info)),
+                    ..
+                }) => {
+                    self.graphics_resources.models[storage_info.id] = model;
+                    storage_info.id
+                }
+                _ => self.graphics_resources.models.insert(model),
+            };
+
+            // The file may have changed while it was being parsed, so the model is only as
+            // new as the moment it was queued
+            self.asset_store.register_asset(
+                &parsed.path,
+                AssetStorageInfo::Model(Some(StorageInfo {
+                    id,
+                    loaded_at_time: parsed.queued_at_time,
+                })),
+            );
         }
 
-        self.asset_store.assets.get(path).cloned()
+        count
     }
 
     pub fn load_models(&mut self) {
         self.load_assets_recursive(Some(&self.asset_store.paths.models_path.clone()));
     }
 
+    pub fn load_textures(&mut self) {
+        self.load_assets_recursive(Some(&self.asset_store.paths.textures_path.clone()));
+    }
+
     pub fn allocate_graphics_model_from_vertex_lists(
         &mut self,
         vertex_lists: graphics::data::VertexLists,
     ) -> graphics::ModelID {
-        self.graphics_resources
-            .models
-            .insert(self.graphics_context.model_from_vertex_list(vertex_lists))
+        let model = self.model_from_vertex_lists(vertex_lists);
+        self.graphics_resources.models.insert(model)
     }
 
-    fn get_graphics_model(&mut self, path: &Path, ext: &str) -> graphics::data::Model {
-        // TODO: Generalize this
-        self.graphics_context.model_from_vertex_list(match ext {
-            "obj" => super::reader::vertex_lists_from_obj(path).unwrap(),
-            "glb" | "gltf" => super::reader::vertex_lists_from_gltf(path).unwrap(),
-            _ => {
-                // Should not happen
-                eprintln!("[loader] (error): Extension {} not recognized.", ext);
-                vec![]
-            }
-        })
+    /// Builds a model with welded, indexed meshes. The unindexed lists are kept on the
+    /// model for merging static meshes.
+    fn model_from_vertex_lists(
+        &self,
+        vertex_lists: graphics::data::VertexLists,
+    ) -> graphics::data::Model {
+        let meshes = vertex_lists
+            .iter()
+            .map(|vertices| {
+                let (vertices, indices) = weld_vertices(vertices);
+                self.graphics_context.indexed_mesh(&vertices, &indices)
+            })
+            .collect_vec();
+
+        graphics::data::Model::new(meshes, vertex_lists)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset_store() -> AssetStore {
+        AssetStore {
+            assets: Default::default(),
+            paths: PathSettings {
+                display_settings_path: Default::default(),
+                extensions_settings_path: Default::default(),
+                assets_path: Default::default(),
+                models_path: Default::default(),
+                textures_path: Default::default(),
+                shader_path: Default::default(),
+                entities_path: Default::default(),
+            },
+            extensions: Extensions {
+                models: vec!["obj".to_string()],
+                textures: vec!["png".to_string(), "jpg".to_string()],
+                shaders: vec![],
+            },
+            model_loader: Default::default(),
+        }
+    }
+
+    #[test]
+    fn textures_are_found_by_file_name_once_loaded() {
+        let mut asset_store = asset_store();
+        for name in ["a.png", "b.jpg", "c.jpeg", "d.obj"] {
+            let path = Path::new("assets/Textures").join(name);
+            let info = asset_store
+                .new_asset_storage_info_from_ext(path.extension().unwrap().to_str().unwrap());
+            asset_store.register_asset(&path, info);
+        }
+
+        // Only the configured extensions are textures
+        assert!(matches!(
+            asset_store.get_asset_storage_info("b.jpg"),
+            Some(AssetStorageInfo::Texture(None))
+        ));
+        assert!(asset_store.get_asset_storage_info("c.jpeg").is_none());
+        assert!(asset_store.get_texture_index("d.obj").is_none());
+
+        // Not loaded yet
+        assert!(asset_store.get_texture_index("a.png").is_none());
+
+        let id = graphics::TextureID::default();
+        asset_store.register_asset(
+            &Path::new("assets/Textures").join("a.png"),
+            AssetStorageInfo::Texture(StorageInfo::now(id)),
+        );
+        assert_eq!(asset_store.get_texture_index("a.png"), Some(id));
     }
 }
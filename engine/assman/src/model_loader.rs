@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use graphics::animation::Skin;
+use graphics::data::{NormalShading, VertexLists};
+
+use crate::reader;
+
+/// A model file read off the main thread, ready to be uploaded to the GPU
+pub struct ParsedModel {
+    pub path: PathBuf,
+    /// When the file was queued. Changes to the file after this are newer than the model.
+    pub queued_at_time: SystemTime,
+    pub result: Result<(VertexLists, Option<Skin>), String>,
+}
+
+/// Parses model files on rayon's thread pool, at most once per file at a time
+#[derive(Default)]
+pub struct ModelLoader {
+    finished: Arc<Mutex<Vec<ParsedModel>>>,
+    in_flight: HashSet<PathBuf>,
+    /// For OBJ files that come without normals
+    pub normal_shading: NormalShading,
+}
+
+impl ModelLoader {
+    /// Starts parsing the file, returns false if it's already being parsed
+    pub fn queue(&mut self, path: &Path) -> bool {
+        if !self.in_flight.insert(path.to_path_buf()) {
+            return false;
+        }
+
+        let path = path.to_path_buf();
+        let queued_at_time = SystemTime::now();
+        let finished = self.finished.clone();
+        let normal_shading = self.normal_shading;
+        rayon::spawn(move || {
+            let result = parse_model(&path, normal_shading);
+            finished.lock().unwrap().push(ParsedModel {
+                path,
+                queued_at_time,
+                result,
+            });
+        });
+        true
+    }
+
+    /// Takes the models that were parsed since the last call, failed ones included
+    pub fn finished(&mut self) -> Vec<ParsedModel> {
+        let finished = std::mem::take(&mut *self.finished.lock().unwrap());
+        for parsed in &finished {
+            self.in_flight.remove(&parsed.path);
+        }
+        finished
+    }
+
+    /// How many queued files haven't been taken by `finished` yet
+    pub fn in_flight(&self) -> usize { self.in_flight.len() }
+}
+
+fn parse_model(
+    path: &Path,
+    normal_shading: NormalShading,
+) -> Result<(VertexLists, Option<Skin>), String> {
+    match path.extension().and_then(|ext| ext.to_str()).unwrap_or("") {
+        "obj" => reader::vertex_lists_from_obj(path, normal_shading)
+            .map(|vertex_lists| (vertex_lists, None)),
+        "glb" | "gltf" => reader::read_gltf(path).and_then(|(document, buffers)| {
+            Ok((
+                reader::vertex_lists_from_gltf(&document, &buffers)?,
+                reader::skin_from_gltf(path, &document, &buffers),
+            ))
+        }),
+        ext => Err(format!("Extension {} not recognized", ext)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn wait_for(loader: &mut ModelLoader, count: usize) -> Vec<ParsedModel> {
+        let started = Instant::now();
+        let mut parsed = vec![];
+        while parsed.len() < count {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            parsed.extend(loader.finished());
+            std::thread::yield_now();
+        }
+        parsed
+    }
+
+    #[test]
+    fn models_are_parsed_off_the_main_thread() {
+        let path = std::env::temp_dir().join("deeper_model_loader_test.obj");
+        std::fs::write(
+            &path,
+            "o triangle\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\n",
+        )
+        .unwrap();
+        let missing = std::env::temp_dir().join("deeper_model_loader_test_missing.obj");
+
+        let mut loader = ModelLoader::default();
+        assert!(loader.queue(&path));
+        // Already being parsed
+        assert!(!loader.queue(&path));
+        assert!(loader.queue(&missing));
+        assert_eq!(loader.in_flight(), 2);
+
+        let parsed = wait_for(&mut loader, 2);
+        assert_eq!(loader.in_flight(), 0);
+
+        let triangle = parsed.iter().find(|parsed| parsed.path == path).unwrap();
+        let (vertex_lists, skin) = triangle.result.as_ref().unwrap();
+        assert_eq!(vertex_lists.iter().map(Vec::len).sum::<usize>(), 3);
+        assert!(skin.is_none());
+        assert!(triangle.queued_at_time <= SystemTime::now());
+
+        let missing = parsed.iter().find(|parsed| parsed.path == missing).unwrap();
+        assert!(missing.result.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+    #[test]
+    fn incomplete_gltf_files_fail_instead_of_panicking() {
+        // A triangle with only positions, the buffer is embedded
+        let path = std::env::temp_dir().join("deeper_model_loader_test_incomplete.gltf");
+        std::fs::write(
+            &path,
+            r#"{
+                "asset": { "version": "2.0" },
+                "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 } }] }],
+                "buffers": [{
+                    "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA",
+                    "byteLength": 36
+                }],
+                "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+                "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                                "min": [0, 0, 0], "max": [1, 1, 0] }]
+            }"#,
+        )
+        .unwrap();
+
+        let mut loader = ModelLoader::default();
+        assert!(loader.queue(&path));
+        let parsed = wait_for(&mut loader, 1);
+        assert_eq!(loader.in_flight(), 0);
+
+        let error = parsed[0].result.as_ref().err().unwrap();
+        assert!(error.contains("normals"), "{}", error);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
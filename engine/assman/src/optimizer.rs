@@ -1,6 +1,8 @@
 #![allow(unused)]
+use std::collections::HashMap;
+
 use cgmath::SquareMatrix;
-use graphics::data::LocalUniforms;
+use graphics::data::{LocalUniforms, Vertex};
 use graphics::{GraphicsResources, ModelID};
 use itertools::Itertools;
 
@@ -9,6 +11,10 @@ pub(crate) struct StaticMeshOptimizationEntry {
     instances: Vec<(ModelID, LocalUniforms)>,
 }
 
+/// Bakes `StaticModelRequest`s that only differ in where they are into one model per material
+/// and tint, so many copies of the same few meshes take a handful of draw calls. The model
+/// matrix is applied to the vertices once instead of every frame. Dungeon floors are merged per
+/// chunk through `MergedModelRequest` instead, so they can be rebuilt as the fog lifts.
 pub(crate) struct StaticMeshOptimizer {
     entries: Vec<StaticMeshOptimizationEntry>,
 }
@@ -42,19 +48,7 @@ impl StaticMeshOptimizer {
                         .instances
                         .iter()
                         .map(|(idx, uniforms)| {
-                            asset_store
-                                .models
-                                .get(*idx)
-                                .unwrap()
-                                .vertex_lists
-                                .iter()
-                                .map(|vertex_list| {
-                                    vertex_list
-                                        .iter()
-                                        .map(|vertex| vertex.transformed(uniforms.model_matrix))
-                                        .collect_vec()
-                                })
-                                .collect_vec()
+                            moved_into_place(asset_store, *idx, uniforms.model_matrix)
                         })
                         .collect_vec()
                         .concat(),
@@ -63,3 +57,143 @@ impl StaticMeshOptimizer {
             .collect_vec()
     }
 }
+
+/// The vertex lists of a model with `model_matrix` applied to them
+fn moved_into_place(
+    asset_store: &GraphicsResources,
+    idx: ModelID,
+    model_matrix: [[f32; 4]; 4],
+) -> graphics::data::VertexLists {
+    asset_store
+        .models
+        .get(idx)
+        .unwrap()
+        .vertex_lists
+        .iter()
+        .map(|vertex_list| {
+            vertex_list
+                .iter()
+                .map(|vertex| vertex.transformed(model_matrix))
+                .collect_vec()
+        })
+        .collect_vec()
+}
+
+/// Every mesh of every part moved into place and flattened into one triangle list,
+/// so the whole lot is a single draw call
+pub(crate) fn merge_into_one_mesh(
+    asset_store: &GraphicsResources,
+    parts: impl IntoIterator<Item = (ModelID, [[f32; 4]; 4])>,
+) -> Vec<Vertex> {
+    parts
+        .into_iter()
+        .flat_map(|(idx, model_matrix)| moved_into_place(asset_store, idx, model_matrix))
+        .concat()
+}
+
+/// Merges bit-identical vertices of a triangle list, returning the unique vertices
+/// and the indices that rebuild the list from them.
+pub(crate) fn weld_vertices(vertices: &[Vertex]) -> (Vec<Vertex>, Vec<u32>) {
+    let mut unique = vec![];
+    let mut index_of: HashMap<&[u8], u32> = HashMap::new();
+
+    let indices = vertices
+        .iter()
+        .map(|vertex| {
+            *index_of
+                .entry(bytemuck::bytes_of(vertex))
+                .or_insert_with(|| {
+                    unique.push(*vertex);
+                    (unique.len() - 1) as u32
+                })
+        })
+        .collect_vec();
+
+    (unique, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube() -> Vec<Vertex> {
+        let corner = |i: usize| Vertex {
+            pos: [(i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2 & 1) as f32],
+            ..bytemuck::Zeroable::zeroed()
+        };
+        #[rustfmt::skip]
+        let faces = [
+            [0, 2, 1], [1, 2, 3], [4, 5, 6], [5, 7, 6],
+            [0, 1, 4], [1, 5, 4], [2, 6, 3], [3, 6, 7],
+            [0, 4, 2], [2, 4, 6], [1, 3, 5], [3, 7, 5],
+        ];
+        faces.iter().flatten().map(|&i| corner(i)).collect()
+    }
+
+    #[test]
+    fn welding_shares_cube_corners() {
+        let triangles = cube();
+        let (vertices, indices) = weld_vertices(&triangles);
+
+        assert_eq!(triangles.len(), 36);
+        assert_eq!(vertices.len(), 8);
+        assert_eq!(indices.len(), 36);
+
+        for (vertex, &index) in triangles.iter().zip(&indices) {
+            assert_eq!(vertex.pos, vertices[index as usize].pos);
+        }
+    }
+
+    #[test]
+    fn tiles_with_the_same_look_become_one_model() {
+        let mut resources = GraphicsResources::new();
+        let tile = resources
+            .models
+            .insert(graphics::data::Model::new(vec![], vec![cube()]));
+
+        let wall_tint = [0.5, 0.5, 0.5, 1.0];
+        let mut optimizer = StaticMeshOptimizer::new();
+        for x in 0..3 {
+            let uniforms =
+                LocalUniforms::simple([x as f32, 0.0, 0.0], 1.0, 0.0, Default::default());
+            optimizer.insert(tile, uniforms);
+            optimizer.insert(tile, uniforms.with_tint(wall_tint));
+        }
+
+        let models = optimizer.finish(&resources);
+        assert_eq!(models.len(), 2);
+
+        let (floor, floor_lists) = &models[0];
+        assert_eq!(floor.tint, graphics::data::NO_TINT);
+        assert_eq!(floor_lists.len(), 3);
+        // The tiles are moved into place, the merged model itself isn't
+        assert_eq!(floor_lists[2][0].pos, [2.0, 0.0, 0.0]);
+        assert_eq!(
+            cgmath::Matrix4::from(floor.model_matrix),
+            cgmath::Matrix4::identity()
+        );
+        assert_eq!(models[1].0.tint, wall_tint);
+    }
+
+    #[test]
+    fn merged_parts_are_one_mesh_in_world_space() {
+        let mut resources = GraphicsResources::new();
+        let tile = resources
+            .models
+            .insert(graphics::data::Model::new(vec![], vec![cube(), cube()]));
+
+        let parts = (0..4).map(|x| {
+            let uniforms =
+                LocalUniforms::simple([x as f32, 0.0, 0.0], 1.0, 90.0, Default::default());
+            (tile, uniforms.model_matrix)
+        });
+        let merged = merge_into_one_mesh(&resources, parts);
+
+        assert_eq!(merged.len(), 4 * 2 * 36);
+        // The last cube was turned a quarter and moved three tiles over
+        let last = &merged[merged.len() - 36..];
+        let max_x = last.iter().map(|v| v.pos[0]).fold(f32::MIN, f32::max);
+        let min_x = last.iter().map(|v| v.pos[0]).fold(f32::MAX, f32::min);
+        assert!((min_x - 2.0).abs() < 1e-5 && (max_x - 3.0).abs() < 1e-5);
+    }
+}
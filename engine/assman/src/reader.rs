@@ -1,8 +1,16 @@
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
+use cgmath::{InnerSpace, Matrix, Matrix4, Quaternion, SquareMatrix, Vector3};
+use gltf::animation::util::ReadOutputs;
+use gltf::animation::Interpolation;
+use graphics::animation::{
+    AnimationClip, Channel, Joint, JointTransform, Keyframes, Skin, MAX_NR_OF_JOINTS,
+};
+use graphics::data::NormalShading;
 use itertools::Itertools;
 use serde::de::DeserializeOwned;
 use wavefront_obj::obj;
@@ -18,51 +26,332 @@ pub fn read_image<P: AsRef<Path>>(path: P) -> Option<image::DynamicImage> {
         .and_then(|e| e.decode().ok())
 }
 
-// TODO: Handle transforms
-pub fn vertex_lists_from_gltf(path: &Path) -> Result<graphics::data::VertexLists, String> {
-    let (document, buffers, _images) = gltf::import(path).unwrap_or_else(|_| {
-        panic!(
-            "[graphics/gltf] : File {} could not be opened",
-            path.display()
-        )
-    });
+/// Imports a glTF file once, so the meshes and the skin can both be read from it
+pub fn read_gltf(path: &Path) -> Result<(gltf::Document, Vec<gltf::buffer::Data>), String> {
+    let (document, buffers, _images) = gltf::import(path)
+        .map_err(|e| format!("File {} could not be opened: {}", path.display(), e))?;
+    Ok((document, buffers))
+}
 
-    // TODO: Add checks for multiple models/scenes, etc.
+/// One vertex list per primitive of every mesh in the default scene, placed where its node
+/// ends up in the scene. Files without scenes get their meshes as they are.
+/// Fails if a primitive is missing positions, normals, texture coordinates or indices.
+pub fn vertex_lists_from_gltf(
+    document: &gltf::Document,
+    buffers: &[gltf::buffer::Data],
+) -> Result<graphics::data::VertexLists, String> {
     let mut vertex_lists = vec![];
 
-    for mesh in document.meshes() {
-        let mut vertex_list = vec![];
-        for primitive in mesh.primitives() {
-            // TODO: Is there a more readable way to do this
-            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-
-            // TODO: This feels ... wrong
-            let positions = reader.read_positions().unwrap().collect_vec();
-            let normals = reader.read_normals().unwrap().collect_vec();
-            // TODO: What is set?
-            let tex_coords = reader.read_tex_coords(0).unwrap().into_f32().collect_vec();
-
-            let indices = reader.read_indices().unwrap().into_u32();
-
-            for idx in indices {
-                let pos = *positions.get(idx as usize).unwrap();
-                let normal = *normals.get(idx as usize).unwrap();
-                let tex_coord = *tex_coords.get(idx as usize).unwrap();
-
-                vertex_list.push(graphics::data::Vertex {
-                    pos,
-                    normal,
-                    tex_coord,
-                })
+    match document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        Some(scene) => {
+            for node in scene.nodes() {
+                gltf_node_vertex_lists(&node, Matrix4::identity(), buffers, &mut vertex_lists)?;
+            }
+        }
+        None => {
+            for mesh in document.meshes() {
+                for primitive in mesh.primitives() {
+                    vertex_lists.push(gltf_primitive_vertices(&primitive, buffers)?);
+                }
             }
         }
-        vertex_lists.push(vertex_list);
     }
 
     Ok(vertex_lists)
 }
 
-pub fn vertex_lists_from_obj(path: &Path) -> Result<graphics::data::VertexLists, String> {
+fn gltf_node_vertex_lists(
+    node: &gltf::Node,
+    parent_transform: Matrix4<f32>,
+    buffers: &[gltf::buffer::Data],
+    vertex_lists: &mut graphics::data::VertexLists,
+) -> Result<(), String> {
+    let transform = parent_transform * Matrix4::from(node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            let mut vertices = gltf_primitive_vertices(&primitive, buffers)?;
+            // Skinned meshes are placed by their joints, the node transform doesn't apply
+            if node.skin().is_none() && transform != Matrix4::identity() {
+                transform_vertices(&mut vertices, transform);
+            }
+            vertex_lists.push(vertices);
+        }
+    }
+
+    for child in node.children() {
+        gltf_node_vertex_lists(&child, transform, buffers, vertex_lists)?;
+    }
+    Ok(())
+}
+
+fn gltf_primitive_vertices(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+) -> Result<Vec<graphics::data::Vertex>, String> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    let missing = |attribute: &str| format!("Primitive {} has no {}", primitive.index(), attribute);
+
+    let positions = reader
+        .read_positions()
+        .ok_or_else(|| missing("positions"))?
+        .collect_vec();
+    let normals = reader
+        .read_normals()
+        .ok_or_else(|| missing("normals"))?
+        .collect_vec();
+    // TODO: What is set?
+    let tex_coords = reader
+        .read_tex_coords(0)
+        .ok_or_else(|| missing("texture coordinates"))?
+        .into_f32()
+        .collect_vec();
+    let tangents = reader.read_tangents().map(|t| t.collect_vec());
+    let joints = reader.read_joints(0).map(|j| j.into_u16().collect_vec());
+    let weights = reader.read_weights(0).map(|w| w.into_f32().collect_vec());
+
+    let indices = reader
+        .read_indices()
+        .ok_or_else(|| missing("indices"))?
+        .into_u32();
+
+    let mut vertices = vec![];
+
+    for idx in indices {
+        let (pos, normal, tex_coord) = match (
+            positions.get(idx as usize),
+            normals.get(idx as usize),
+            tex_coords.get(idx as usize),
+        ) {
+            (Some(&pos), Some(&normal), Some(&tex_coord)) => (pos, normal, tex_coord),
+            _ => {
+                return Err(format!(
+                    "Primitive {} indexes vertex {} it doesn't have",
+                    primitive.index(),
+                    idx
+                ))
+            }
+        };
+        let tangent = tangents
+            .as_ref()
+            .and_then(|t| t.get(idx as usize).copied())
+            .unwrap_or_default();
+        let (joints, weights) = attached_joints(
+            joints
+                .as_ref()
+                .and_then(|j| j.get(idx as usize).copied())
+                .unwrap_or_default(),
+            weights
+                .as_ref()
+                .and_then(|w| w.get(idx as usize).copied())
+                .unwrap_or_default(),
+        );
+
+        vertices.push(graphics::data::Vertex {
+            pos,
+            normal,
+            tex_coord,
+            tangent,
+            joints,
+            weights,
+        })
+    }
+
+    // Only derive tangents if the file didn't ship its own
+    if tangents.is_none() {
+        graphics::data::compute_tangents(&mut vertices);
+    }
+
+    Ok(vertices)
+}
+
+/// Joints the shader has no matrix for lose their weight, the rest is scaled back up to one
+fn attached_joints(joints: [u16; 4], weights: [f32; 4]) -> ([u32; 4], [f32; 4]) {
+    if joints
+        .iter()
+        .all(|&joint| (joint as usize) < MAX_NR_OF_JOINTS)
+    {
+        let [a, b, c, d] = joints;
+        return ([a as u32, b as u32, c as u32, d as u32], weights);
+    }
+
+    let mut attached = ([0; 4], [0.0; 4]);
+    for (i, (&joint, &weight)) in joints.iter().zip(&weights).enumerate() {
+        if (joint as usize) < MAX_NR_OF_JOINTS {
+            attached.0[i] = joint as u32;
+            attached.1[i] = weight;
+        }
+    }
+    let total: f32 = attached.1.iter().sum();
+    if total > 0.0 {
+        for weight in &mut attached.1 {
+            *weight /= total;
+        }
+    }
+    attached
+}
+
+/// Moves vertices into their node's parent space. Normals and tangents go through the
+/// inverse transpose, so scaled nodes don't skew them.
+fn transform_vertices(vertices: &mut [graphics::data::Vertex], transform: Matrix4<f32>) {
+    let normal_matrix = transform
+        .invert()
+        .map(|inverse| inverse.transpose())
+        .unwrap_or(transform);
+    let direction = |matrix: Matrix4<f32>, v: [f32; 3]| {
+        let v = (matrix * Vector3::from(v).extend(0.0)).truncate();
+        if v.magnitude2() > 0.0 {
+            v.normalize()
+        } else {
+            v
+        }
+    };
+
+    for vertex in vertices {
+        vertex.pos = (transform * Vector3::from(vertex.pos).extend(1.0))
+            .truncate()
+            .into();
+        vertex.normal = direction(normal_matrix, vertex.normal).into();
+        let [x, y, z, handedness] = vertex.tangent;
+        vertex.tangent = direction(transform, [x, y, z]).extend(handedness).into();
+    }
+}
+
+/// Reads the first skin of a glTF file along with every animation that targets its joints.
+/// Transforms of nodes above the skeleton root are ignored, and cubic spline
+/// keyframes are played back linearly.
+pub fn skin_from_gltf(
+    path: &Path,
+    document: &gltf::Document,
+    buffers: &[gltf::buffer::Data],
+) -> Option<Skin> {
+    let gltf_skin = document.skins().next()?;
+
+    let joint_nodes = gltf_skin.joints().collect_vec();
+    if joint_nodes.len() > MAX_NR_OF_JOINTS {
+        eprintln!(
+            "[assman/gltf] (warning): {} has {} joints, vertices on joints past {} stay put",
+            path.display(),
+            joint_nodes.len(),
+            MAX_NR_OF_JOINTS
+        );
+    }
+
+    // Maps glTF node indices to indices into the skin's joints
+    let joint_of_node: HashMap<usize, usize> = joint_nodes
+        .iter()
+        .enumerate()
+        .map(|(joint, node)| (node.index(), joint))
+        .collect();
+
+    let mut parents = vec![None; joint_nodes.len()];
+    for (joint, node) in joint_nodes.iter().enumerate() {
+        for child in node.children() {
+            if let Some(&child_joint) = joint_of_node.get(&child.index()) {
+                parents[child_joint] = Some(joint);
+            }
+        }
+    }
+
+    let inverse_binds = gltf_skin
+        .reader(|buffer| Some(&buffers[buffer.index()]))
+        .read_inverse_bind_matrices()
+        .map(|matrices| matrices.map(Matrix4::from).collect_vec())
+        .unwrap_or_default();
+
+    let joints = joint_nodes
+        .iter()
+        .enumerate()
+        .map(|(joint, node)| {
+            let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+            Joint {
+                parent: parents[joint],
+                inverse_bind: inverse_binds
+                    .get(joint)
+                    .copied()
+                    .unwrap_or_else(Matrix4::identity),
+                rest: JointTransform {
+                    translation: translation.into(),
+                    rotation: Quaternion::new(w, x, y, z),
+                    scale: scale.into(),
+                },
+            }
+        })
+        .collect_vec();
+
+    let clips = document
+        .animations()
+        .map(|animation| {
+            let channels = animation
+                .channels()
+                .filter_map(|channel| {
+                    let joint = *joint_of_node.get(&channel.target().node().index())?;
+                    let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+                    let times = reader.read_inputs()?.collect_vec();
+
+                    let cubic = channel.sampler().interpolation() == Interpolation::CubicSpline;
+                    let keyframes = match reader.read_outputs()? {
+                        ReadOutputs::Translations(t) => {
+                            let t = t.map(Vector3::from).collect_vec();
+                            Keyframes::Translation(keyframe_values(t, cubic))
+                        }
+                        ReadOutputs::Rotations(r) => {
+                            let r = r
+                                .into_f32()
+                                .map(|[x, y, z, w]| Quaternion::new(w, x, y, z))
+                                .collect_vec();
+                            Keyframes::Rotation(keyframe_values(r, cubic))
+                        }
+                        ReadOutputs::Scales(s) => {
+                            let s = s.map(Vector3::from).collect_vec();
+                            Keyframes::Scale(keyframe_values(s, cubic))
+                        }
+                        ReadOutputs::MorphTargetWeights(_) => return None,
+                    };
+
+                    Some(Channel {
+                        joint,
+                        times,
+                        keyframes,
+                    })
+                })
+                .collect_vec();
+
+            let duration = channels
+                .iter()
+                .filter_map(|channel| channel.times.last().copied())
+                .fold(0.0, f32::max);
+
+            AnimationClip {
+                name: animation
+                    .name()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("animation{}", animation.index())),
+                duration,
+                channels,
+            }
+        })
+        .collect_vec();
+
+    Some(Skin { joints, clips })
+}
+
+// Cubic splines store an in-tangent, the value and an out-tangent per keyframe
+fn keyframe_values<T>(outputs: Vec<T>, cubic: bool) -> Vec<T> {
+    match cubic {
+        true => outputs.into_iter().skip(1).step_by(3).collect_vec(),
+        false => outputs,
+    }
+}
+
+/// Objects without normals for every corner get the missing ones made up with `shading`
+pub fn vertex_lists_from_obj(
+    path: &Path,
+    shading: NormalShading,
+) -> Result<graphics::data::VertexLists, String> {
     let mut f;
 
     if let Ok(file) = File::open(path) {
@@ -77,12 +366,13 @@ pub fn vertex_lists_from_obj(path: &Path) -> Result<graphics::data::VertexLists,
     let mut buf = String::new();
     let _ = f.read_to_string(&mut buf);
 
-    let obj_set = obj::parse(buf).expect("Failed to parse obj file");
+    let obj_set = obj::parse(buf).map_err(|e| format!("Failed to parse obj file: {:?}", e))?;
 
     let mut vertex_lists = vec![];
 
     for obj in &obj_set.objects {
         let mut vertices = vec![];
+        let mut missing_normals = false;
 
         for geometry in &obj.geometry {
             let mut indices = vec![];
@@ -98,6 +388,7 @@ pub fn vertex_lists_from_obj(path: &Path) -> Result<graphics::data::VertexLists,
             for idx in &indices {
                 let pos = obj.vertices[idx.0];
 
+                missing_normals |= idx.2.is_none();
                 let normal = match idx.2 {
                     Some(i) => obj.normals[i],
                     _ => obj::Normal {
@@ -120,11 +411,113 @@ pub fn vertex_lists_from_obj(path: &Path) -> Result<graphics::data::VertexLists,
                     pos: [pos.x as f32, pos.y as f32, pos.z as f32],
                     normal: [normal.x as f32, normal.y as f32, normal.z as f32],
                     tex_coord: [tc.u as f32, tc.v as f32],
+                    tangent: [0.0; 4],
+                    joints: [0; 4],
+                    weights: [0.0; 4],
                 };
                 vertices.push(v);
             }
         }
+        if missing_normals {
+            graphics::data::compute_normals(&mut vertices, shading);
+        }
+        graphics::data::compute_tangents(&mut vertices);
         vertex_lists.push(vertices);
     }
     Ok(vertex_lists)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node rotated a quarter turn around x holding a translated child with a two primitive
+    /// triangle mesh, next to an untransformed node with the same mesh
+    const NESTED_GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0, 2] }],
+        "nodes": [
+            { "children": [1], "translation": [1, 0, 0], "rotation": [0.70710677, 0, 0, 0.70710677] },
+            { "mesh": 0, "translation": [0, 0, 2] },
+            { "mesh": 0 }
+        ],
+        "meshes": [{ "primitives": [
+            { "attributes": { "POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2 }, "indices": 3 },
+            { "attributes": { "POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2 }, "indices": 3 }
+        ] }],
+        "buffers": [{ "uri": "deeper_nested_test.bin", "byteLength": 104 }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 72, "byteLength": 24 },
+            { "buffer": 0, "byteOffset": 96, "byteLength": 6 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+              "min": [0, 0, 0], "max": [1, 1, 0] },
+            { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" },
+            { "bufferView": 2, "componentType": 5126, "count": 3, "type": "VEC2" },
+            { "bufferView": 3, "componentType": 5123, "count": 3, "type": "SCALAR" }
+        ]
+    }"#;
+
+    #[test]
+    fn gltf_nodes_place_their_primitives() {
+        let positions = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let normals = [0.0f32, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0];
+        let tex_coords = [0.0f32, 0.0, 1.0, 0.0, 0.0, 1.0];
+        let mut buffer = [&positions[..], &normals[..], &tex_coords[..]]
+            .concat()
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect_vec();
+        buffer.extend([0u16, 1, 2, 0].iter().flat_map(|i| i.to_le_bytes()));
+
+        let dir = std::env::temp_dir();
+        fs::write(dir.join("deeper_nested_test.bin"), buffer).unwrap();
+        let path = dir.join("deeper_nested_test.gltf");
+        fs::write(&path, NESTED_GLTF).unwrap();
+
+        let (document, buffers) = read_gltf(&path).unwrap();
+        let vertex_lists = vertex_lists_from_gltf(&document, &buffers).unwrap();
+        // Primitives stay apart
+        assert_eq!(vertex_lists.len(), 4);
+
+        let close =
+            |a: [f32; 3], b: [f32; 3]| (Vector3::from(a) - Vector3::from(b)).magnitude() < 1e-5;
+        for nested in &vertex_lists[..2] {
+            let expected = [[1.0, -2.0, 0.0], [2.0, -2.0, 0.0], [1.0, -2.0, 1.0]];
+            for (vertex, expected) in nested.iter().zip(expected) {
+                assert!(close(vertex.pos, expected), "{:?}", vertex.pos);
+                assert!(close(vertex.normal, [0.0, -1.0, 0.0]));
+            }
+        }
+        for untransformed in &vertex_lists[2..] {
+            for (vertex, expected) in untransformed.iter().zip(positions.chunks(3)) {
+                assert_eq!(vertex.pos[..], *expected);
+                assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+            }
+        }
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(dir.join("deeper_nested_test.bin")).unwrap();
+    }
+
+    #[test]
+    fn joints_without_a_matrix_lose_their_weight() {
+        let out_of_range = MAX_NR_OF_JOINTS as u16;
+        assert_eq!(
+            attached_joints([1, 2, 0, 0], [0.5, 0.5, 0.0, 0.0]),
+            ([1, 2, 0, 0], [0.5, 0.5, 0.0, 0.0])
+        );
+        assert_eq!(
+            attached_joints([1, out_of_range, 3, 0], [0.25, 0.5, 0.25, 0.0]),
+            ([1, 0, 3, 0], [0.5, 0.0, 0.5, 0.0])
+        );
+        assert_eq!(
+            attached_joints([out_of_range, 0, 0, 0], [1.0, 0.0, 0.0, 0.0]),
+            ([0; 4], [0.0; 4])
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use entity_smith::Smith;
 use graphics::components::{DynamicModel, StaticModel};
 use graphics::models::ModelRenderPipeline;
@@ -7,8 +9,8 @@ use itertools::Itertools;
 use legion::systems::ParallelRunnable;
 use legion::{Entity, IntoQuery, SystemBuilder};
 
-use crate::components::{DynamicModelRequest, StaticModelRequest};
-use crate::optimizer::StaticMeshOptimizer;
+use crate::components::{DynamicModelRequest, MergedModelRequest, StaticModelRequest};
+use crate::optimizer::{merge_into_one_mesh, StaticMeshOptimizer};
 use crate::{AssetStore, GraphicsAssetManager};
 
 pub trait AssetManagerBuilderExtender {
@@ -17,12 +19,28 @@ pub trait AssetManagerBuilderExtender {
 
 impl AssetManagerBuilderExtender for legion::systems::Builder {
     fn add_assman_systems(&mut self) -> &mut Self {
-        self.add_system(assman_process_dynamic_model_requests())
+        self.add_system(assman_upload_loaded_models())
+            .add_system(assman_process_dynamic_model_requests())
             .add_system(assman_process_static_model_requests())
+            .add_system(assman_process_merged_model_requests())
             .add_system(hot_loading_system())
     }
 }
 
+/// Uploads models parsed in the background, requests for them resolve the same frame
+fn assman_upload_loaded_models() -> impl ParallelRunnable {
+    SystemBuilder::new("upload_loaded_models")
+        .write_resource::<AssetStore>()
+        .write_resource::<GraphicsResources>()
+        .write_resource::<GraphicsContext>()
+        .build(
+            move |_, _, (asset_store, graphics_resources, graphics_context), _| {
+                GraphicsAssetManager::new(asset_store, graphics_resources, graphics_context)
+                    .poll_loaded();
+            },
+        )
+}
+
 fn assman_process_dynamic_model_requests() -> impl ParallelRunnable {
     SystemBuilder::new("process_dynamic_model_requests")
         .write_component::<DynamicModelRequest>()
@@ -111,16 +129,107 @@ fn assman_process_static_model_requests() -> impl ParallelRunnable {
         )
 }
 
+fn assman_process_merged_model_requests() -> impl ParallelRunnable {
+    SystemBuilder::new("process_merged_model_requests")
+        .write_component::<MergedModelRequest>()
+        .write_component::<StaticModel>()
+        .write_resource::<AssetStore>()
+        .write_resource::<GraphicsResources>()
+        .write_resource::<GraphicsContext>()
+        .read_resource::<ModelRenderPipeline>()
+        .with_query(<(Entity, &MergedModelRequest, Option<&StaticModel>)>::query())
+        .build(
+            move |command_buffer,
+                  world,
+                  (asset_store, graphics_resources, graphics_context, model_render_pass),
+                  query| {
+                query.for_each(world, |(entity, request, old)| {
+                    // Looking models up by name is slow and the same few come up thousands of times
+                    let mut indices = HashMap::new();
+                    let parts = request
+                        .parts
+                        .iter()
+                        .map(|(label, model_matrix)| {
+                            let idx = *indices
+                                .entry(label)
+                                .or_insert_with(|| asset_store.get_model_index(label));
+                            idx.map(|idx| (idx, *model_matrix))
+                        })
+                        .collect::<Option<Vec<_>>>();
+
+                    // Wait until every part has loaded
+                    let parts = match parts {
+                        Some(parts) => parts,
+                        None => return,
+                    };
+
+                    // Nothing draws before the commands below swap out the StaticModel,
+                    // so its model can go right away
+                    if let Some(old) = old {
+                        graphics_resources.models.remove(old.idx);
+                    }
+                    if parts.is_empty() {
+                        command_buffer
+                            .forge(*entity)
+                            .remove_component::<StaticModel>()
+                            .remove_component::<MergedModelRequest>();
+                        return;
+                    }
+
+                    let vertices = merge_into_one_mesh(graphics_resources, parts);
+                    let idx = GraphicsAssetManager::new(
+                        asset_store,
+                        graphics_resources,
+                        graphics_context,
+                    )
+                    .allocate_graphics_model_from_vertex_lists(vec![vertices]);
+
+                    command_buffer
+                        .forge(*entity)
+                        .add_component(StaticModel::from_uniforms(
+                            idx,
+                            request.uniforms,
+                            graphics_context,
+                            model_render_pass,
+                        ))
+                        .remove_component::<MergedModelRequest>();
+                })
+            },
+        )
+}
+
+/// Models reload on request, shaders and textures whenever their files change while hot loading is on
 pub fn hot_loading_system() -> impl ParallelRunnable {
     SystemBuilder::new("hot_loading_system")
         .write_resource::<AssetStore>()
         .write_resource::<GraphicsResources>()
         .write_resource::<GraphicsContext>()
+        .write_resource::<ModelRenderPipeline>()
         .read_resource::<CommandManager>()
         .build(
-            move |_, _, (asset_store, graphics_resources, graphics_context, command_manager), _| {
-                if command_manager.get(Command::DevToggleHotLoading) {
-                    //asset_store.load_shaders(shaders_loaded_at, context)
+            move |_,
+                  _,
+                  (
+                asset_store,
+                graphics_resources,
+                graphics_context,
+                model_render_pipeline,
+                command_manager,
+            ),
+                  _| {
+                if command_manager.get(Command::DevToggleHotLoading)
+                    && GraphicsAssetManager::new(asset_store, graphics_resources, graphics_context)
+                        .reload_changed_shaders()
+                {
+                    model_render_pipeline
+                        .reload_shaders(&graphics_context.device, graphics_resources);
+                }
+
+                if command_manager.get(Command::DevToggleHotLoading)
+                    && GraphicsAssetManager::new(asset_store, graphics_resources, graphics_context)
+                        .reload_changed_textures()
+                {
+                    graphics_resources.refresh_materials();
                 }
 
                 if command_manager.get(Command::DevHotLoadModels) {
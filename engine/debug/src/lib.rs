@@ -1,5 +1,7 @@
 #![feature(slice_group_by)]
 
+pub mod stats;
 pub mod timer;
 
+pub use stats::*;
 pub use timer::*;
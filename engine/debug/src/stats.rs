@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Whether the schedule is instrumented, enable the `frame_stats` feature to turn it on.
+/// Without it nothing records into `FrameStats`, so release builds don't pay for the barriers.
+pub const FRAME_STATS: bool = cfg!(feature = "frame_stats");
+
+/// How many frames `FrameStats` remembers
+pub const FRAME_HISTORY: usize = 120;
+
+/// The parts of a frame that are timed separately
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameSection {
+    Physics,
+    Ai,
+    Render,
+    /// Time spent handing command buffers to the GPU, a part of `Render`
+    GpuSubmit,
+}
+
+impl FrameSection {
+    pub const ALL: [FrameSection; 4] = [
+        FrameSection::Physics,
+        FrameSection::Ai,
+        FrameSection::Render,
+        FrameSection::GpuSubmit,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            FrameSection::Physics => "Physics",
+            FrameSection::Ai => "AI",
+            FrameSection::Render => "Render",
+            FrameSection::GpuSubmit => "GPU Submit",
+        }
+    }
+
+    fn index(&self) -> usize { *self as usize }
+}
+
+/// CPU time spent in each section of a single frame
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameTiming {
+    pub frame: Duration,
+    pub sections: [Duration; 4],
+}
+
+impl FrameTiming {
+    pub fn section(&self, section: FrameSection) -> Duration { self.sections[section.index()] }
+}
+
+/// Timings of the last `FRAME_HISTORY` frames, oldest first
+pub struct FrameStats {
+    history: VecDeque<FrameTiming>,
+    current: FrameTiming,
+    started: [Option<Instant>; 4],
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self {
+            history: VecDeque::with_capacity(FRAME_HISTORY),
+            current: Default::default(),
+            started: [None; 4],
+        }
+    }
+}
+
+impl FrameStats {
+    pub fn new() -> Self { Default::default() }
+
+    pub fn begin(&mut self, section: FrameSection) {
+        self.started[section.index()] = Some(Instant::now());
+    }
+
+    /// Does nothing if the section wasn't begun
+    pub fn end(&mut self, section: FrameSection) {
+        if let Some(started) = self.started[section.index()].take() {
+            self.record(section, started.elapsed());
+        }
+    }
+
+    /// Adds to a section of the current frame, for time measured elsewhere
+    pub fn record(&mut self, section: FrameSection, duration: Duration) {
+        self.current.sections[section.index()] += duration;
+    }
+
+    /// Closes the current frame and starts a new one, forgetting the oldest if full
+    pub fn finish_frame(&mut self, frame: Duration) {
+        if self.history.len() == FRAME_HISTORY {
+            self.history.pop_front();
+        }
+
+        self.current.frame = frame;
+        self.history.push_back(self.current);
+        self.current = Default::default();
+        self.started = [None; 4];
+    }
+
+    pub fn history(&self) -> impl ExactSizeIterator<Item = &FrameTiming> { self.history.iter() }
+
+    pub fn latest(&self) -> Option<&FrameTiming> { self.history.back() }
+
+    /// The mean of every remembered frame
+    pub fn average(&self) -> FrameTiming {
+        let mut average = FrameTiming::default();
+        if self.history.is_empty() {
+            return average;
+        }
+
+        let count = self.history.len() as u32;
+        for timing in &self.history {
+            average.frame += timing.frame / count;
+            for (sum, section) in average.sections.iter_mut().zip(&timing.sections) {
+                *sum += *section / count;
+            }
+        }
+        average
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration { Duration::from_millis(millis) }
+
+    #[test]
+    fn history_is_a_ring_buffer() {
+        let mut stats = FrameStats::new();
+
+        for frame in 0..FRAME_HISTORY as u64 + 10 {
+            stats.record(FrameSection::Physics, ms(frame));
+            stats.finish_frame(ms(frame + 1));
+        }
+
+        assert_eq!(stats.history().len(), FRAME_HISTORY);
+        assert_eq!(stats.history().next().unwrap().frame, ms(11));
+        assert_eq!(stats.latest().unwrap().frame, ms(FRAME_HISTORY as u64 + 10));
+    }
+
+    #[test]
+    fn sections_accumulate_within_a_frame() {
+        let mut stats = FrameStats::new();
+
+        stats.record(FrameSection::GpuSubmit, ms(2));
+        stats.record(FrameSection::GpuSubmit, ms(3));
+        // Ending a section that never began doesn't count
+        stats.end(FrameSection::Ai);
+        stats.finish_frame(ms(16));
+
+        let latest = stats.latest().unwrap();
+        assert_eq!(latest.section(FrameSection::GpuSubmit), ms(5));
+        assert_eq!(latest.section(FrameSection::Ai), Duration::default());
+
+        stats.finish_frame(ms(20));
+        assert_eq!(
+            stats.latest().unwrap().section(FrameSection::GpuSubmit),
+            ms(0)
+        );
+        assert_eq!(stats.average().frame, ms(18));
+    }
+}
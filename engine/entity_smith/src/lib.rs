@@ -6,6 +6,45 @@ use legion::Entity;
 
 pub struct FrameTime(pub f32);
 
+/// Turns raw frame durations into the `FrameTime` that systems integrate with.
+///
+/// The raw delta is clamped to `max_delta`, so a single long frame (a disk hitch or a debugger
+/// pause) can't move entities through walls. The trade-off is that when frames are consistently
+/// slower than `max_delta` the game runs in slow motion rather than tunneling.
+pub struct FrameClock {
+    pub max_delta: f32,
+    /// How much of the previous smoothed value is kept each frame, in [0, 1)
+    pub smoothing: f32,
+    smoothed: f32,
+}
+
+impl Default for FrameClock {
+    fn default() -> Self { Self::new(1.0 / 15.0, 0.9) }
+}
+
+impl FrameClock {
+    pub fn new(max_delta: f32, smoothing: f32) -> Self {
+        Self {
+            max_delta,
+            smoothing,
+            smoothed: 0.0,
+        }
+    }
+
+    pub fn tick(&mut self, raw_delta: f32) -> FrameTime {
+        let delta = raw_delta.min(self.max_delta).max(0.0);
+        self.smoothed = if self.smoothed == 0.0 {
+            delta
+        } else {
+            self.smoothing * self.smoothed + (1.0 - self.smoothing) * delta
+        };
+        FrameTime(delta)
+    }
+
+    /// An exponential moving average of the clamped frame times, useful for display
+    pub fn smoothed(&self) -> f32 { self.smoothed }
+}
+
 pub struct Marker;
 
 pub struct Name(String);
@@ -58,7 +97,8 @@ impl<'a> EntitySmith<'a> {
         self.interface.remove_component::<T>(self.entity);
         self
     }
-    pub fn done(&self) {}
+    /// Ends a chain, handing back the entity so it can be pointed at
+    pub fn done(&self) -> Entity { self.entity }
     pub fn get_entity(&self) -> Entity { self.entity }
     pub fn craft(self) -> Self { self }
     pub fn scrap(&mut self) { self.interface.scrap(self.entity); }
@@ -102,3 +142,50 @@ impl<'a> EntitySmith<'a> {
 pub struct Speed(pub f32);
 
 pub struct Acceleration(pub f32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_frames_are_clamped() {
+        let mut clock = FrameClock::default();
+        let velocity = 10.0;
+
+        let mut position = 0.0;
+        position += velocity * clock.tick(2.0).0;
+
+        assert_eq!(position, velocity * clock.max_delta);
+
+        // regular frames are left alone
+        assert_eq!(clock.tick(1.0 / 60.0).0, 1.0 / 60.0);
+    }
+
+    #[test]
+    fn a_finished_chain_hands_back_its_entity() {
+        let mut world = legion::World::default();
+        let mut command_buffer = CommandBuffer::new(&world);
+
+        let target = command_buffer.smith().mark().done();
+        let follower = command_buffer.smith().name("follower").done();
+        command_buffer.flush(&mut world, &mut legion::Resources::default());
+
+        assert_ne!(target, follower);
+        let entry = world.entry(target).unwrap();
+        assert!(entry.get_component::<Marker>().is_ok());
+        assert!(world
+            .entry(follower)
+            .unwrap()
+            .get_component::<Name>()
+            .is_ok());
+    }
+
+    #[test]
+    fn smoothed_frame_time_follows_ticks() {
+        let mut clock = FrameClock::new(1.0, 0.5);
+        clock.tick(0.2);
+        assert_eq!(clock.smoothed(), 0.2);
+        clock.tick(0.4);
+        assert!((clock.smoothed() - 0.3).abs() < 1e-6);
+    }
+}
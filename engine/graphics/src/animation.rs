@@ -0,0 +1,279 @@
+use bytemuck::{Pod, Zeroable};
+use cgmath::{Matrix4, Quaternion, SquareMatrix, Vector3, VectorSpace};
+
+/// The most joints a skinned model can have, has to agree with `MAX_NR_OF_JOINTS` in forward.vert
+pub const MAX_NR_OF_JOINTS: usize = 64;
+
+/// A joint's transform relative to its parent
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct JointTransform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Default for JointTransform {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl JointTransform {
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+pub struct Joint {
+    /// Index of the parent joint, `None` for roots
+    pub parent: Option<usize>,
+    /// Takes a vertex from model space into the joint's space in the bind pose
+    pub inverse_bind: Matrix4<f32>,
+    /// Used for whatever the current clip doesn't animate
+    pub rest: JointTransform,
+}
+
+pub enum Keyframes {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<Quaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+}
+
+/// Keyframes for one property of one joint, `times` and the keyframes line up
+pub struct Channel {
+    pub joint: usize,
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+}
+
+pub struct AnimationClip {
+    pub name: String,
+    /// In seconds
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+/// The joint hierarchy of a skinned model and the clips that animate it.
+/// Vertex `joints` index into `joints` here.
+pub struct Skin {
+    pub joints: Vec<Joint>,
+    pub clips: Vec<AnimationClip>,
+}
+
+impl Skin {
+    pub fn clip_index(&self, name: &str) -> Option<usize> {
+        self.clips.iter().position(|clip| clip.name == name)
+    }
+
+    /// Samples a clip at `time` into one skinning matrix per joint.
+    /// Keyframes are blended linearly, quaternions by normalized lerp.
+    pub fn sample(&self, clip: usize, time: f32) -> Vec<Matrix4<f32>> {
+        let mut locals = self
+            .joints
+            .iter()
+            .map(|joint| joint.rest)
+            .collect::<Vec<_>>();
+
+        if let Some(clip) = self.clips.get(clip) {
+            for channel in &clip.channels {
+                let local = match locals.get_mut(channel.joint) {
+                    Some(local) => local,
+                    None => continue,
+                };
+                let (i, t) = match keyframe_position(&channel.times, time) {
+                    Some(position) => position,
+                    None => continue,
+                };
+
+                match &channel.keyframes {
+                    Keyframes::Translation(values) => {
+                        local.translation = values[i].lerp(values[(i + 1).min(values.len() - 1)], t)
+                    }
+                    Keyframes::Rotation(values) => {
+                        local.rotation = values[i].nlerp(values[(i + 1).min(values.len() - 1)], t)
+                    }
+                    Keyframes::Scale(values) => {
+                        local.scale = values[i].lerp(values[(i + 1).min(values.len() - 1)], t)
+                    }
+                }
+            }
+        }
+
+        let mut globals: Vec<Option<Matrix4<f32>>> = vec![None; self.joints.len()];
+        (0..self.joints.len())
+            .map(|i| self.global_matrix(i, &locals, &mut globals) * self.joints[i].inverse_bind)
+            .collect()
+    }
+
+    // Joints aren't guaranteed to come after their parents, so resolve them on demand
+    fn global_matrix(
+        &self,
+        joint: usize,
+        locals: &[JointTransform],
+        globals: &mut [Option<Matrix4<f32>>],
+    ) -> Matrix4<f32> {
+        if let Some(global) = globals[joint] {
+            return global;
+        }
+
+        let local = locals[joint].matrix();
+        let global = match self.joints[joint].parent {
+            Some(parent) => self.global_matrix(parent, locals, globals) * local,
+            None => local,
+        };
+        globals[joint] = Some(global);
+        global
+    }
+}
+
+/// The keyframe at or before `time` and how far along it is towards the next one
+fn keyframe_position(times: &[f32], time: f32) -> Option<(usize, f32)> {
+    let last = times.len().checked_sub(1)?;
+    let next = times.iter().position(|&t| t > time).unwrap_or(times.len());
+
+    Some(match next {
+        0 => (0, 0.0),
+        next if next > last => (last, 0.0),
+        next => {
+            let (start, end) = (times[next - 1], times[next]);
+            (next - 1, (time - start) / (end - start))
+        }
+    })
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct JointUniforms {
+    pub matrices: [[[f32; 4]; 4]; MAX_NR_OF_JOINTS],
+}
+
+impl Default for JointUniforms {
+    fn default() -> Self {
+        Self {
+            matrices: [Matrix4::identity().into(); MAX_NR_OF_JOINTS],
+        }
+    }
+}
+
+impl JointUniforms {
+    /// Joints past `MAX_NR_OF_JOINTS` are dropped and keep the identity matrix
+    pub fn from_pose(pose: &[Matrix4<f32>]) -> Self {
+        let mut uniforms = Self::default();
+        for (slot, matrix) in uniforms.matrices.iter_mut().zip(pose) {
+            *slot = (*matrix).into();
+        }
+        uniforms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, InnerSpace, Rotation3, Transform, Vector4};
+
+    use super::*;
+    use crate::components::Animator;
+
+    fn arm() -> Skin {
+        let shoulder = JointTransform::default();
+        let elbow = JointTransform {
+            translation: Vector3::new(1.0, 0.0, 0.0),
+            ..Default::default()
+        };
+
+        Skin {
+            joints: vec![
+                // The child comes first to check that ordering doesn't matter
+                Joint {
+                    parent: Some(1),
+                    inverse_bind: Matrix4::from_translation(Vector3::new(-1.0, 0.0, 0.0)),
+                    rest: elbow,
+                },
+                Joint {
+                    parent: None,
+                    inverse_bind: Matrix4::identity(),
+                    rest: shoulder,
+                },
+            ],
+            clips: vec![AnimationClip {
+                name: "raise".to_string(),
+                duration: 1.0,
+                channels: vec![Channel {
+                    joint: 1,
+                    times: vec![0.0, 1.0],
+                    keyframes: Keyframes::Rotation(vec![
+                        Quaternion::from_angle_z(Deg(0.0)),
+                        Quaternion::from_angle_z(Deg(90.0)),
+                    ]),
+                }],
+            }],
+        }
+    }
+
+    fn assert_close(a: Vector4<f32>, b: Vector4<f32>) {
+        assert!((a - b).magnitude() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn bind_pose_is_identity() {
+        let skin = arm();
+        for matrix in skin.sample(0, 0.0) {
+            assert_close(
+                matrix.transform_vector(Vector3::unit_x()).extend(0.0),
+                Vector4::unit_x(),
+            );
+            assert_close(
+                matrix * Vector4::new(2.0, 0.0, 0.0, 1.0),
+                Vector4::new(2.0, 0.0, 0.0, 1.0),
+            );
+        }
+    }
+
+    #[test]
+    fn children_follow_their_parents() {
+        let skin = arm();
+        let hand = Vector4::new(2.0, 0.0, 0.0, 1.0);
+
+        let raised = skin.sample(skin.clip_index("raise").unwrap(), 1.0);
+        assert_close(raised[0] * hand, Vector4::new(0.0, 2.0, 0.0, 1.0));
+
+        // Halfway through, the hand is halfway around the quarter circle
+        let halfway = skin.sample(0, 0.5);
+        let angle = std::f32::consts::FRAC_PI_4;
+        assert_close(
+            halfway[0] * hand,
+            Vector4::new(2.0 * angle.cos(), 2.0 * angle.sin(), 0.0, 1.0),
+        );
+
+        // Past the end it holds the last keyframe
+        assert_close(skin.sample(0, 3.0)[0] * hand, raised[0] * hand);
+    }
+
+    #[test]
+    fn keyframe_positions() {
+        let times = [0.0, 1.0, 3.0];
+        assert_eq!(keyframe_position(&times, -1.0), Some((0, 0.0)));
+        assert_eq!(keyframe_position(&times, 2.0), Some((1, 0.5)));
+        assert_eq!(keyframe_position(&times, 5.0), Some((2, 0.0)));
+        assert_eq!(keyframe_position(&[], 1.0), None);
+    }
+
+    #[test]
+    fn animators_loop_or_hold() {
+        let mut looping = Animator::new(0);
+        looping.advance(2.5, 1.0);
+        assert!((looping.time - 0.5).abs() < 1e-6);
+
+        let mut once = Animator {
+            looping: false,
+            ..Animator::new(0)
+        };
+        once.advance(2.5, 1.0);
+        assert_eq!(once.time, 1.0);
+    }
+}
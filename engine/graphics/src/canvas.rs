@@ -346,6 +346,7 @@ impl CanvasRenderPipeline {
             )
             .into(),
             eye_position: [0.0, 0.0, 1.0, 0.0],
+            ..Default::default()
         };
 
         let global_uniform_buf =
@@ -432,11 +433,7 @@ impl CanvasRenderPipeline {
                     usage: wgpu::BufferUsage::VERTEX,
                 });
 
-        let quad_mesh = super::data::Mesh {
-            num_vertices: 6,
-            vertex_buffer: vertex_buf,
-            offset: [0.0, 0.0, 0.0],
-        };
+        let quad_mesh = super::data::Mesh::new(6, vertex_buf);
 
         Self {
             global_uniform_buf,
@@ -478,7 +475,7 @@ impl CanvasRenderPipeline {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
                 color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &render_context.current_frame.output.view,
+                    attachment: render_context.color_view(),
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Load,
@@ -512,15 +509,14 @@ impl CanvasRenderPipeline {
             }
         }
 
-        render_context
-            .queue
-            .submit(std::iter::once(encoder.finish()));
+        render_context.submit(encoder.finish());
     }
 
     pub fn set_camera(&mut self, queue: &wgpu::Queue, window_size: winit::dpi::PhysicalSize<u32>) {
         let global_uniforms = GlobalUniforms {
             projection_view_matrix: super::util::generate_ortho_matrix(window_size.cast()).into(),
             eye_position: [0.0, 0.0, 1.0, 0.0],
+            ..Default::default()
         };
 
         queue.write_buffer(
@@ -1,15 +1,54 @@
 use std::sync::Arc;
 
-use cgmath::{Matrix4, Vector3};
+use cgmath::{Deg, Matrix4, Vector3};
 use legion::Entity;
 use wgpu::util::DeviceExt;
 
+use crate::animation::JointUniforms;
 use crate::data::{LocalUniforms, Material};
 use crate::models::ModelRenderPipeline;
 use crate::{GraphicsContext, ModelID};
 
+/// How a camera flattens the world onto the screen
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// Things shrink with distance, `fov` is the vertical field of view in degrees
+    Perspective { fov: f32 },
+    /// Things keep their size, `height` is how much of the world fits vertically on screen
+    Orthographic { height: f32 },
+}
+
+impl Default for Projection {
+    fn default() -> Self { Projection::Perspective { fov: 30.0 } }
+}
+
+impl Projection {
+    const NEAR: f32 = 1.0;
+    const FAR: f32 = 1000.0;
+
+    /// The projection matrix in OpenGL clip space, see `util::correction_matrix`
+    pub fn matrix(&self, aspect_ratio: f32) -> Matrix4<f32> {
+        match *self {
+            Projection::Perspective { fov } => {
+                cgmath::perspective(Deg(fov), aspect_ratio, Self::NEAR, Self::FAR)
+            }
+            Projection::Orthographic { height } => {
+                let (half_width, half_height) = (height * aspect_ratio / 2.0, height / 2.0);
+                cgmath::ortho(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    Self::NEAR,
+                    Self::FAR,
+                )
+            }
+        }
+    }
+}
+
 pub struct Camera {
-    pub fov: f32,
+    pub projection: Projection,
     pub up: Vector3<f32>,
     pub roaming: bool,
 }
@@ -22,11 +61,137 @@ pub struct Target {
     pub entity: Entity,
 }
 
+/// Multiplied into the albedo of a model when it is rendered
+pub struct Tint(pub [f32; 4]);
+
+/// What the frame is cleared to before any models are drawn, black by default
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClearColor(pub wgpu::Color);
+
+impl Default for ClearColor {
+    fn default() -> Self { Self(wgpu::Color::BLACK) }
+}
+
+/// Fades models into `color` with their distance from the camera, from nothing at `start`
+/// to `density` at `end`. A density of 0 turns fog off, which is the default.
+/// Set through `ModelRenderPipeline::set_fog`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fog {
+    pub color: Vector3<f32>,
+    pub start: f32,
+    pub end: f32,
+    /// How much of the fog color far away models end up as, between 0 and 1
+    pub density: f32,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self {
+            color: Vector3::new(0.0, 0.0, 0.0),
+            start: 0.0,
+            end: 1.0,
+            density: 0.0,
+        }
+    }
+}
+
+/// Models on lower layers are drawn first, models without one are on layer 0.
+/// Within a layer models keep the order they were queued in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RenderLayer(pub u8);
+
+/// Alpha blends a dynamic model over the opaque ones instead of hiding what's behind it,
+/// e.g. glass or a fading corpse. The alpha comes from the textures and `Tint`.
+/// Transparent models are drawn back to front and don't cast shadows.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Transparent;
+
+/// Keeps a model turned towards the active camera, replacing its rotation when rendered.
+/// The model is expected to face down its local -Y axis with +Z up, like a standing sprite.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Billboard {
+    /// Only turns around the world Z axis, so the model stays upright
+    #[default]
+    Cylindrical,
+    /// Faces the camera head on, tilting with it
+    Spherical,
+}
+
+/// A light shining in all directions from the entity's position
+pub struct PointLight {
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    pub range: f32,
+}
+
+/// A cone of light from the entity's position, e.g. a torch or a lamp.
+/// `direction` is in the entity's local space and `cutoff_angle` is the half-angle of the cone.
+pub struct SpotLight {
+    pub direction: Vector3<f32>,
+    pub cutoff_angle: Deg<f32>,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    pub range: f32,
+}
+
+/// Light from far away that reaches everything equally, like the sun.
+/// `direction` is the way the light travels, in the entity's local space.
+pub struct DirectionalLight {
+    pub direction: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+}
+
+/// Plays a clip from the skin of the entity's `DynamicModel`
+pub struct Animator {
+    pub clip: usize,
+    /// Seconds into the clip
+    pub time: f32,
+    pub speed: f32,
+    pub looping: bool,
+    /// The skinning matrices sampled this frame, one per joint
+    pub pose: Vec<Matrix4<f32>>,
+}
+
+impl Animator {
+    pub fn new(clip: usize) -> Self {
+        Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+            pose: vec![],
+        }
+    }
+
+    /// Plays `clip` from its start, unless it's already playing
+    pub fn play(&mut self, clip: usize) {
+        if self.clip != clip {
+            self.clip = clip;
+            self.time = 0.0;
+        }
+    }
+
+    /// Moves the playhead `delta` seconds along a clip lasting `duration` seconds
+    pub fn advance(&mut self, delta: f32, duration: f32) {
+        self.time += delta * self.speed;
+        if duration <= 0.0 {
+            self.time = 0.0;
+        } else if self.looping {
+            self.time = self.time.rem_euclid(duration);
+        } else {
+            self.time = self.time.clamp(0.0, duration);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DynamicModel {
     pub idx: ModelID,
     pub bind_group: Arc<wgpu::BindGroup>,
     pub buffer: Arc<wgpu::Buffer>,
+    pub joint_bind_group: Arc<wgpu::BindGroup>,
+    pub joint_buffer: Arc<wgpu::Buffer>,
 }
 
 // Note(Jökull): Probably not great to have both constructor and builder patterns
@@ -61,10 +226,28 @@ impl DynamicModel {
                 }],
             },
         ));
+
+        // Starts out in the bind pose, so skinned models without an Animator still look right
+        let joint_buffer = Arc::new(graphics_context.device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Joint Uniforms"),
+                contents: bytemuck::bytes_of(&JointUniforms::default()),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            },
+        ));
+
+        let joint_bind_group = Arc::new(ModelRenderPipeline::create_joint_bind_group(
+            &graphics_context.device,
+            &model_render_pass.joint_bind_group_layout,
+            &joint_buffer,
+        ));
+
         Self {
             idx,
             bind_group,
             buffer,
+            joint_bind_group,
+            joint_buffer,
         }
     }
 }
@@ -73,6 +256,8 @@ impl DynamicModel {
 pub struct StaticModel {
     pub idx: ModelID,
     pub bind_group: Arc<wgpu::BindGroup>,
+    /// The model matrix in the uniforms, kept to cull the model on the CPU
+    pub model_matrix: Matrix4<f32>,
 }
 
 impl StaticModel {
@@ -124,6 +309,10 @@ impl StaticModel {
             },
         ));
 
-        Self { idx, bind_group }
+        Self {
+            idx,
+            bind_group,
+            model_matrix: local_uniforms.model_matrix.into(),
+        }
     }
 }
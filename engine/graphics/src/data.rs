@@ -1,9 +1,12 @@
 #![allow(unused)]
+use std::ops::Range;
+
 use bytemuck::{Pod, Zeroable};
 use cgmath::{Matrix4, Vector3, Vector4};
 use image::{EncodableLayout, GenericImageView};
+use transforms::{Position, Rotation, Scale, Transform};
 
-use crate::MAX_NR_OF_POINT_LIGHTS;
+use crate::{MAX_NR_OF_DIRECTIONAL_LIGHTS, MAX_NR_OF_POINT_LIGHTS};
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
@@ -11,6 +14,11 @@ pub struct Vertex {
     pub pos: [f32; 3],
     pub normal: [f32; 3],
     pub tex_coord: [f32; 2],
+    /// Tangent along increasing u, w holds the handedness of the bitangent
+    pub tangent: [f32; 4],
+    /// Indices into the model's skin, only read if the weights are non-zero
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
 }
 
 impl Vertex {
@@ -27,6 +35,116 @@ impl Vertex {
                     .into()
             },
             tex_coord: self.tex_coord,
+            tangent: {
+                let tangent = Matrix4::from(model_matrix)
+                    * Vector3::new(self.tangent[0], self.tangent[1], self.tangent[2]).extend(0.0);
+                [tangent.x, tangent.y, tangent.z, self.tangent[3]]
+            },
+            joints: self.joints,
+            weights: self.weights,
+        }
+    }
+}
+
+/// How normals are made up for geometry that comes without them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NormalShading {
+    /// Every triangle faces its own way, hard edges everywhere
+    Flat,
+    /// Corners at the same position share the average of the triangles around them,
+    /// weighted by area
+    #[default]
+    Smooth,
+}
+
+/// Fills in the normals of a triangle list that are zero, which is how missing ones are left.
+/// Vertices that already have a normal are kept as they are.
+pub fn compute_normals(vertices: &mut [Vertex], shading: NormalShading) {
+    use std::collections::HashMap;
+
+    use cgmath::{InnerSpace, Zero};
+
+    // Left unnormalized, so larger triangles count for more when smoothing
+    let face_normal = |triangle: &[Vertex]| {
+        let p = |v: &Vertex| Vector3::from(v.pos);
+        (p(&triangle[1]) - p(&triangle[0])).cross(p(&triangle[2]) - p(&triangle[0]))
+    };
+    let key = |v: &Vertex| v.pos.map(f32::to_bits);
+
+    let mut smooth_normals = HashMap::new();
+    if shading == NormalShading::Smooth {
+        for triangle in vertices.chunks_exact(3) {
+            let normal = face_normal(triangle);
+            for vertex in triangle {
+                *smooth_normals
+                    .entry(key(vertex))
+                    .or_insert_with(Vector3::zero) += normal;
+            }
+        }
+    }
+
+    for triangle in vertices.chunks_exact_mut(3) {
+        let flat = face_normal(triangle);
+        for vertex in triangle.iter_mut() {
+            if Vector3::from(vertex.normal) != Vector3::zero() {
+                continue;
+            }
+
+            let normal = match shading {
+                NormalShading::Flat => flat,
+                NormalShading::Smooth => smooth_normals[&key(vertex)],
+            };
+            // Degenerate triangles stay without a normal
+            if normal.magnitude2() > f32::EPSILON * f32::EPSILON {
+                vertex.normal = normal.normalize().into();
+            }
+        }
+    }
+}
+
+/// Fills in per-vertex tangents for a triangle list from its positions and UVs.
+/// Triangles without a usable UV mapping are given a zero tangent, which the
+/// shader treats as "use the geometric normal".
+pub fn compute_tangents(vertices: &mut [Vertex]) {
+    use cgmath::{InnerSpace, Vector2};
+
+    for triangle in vertices.chunks_exact_mut(3) {
+        let p = |v: &Vertex| Vector3::from(v.pos);
+        let uv = |v: &Vertex| Vector2::from(v.tex_coord);
+
+        let edge_1 = p(&triangle[1]) - p(&triangle[0]);
+        let edge_2 = p(&triangle[2]) - p(&triangle[0]);
+        let duv_1 = uv(&triangle[1]) - uv(&triangle[0]);
+        let duv_2 = uv(&triangle[2]) - uv(&triangle[0]);
+
+        let det = duv_1.x * duv_2.y - duv_2.x * duv_1.y;
+
+        if det.abs() < f32::EPSILON {
+            triangle.iter_mut().for_each(|v| v.tangent = [0.0; 4]);
+            continue;
+        }
+
+        let tangent = (edge_1 * duv_2.y - edge_2 * duv_1.y) / det;
+        let bitangent = (edge_2 * duv_1.x - edge_1 * duv_2.x) / det;
+
+        for vertex in triangle.iter_mut() {
+            let normal = Vector3::from(vertex.normal);
+
+            // Gram-Schmidt against the vertex normal so the basis stays orthogonal
+            let t = tangent - normal * normal.dot(tangent);
+            if t.magnitude2() < f32::EPSILON {
+                vertex.tangent = [0.0; 4];
+                continue;
+            }
+            let t = t.normalize();
+
+            let handedness = if normal.cross(t).dot(bitangent) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+
+            vertex.tangent = [t.x, t.y, t.z, handedness];
         }
     }
 }
@@ -36,6 +154,24 @@ impl Vertex {
 pub struct GlobalUniforms {
     pub projection_view_matrix: [[f32; 4]; 4],
     pub eye_position: [f32; 4],
+    /// Only forward.frag reads the fog, see `components::Fog`
+    pub fog_color: [f32; 4],
+    pub fog_start: f32,
+    pub fog_end: f32,
+    pub fog_density: f32,
+    pub _pad: f32,
+}
+
+/// What forward.frag needs to look up the shadow map of the first directional light
+#[repr(C)]
+#[derive(Clone, Copy, Default, Pod, Zeroable)]
+pub struct ShadowUniforms {
+    pub light_view_proj: [[f32; 4]; 4],
+    /// 0 when nothing was rendered into the shadow map this frame
+    pub enabled: u32,
+    /// Size of one shadow map texel in texture coordinates, spaces out the PCF samples
+    pub texel_size: f32,
+    pub _pad: [u32; 2],
 }
 
 #[repr(C)]
@@ -90,8 +226,14 @@ impl Material {
     }
 }
 
+pub const NO_TINT: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
 // TODO: make it so we don't need to manually manage this somehow
-const LU_BYTES: usize = std::mem::size_of::<[[f32; 4]; 4]>() + std::mem::size_of::<Material>();
+const LU_BYTES: usize = std::mem::size_of::<[[f32; 4]; 4]>()
+    + std::mem::size_of::<Material>()
+    + std::mem::size_of::<[f32; 2]>()
+    + std::mem::size_of::<[f32; 4]>()
+    + std::mem::size_of::<[u32; 4]>();
 const LU_ALIGN: usize = wgpu::BIND_BUFFER_ALIGNMENT as usize - LU_BYTES;
 
 #[repr(C)]
@@ -99,6 +241,12 @@ const LU_ALIGN: usize = wgpu::BIND_BUFFER_ALIGNMENT as usize - LU_BYTES;
 pub struct LocalUniforms {
     pub model_matrix: [[f32; 4]; 4],
     pub material: Material,
+    // std140 rounds the Material struct up to 32 bytes, so the tint has to start on the next vec4
+    _material_pad: [f32; 2],
+    pub tint: [f32; 4],
+    /// Non-zero if the model should sample the normal map
+    pub normal_mapped: u32,
+    _normal_mapped_pad: [u32; 3],
     _align: [f64; LU_ALIGN / 8],
 }
 
@@ -107,6 +255,10 @@ impl LocalUniforms {
         Self {
             model_matrix,
             material,
+            _material_pad: [0.0; 2],
+            tint: NO_TINT,
+            normal_mapped: 0,
+            _normal_mapped_pad: [0; 3],
             _align: [0.0; LU_ALIGN / 8],
         }
     }
@@ -117,9 +269,11 @@ impl LocalUniforms {
         z_rotation_deg: f32,
         material: Material,
     ) -> Self {
-        let mut matrix = Matrix4::from_scale(scale);
-        matrix = Matrix4::from_angle_z(cgmath::Deg(z_rotation_deg)) * matrix;
-        matrix = Matrix4::from_translation(translation.into()) * matrix;
+        let matrix = Transform::compose(
+            &Position(translation.into()),
+            &Rotation::from_deg(z_rotation_deg),
+            &Scale::uniform(scale),
+        );
 
         Self::new(matrix.into(), material)
     }
@@ -127,68 +281,308 @@ impl LocalUniforms {
     pub fn with_model_matrix(&self, model_matrix: [[f32; 4]; 4]) -> Self {
         Self {
             model_matrix,
-            material: self.material,
-            _align: self._align,
+            ..*self
         }
     }
 
-    pub fn with_material(&self, material: Material) -> Self {
+    pub fn with_material(&self, material: Material) -> Self { Self { material, ..*self } }
+
+    pub fn with_tint(&self, tint: [f32; 4]) -> Self { Self { tint, ..*self } }
+
+    pub fn with_normal_map(&self, normal_mapped: bool) -> Self {
         Self {
-            model_matrix: self.model_matrix,
-            material,
-            _align: self._align,
+            normal_mapped: normal_mapped as u32,
+            ..*self
         }
     }
 
     /// Used to gauge whether uniforms are equal besides
     /// model_matrix. Can use bevy::reflect to generalize
-    pub fn similar_to(&self, other: &Self) -> bool { self.material == other.material }
+    pub fn similar_to(&self, other: &Self) -> bool {
+        self.material == other.material
+            && self.tint == other.tint
+            && self.normal_mapped == other.normal_mapped
+    }
 
     pub fn init() -> Self {
         use cgmath::SquareMatrix;
-        Self {
-            model_matrix: cgmath::Matrix4::identity().into(),
-            material: Material::default(),
-            _align: [0.0; LU_ALIGN / 8],
-        }
+        Self::new(cgmath::Matrix4::identity().into(), Material::default())
     }
 }
 
+/// Light coming from far away, the same everywhere. `direction` is the way the light travels.
 #[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable, Default)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable, Default)]
 pub struct DirectionalLight {
     pub direction: [f32; 4],
-    pub ambient: [f32; 4],
     pub color: [f32; 4],
 }
 
+pub const LIGHT_KIND_POINT: u32 = 0;
+pub const LIGHT_KIND_SPOT: u32 = 1;
+
+/// A light with a position. Spot lights share these slots with point lights,
+/// told apart by `kind`. The cutoffs are cosines and only used by spot lights.
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable, Default)]
 pub struct PointLight {
     pub radius: f32,
-    pub pad: [f32; 3],
+    pub kind: u32,
+    pub inner_cutoff: f32,
+    pub outer_cutoff: f32,
     pub position: [f32; 4],
     pub color: [f32; 4],
+    pub direction: [f32; 4],
 }
 
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable, Default)]
 pub struct Lights {
-    pub directional_light: DirectionalLight,
+    pub directional_lights: [DirectionalLight; MAX_NR_OF_DIRECTIONAL_LIGHTS],
+    pub nr_of_point_lights: u32,
+    pub nr_of_directional_lights: u32,
+    _pad: [u32; 2],
     pub point_lights: [PointLight; MAX_NR_OF_POINT_LIGHTS],
 }
 
+/// Overrides the model-wide texture and tint for a single mesh,
+/// e.g. so a character's armor can use a different texture than its skin
+///
+/// `texture` is a `TextureID` from `GraphicsResources::textures`, the asset manager's
+/// `AssetStorageInfo::Texture` of a loaded image holds it. Set it through `Mesh::set_material`,
+/// the bind group is built by `ModelRenderPipeline::prepare_mesh_materials` before the mesh is
+/// next drawn. The texture is sampled with the mesh's vertex texture coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshMaterial {
+    pub texture: Option<crate::TextureID>,
+    pub tint: [f32; 4],
+}
+
+impl Default for MeshMaterial {
+    fn default() -> Self {
+        Self {
+            texture: None,
+            tint: NO_TINT,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct MaterialUniforms {
+    pub tint: [f32; 4],
+}
+
 pub struct Mesh {
     pub num_vertices: usize,
     pub vertex_buffer: wgpu::Buffer,
+    /// Meshes with an index buffer are drawn indexed, `num_indices` is only used then
+    pub index_buffer: Option<wgpu::Buffer>,
+    pub num_indices: usize,
     pub offset: [f32; 3],
+    /// Box around the mesh's vertices in model space, a point at the origin if unknown
+    pub aabb: Aabb,
+    /// Meshes without a material use the default one of the render pipeline
+    pub material: Option<MeshMaterial>,
+    pub(crate) material_bind_group: Option<wgpu::BindGroup>,
+}
+
+impl Mesh {
+    pub fn new(num_vertices: usize, vertex_buffer: wgpu::Buffer) -> Self {
+        Self {
+            num_vertices,
+            vertex_buffer,
+            index_buffer: None,
+            num_indices: 0,
+            offset: [0.0, 0.0, 0.0],
+            aabb: Aabb::from_vertices(&[]),
+            material: None,
+            material_bind_group: None,
+        }
+    }
+
+    /// `index_buffer` holds `num_indices` u32 indices into the vertex buffer
+    pub fn indexed(
+        num_vertices: usize,
+        vertex_buffer: wgpu::Buffer,
+        num_indices: usize,
+        index_buffer: wgpu::Buffer,
+    ) -> Self {
+        Self {
+            index_buffer: Some(index_buffer),
+            num_indices,
+            ..Self::new(num_vertices, vertex_buffer)
+        }
+    }
+
+    pub(crate) fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        self.draw_instances(render_pass, 0..1);
+    }
+
+    /// Instanced pipelines read their per-instance data from vertex buffer 1, which has to be
+    /// set before drawing
+    pub(crate) fn draw_instances<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        instances: Range<u32>,
+    ) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        match &self.index_buffer {
+            Some(index_buffer) => {
+                render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..self.num_indices as u32, 0, instances)
+            }
+            None => render_pass.draw(0..self.num_vertices as u32, instances),
+        }
+    }
+
+    /// The bind group is rebuilt from the new material before the mesh is next drawn
+    pub fn set_material(&mut self, material: Option<MeshMaterial>) {
+        self.material = material;
+        self.material_bind_group = None;
+    }
 }
 
 pub type VertexLists = Vec<Vec<Vertex>>;
 
+/// An axis aligned box around vertices, e.g. for picking or framing a model with the camera
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    /// No vertices give a point at the origin
+    pub fn from_vertices<'a>(vertices: impl IntoIterator<Item = &'a Vertex>) -> Self {
+        Self::from_positions(vertices.into_iter().map(|vertex| Vector3::from(vertex.pos)))
+    }
+
+    pub fn from_vertex_lists(vertex_lists: &[Vec<Vertex>]) -> Self {
+        Self::from_vertices(vertex_lists.iter().flatten())
+    }
+
+    fn from_positions(positions: impl IntoIterator<Item = Vector3<f32>>) -> Self {
+        let mut positions = positions.into_iter();
+
+        let first = match positions.next() {
+            Some(first) => first,
+            None => {
+                let origin = Vector3::new(0.0, 0.0, 0.0);
+                return Self {
+                    min: origin,
+                    max: origin,
+                };
+            }
+        };
+
+        positions.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |aabb, pos| Self {
+                min: Vector3::new(
+                    aabb.min.x.min(pos.x),
+                    aabb.min.y.min(pos.y),
+                    aabb.min.z.min(pos.z),
+                ),
+                max: Vector3::new(
+                    aabb.max.x.max(pos.x),
+                    aabb.max.y.max(pos.y),
+                    aabb.max.z.max(pos.z),
+                ),
+            },
+        )
+    }
+
+    pub fn center(&self) -> Vector3<f32> { (self.min + self.max) / 2.0 }
+
+    pub fn size(&self) -> Vector3<f32> { self.max - self.min }
+
+    /// From the center to the corners
+    pub fn radius(&self) -> f32 {
+        use cgmath::InnerSpace;
+
+        (self.size() / 2.0).magnitude()
+    }
+
+    /// The box around this one once `model_matrix` is applied to it,
+    /// rotations make it looser than a box around the transformed vertices
+    pub fn transformed(&self, model_matrix: Matrix4<f32>) -> Self {
+        let corners = (0..8).map(|i| {
+            let corner = Vector3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            (model_matrix * corner.extend(1.0)).truncate()
+        });
+
+        Self::from_positions(corners)
+    }
+}
+
+/// A sphere around every vertex of a model, used to skip models the camera can't see
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vector3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    /// Centered on the box around the vertices, empty lists give a point at the origin
+    pub fn from_vertex_lists(vertex_lists: &[Vec<Vertex>]) -> Self {
+        use cgmath::InnerSpace;
+
+        let center = Aabb::from_vertex_lists(vertex_lists).center();
+        let radius = vertex_lists
+            .iter()
+            .flatten()
+            .map(|vertex| (Vector3::from(vertex.pos) - center).magnitude())
+            .fold(0.0, f32::max);
+
+        Self { center, radius }
+    }
+
+    /// The sphere around the model once `model_matrix` is applied to it
+    pub fn transformed(&self, model_matrix: Matrix4<f32>) -> Self {
+        use cgmath::InnerSpace;
+
+        let scale = [model_matrix.x, model_matrix.y, model_matrix.z]
+            .iter()
+            .map(|axis| axis.truncate().magnitude())
+            .fold(0.0, f32::max);
+
+        Self {
+            center: (model_matrix * self.center.extend(1.0)).truncate(),
+            radius: self.radius * scale,
+        }
+    }
+}
+
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub vertex_lists: VertexLists,
+    /// Bounds of `vertex_lists` in the bind pose
+    pub bounds: BoundingSphere,
+    /// Box around `vertex_lists` in the bind pose
+    pub aabb: Aabb,
+    /// Joints and animations, for models that can be posed by an `Animator`
+    pub skin: Option<std::sync::Arc<crate::animation::Skin>>,
+}
+
+impl Model {
+    /// Without a skin, the bounds are worked out from `vertex_lists`
+    pub fn new(meshes: Vec<Mesh>, vertex_lists: VertexLists) -> Self {
+        Self {
+            meshes,
+            bounds: BoundingSphere::from_vertex_lists(&vertex_lists),
+            aabb: Aabb::from_vertex_lists(&vertex_lists),
+            vertex_lists,
+            skin: None,
+        }
+    }
 }
 
 pub struct Texture {
@@ -273,3 +667,219 @@ impl Texture {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+
+    use super::*;
+
+    #[test]
+    fn local_uniforms_layout() {
+        assert_eq!(
+            std::mem::size_of::<LocalUniforms>(),
+            wgpu::BIND_BUFFER_ALIGNMENT as usize
+        );
+
+        let uniforms = LocalUniforms::init();
+        let base = &uniforms as *const _ as usize;
+        let tint_offset = &uniforms.tint as *const _ as usize - base;
+        let normal_mapped_offset = &uniforms.normal_mapped as *const _ as usize - base;
+
+        // Matches the std140 offsets of `u_Tint` and `u_NormalMapped` in forward.frag
+        assert_eq!(tint_offset, 96);
+        assert_eq!(normal_mapped_offset, 112);
+        assert_eq!(uniforms.tint, NO_TINT);
+        assert_eq!(uniforms.normal_mapped, 0);
+    }
+
+    fn vertex(pos: [f32; 3], tex_coord: [f32; 2]) -> Vertex {
+        Vertex {
+            pos,
+            normal: [0.0, 0.0, 1.0],
+            tex_coord,
+            tangent: [0.0; 4],
+            joints: [0; 4],
+            weights: [0.0; 4],
+        }
+    }
+
+    /// Two triangles folded 90 degrees along the edge they share, the x axis
+    fn fold() -> Vec<Vertex> {
+        let corner = |pos| Vertex {
+            normal: [0.0; 3],
+            ..vertex(pos, [0.0, 0.0])
+        };
+        vec![
+            corner([0.0, 0.0, 0.0]),
+            corner([1.0, 0.0, 0.0]),
+            corner([0.0, 1.0, 0.0]),
+            corner([1.0, 0.0, 0.0]),
+            corner([0.0, 0.0, 0.0]),
+            corner([0.0, 0.0, 1.0]),
+        ]
+    }
+
+    #[test]
+    fn global_uniforms_layout() {
+        let uniforms = GlobalUniforms::default();
+        let base = &uniforms as *const _ as usize;
+        let offset_of = |field: usize| field - base;
+
+        // Matches the std140 offsets of the fog in forward.frag
+        assert_eq!(offset_of(&uniforms.fog_color as *const _ as usize), 80);
+        assert_eq!(offset_of(&uniforms.fog_start as *const _ as usize), 96);
+        assert_eq!(offset_of(&uniforms.fog_density as *const _ as usize), 104);
+        assert_eq!(std::mem::size_of::<GlobalUniforms>(), 112);
+    }
+
+    #[test]
+    fn missing_normals_are_computed() {
+        let mut flat = fold();
+        compute_normals(&mut flat, NormalShading::Flat);
+        assert!(flat[..3].iter().all(|v| v.normal == [0.0, 0.0, 1.0]));
+        assert!(flat[3..].iter().all(|v| v.normal == [0.0, 1.0, 0.0]));
+
+        let mut smooth = fold();
+        compute_normals(&mut smooth, NormalShading::Smooth);
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        for v in &smooth {
+            let expected = match v.pos {
+                // Only touches the one triangle
+                [0.0, 1.0, 0.0] => [0.0, 0.0, 1.0],
+                [0.0, 0.0, 1.0] => [0.0, 1.0, 0.0],
+                _ => [0.0, half, half],
+            };
+            assert!((Vector3::from(v.normal) - Vector3::from(expected)).magnitude() < 1e-6);
+        }
+
+        // Normals that came with the geometry are left alone
+        let mut given = fold();
+        given[0].normal = [1.0, 0.0, 0.0];
+        compute_normals(&mut given, NormalShading::Smooth);
+        assert_eq!(given[0].normal, [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn tangents_follow_u() {
+        let mut triangle = [
+            vertex([0.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([2.0, 0.0, 0.0], [1.0, 0.0]),
+            vertex([0.0, 2.0, 0.0], [0.0, 1.0]),
+        ];
+        compute_tangents(&mut triangle);
+
+        for v in &triangle {
+            assert_eq!(v.tangent, [1.0, 0.0, 0.0, 1.0]);
+        }
+
+        // Mirroring the UVs flips the handedness
+        let mut mirrored = [
+            vertex([0.0, 0.0, 0.0], [0.0, 1.0]),
+            vertex([2.0, 0.0, 0.0], [1.0, 1.0]),
+            vertex([0.0, 2.0, 0.0], [0.0, 0.0]),
+        ];
+        compute_tangents(&mut mirrored);
+
+        for v in &mirrored {
+            assert_eq!(v.tangent, [1.0, 0.0, 0.0, -1.0]);
+        }
+    }
+
+    #[test]
+    fn degenerate_uvs_give_no_tangent() {
+        let mut triangle = [
+            vertex([0.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([1.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([0.0, 1.0, 0.0], [0.0, 0.0]),
+        ];
+        compute_tangents(&mut triangle);
+
+        for v in &triangle {
+            assert_eq!(v.tangent, [0.0; 4]);
+        }
+    }
+
+    #[test]
+    fn bounding_sphere_follows_the_model() {
+        let vertex_lists = vec![
+            vec![
+                vertex([0.0, 0.0, 0.0], [0.0; 2]),
+                vertex([2.0, 0.0, 0.0], [0.0; 2]),
+            ],
+            vec![vertex([2.0, 2.0, 0.0], [0.0; 2])],
+        ];
+        let bounds = BoundingSphere::from_vertex_lists(&vertex_lists);
+
+        assert_eq!(bounds.center, Vector3::new(1.0, 1.0, 0.0));
+        assert!((bounds.radius - 2.0f32.sqrt()).abs() < 1e-6);
+
+        let moved = bounds.transformed(
+            Matrix4::from_translation(Vector3::new(0.0, 0.0, 5.0)) * Matrix4::from_scale(3.0),
+        );
+        assert_eq!(moved.center, Vector3::new(3.0, 3.0, 5.0));
+        assert!((moved.radius - 3.0 * 2.0f32.sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn aabb_fits_the_vertices() {
+        let vertex_lists = vec![
+            vec![
+                vertex([-1.0, 0.0, 2.0], [0.0; 2]),
+                vertex([3.0, -2.0, 0.0], [0.0; 2]),
+            ],
+            vec![vertex([0.0, 4.0, 1.0], [0.0; 2])],
+        ];
+        let aabb = Aabb::from_vertex_lists(&vertex_lists);
+
+        assert_eq!(aabb.min, Vector3::new(-1.0, -2.0, 0.0));
+        assert_eq!(aabb.max, Vector3::new(3.0, 4.0, 2.0));
+        assert_eq!(aabb.center(), Vector3::new(1.0, 1.0, 1.0));
+        assert!((aabb.radius() - 14.0f32.sqrt()).abs() < 1e-6);
+
+        // A quarter turn around z swaps the x and y extents
+        let turned = aabb.transformed(Matrix4::from_angle_z(cgmath::Deg(90.0)));
+        assert!((turned.min - Vector3::new(-4.0, -1.0, 0.0)).magnitude() < 1e-5);
+        assert!((turned.max - Vector3::new(2.0, 3.0, 2.0)).magnitude() < 1e-5);
+
+        assert_eq!(
+            Aabb::from_vertex_lists(&[]).size(),
+            Vector3::new(0.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn lights_layout() {
+        // These offsets have to agree with the std140 layout of `Lights` in forward.frag
+        assert_eq!(std::mem::size_of::<DirectionalLight>(), 32);
+        assert_eq!(std::mem::size_of::<PointLight>(), 64);
+
+        let lights = Lights::default();
+        let base = &lights as *const _ as usize;
+        let offset_of = |field: usize| field - base;
+
+        let directional = 32 * MAX_NR_OF_DIRECTIONAL_LIGHTS;
+        assert_eq!(
+            offset_of(&lights.nr_of_point_lights as *const _ as usize),
+            directional
+        );
+        assert_eq!(
+            offset_of(&lights.nr_of_directional_lights as *const _ as usize),
+            directional + 4
+        );
+        assert_eq!(
+            offset_of(&lights.point_lights as *const _ as usize),
+            directional + 16
+        );
+        assert_eq!(
+            std::mem::size_of::<Lights>(),
+            directional + 16 + 64 * MAX_NR_OF_POINT_LIGHTS
+        );
+
+        let light = PointLight::default();
+        let base = &light as *const _ as usize;
+        assert_eq!(&light.position as *const _ as usize - base, 16);
+        assert_eq!(&light.color as *const _ as usize - base, 32);
+        assert_eq!(&light.direction as *const _ as usize - base, 48);
+    }
+}
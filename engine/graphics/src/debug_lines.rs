@@ -0,0 +1,311 @@
+use bytemuck::{Pod, Zeroable};
+use cgmath::{Vector2, Vector3};
+use wgpu::util::DeviceExt;
+
+use crate::components::Camera;
+use crate::data::GlobalUniforms;
+use crate::{GraphicsContext, GraphicsResources, RenderContext};
+
+/// How many segments a circle is drawn with
+const CIRCLE_SEGMENTS: usize = 32;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+pub struct DebugLineVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+/// World space line segments to draw over this frame, a resource.
+/// Anything can annotate the scene with it, it's emptied once the frame is rendered.
+#[derive(Default)]
+pub struct DebugLines {
+    vertices: Vec<DebugLineVertex>,
+}
+
+impl DebugLines {
+    pub fn new() -> Self { Default::default() }
+
+    pub fn push_line(&mut self, from: Vector3<f32>, to: Vector3<f32>, color: Vector3<f32>) {
+        for position in [from, to] {
+            self.vertices.push(DebugLineVertex {
+                position: position.into(),
+                color: color.into(),
+            });
+        }
+    }
+
+    /// A circle lying in the ground plane at the height of its center
+    pub fn push_circle(&mut self, center: Vector3<f32>, radius: f32, color: Vector3<f32>) {
+        let point = |i: usize| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+            center + Vector2::new(angle.cos(), angle.sin()).extend(0.0) * radius
+        };
+
+        for i in 0..CIRCLE_SEGMENTS {
+            self.push_line(point(i), point(i + 1), color);
+        }
+    }
+
+    /// The 12 edges of the axis aligned box between two opposite corners
+    pub fn push_aabb(&mut self, min: Vector3<f32>, max: Vector3<f32>, color: Vector3<f32>) {
+        let corner = |x: bool, y: bool, z: bool| {
+            Vector3::new(
+                if x { max.x } else { min.x },
+                if y { max.y } else { min.y },
+                if z { max.z } else { min.z },
+            )
+        };
+
+        for a in [false, true] {
+            for b in [false, true] {
+                self.push_line(corner(false, a, b), corner(true, a, b), color);
+                self.push_line(corner(a, false, b), corner(a, true, b), color);
+                self.push_line(corner(a, b, false), corner(a, b, true), color);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) { self.vertices.clear(); }
+
+    pub fn is_empty(&self) -> bool { self.vertices.is_empty() }
+
+    /// Two vertices per line
+    pub fn vertices(&self) -> &[DebugLineVertex] { &self.vertices }
+}
+
+/// Draws `DebugLines` unlit and without depth testing, so they show through walls
+pub struct DebugLineRenderPipeline {
+    global_uniform_buf: wgpu::Buffer,
+    global_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+
+    vertex_buf: wgpu::Buffer,
+    /// How many vertices fit in `vertex_buf`, it grows when a frame has more
+    capacity: usize,
+}
+
+impl DebugLineRenderPipeline {
+    const INITIAL_CAPACITY: usize = 1024;
+
+    pub fn new(graphics_context: &GraphicsContext, graphics_resources: &GraphicsResources) -> Self {
+        let device = &graphics_context.device;
+
+        let global_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let global_uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Debug Line Uniforms"),
+            contents: bytemuck::bytes_of(&GlobalUniforms::default()),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let global_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &global_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &global_uniform_buf,
+                    offset: 0,
+                    size: None,
+                },
+            }],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&global_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Debug Lines"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &graphics_resources.shaders["lines.vert"],
+                entry_point: "main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<DebugLineVertex>() as u64,
+                    step_mode: wgpu::InputStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3],
+                }],
+            },
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            fragment: Some(wgpu::FragmentState {
+                module: &graphics_resources.shaders["lines.frag"],
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: super::COLOR_FORMAT,
+                    alpha_blend: wgpu::BlendState::REPLACE,
+                    color_blend: wgpu::BlendState::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        Self {
+            global_uniform_buf,
+            global_bind_group,
+            pipeline,
+            vertex_buf: Self::vertex_buffer(device, Self::INITIAL_CAPACITY),
+            capacity: Self::INITIAL_CAPACITY,
+        }
+    }
+
+    fn vertex_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Debug Line Vertices"),
+            size: (capacity * std::mem::size_of::<DebugLineVertex>()) as u64,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Looks at the scene the same way `ModelRenderPipeline::set_camera` does
+    pub fn set_camera(
+        &self,
+        graphics_context: &GraphicsContext,
+        camera: &Camera,
+        position: Vector3<f32>,
+        target: Vector3<f32>,
+    ) {
+        let proj_view_matrix = super::util::generate_view_matrix(
+            camera,
+            position,
+            target,
+            graphics_context.window_size.width as f32 / graphics_context.window_size.height as f32,
+        );
+
+        graphics_context.queue.write_buffer(
+            &self.global_uniform_buf,
+            0,
+            bytemuck::bytes_of(&GlobalUniforms {
+                projection_view_matrix: proj_view_matrix.into(),
+                eye_position: position.extend(0.0).into(),
+                ..Default::default()
+            }),
+        );
+    }
+
+    /// Draws the lines over what's already in the frame
+    pub fn render(&mut self, render_context: &RenderContext, debug_lines: &DebugLines) {
+        if debug_lines.is_empty() {
+            return;
+        }
+
+        let vertices = debug_lines.vertices();
+        if vertices.len() > self.capacity {
+            self.capacity = vertices.len().next_power_of_two();
+            self.vertex_buf = Self::vertex_buffer(render_context.device, self.capacity);
+        }
+        render_context
+            .queue
+            .write_buffer(&self.vertex_buf, 0, bytemuck::cast_slice(vertices));
+
+        let mut encoder =
+            render_context
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Debug Line Render"),
+                });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: render_context.color_view(),
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.global_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.vertex_buf.slice(..));
+            render_pass.draw(0..vertices.len() as u32, 0..1);
+        }
+
+        render_context.submit(encoder.finish());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+
+    use super::*;
+
+    fn lines(debug_lines: &DebugLines) -> Vec<(Vector3<f32>, Vector3<f32>)> {
+        debug_lines
+            .vertices()
+            .chunks(2)
+            .map(|line| (line[0].position.into(), line[1].position.into()))
+            .collect()
+    }
+
+    #[test]
+    fn circles_close_around_their_center() {
+        let mut debug_lines = DebugLines::new();
+        let center = Vector3::new(1.0, 2.0, 0.5);
+        debug_lines.push_circle(center, 2.0, Vector3::new(1.0, 0.0, 0.0));
+
+        let lines = lines(&debug_lines);
+        assert_eq!(lines.len(), CIRCLE_SEGMENTS);
+        for (i, (from, to)) in lines.iter().enumerate() {
+            assert!(((from - center).magnitude() - 2.0).abs() < 1e-5);
+            assert_eq!(from.z, center.z);
+            // Each segment starts where the one before it ended
+            let (_, previous_end) = lines[(i + CIRCLE_SEGMENTS - 1) % CIRCLE_SEGMENTS];
+            assert!((previous_end - from).magnitude() < 1e-5);
+            assert!((to - from).magnitude() > 0.0);
+        }
+    }
+
+    #[test]
+    fn aabbs_have_twelve_axis_aligned_edges() {
+        let mut debug_lines = DebugLines::new();
+        let (min, max) = (Vector3::new(-1.0, 0.0, 0.0), Vector3::new(1.0, 2.0, 3.0));
+        debug_lines.push_aabb(min, max, Vector3::new(0.0, 1.0, 0.0));
+
+        let lines = lines(&debug_lines);
+        assert_eq!(lines.len(), 12);
+        for (from, to) in lines {
+            let along = to - from;
+            let axes = [along.x, along.y, along.z]
+                .iter()
+                .filter(|length| **length != 0.0)
+                .count();
+            assert_eq!(axes, 1);
+            for corner in [from, to] {
+                assert!(corner.x == min.x || corner.x == max.x);
+                assert!(corner.y == min.y || corner.y == max.y);
+                assert!(corner.z == min.z || corner.z == max.z);
+            }
+        }
+
+        debug_lines.clear();
+        assert!(debug_lines.is_empty());
+    }
+}
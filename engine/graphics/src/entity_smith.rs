@@ -0,0 +1,16 @@
+use entity_smith::EntitySmith;
+use legion::Entity;
+
+use crate::components::{Camera, Target, Tint};
+
+pub trait GraphicsEntitySmith {
+    fn camera(&mut self, camera: Camera) -> &mut Self;
+    fn target(&mut self, entity: Entity) -> &mut Self;
+    fn tint(&mut self, tint: [f32; 4]) -> &mut Self;
+}
+
+impl<'a> GraphicsEntitySmith for EntitySmith<'a> {
+    fn camera(&mut self, camera: Camera) -> &mut Self { self.add_component(camera) }
+    fn target(&mut self, entity: Entity) -> &mut Self { self.add_component(Target { entity }) }
+    fn tint(&mut self, tint: [f32; 4]) -> &mut Self { self.add_component(Tint(tint)) }
+}
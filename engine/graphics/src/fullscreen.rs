@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use winit::monitor::VideoMode;
+use winit::window::Window;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum Fullscreen {
+    #[default]
+    Windowed,
+    /// Covers the monitor the window is on without changing its video mode
+    Borderless,
+    /// Switches the monitor to a video mode of this resolution
+    Exclusive {
+        resolution: (u32, u32),
+    },
+}
+
+impl Fullscreen {
+    /// Applies the mode to the window and returns the one that was actually used.
+    /// Exclusive fullscreen falls back to borderless if the monitor has no matching video mode.
+    /// The window is resized by this, which arrives as a `WindowEvent::Resized` later.
+    pub fn apply(self, window: &Window) -> Self {
+        match self {
+            Fullscreen::Windowed => {
+                window.set_fullscreen(None);
+                self
+            }
+            Fullscreen::Borderless => {
+                window.set_fullscreen(Some(winit::window::Fullscreen::Borderless(
+                    window.current_monitor(),
+                )));
+                self
+            }
+            Fullscreen::Exclusive { resolution } => {
+                let modes = window
+                    .current_monitor()
+                    .map(|monitor| monitor.video_modes().collect())
+                    .unwrap_or_default();
+
+                match best_video_mode(modes, resolution, |mode: &VideoMode| {
+                    let size = mode.size();
+                    ((size.width, size.height), mode.refresh_rate())
+                }) {
+                    Some(mode) => {
+                        window.set_fullscreen(Some(winit::window::Fullscreen::Exclusive(mode)));
+                        self
+                    }
+                    None => {
+                        eprintln!(
+                            "[graphics] (warning): No {}x{} video mode, using borderless fullscreen",
+                            resolution.0, resolution.1
+                        );
+                        Fullscreen::Borderless.apply(window)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The mode with the highest refresh rate among those with exactly `resolution`.
+/// `describe` gives the size and refresh rate of a mode.
+fn best_video_mode<T>(
+    modes: Vec<T>,
+    resolution: (u32, u32),
+    describe: impl Fn(&T) -> ((u32, u32), u16),
+) -> Option<T> {
+    modes
+        .into_iter()
+        .filter(|mode| describe(mode).0 == resolution)
+        .max_by_key(|mode| describe(mode).1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_fastest_matching_video_mode() {
+        let modes = vec![
+            ((1920, 1080), 60),
+            ((1920, 1080), 144),
+            ((2560, 1440), 165),
+            ((1280, 720), 240),
+        ];
+
+        assert_eq!(
+            best_video_mode(modes.clone(), (1920, 1080), |mode| *mode),
+            Some(((1920, 1080), 144))
+        );
+        assert_eq!(best_video_mode(modes, (800, 600), |mode| *mode), None);
+    }
+}
@@ -1,7 +1,9 @@
 // Welcome to crazy-land
 
-use debug::{DebugTimerInfo, TimerInfo};
-use imgui::TreeNode;
+pub mod text;
+
+use debug::{DebugTimerInfo, FrameSection, FrameStats, TimerInfo};
+use imgui::{PlotLines, TreeNode};
 
 use crate::RenderContext;
 
@@ -104,7 +106,7 @@ impl GuiRenderPipeline {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: None,
             color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                attachment: &render_context.current_frame.output.view,
+                attachment: render_context.color_view(),
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Load,
@@ -125,9 +127,7 @@ impl GuiRenderPipeline {
 
         drop(render_pass);
 
-        render_context
-            .queue
-            .submit(std::iter::once(encoder.finish()));
+        render_context.submit(encoder.finish());
     }
 
     pub fn debug_render(
@@ -164,6 +164,37 @@ impl GuiRenderPipeline {
         self.render(window, render_context);
     }
 
+    /// Graphs the remembered frame times, lists the average of each section and how many
+    /// models were culled last frame
+    pub fn frame_stats_window(stats: &FrameStats, culled_models: usize) {
+        use imgui::im_str;
+        Self::with_ui(|ui| {
+            let frame_times = stats
+                .history()
+                .map(|timing| timing.frame.as_secs_f32() * 1000.0)
+                .collect::<Vec<_>>();
+            let average = stats.average();
+
+            imgui::Window::new(im_str!("Frame Stats"))
+                .always_auto_resize(true)
+                .build(ui, || {
+                    PlotLines::new(ui, im_str!("Frame (ms)"), &frame_times)
+                        .scale_min(0.0)
+                        .graph_size([240.0, 60.0])
+                        .build();
+                    ui.text(format!("Frame : {:?}", average.frame));
+                    for section in FrameSection::ALL.iter() {
+                        ui.text(format!(
+                            "{} : {:?}",
+                            section.label(),
+                            average.section(*section)
+                        ));
+                    }
+                    ui.text(format!("Culled models : {}", culled_models));
+                });
+        });
+    }
+
     pub fn wants_input(&self) -> bool {
         let io = self.imgui_ctx.io();
         io.want_capture_mouse || io.want_capture_keyboard || io.want_text_input
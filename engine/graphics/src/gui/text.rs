@@ -0,0 +1,159 @@
+use cgmath::{Vector2, Vector4};
+
+use crate::sprites::{Sprite, SpriteBatch};
+use crate::{GraphicsResources, TextureID};
+
+/// The atlas is a grid of equally sized cells holding the printable ASCII characters,
+/// left to right and top to bottom starting at the space
+const COLUMNS: u32 = 16;
+const ROWS: u32 = 6;
+const FIRST_GLYPH: char = ' ';
+const LAST_GLYPH: char = '~';
+/// Stands in for characters the atlas doesn't have
+const MISSING_GLYPH: char = '?';
+
+/// Lays out text as monospaced glyph quads for the `SpriteBatch`, in screen space
+pub struct TextRenderer {
+    atlas: TextureID,
+    /// Width of a glyph for every pixel of height
+    glyph_aspect: f32,
+}
+
+impl TextRenderer {
+    /// None if the atlas texture isn't loaded
+    pub fn new(atlas: TextureID, graphics_resources: &GraphicsResources) -> Option<Self> {
+        let size = graphics_resources.textures.get(atlas)?.texture_size;
+        let cell_width = size.width as f32 / COLUMNS as f32;
+        let cell_height = size.height as f32 / ROWS as f32;
+
+        Some(Self {
+            atlas,
+            glyph_aspect: cell_width / cell_height,
+        })
+    }
+
+    /// Width and height in pixels of `text` drawn with lines `size` pixels tall
+    pub fn measure(&self, text: &str, size: f32) -> Vector2<f32> {
+        let (lines, longest) = text.split('\n').fold((0, 0), |(lines, longest), line| {
+            (lines + 1, longest.max(line.chars().count()))
+        });
+
+        Vector2::new(longest as f32 * self.glyph_width(size), lines as f32 * size)
+    }
+
+    /// One sprite per visible character, `position` being the top left of the first line
+    pub fn layout<'a>(
+        &'a self,
+        text: &'a str,
+        position: Vector2<f32>,
+        size: f32,
+        color: Vector4<f32>,
+    ) -> impl Iterator<Item = Sprite> + 'a {
+        let glyph_size = Vector2::new(self.glyph_width(size), size);
+
+        text.split('\n').enumerate().flat_map(move |(row, line)| {
+            line.chars()
+                .enumerate()
+                .filter(|(_, c)| !c.is_whitespace())
+                .map(move |(column, c)| Sprite {
+                    position: position
+                        + Vector2::new(column as f32 * glyph_size.x, row as f32 * size),
+                    size: glyph_size,
+                    uv_rect: Self::uv_rect(c),
+                    tint: color,
+                    texture: Some(self.atlas),
+                })
+        })
+    }
+
+    pub fn draw(
+        &self,
+        sprite_batch: &mut SpriteBatch,
+        text: &str,
+        position: Vector2<f32>,
+        size: f32,
+        color: Vector4<f32>,
+    ) {
+        for sprite in self.layout(text, position, size, color) {
+            sprite_batch.draw(sprite);
+        }
+    }
+
+    fn glyph_width(&self, size: f32) -> f32 { size * self.glyph_aspect }
+
+    fn uv_rect(c: char) -> Vector4<f32> {
+        let c = if (FIRST_GLYPH..=LAST_GLYPH).contains(&c) {
+            c
+        } else {
+            MISSING_GLYPH
+        };
+        let index = c as u32 - FIRST_GLYPH as u32;
+        let (column, row) = ((index % COLUMNS) as f32, (index / COLUMNS) as f32);
+        let (width, height) = (1.0 / COLUMNS as f32, 1.0 / ROWS as f32);
+
+        Vector4::new(
+            column * width,
+            row * height,
+            (column + 1.0) * width,
+            (row + 1.0) * height,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slotmap::SlotMap;
+
+    use super::*;
+
+    fn text_renderer() -> TextRenderer {
+        TextRenderer {
+            atlas: SlotMap::<TextureID, ()>::new().insert(()),
+            glyph_aspect: 0.5,
+        }
+    }
+
+    #[test]
+    fn newlines_start_over_below_the_anchor() {
+        let text_renderer = text_renderer();
+        let white = Vector4::new(1.0, 1.0, 1.0, 1.0);
+        let sprites = text_renderer
+            .layout("ab\nc d", Vector2::new(10.0, 20.0), 16.0, white)
+            .collect::<Vec<_>>();
+
+        let positions = sprites
+            .iter()
+            .map(|sprite| sprite.position)
+            .collect::<Vec<_>>();
+        // The space takes up room without a quad of its own
+        assert_eq!(
+            positions,
+            vec![
+                Vector2::new(10.0, 20.0),
+                Vector2::new(18.0, 20.0),
+                Vector2::new(10.0, 36.0),
+                Vector2::new(26.0, 36.0),
+            ]
+        );
+        assert!(sprites
+            .iter()
+            .all(|sprite| sprite.size == Vector2::new(8.0, 16.0)));
+
+        assert_eq!(
+            text_renderer.measure("ab\nc d", 16.0),
+            Vector2::new(24.0, 32.0)
+        );
+        assert_eq!(text_renderer.measure("", 16.0), Vector2::new(0.0, 16.0));
+    }
+
+    #[test]
+    fn glyphs_come_from_their_atlas_cell() {
+        // '0' is the first character of the second row
+        let zero = TextRenderer::uv_rect('0');
+        assert_eq!(zero.x, 0.0);
+        assert!((zero.y - 1.0 / 6.0).abs() < 1e-6);
+        assert!((zero.z - 1.0 / 16.0).abs() < 1e-6);
+
+        assert_eq!(TextRenderer::uv_rect('é'), TextRenderer::uv_rect('?'));
+    }
+}
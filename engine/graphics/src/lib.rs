@@ -4,27 +4,47 @@ use winit::window::Window;
 
 pub const COLOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Bgra8Unorm;
 pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+/// Shows frames as soon as they're done without tearing, see `GraphicsContext::set_present_mode`
+pub const DEFAULT_PRESENT_MODE: wgpu::PresentMode = wgpu::PresentMode::Mailbox;
+/// Requested when the adapter has them, check `Device::features` before relying on one.
+/// `NON_FILL_POLYGON_MODE` is for drawing wireframes.
+pub const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::NON_FILL_POLYGON_MODE;
 
 pub const MAX_NR_OF_POINT_LIGHTS: usize = 10;
+pub const MAX_NR_OF_DIRECTIONAL_LIGHTS: usize = 4;
 
+pub use crate::entity_smith::GraphicsEntitySmith;
+
+pub mod animation;
 pub mod canvas;
 pub mod components;
 pub mod data;
+pub mod debug_lines;
+mod entity_smith;
+pub mod fullscreen;
 pub mod gui;
 pub mod models;
+pub mod offscreen;
+pub mod picking;
+pub mod post_process;
+pub mod sprites;
 pub mod systems;
 pub mod unit;
 mod util;
 
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use cgmath::{EuclideanSpace, Point3, Vector2, Vector3, Vector4};
+use cgmath::{Vector2, Vector3, Vector4};
+use legion::{Entity, EntityStore, IntoQuery};
 use slotmap::SlotMap;
+use transforms::Transform;
 
 use crate::components::Camera;
 use crate::data::Vertex;
-use crate::util::{correction_matrix, project_screen_to_world};
+use crate::util::{project_screen_to_world, project_world_to_screen};
 
 pub type ModelID = slotmap::DefaultKey;
 pub type TextureID = slotmap::DefaultKey;
@@ -48,79 +68,258 @@ impl Default for GraphicsResources {
 
 impl GraphicsResources {
     pub fn new() -> Self { Default::default() }
+
+    /// Mesh materials hold on to the textures they were built with, this has them rebuilt
+    /// before they're next drawn so replaced textures show up
+    pub fn refresh_materials(&mut self) {
+        for mesh in self
+            .models
+            .values_mut()
+            .flat_map(|model| model.meshes.iter_mut())
+        {
+            mesh.material_bind_group = None;
+        }
+    }
 }
 
+/// One frame of rendering, every pass of the frame draws into its `color_view`.
+///
+/// Passes run on the GPU in the order they `submit`, so later passes draw over earlier ones.
+/// The frame is shown by `present` once every pass has submitted, which is why passes only
+/// ever borrow the context.
 pub struct RenderContext<'a> {
     pub device: &'a wgpu::Device,
     pub queue: &'a wgpu::Queue,
-    pub current_frame: Arc<wgpu::SwapChainFrame>,
     pub window_size: PhysicalSize<u32>,
+    frame: wgpu::SwapChainFrame,
+    submit_time: Cell<Duration>,
 }
 
-pub struct GraphicsContext {
-    pub device: wgpu::Device,
-    pub queue: wgpu::Queue,
+impl<'a> RenderContext<'a> {
+    /// The swap chain texture this frame is presented from
+    pub fn color_view(&self) -> &wgpu::TextureView { &self.frame.output.view }
 
+    /// Shows the frame. Dropping the context presents it as well, this just makes it explicit.
+    pub fn present(self) { drop(self.frame); }
+
+    /// Submits to the queue, keeping track of how long it took when frame stats are on
+    pub fn submit(&self, command_buffer: wgpu::CommandBuffer) {
+        if !debug::FRAME_STATS {
+            self.queue.submit(std::iter::once(command_buffer));
+            return;
+        }
+
+        let start = Instant::now();
+        self.queue.submit(std::iter::once(command_buffer));
+        self.submit_time
+            .set(self.submit_time.get() + start.elapsed());
+    }
+
+    /// Total time spent in `submit` so far this frame
+    pub fn submit_time(&self) -> Duration { self.submit_time.get() }
+}
+
+/// Why a GraphicsContext couldn't be created
+#[derive(Debug)]
+pub enum GraphicsError {
+    /// No GPU supports the window's surface
+    NoAdapter,
+    RequestDevice(wgpu::RequestDeviceError),
+    /// The swap chain couldn't give a frame to draw to
+    Surface(wgpu::SwapChainError),
+}
+
+impl std::fmt::Display for GraphicsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GraphicsError::NoAdapter => write!(f, "No compatible graphics adapter found"),
+            GraphicsError::RequestDevice(e) => write!(f, "Graphics device request failed: {}", e),
+            GraphicsError::Surface(e) => write!(f, "Window surface creation failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GraphicsError {}
+
+/// Which GPU a GraphicsContext is created on, e.g. to force the discrete card on laptops
+/// with two. Defaults to any of the primary backends with wgpu's default power preference.
+#[derive(Copy, Clone, Debug)]
+pub struct AdapterOptions {
+    pub backends: wgpu::BackendBit,
+    pub power_preference: wgpu::PowerPreference,
+}
+
+impl Default for AdapterOptions {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::BackendBit::PRIMARY,
+            power_preference: wgpu::PowerPreference::default(),
+        }
+    }
+}
+
+/// The window side of a GraphicsContext, absent when running headless
+struct Presenter {
     surface: wgpu::Surface,
     swap_chain: wgpu::SwapChain,
     sc_desc: wgpu::SwapChainDescriptor,
+}
+
+pub struct GraphicsContext {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    adapter: wgpu::Adapter,
+
+    presenter: Option<Presenter>,
+    present_mode: wgpu::PresentMode,
     pub window_size: PhysicalSize<u32>,
 }
 
 impl GraphicsContext {
-    pub async fn new(window: &Window) -> Self {
+    /// Fails on machines without a GPU that can draw to the window, or none matching `options`
+    pub async fn new(window: &Window, options: AdapterOptions) -> Result<Self, GraphicsError> {
         let window_size = window.inner_size();
 
         // This creates a wgpu instance. We use this to create an Adapter and a Surface
-        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        let instance = wgpu::Instance::new(options.backends);
         // A surface is a platform-specific target that you can render images onto
         let surface = unsafe { instance.create_surface(window) };
         // The device represents the GPU essentially
         // and the queue represents a command queue
         // present on the GPU
-        let (device, queue) = instance
+        let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: None,
+                power_preference: options.power_preference,
+                compatible_surface: Some(&surface),
             })
             .await
-            .unwrap()
+            .ok_or(GraphicsError::NoAdapter)?;
+        let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    features: wgpu::Features::default(),
+                    features: adapter.features() & OPTIONAL_FEATURES,
                     limits: wgpu::Limits::default(),
                 },
                 None,
             )
             .await
-            .unwrap();
+            .map_err(GraphicsError::RequestDevice)?;
 
         // The swap_chain represents the images that will be presented to our surface.
         // You ask the swap_chain for the current frame that is being rendered to
         // and when you drop it, the swap chain will present the frame to the surface.
-        let sc_desc = util::sc_desc_from_size(window_size);
+        let sc_desc = util::sc_desc_from_size(window_size, DEFAULT_PRESENT_MODE);
         let swap_chain = device.create_swap_chain(&surface, &sc_desc);
 
-        Self {
+        // Presenting a cleared frame checks that the surface works before anything is loaded
+        let frame = swap_chain
+            .get_current_frame()
+            .map_err(GraphicsError::Surface)?;
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Surface Check"),
+        });
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &frame.output.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        queue.submit(std::iter::once(encoder.finish()));
+        drop(frame);
+
+        Ok(Self {
             device,
             queue,
-            surface,
-            swap_chain,
-            sc_desc,
+            adapter,
+            presenter: Some(Presenter {
+                surface,
+                swap_chain,
+                sc_desc,
+            }),
+            present_mode: DEFAULT_PRESENT_MODE,
             window_size,
-        }
+        })
     }
 
-    pub fn begin_render(&self) -> RenderContext {
-        RenderContext {
+    /// A context without a window, for tests and offscreen work. Anything that
+    /// only needs the device and queue works, but there is no frame to render to,
+    /// so `begin_render` returns `None`. Returns `None` if no adapter is available.
+    pub async fn new_headless(size: PhysicalSize<u32>) -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+            })
+            .await?;
+
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features: adapter.features() & OPTIONAL_FEATURES,
+                    limits: wgpu::Limits::default(),
+                },
+                None,
+            )
+            .await
+            .ok()?;
+
+        Some(Self {
+            device,
+            queue,
+            adapter,
+            presenter: None,
+            present_mode: DEFAULT_PRESENT_MODE,
+            window_size: size,
+        })
+    }
+
+    pub fn is_headless(&self) -> bool { self.presenter.is_none() }
+
+    /// The GPU's name, driver type and backend, e.g. for a diagnostics panel
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo { self.adapter.get_info() }
+
+    /// None when headless. An outdated or lost swap chain, from resizing the window or moving it
+    /// to another monitor, is recreated and asked once more for a frame.
+    pub fn begin_render(&mut self) -> Result<Option<RenderContext<'_>>, GraphicsError> {
+        let presenter = match &mut self.presenter {
+            Some(presenter) => presenter,
+            None => return Ok(None),
+        };
+
+        let frame = match presenter.swap_chain.get_current_frame() {
+            Ok(frame) => frame,
+            Err(wgpu::SwapChainError::Outdated) | Err(wgpu::SwapChainError::Lost) => {
+                presenter.swap_chain = self
+                    .device
+                    .create_swap_chain(&presenter.surface, &presenter.sc_desc);
+                presenter
+                    .swap_chain
+                    .get_current_frame()
+                    .map_err(GraphicsError::Surface)?
+            }
+            Err(e) => return Err(GraphicsError::Surface(e)),
+        };
+
+        Ok(Some(RenderContext {
             device: &self.device,
             queue: &self.queue,
-            current_frame: Arc::new(self.swap_chain.get_current_frame().unwrap()),
             window_size: self.window_size,
-        }
+            frame,
+            submit_time: Cell::new(Duration::default()),
+        }))
     }
 
+    /// Unindexed meshes, one vertex per triangle corner. Models loaded by the asset manager get
+    /// their vertices welded and go through `indexed_mesh` instead.
     pub fn model_from_vertex_list(&self, vertex_lists: Vec<Vec<Vertex>>) -> data::Model {
         let mut meshes = vec![];
 
@@ -134,25 +333,88 @@ impl GraphicsContext {
                 });
 
             meshes.push(data::Mesh {
-                num_vertices: vertices.len(),
-                vertex_buffer: vertex_buf,
-                offset: [0.0, 0.0, 0.0],
+                aabb: data::Aabb::from_vertices(vertices),
+                ..data::Mesh::new(vertices.len(), vertex_buf)
             });
         }
 
-        data::Model {
-            meshes,
-            vertex_lists,
+        data::Model::new(meshes, vertex_lists)
+    }
+
+    pub fn texture_from_image(&self, image: image::DynamicImage) -> data::Texture {
+        data::Texture::new(image, self)
+    }
+
+    /// Uploads welded vertices along with the triangle list indexing into them
+    pub fn indexed_mesh(&self, vertices: &[Vertex], indices: &[u32]) -> data::Mesh {
+        let vertex_buf = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(vertices),
+                usage: wgpu::BufferUsage::VERTEX,
+            });
+        let index_buf = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(indices),
+                usage: wgpu::BufferUsage::INDEX,
+            });
+
+        data::Mesh {
+            aabb: data::Aabb::from_vertices(vertices),
+            ..data::Mesh::indexed(vertices.len(), vertex_buf, indices.len(), index_buf)
         }
     }
 
+    /// Rebuilds the swap chain for the new size, e.g. after toggling fullscreen.
+    /// Minimized windows report a size of zero, which keeps the old swap chain around.
     pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        if size.width == 0 || size.height == 0 {
+            return;
+        }
+
         self.window_size = size;
 
-        self.sc_desc = util::sc_desc_from_size(size);
-        self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+        if let Some(presenter) = &mut self.presenter {
+            presenter.sc_desc = util::sc_desc_from_size(size, self.present_mode);
+            presenter.swap_chain = self
+                .device
+                .create_swap_chain(&presenter.surface, &presenter.sc_desc);
+        }
     }
 
+    pub fn present_mode(&self) -> wgpu::PresentMode { self.present_mode }
+
+    /// Rebuilds the swap chain to show frames with `present_mode`. Every surface supports
+    /// `Fifo`, wgpu can't tell us about the others and falls back to `Fifo` itself when the
+    /// surface doesn't support the one asked for.
+    pub fn set_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        if present_mode == self.present_mode {
+            return;
+        }
+
+        self.present_mode = present_mode;
+        if let Some(presenter) = &mut self.presenter {
+            presenter.sc_desc.present_mode = present_mode;
+            presenter.swap_chain = self
+                .device
+                .create_swap_chain(&presenter.surface, &presenter.sc_desc);
+        }
+    }
+
+    /// VSync waits for the display to refresh before showing a frame, without it frames are
+    /// shown with `DEFAULT_PRESENT_MODE`
+    pub fn set_vsync(&mut self, vsync: bool) {
+        self.set_present_mode(match vsync {
+            true => wgpu::PresentMode::Fifo,
+            false => DEFAULT_PRESENT_MODE,
+        });
+    }
+
+    pub fn vsync(&self) -> bool { self.present_mode == wgpu::PresentMode::Fifo }
+
     pub fn screen_to_world(
         &self,
         mouse_pos: Vector2<f32>,
@@ -160,24 +422,122 @@ impl GraphicsContext {
         camera_position: Vector3<f32>,
         camera_target_pos: Vector3<f32>,
     ) -> Option<Vector3<f32>> {
-        let aspect_ratio = self.window_size.width as f32 / self.window_size.height as f32;
-
-        let mx_view = cgmath::Matrix4::look_at_rh(
-            Point3::from_vec(camera_position),
-            Point3::from_vec(camera_target_pos),
-            Vector3::unit_z(),
-        );
-        let mx_projection = cgmath::perspective(cgmath::Deg(camera.fov), aspect_ratio, 1.0, 1000.0);
-
         project_screen_to_world(
             Vector3::new(mouse_pos.x, mouse_pos.y, 1.0),
-            correction_matrix() * mx_projection * mx_view,
-            Vector4::new(
-                0.0,
-                0.0,
-                self.window_size.width as f32,
-                self.window_size.height as f32,
-            ),
+            self.view_projection(camera, camera_position, camera_target_pos),
+            self.viewport(),
+        )
+    }
+
+    /// The ray under the mouse as a point on it and its direction, for picking with either
+    /// projection
+    pub fn screen_to_ray(
+        &self,
+        mouse_pos: Vector2<f32>,
+        camera: &Camera,
+        camera_position: Vector3<f32>,
+        camera_target_pos: Vector3<f32>,
+    ) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        util::screen_ray(
+            mouse_pos,
+            self.view_projection(camera, camera_position, camera_target_pos),
+            self.viewport(),
         )
     }
+
+    /// Where a point in the world ends up on screen, e.g. to place a health bar above an entity.
+    /// Returns `None` if the point is behind the camera.
+    pub fn world_to_screen(
+        &self,
+        world_pos: Vector3<f32>,
+        camera: &Camera,
+        camera_position: Vector3<f32>,
+        camera_target_pos: Vector3<f32>,
+    ) -> Option<Vector2<f32>> {
+        project_world_to_screen(
+            world_pos,
+            self.view_projection(camera, camera_position, camera_target_pos),
+            self.viewport(),
+        )
+        .map(|screen| screen.truncate())
+    }
+
+    /// The entities whose `Transform` lands inside a screen space box, e.g. the one from
+    /// `MouseState::drag_rect`. Entities behind the camera are left out.
+    pub fn entities_in_rect(
+        &self,
+        rect: (Vector2<f32>, Vector2<f32>),
+        world: &impl EntityStore,
+        camera: &Camera,
+        camera_position: Vector3<f32>,
+        camera_target_pos: Vector3<f32>,
+    ) -> Vec<Entity> {
+        let view_projection = self.view_projection(camera, camera_position, camera_target_pos);
+        let viewport = self.viewport();
+
+        <(Entity, &Transform)>::query()
+            .iter(world)
+            .filter(|(_, transform)| {
+                util::projects_into_rect(
+                    transform.world_position(),
+                    view_projection,
+                    viewport,
+                    rect,
+                )
+            })
+            .map(|(entity, _)| *entity)
+            .collect()
+    }
+
+    fn view_projection(
+        &self,
+        camera: &Camera,
+        camera_position: Vector3<f32>,
+        camera_target_pos: Vector3<f32>,
+    ) -> cgmath::Matrix4<f32> {
+        util::generate_view_matrix(
+            camera,
+            camera_position,
+            camera_target_pos,
+            self.window_size.width as f32 / self.window_size.height as f32,
+        )
+    }
+
+    fn viewport(&self) -> Vector4<f32> {
+        Vector4::new(
+            0.0,
+            0.0,
+            self.window_size.width as f32,
+            self.window_size.height as f32,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headless() -> Option<GraphicsContext> {
+        futures::executor::block_on(GraphicsContext::new_headless(PhysicalSize::new(64, 64)))
+    }
+
+    #[test]
+    fn headless_context_creates_models() {
+        // Machines without any adapter have nothing to test against
+        let mut context = match headless() {
+            Some(context) => context,
+            None => return,
+        };
+
+        assert!(context.is_headless());
+        let backend = wgpu::BackendBit::from(context.adapter_info().backend);
+        assert!(AdapterOptions::default().backends.contains(backend));
+        assert!(context.begin_render().unwrap().is_none());
+
+        let vertices = vec![bytemuck::Zeroable::zeroed(); 6];
+        let model = context.model_from_vertex_list(vec![vertices.clone(), vertices]);
+
+        assert_eq!(model.meshes.len(), 2);
+        assert!(model.meshes.iter().all(|mesh| mesh.num_vertices == 6));
+    }
 }
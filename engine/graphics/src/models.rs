@@ -1,25 +1,89 @@
+use std::sync::Arc;
+
 use debug::DebugTimer;
 use itertools::Itertools;
 use wgpu::util::DeviceExt;
 
-use crate::components::{Camera, DynamicModel, StaticModel};
-use crate::data::{GlobalUniforms, LocalUniforms};
-use crate::{GraphicsContext, GraphicsResources, RenderContext, TextureID};
+use crate::animation::JointUniforms;
+use crate::components::{Camera, ClearColor, DynamicModel, Fog, RenderLayer, StaticModel};
+use crate::data::{
+    BoundingSphere, GlobalUniforms, Lights, LocalUniforms, MaterialUniforms, Mesh, ShadowUniforms,
+    Texture, NO_TINT,
+};
+use crate::offscreen::RenderTarget;
+use crate::util::{generate_light_matrix, Frustum};
+use crate::{
+    GraphicsContext, GraphicsResources, RenderContext, TextureID, COLOR_FORMAT, DEPTH_FORMAT,
+};
 
 // TODO: Have ass_man auto-load all Shaders
 //const FRAG_SRC: &str = include_str!("../../assets/Shaders/forward.frag");
 //const DYNAMIC_VERT_SRC: &str = include_str!("../../assets/Shaders/forward.vert");
 //const STATIC_VERT_SRC: &str = include_str!("../../assets/Shaders/static.vert");
 
+type Pose = Option<Box<JointUniforms>>;
+type DynamicEntry = (DynamicModel, LocalUniforms, RenderLayer, Pose);
+
+pub(crate) const VERTEX_ATTRIBUTES: [wgpu::VertexAttribute; 6] = wgpu::vertex_attr_array![
+    0 => Float3,
+    1 => Float3,
+    2 => Float2,
+    3 => Float4,
+    4 => Uint4,
+    5 => Float4
+];
+
+const VERTEX_BUFFER_LAYOUT: wgpu::VertexBufferLayout = wgpu::VertexBufferLayout {
+    array_stride: std::mem::size_of::<super::data::Vertex>() as u64,
+    step_mode: wgpu::InputStepMode::Vertex,
+    attributes: &VERTEX_ATTRIBUTES,
+};
+
+const fn instance_attribute(offset: u64, shader_location: u32) -> wgpu::VertexAttribute {
+    wgpu::VertexAttribute {
+        format: wgpu::VertexFormat::Float4,
+        offset,
+        shader_location,
+    }
+}
+
+/// The parts of `LocalUniforms` instanced.vert reads, the model matrix column by column,
+/// the albedo and the tint
+const INSTANCE_ATTRIBUTES: [wgpu::VertexAttribute; 6] = [
+    instance_attribute(0, 6),
+    instance_attribute(16, 7),
+    instance_attribute(32, 8),
+    instance_attribute(48, 9),
+    instance_attribute(64, 10),
+    instance_attribute(96, 11),
+];
+
+/// Each instance is a whole `LocalUniforms`, the same as goes in a model's uniform buffer
+const INSTANCE_BUFFER_LAYOUT: wgpu::VertexBufferLayout = wgpu::VertexBufferLayout {
+    array_stride: std::mem::size_of::<LocalUniforms>() as u64,
+    step_mode: wgpu::InputStepMode::Instance,
+    attributes: &INSTANCE_ATTRIBUTES,
+};
+
+/// How many instances `instance_buf` has room for to begin with
+const INITIAL_INSTANCE_CAPACITY: usize = 64;
+
+const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+/// How far from the camera target the shadow map reaches, in world units
+const SHADOW_EXTENT: f32 = 20.0;
+
 pub struct ModelQueue {
-    dynamic_models: Vec<(DynamicModel, LocalUniforms)>,
-    static_models: Vec<StaticModel>,
+    dynamic_models: Vec<DynamicEntry>,
+    /// Blended over everything else once the opaque models are drawn
+    transparent_models: Vec<DynamicEntry>,
+    static_models: Vec<(StaticModel, RenderLayer)>,
 }
 
 impl Default for ModelQueue {
     fn default() -> Self {
         Self {
             dynamic_models: vec![],
+            transparent_models: vec![],
             static_models: vec![],
         }
     }
@@ -28,33 +92,191 @@ impl Default for ModelQueue {
 impl ModelQueue {
     pub fn new() -> Self { Default::default() }
 
-    pub fn push_static_model(&mut self, model: StaticModel) { self.static_models.push(model); }
+    pub fn push_static_model(&mut self, model: StaticModel, layer: RenderLayer) {
+        self.static_models.push((model, layer));
+    }
+
+    /// Models pushed with the same `idx` are drawn together as instances of it
+    pub fn push_model(&mut self, model: DynamicModel, uniforms: LocalUniforms, layer: RenderLayer) {
+        self.dynamic_models.push((model, uniforms, layer, None));
+    }
+
+    /// Like `push_model`, but with the skinning matrices from an `Animator`
+    pub fn push_posed_model(
+        &mut self,
+        model: DynamicModel,
+        uniforms: LocalUniforms,
+        layer: RenderLayer,
+        pose: JointUniforms,
+    ) {
+        self.dynamic_models
+            .push((model, uniforms, layer, Some(Box::new(pose))));
+    }
+
+    /// Like `push_model` or `push_posed_model`, but the model is alpha blended
+    /// and doesn't hide what's behind it
+    pub fn push_transparent_model(
+        &mut self,
+        model: DynamicModel,
+        uniforms: LocalUniforms,
+        layer: RenderLayer,
+        pose: Option<JointUniforms>,
+    ) {
+        self.transparent_models
+            .push((model, uniforms, layer, pose.map(Box::new)));
+    }
 
-    pub fn push_model(&mut self, model: DynamicModel, uniforms: LocalUniforms) {
-        self.dynamic_models.push((model, uniforms));
+    /// Orders the queues by render layer, keeping the queued order within each layer
+    pub fn sort_by_layer(&mut self) {
+        sort_by_layer(&mut self.static_models, |(_, layer)| *layer);
+        sort_by_layer(&mut self.dynamic_models, |(_, _, layer, _)| *layer);
+        sort_by_layer(&mut self.transparent_models, |(_, _, layer, _)| *layer);
     }
 
     pub fn clear(&mut self) {
         self.dynamic_models.clear();
+        self.transparent_models.clear();
         self.static_models.clear();
     }
 
     pub fn drain(&mut self) -> Self {
         Self {
             dynamic_models: self.dynamic_models.drain(..).collect_vec(),
+            transparent_models: self.transparent_models.drain(..).collect_vec(),
             static_models: self.static_models.drain(..).collect_vec(),
         }
     }
 }
 
+// `sort_by_key` is stable, which is what keeps same-layer models in queue order
+fn sort_by_layer<T>(entries: &mut [T], layer: impl Fn(&T) -> RenderLayer) {
+    entries.sort_by_key(layer);
+}
+
+/// Orders entries by their distance to `eye` within each layer. Opaque models go front to
+/// back so hidden fragments fail the depth test early, transparent ones back to front so
+/// they blend over what's behind them.
+fn sort_by_distance<T>(
+    entries: &mut [T],
+    eye: cgmath::Vector3<f32>,
+    back_to_front: bool,
+    layer_and_position: impl Fn(&T) -> (RenderLayer, cgmath::Vector3<f32>),
+) {
+    use cgmath::InnerSpace;
+
+    entries.sort_by(|a, b| {
+        let (layer_a, position_a) = layer_and_position(a);
+        let (layer_b, position_b) = layer_and_position(b);
+        let (distance_a, distance_b) = (
+            (position_a - eye).magnitude2(),
+            (position_b - eye).magnitude2(),
+        );
+        let by_distance = match back_to_front {
+            true => distance_b.partial_cmp(&distance_a),
+            false => distance_a.partial_cmp(&distance_b),
+        };
+
+        layer_a
+            .cmp(&layer_b)
+            .then(by_distance.unwrap_or(std::cmp::Ordering::Equal))
+    });
+}
+
+/// Groups entries that share a key, each group in the order its first entry came in so the
+/// draw order mostly holds. Entries without a key get a group of their own.
+fn group_by_key<T, K: PartialEq>(
+    entries: impl IntoIterator<Item = T>,
+    key: impl Fn(&T) -> Option<K>,
+) -> Vec<Vec<T>> {
+    let mut groups: Vec<(Option<K>, Vec<T>)> = vec![];
+    for entry in entries {
+        let entry_key = key(&entry);
+        let group = match &entry_key {
+            Some(_) => groups
+                .iter_mut()
+                .find(|(group_key, _)| *group_key == entry_key),
+            None => None,
+        };
+        match group {
+            Some((_, group)) => group.push(entry),
+            None => groups.push((entry_key, vec![entry])),
+        }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
 pub struct ModelRenderPipeline {
-    global_uniform_buf: wgpu::Buffer,
+    /// Kept so the camera and fog can be updated separately
+    global_uniforms: GlobalUniforms,
+    pub(crate) global_uniform_buf: wgpu::Buffer,
+    lights_uniform_buf: wgpu::Buffer,
     global_bind_group: wgpu::BindGroup,
     pub(crate) local_bind_group_layout: wgpu::BindGroupLayout,
+    material_bind_group_layout: wgpu::BindGroupLayout,
+    default_material_bind_group: wgpu::BindGroup,
+    pub(crate) joint_bind_group_layout: wgpu::BindGroupLayout,
+    default_joint_bind_group: wgpu::BindGroup,
+    color_texture_id: TextureID,
     static_pipeline: wgpu::RenderPipeline,
     dynamic_pipeline: wgpu::RenderPipeline,
-    _pipeline_layout: wgpu::PipelineLayout,
+    /// Draws unposed dynamic models that share a model in one go
+    instanced_pipeline: wgpu::RenderPipeline,
+    transparent_pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    static_vs_module: Arc<wgpu::ShaderModule>,
+    dynamic_vs_module: Arc<wgpu::ShaderModule>,
+    instanced_vs_module: Arc<wgpu::ShaderModule>,
+    fs_module: Arc<wgpu::ShaderModule>,
+    color_format: wgpu::TextureFormat,
+    depth_format: wgpu::TextureFormat,
+    sample_count: u32,
+    wireframe: bool,
+    /// What the depth is cleared to before the models are drawn
+    depth_clear_value: f32,
     _texture_sampler: wgpu::Sampler,
+    _flat_normal_texture: Option<Texture>,
+    /// Stands in for material textures that are no longer loaded
+    white_texture: Texture,
+    /// What the camera sees, nothing is culled until a camera is set
+    frustum: Option<Frustum>,
+    /// Where the camera is, models are sorted by their distance to it
+    eye_position: Option<cgmath::Vector3<f32>>,
+    culled_models: usize,
+    /// The `LocalUniforms` of every instanced model this frame
+    instance_buf: wgpu::Buffer,
+    /// How many instances fit in `instance_buf`, it grows when a frame has more
+    instance_capacity: usize,
+    shadows: Shadows,
+    /// Made on the first frame and again when the target is resized or the formats change
+    attachments: Option<Attachments>,
+}
+
+/// The depth texture, and the multisampled color texture when multisampling, that the models
+/// are drawn into before being resolved into the target
+struct Attachments {
+    size: winit::dpi::PhysicalSize<u32>,
+    depth: wgpu::TextureView,
+    color: Option<wgpu::TextureView>,
+}
+
+/// The depth-only pass that renders the scene from the first directional light
+struct Shadows {
+    uniform_buf: wgpu::Buffer,
+    global_uniform_buf: wgpu::Buffer,
+    global_bind_group: wgpu::BindGroup,
+    static_pipeline: wgpu::RenderPipeline,
+    dynamic_pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    _map: wgpu::Texture,
+    map_view: wgpu::TextureView,
+    _sampler: wgpu::Sampler,
+    map_size: u32,
+    enabled: bool,
+    /// Direction of the first directional light, if there is one
+    light_direction: Option<cgmath::Vector3<f32>>,
+    /// The map is centered on the camera target
+    focus: cgmath::Vector3<f32>,
 }
 
 impl ModelRenderPipeline {
@@ -62,6 +284,9 @@ impl ModelRenderPipeline {
         context: &GraphicsContext,
         graphics_resources: &GraphicsResources,
         color_texture_id: TextureID,
+        normal_texture_id: Option<TextureID>,
+        sample_count: u32,
+        shadow_map_size: u32,
     ) -> Self {
         let device = &context.device;
 
@@ -80,7 +305,26 @@ impl ModelRenderPipeline {
                         count: None,
                     },
                     wgpu::BindGroupLayoutEntry {
-                        binding: 1,
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            filtering: true,
+                            comparison: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
                         visibility: wgpu::ShaderStage::FRAGMENT,
                         ty: wgpu::BindingType::Texture {
                             sample_type: wgpu::TextureSampleType::Float { filterable: true },
@@ -89,12 +333,33 @@ impl ModelRenderPipeline {
                         },
                         count: None,
                     },
+                    // The shadow map would get a group of its own, but all four are taken
                     wgpu::BindGroupLayoutEntry {
-                        binding: 2,
+                        binding: 5,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Depth,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
                         visibility: wgpu::ShaderStage::FRAGMENT,
                         ty: wgpu::BindingType::Sampler {
                             filtering: true,
-                            comparison: false,
+                            comparison: true,
                         },
                         count: None,
                     },
@@ -104,6 +369,48 @@ impl ModelRenderPipeline {
         let local_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Local Bind Group Layout -- Models"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let material_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Material Bind Group Layout -- Models"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let joint_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Joint Bind Group Layout -- Models"),
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
                     visibility: wgpu::ShaderStage::VERTEX,
@@ -124,12 +431,34 @@ impl ModelRenderPipeline {
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
 
+        let lights: Lights = Default::default();
+
+        let lights_uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lights Uniforms"),
+            contents: bytemuck::bytes_of(&lights),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
         let color_texture_view = &graphics_resources
             .textures
             .get(color_texture_id)
             .unwrap()
             .texture_view;
 
+        // Without a normal map we still need something bound, a single "straight up" texel will do
+        let mut flat_normal_texture = None;
+        let normal_texture_view = match normal_texture_id {
+            Some(id) => &graphics_resources.textures.get(id).unwrap().texture_view,
+            None => {
+                let flat = image::ImageBuffer::from_pixel(1, 1, image::Rgba([128, 128, 255, 255]));
+                let texture = Texture::new(image::DynamicImage::ImageRgba8(flat), context);
+                &flat_normal_texture.insert(texture).texture_view
+            }
+        };
+
+        let white = image::ImageBuffer::from_pixel(1, 1, image::Rgba([255, 255, 255, 255]));
+        let white_texture = Texture::new(image::DynamicImage::ImageRgba8(white), context);
+
         let texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: None,
             address_mode_u: wgpu::AddressMode::ClampToEdge,
@@ -141,6 +470,41 @@ impl ModelRenderPipeline {
             ..Default::default()
         });
 
+        // A size of 0 turns shadows off for good, the shader still wants a map bound
+        let shadow_map = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Shadow Map"),
+            size: wgpu::Extent3d {
+                width: shadow_map_size.max(1),
+                height: shadow_map_size.max(1),
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: SHADOW_FORMAT,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        let shadow_map_view = shadow_map.create_view(&Default::default());
+
+        // Linear filtering on a comparison sampler blends the 4 nearest tests for free
+        let shadow_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let shadow_uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Shadow Uniforms"),
+            contents: bytemuck::bytes_of(&ShadowUniforms::default()),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
         let global_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout: &global_bind_group_layout,
@@ -153,55 +517,541 @@ impl ModelRenderPipeline {
                         size: None,
                     },
                 },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(color_texture_view),
-                },
                 wgpu::BindGroupEntry {
                     binding: 2,
                     resource: wgpu::BindingResource::Sampler(&texture_sampler),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &lights_uniform_buf,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(normal_texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &shadow_uniform_buf,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&shadow_map_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Sampler(&shadow_sampler),
+                },
             ],
         });
 
-        let static_vs_module = graphics_resources.shaders.get("static.vert").unwrap();
-        let dynamic_vs_module = graphics_resources.shaders.get("forward.vert").unwrap();
-        let fs_module = graphics_resources.shaders.get("forward.frag").unwrap();
+        let default_material_bind_group = Self::create_material_bind_group(
+            device,
+            &material_bind_group_layout,
+            color_texture_view,
+            NO_TINT,
+        );
+
+        // Everything that isn't posed by an Animator is drawn in its bind pose
+        let default_joint_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Default Joint Uniforms"),
+            contents: bytemuck::bytes_of(&JointUniforms::default()),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+        let default_joint_bind_group =
+            Self::create_joint_bind_group(device, &joint_bind_group_layout, &default_joint_buffer);
+
+        let static_vs_module = graphics_resources.shaders["static.vert"].clone();
+        let dynamic_vs_module = graphics_resources.shaders["forward.vert"].clone();
+        let instanced_vs_module = graphics_resources.shaders["instanced.vert"].clone();
+        let fs_module = graphics_resources.shaders["forward.frag"].clone();
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Model Render Pipeline Layout"),
-            bind_group_layouts: &[&global_bind_group_layout, &local_bind_group_layout],
+            bind_group_layouts: &[
+                &global_bind_group_layout,
+                &local_bind_group_layout,
+                &material_bind_group_layout,
+                &joint_bind_group_layout,
+            ],
             push_constant_ranges: &[],
         });
 
-        let static_pipeline =
-            Self::compile_pipeline(&device, &pipeline_layout, &static_vs_module, &fs_module);
+        let (color_format, depth_format, sample_count) = (
+            COLOR_FORMAT,
+            DEPTH_FORMAT,
+            supported_sample_count(sample_count),
+        );
+
+        let static_pipeline = Self::compile_pipeline(
+            device,
+            &pipeline_layout,
+            &static_vs_module,
+            &fs_module,
+            &[VERTEX_BUFFER_LAYOUT],
+            color_format,
+            depth_format,
+            sample_count,
+            false,
+            wgpu::PolygonMode::Fill,
+        );
+
+        let dynamic_pipeline = Self::compile_pipeline(
+            device,
+            &pipeline_layout,
+            &dynamic_vs_module,
+            &fs_module,
+            &[VERTEX_BUFFER_LAYOUT],
+            color_format,
+            depth_format,
+            sample_count,
+            false,
+            wgpu::PolygonMode::Fill,
+        );
+
+        let instanced_pipeline = Self::compile_pipeline(
+            device,
+            &pipeline_layout,
+            &instanced_vs_module,
+            &fs_module,
+            &[VERTEX_BUFFER_LAYOUT, INSTANCE_BUFFER_LAYOUT],
+            color_format,
+            depth_format,
+            sample_count,
+            false,
+            wgpu::PolygonMode::Fill,
+        );
+
+        let transparent_pipeline = Self::compile_pipeline(
+            device,
+            &pipeline_layout,
+            &dynamic_vs_module,
+            &fs_module,
+            &[VERTEX_BUFFER_LAYOUT],
+            color_format,
+            depth_format,
+            sample_count,
+            true,
+            wgpu::PolygonMode::Fill,
+        );
+
+        // The shadow pass only needs the light's view of the scene from the globals,
+        // the material group is never read but has to be there to reach the joints
+        let shadow_global_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Global Bind Group Layout -- Shadows"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let shadow_global_uniform_buf =
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Shadow Global Uniforms"),
+                contents: bytemuck::bytes_of(&GlobalUniforms::default()),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            });
+
+        let shadow_global_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Global Bind Group -- Shadows"),
+            layout: &shadow_global_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &shadow_global_uniform_buf,
+                    offset: 0,
+                    size: None,
+                },
+            }],
+        });
+
+        let shadow_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Shadow Pipeline Layout"),
+                bind_group_layouts: &[
+                    &shadow_global_bind_group_layout,
+                    &local_bind_group_layout,
+                    &material_bind_group_layout,
+                    &joint_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
 
-        let dynamic_pipeline =
-            Self::compile_pipeline(&device, &pipeline_layout, &dynamic_vs_module, &fs_module);
+        let shadows = Shadows {
+            uniform_buf: shadow_uniform_buf,
+            global_uniform_buf: shadow_global_uniform_buf,
+            global_bind_group: shadow_global_bind_group,
+            static_pipeline: Self::compile_shadow_pipeline(
+                device,
+                &shadow_pipeline_layout,
+                &static_vs_module,
+            ),
+            dynamic_pipeline: Self::compile_shadow_pipeline(
+                device,
+                &shadow_pipeline_layout,
+                &dynamic_vs_module,
+            ),
+            pipeline_layout: shadow_pipeline_layout,
+            _map: shadow_map,
+            map_view: shadow_map_view,
+            _sampler: shadow_sampler,
+            map_size: shadow_map_size,
+            enabled: shadow_map_size > 0,
+            light_direction: None,
+            focus: cgmath::Vector3::new(0.0, 0.0, 0.0),
+        };
 
         Self {
+            global_uniforms,
             global_uniform_buf,
+            lights_uniform_buf,
             global_bind_group,
             local_bind_group_layout,
+            material_bind_group_layout,
+            default_material_bind_group,
+            joint_bind_group_layout,
+            default_joint_bind_group,
+            color_texture_id,
             static_pipeline,
             dynamic_pipeline,
-            _pipeline_layout: pipeline_layout,
+            instanced_pipeline,
+            transparent_pipeline,
+            pipeline_layout,
+            static_vs_module,
+            dynamic_vs_module,
+            instanced_vs_module,
+            fs_module,
+            color_format,
+            depth_format,
+            sample_count,
+            wireframe: false,
+            depth_clear_value: 1.0,
             _texture_sampler: texture_sampler,
+            _flat_normal_texture: flat_normal_texture,
+            attachments: None,
+            white_texture,
+            frustum: None,
+            eye_position: None,
+            culled_models: 0,
+            instance_buf: Self::instance_buffer(device, INITIAL_INSTANCE_CAPACITY),
+            instance_capacity: INITIAL_INSTANCE_CAPACITY,
+            shadows,
         }
     }
 
-    pub fn render(
+    pub fn color_format(&self) -> wgpu::TextureFormat { self.color_format }
+
+    pub fn depth_format(&self) -> wgpu::TextureFormat { self.depth_format }
+
+    pub fn sample_count(&self) -> u32 { self.sample_count }
+
+    /// Rebuilds the pipelines for new render target formats.
+    /// `color_format` has to match what the frames are presented in, e.g. the swap chain.
+    /// With more than one sample, frames are drawn multisampled and resolved into the frame,
+    /// see `supported_sample_count` for which counts can be used.
+    pub fn reconfigure(
+        &mut self,
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) {
+        self.color_format = color_format;
+        self.depth_format = depth_format;
+        self.sample_count = supported_sample_count(sample_count);
+        self.attachments = None;
+
+        self.static_pipeline = Self::compile_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.static_vs_module,
+            &self.fs_module,
+            &[VERTEX_BUFFER_LAYOUT],
+            self.color_format,
+            self.depth_format,
+            self.sample_count,
+            false,
+            self.polygon_mode(),
+        );
+        self.dynamic_pipeline = Self::compile_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.dynamic_vs_module,
+            &self.fs_module,
+            &[VERTEX_BUFFER_LAYOUT],
+            self.color_format,
+            self.depth_format,
+            self.sample_count,
+            false,
+            self.polygon_mode(),
+        );
+        self.instanced_pipeline = Self::compile_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.instanced_vs_module,
+            &self.fs_module,
+            &[VERTEX_BUFFER_LAYOUT, INSTANCE_BUFFER_LAYOUT],
+            self.color_format,
+            self.depth_format,
+            self.sample_count,
+            false,
+            self.polygon_mode(),
+        );
+        self.transparent_pipeline = Self::compile_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.dynamic_vs_module,
+            &self.fs_module,
+            &[VERTEX_BUFFER_LAYOUT],
+            self.color_format,
+            self.depth_format,
+            self.sample_count,
+            true,
+            self.polygon_mode(),
+        );
+    }
+
+    /// Rebuilds the pipelines if any of their shaders in `graphics_resources` were replaced
+    pub fn reload_shaders(
+        &mut self,
+        device: &wgpu::Device,
+        graphics_resources: &GraphicsResources,
+    ) {
+        let shaders = &graphics_resources.shaders;
+        let modules = [
+            (&mut self.static_vs_module, "static.vert"),
+            (&mut self.dynamic_vs_module, "forward.vert"),
+            (&mut self.instanced_vs_module, "instanced.vert"),
+            (&mut self.fs_module, "forward.frag"),
+        ];
+
+        let mut changed = false;
+        for (current, name) in modules {
+            // Keeps drawing with the old module rather than going without one
+            let loaded = match shaders.get(name) {
+                Some(loaded) => loaded,
+                None => {
+                    eprintln!(
+                        "[graphics] (warning): Shader {} is not loaded, keeping the old one",
+                        name
+                    );
+                    continue;
+                }
+            };
+            if !Arc::ptr_eq(current, loaded) {
+                *current = loaded.clone();
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.reconfigure(
+                device,
+                self.color_format,
+                self.depth_format,
+                self.sample_count,
+            );
+
+            let shadows = &mut self.shadows;
+            shadows.static_pipeline = Self::compile_shadow_pipeline(
+                device,
+                &shadows.pipeline_layout,
+                &self.static_vs_module,
+            );
+            shadows.dynamic_pipeline = Self::compile_shadow_pipeline(
+                device,
+                &shadows.pipeline_layout,
+                &self.dynamic_vs_module,
+            );
+        }
+    }
+
+    /// Turns the shadow pass on or off. Shadows stay off if the pipeline was made without
+    /// a shadow map, i.e. with a shadow map size of 0.
+    pub fn set_shadows_enabled(&mut self, enabled: bool) {
+        if enabled && self.shadows.map_size == 0 {
+            eprintln!("[graphics] (warning): Can't enable shadows without a shadow map");
+        }
+        self.shadows.enabled = enabled && self.shadows.map_size > 0;
+    }
+
+    pub fn shadows_enabled(&self) -> bool { self.shadows.enabled }
+
+    /// Switches multisampling, e.g. from a settings menu. Takes effect from the next frame.
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, sample_count: u32) {
+        if supported_sample_count(sample_count) != self.sample_count {
+            self.reconfigure(device, self.color_format, self.depth_format, sample_count);
+        }
+    }
+
+    /// Draws the models' edges instead of filling them in, if the device can.
+    /// Shadows are still cast by the filled in models.
+    pub fn set_wireframe(&mut self, device: &wgpu::Device, wireframe: bool) {
+        if wireframe == self.wireframe {
+            return;
+        }
+        if wireframe
+            && !device
+                .features()
+                .contains(wgpu::Features::NON_FILL_POLYGON_MODE)
+        {
+            eprintln!("[graphics] (warning): The device can't draw wireframes, keeping fill mode");
+            return;
+        }
+
+        self.wireframe = wireframe;
+        self.reconfigure(
+            device,
+            self.color_format,
+            self.depth_format,
+            self.sample_count,
+        );
+    }
+
+    pub fn wireframe(&self) -> bool { self.wireframe }
+
+    /// 1.0, the far plane, by default. Only the clear value changes, e.g. for reverse-Z
+    /// experiments the projection and depth test have to follow along.
+    pub fn set_depth_clear_value(&mut self, depth_clear_value: f32) {
+        self.depth_clear_value = depth_clear_value;
+    }
+
+    pub fn depth_clear_value(&self) -> f32 { self.depth_clear_value }
+
+    fn polygon_mode(&self) -> wgpu::PolygonMode {
+        match self.wireframe {
+            true => wgpu::PolygonMode::Line,
+            false => wgpu::PolygonMode::Fill,
+        }
+    }
+
+    /// How many queued models the last `render` skipped for being out of view
+    pub fn culled_models(&self) -> usize { self.culled_models }
+
+    /// Whether a model with these bounds could be on screen
+    fn in_view(&self, bounds: &BoundingSphere, model_matrix: cgmath::Matrix4<f32>) -> bool {
+        match &self.frustum {
+            Some(frustum) => frustum.intersects(&bounds.transformed(model_matrix)),
+            None => true,
+        }
+    }
+
+    /// The entries in the order they should be drawn, see `sort_by_distance`.
+    /// Until a camera is set they're drawn as queued.
+    fn draw_order<'a, T>(
         &self,
+        entries: &'a [T],
+        back_to_front: bool,
+        layer_and_position: impl Fn(&T) -> (RenderLayer, cgmath::Vector3<f32>),
+    ) -> Vec<&'a T> {
+        let mut ordered = entries.iter().collect_vec();
+        if let Some(eye) = self.eye_position {
+            sort_by_distance(&mut ordered, eye, back_to_front, |entry| {
+                layer_and_position(entry)
+            });
+        }
+        ordered
+    }
+
+    /// Draws the queued models into `target`, which has to be in `color_format`
+    pub fn render(
+        &mut self,
         render_context: &RenderContext,
+        target: RenderTarget,
         graphics_resources: &GraphicsResources,
         model_queue: &ModelQueue,
+        clear_color: ClearColor,
         debug_info: &mut DebugTimer,
     ) {
         debug_info.push("Model Render Pass");
 
-        let depth_view =
-            Self::create_depth_view(&render_context.device, render_context.window_size);
+        let mut culled_models = 0;
+
+        let target_size = target.size(render_context);
+
+        if self
+            .attachments
+            .as_ref()
+            .map(|attachments| attachments.size)
+            != Some(target_size)
+        {
+            self.attachments = Some(Attachments {
+                size: target_size,
+                depth: self.create_target_view(
+                    render_context.device,
+                    target_size,
+                    self.depth_format,
+                ),
+                color: match self.sample_count {
+                    1 => None,
+                    _ => Some(self.create_target_view(
+                        render_context.device,
+                        target_size,
+                        self.color_format,
+                    )),
+                },
+            });
+        }
+        let dynamic_position = |(model, uniforms, layer, _): &DynamicEntry| {
+            let bounds = &graphics_resources.models[model.idx].bounds;
+            (
+                *layer,
+                bounds.transformed(uniforms.model_matrix.into()).center,
+            )
+        };
+        let dynamic_models = self.draw_order(&model_queue.dynamic_models, false, dynamic_position);
+        let batches = self.instance_batches(graphics_resources, &dynamic_models);
+        culled_models += dynamic_models.len() - batches.iter().map(Vec::len).sum::<usize>();
+        self.write_instances(render_context, &batches);
+
+        let attachments = self.attachments.as_ref().unwrap();
+
+        // Offscreen targets bring their own depth, unless it has to be multisampled
+        let depth_view = match (target, self.sample_count) {
+            (RenderTarget::Offscreen(target), 1) => target.depth_view(),
+            _ => &attachments.depth,
+        };
+
+        // Multisampled frames are drawn off screen and resolved into the target
+        let frame_view = target.color_view(render_context);
+        let (attachment, resolve_target) = match &attachments.color {
+            Some(view) => (view, Some(frame_view)),
+            None => (frame_view, None),
+        };
+
+        // The shadow pass draws the dynamic models too, so they're placed up front
+        let all_dynamic_models = model_queue
+            .dynamic_models
+            .iter()
+            .chain(&model_queue.transparent_models);
+        for (model, uniforms, _, pose) in all_dynamic_models {
+            render_context
+                .queue
+                .write_buffer(&model.buffer, 0, bytemuck::bytes_of(uniforms));
+            if let Some(pose) = pose {
+                render_context.queue.write_buffer(
+                    &model.joint_buffer,
+                    0,
+                    bytemuck::bytes_of(&**pose),
+                );
+            }
+        }
+
+        self.render_shadows(render_context, graphics_resources, model_queue, debug_info);
 
         debug_info.push("Static Model Render");
 
@@ -215,17 +1065,17 @@ impl ModelRenderPipeline {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: None,
             color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                attachment: &render_context.current_frame.output.view,
-                resolve_target: None,
+                attachment,
+                resolve_target,
                 ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    load: wgpu::LoadOp::Clear(clear_color.0),
                     store: true,
                 },
             }],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                attachment: &depth_view,
+                attachment: depth_view,
                 depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
+                    load: wgpu::LoadOp::Clear(self.depth_clear_value),
                     store: true,
                 }),
                 stencil_ops: None,
@@ -234,32 +1084,36 @@ impl ModelRenderPipeline {
 
         render_pass.set_pipeline(&self.static_pipeline);
         render_pass.set_bind_group(0, &self.global_bind_group, &[]);
+        render_pass.set_bind_group(3, &self.default_joint_bind_group, &[]);
+
+        let static_models = self.draw_order(&model_queue.static_models, false, |(model, layer)| {
+            let bounds = &graphics_resources.models[model.idx].bounds;
+            (*layer, bounds.transformed(model.model_matrix).center)
+        });
 
         // render static meshes
-        for model in &model_queue.static_models {
+        for (model, _) in static_models {
+            let gpu_model = &graphics_resources.models[model.idx];
+            if !self.in_view(&gpu_model.bounds, model.model_matrix) {
+                culled_models += 1;
+                continue;
+            }
+
             render_pass.set_bind_group(1, &model.bind_group, &[]);
-            for mesh in &graphics_resources.models[model.idx].meshes {
-                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-                render_pass.draw(0..mesh.num_vertices as u32, 0..1)
+            for mesh in &gpu_model.meshes {
+                render_pass.set_bind_group(2, self.material_bind_group(mesh), &[]);
+                mesh.draw(&mut render_pass);
             }
         }
 
         drop(render_pass);
 
-        render_context
-            .queue
-            .submit(std::iter::once(encoder.finish()));
+        render_context.submit(encoder.finish());
 
         debug_info.pop();
 
         debug_info.push("Dynamic Model Render");
 
-        for (model, uniforms) in &model_queue.dynamic_models {
-            render_context
-                .queue
-                .write_buffer(&model.buffer, 0, bytemuck::bytes_of(uniforms));
-        }
-
         let mut encoder =
             render_context
                 .device
@@ -270,15 +1124,15 @@ impl ModelRenderPipeline {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: None,
             color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                attachment: &render_context.current_frame.output.view,
-                resolve_target: None,
+                attachment,
+                resolve_target,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Load,
                     store: true,
                 },
             }],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                attachment: &depth_view,
+                attachment: depth_view,
                 depth_ops: Some(wgpu::Operations {
                     load: wgpu::LoadOp::Load,
                     store: true,
@@ -287,24 +1141,219 @@ impl ModelRenderPipeline {
             }),
         });
 
-        render_pass.set_pipeline(&self.dynamic_pipeline);
+        let transparent_models =
+            self.draw_order(&model_queue.transparent_models, true, dynamic_position);
+
         render_pass.set_bind_group(0, &self.global_bind_group, &[]);
 
-        // render dynamic meshes
-        for (model, _) in model_queue.dynamic_models.iter() {
+        let mut first_instance = 0;
+        for batch in &batches {
+            let (model, _, _, pose) = batch[0];
+            if pose.is_some() {
+                render_pass.set_pipeline(&self.dynamic_pipeline);
+                self.draw_dynamic_models(&mut render_pass, graphics_resources, batch);
+                continue;
+            }
+
+            let instances = first_instance..first_instance + batch.len() as u32;
+            first_instance = instances.end;
+
+            render_pass.set_pipeline(&self.instanced_pipeline);
+            // The normal mapping is the same for the whole batch, so the first model's will do
             render_pass.set_bind_group(1, &model.bind_group, &[]);
+            render_pass.set_bind_group(3, &self.default_joint_bind_group, &[]);
+            render_pass.set_vertex_buffer(1, self.instance_buf.slice(..));
             for mesh in &graphics_resources.models[model.idx].meshes {
-                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-                render_pass.draw(0..mesh.num_vertices as u32, 0..1)
+                render_pass.set_bind_group(2, self.material_bind_group(mesh), &[]);
+                mesh.draw_instances(&mut render_pass, instances.clone());
             }
         }
+
+        // Transparent models test against the opaque depth, but don't write their own
+        render_pass.set_pipeline(&self.transparent_pipeline);
+        culled_models +=
+            self.draw_dynamic_models(&mut render_pass, graphics_resources, &transparent_models);
         drop(render_pass);
 
+        render_context.submit(encoder.finish());
+
+        self.culled_models = culled_models;
+
+        debug_info.pop();
+
+        debug_info.pop();
+    }
+
+    /// Draws in the given order, returns how many models were culled.
+    /// Posed models can reach outside their bind pose bounds, so they're never culled.
+    fn draw_dynamic_models<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        graphics_resources: &'a GraphicsResources,
+        entries: &[&'a DynamicEntry],
+    ) -> usize {
+        let mut culled_models = 0;
+
+        for (model, uniforms, _, pose) in entries.iter().copied() {
+            let gpu_model = &graphics_resources.models[model.idx];
+            if pose.is_none() && !self.in_view(&gpu_model.bounds, uniforms.model_matrix.into()) {
+                culled_models += 1;
+                continue;
+            }
+
+            render_pass.set_bind_group(1, &model.bind_group, &[]);
+            let joint_bind_group = match pose {
+                Some(_) => &model.joint_bind_group,
+                None => &self.default_joint_bind_group,
+            };
+            render_pass.set_bind_group(3, joint_bind_group, &[]);
+            for mesh in &gpu_model.meshes {
+                render_pass.set_bind_group(2, self.material_bind_group(mesh), &[]);
+                mesh.draw(render_pass);
+            }
+        }
+
+        culled_models
+    }
+
+    /// The opaque dynamic models in view, grouped into instanced draws. Unposed models that share
+    /// a model, layer and normal mapping are drawn together, posed models are drawn one by one.
+    fn instance_batches<'a>(
+        &self,
+        graphics_resources: &GraphicsResources,
+        entries: &[&'a DynamicEntry],
+    ) -> Vec<Vec<&'a DynamicEntry>> {
+        let visible = entries
+            .iter()
+            .copied()
+            .filter(|(model, uniforms, _, pose)| {
+                pose.is_some()
+                    || self.in_view(
+                        &graphics_resources.models[model.idx].bounds,
+                        uniforms.model_matrix.into(),
+                    )
+            });
+
+        group_by_key(visible, |(model, uniforms, layer, pose)| match pose {
+            Some(_) => None,
+            None => Some((model.idx, *layer, uniforms.normal_mapped)),
+        })
+    }
+
+    /// Uploads the uniforms of the instanced batches back to back, in the order they're drawn
+    fn write_instances(&mut self, render_context: &RenderContext, batches: &[Vec<&DynamicEntry>]) {
+        let instances = batches
+            .iter()
+            .filter(|batch| batch[0].3.is_none())
+            .flatten()
+            .map(|(_, uniforms, _, _)| *uniforms)
+            .collect_vec();
+        if instances.is_empty() {
+            return;
+        }
+
+        if instances.len() > self.instance_capacity {
+            self.instance_capacity = instances.len().next_power_of_two();
+            self.instance_buf =
+                Self::instance_buffer(render_context.device, self.instance_capacity);
+        }
         render_context
             .queue
-            .submit(std::iter::once(encoder.finish()));
+            .write_buffer(&self.instance_buf, 0, bytemuck::cast_slice(&instances));
+    }
 
-        debug_info.pop();
+    /// Renders the depth of everything opaque, culled or not, as seen by the first
+    /// directional light. Without one, or with shadows off, the shader is told to skip it.
+    fn render_shadows(
+        &self,
+        render_context: &RenderContext,
+        graphics_resources: &GraphicsResources,
+        model_queue: &ModelQueue,
+        debug_info: &mut DebugTimer,
+    ) {
+        let shadows = &self.shadows;
+        let light_view_proj = match shadows.light_direction {
+            Some(direction) if shadows.enabled => {
+                generate_light_matrix(direction, shadows.focus, SHADOW_EXTENT)
+            }
+            _ => {
+                render_context.queue.write_buffer(
+                    &shadows.uniform_buf,
+                    0,
+                    bytemuck::bytes_of(&ShadowUniforms::default()),
+                );
+                return;
+            }
+        };
+
+        debug_info.push("Shadow Render");
+
+        render_context.queue.write_buffer(
+            &shadows.global_uniform_buf,
+            0,
+            bytemuck::bytes_of(&GlobalUniforms {
+                projection_view_matrix: light_view_proj.into(),
+                ..Default::default()
+            }),
+        );
+        render_context.queue.write_buffer(
+            &shadows.uniform_buf,
+            0,
+            bytemuck::bytes_of(&ShadowUniforms {
+                light_view_proj: light_view_proj.into(),
+                enabled: 1,
+                texel_size: 1.0 / shadows.map_size as f32,
+                _pad: Default::default(),
+            }),
+        );
+
+        let mut encoder =
+            render_context
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Shadow Render"),
+                });
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &shadows.map_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_bind_group(0, &shadows.global_bind_group, &[]);
+        render_pass.set_bind_group(2, &self.default_material_bind_group, &[]);
+        render_pass.set_bind_group(3, &self.default_joint_bind_group, &[]);
+
+        render_pass.set_pipeline(&shadows.static_pipeline);
+        for (model, _) in &model_queue.static_models {
+            render_pass.set_bind_group(1, &model.bind_group, &[]);
+            for mesh in &graphics_resources.models[model.idx].meshes {
+                mesh.draw(&mut render_pass);
+            }
+        }
+
+        render_pass.set_pipeline(&shadows.dynamic_pipeline);
+        for (model, _, _, pose) in &model_queue.dynamic_models {
+            render_pass.set_bind_group(1, &model.bind_group, &[]);
+            let joint_bind_group = match pose {
+                Some(_) => &model.joint_bind_group,
+                None => &self.default_joint_bind_group,
+            };
+            render_pass.set_bind_group(3, joint_bind_group, &[]);
+            for mesh in &graphics_resources.models[model.idx].meshes {
+                mesh.draw(&mut render_pass);
+            }
+        }
+        drop(render_pass);
+
+        render_context.submit(encoder.finish());
 
         debug_info.pop();
     }
@@ -324,21 +1373,148 @@ impl ModelRenderPipeline {
             graphics_context.window_size.width as f32 / graphics_context.window_size.height as f32,
         );
 
+        self.frustum = Some(Frustum::from_matrix(proj_view_matrix));
+        self.eye_position = Some(position);
+        self.shadows.focus = target;
+
+        self.global_uniforms.projection_view_matrix = proj_view_matrix.into();
+        self.global_uniforms.eye_position = [position.x, position.y, position.z, 0.0];
+        self.write_global_uniforms(graphics_context);
+    }
+
+    /// Fades models into the fog color with their distance from the camera
+    pub fn set_fog(&mut self, graphics_context: &GraphicsContext, fog: Fog) {
+        self.global_uniforms.fog_color = fog.color.extend(1.0).into();
+        self.global_uniforms.fog_start = fog.start;
+        self.global_uniforms.fog_end = fog.end;
+        self.global_uniforms.fog_density = fog.density.clamp(0.0, 1.0);
+        self.write_global_uniforms(graphics_context);
+    }
+
+    fn write_global_uniforms(&self, graphics_context: &GraphicsContext) {
         graphics_context.queue.write_buffer(
             &self.global_uniform_buf,
             0,
-            bytemuck::bytes_of(&GlobalUniforms {
-                projection_view_matrix: proj_view_matrix.into(),
-                eye_position: [position.x, position.y, position.z, 0.0],
-            }),
+            bytemuck::bytes_of(&self.global_uniforms),
+        );
+    }
+
+    pub fn set_lights(&mut self, graphics_context: &GraphicsContext, lights: &Lights) {
+        self.shadows.light_direction = match lights.nr_of_directional_lights {
+            0 => None,
+            _ => Some(cgmath::Vector4::from(lights.directional_lights[0].direction).truncate()),
+        };
+
+        graphics_context.queue.write_buffer(
+            &self.lights_uniform_buf,
+            0,
+            bytemuck::bytes_of(lights),
         );
     }
 
-    fn create_depth_view(
+    /// Builds bind groups for meshes that got a material since they were last drawn.
+    /// A material without a texture uses the color texture of the pipeline, one whose texture
+    /// isn't loaded (anymore) is drawn with a plain white texture so only its tint shows.
+    pub fn prepare_mesh_materials(
+        &self,
+        graphics_context: &GraphicsContext,
+        graphics_resources: &mut GraphicsResources,
+    ) {
+        let GraphicsResources {
+            models, textures, ..
+        } = graphics_resources;
+
+        let meshes = models
+            .values_mut()
+            .flat_map(|model| model.meshes.iter_mut())
+            .filter(|mesh| mesh.material_bind_group.is_none());
+
+        for mesh in meshes {
+            if let Some(material) = mesh.material {
+                let texture = match material.texture {
+                    Some(id) => textures.get(id).unwrap_or_else(|| {
+                        eprintln!("[graphics] (warning): Mesh material texture is not loaded");
+                        &self.white_texture
+                    }),
+                    None => textures
+                        .get(self.color_texture_id)
+                        .unwrap_or(&self.white_texture),
+                };
+
+                mesh.material_bind_group = Some(Self::create_material_bind_group(
+                    &graphics_context.device,
+                    &self.material_bind_group_layout,
+                    &texture.texture_view,
+                    material.tint,
+                ));
+            }
+        }
+    }
+
+    fn material_bind_group<'a>(&'a self, mesh: &'a Mesh) -> &'a wgpu::BindGroup {
+        mesh.material_bind_group
+            .as_ref()
+            .unwrap_or(&self.default_material_bind_group)
+    }
+
+    fn create_material_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        texture_view: &wgpu::TextureView,
+        tint: [f32; 4],
+    ) -> wgpu::BindGroup {
+        let uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Material Uniforms"),
+            contents: bytemuck::bytes_of(&MaterialUniforms { tint }),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Material Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(texture_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &uniform_buf,
+                        offset: 0,
+                        size: None,
+                    },
+                },
+            ],
+        })
+    }
+
+    pub(crate) fn create_joint_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        joint_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Joint Bind Group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: joint_buffer,
+                    offset: 0,
+                    size: None,
+                },
+            }],
+        })
+    }
+
+    fn create_target_view(
+        &self,
         device: &wgpu::Device,
         size: winit::dpi::PhysicalSize<u32>,
+        format: wgpu::TextureFormat,
     ) -> wgpu::TextureView {
-        let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: None,
             size: wgpu::Extent3d {
                 width: size.width,
@@ -346,41 +1522,61 @@ impl ModelRenderPipeline {
                 depth: 1,
             },
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count: self.sample_count,
             dimension: wgpu::TextureDimension::D2,
-            format: super::DEPTH_FORMAT,
+            format,
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
         });
 
-        depth_texture.create_view(&Default::default())
+        texture.create_view(&Default::default())
+    }
+
+    fn instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Model Instances"),
+            size: (capacity * std::mem::size_of::<LocalUniforms>()) as u64,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn compile_pipeline(
         device: &wgpu::Device,
         pipeline_layout: &wgpu::PipelineLayout,
         vs_module: &wgpu::ShaderModule,
         fs_module: &wgpu::ShaderModule,
+        buffers: &[wgpu::VertexBufferLayout],
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+        sample_count: u32,
+        transparent: bool,
+        polygon_mode: wgpu::PolygonMode,
     ) -> wgpu::RenderPipeline {
+        let blend = match transparent {
+            true => wgpu::BlendState {
+                src_factor: wgpu::BlendFactor::SrcAlpha,
+                dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                operation: wgpu::BlendOperation::Add,
+            },
+            false => wgpu::BlendState::REPLACE,
+        };
+
         device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: None,
             layout: Option::from(pipeline_layout),
             vertex: wgpu::VertexState {
                 module: vs_module,
                 entry_point: "main",
-                buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<super::data::Vertex>() as u64,
-                    step_mode: wgpu::InputStepMode::Vertex,
-                    attributes: &wgpu::vertex_attr_array![
-                        0 => Float3,
-                        1 => Float3,
-                        2 => Float2
-                    ],
-                }],
+                buffers,
+            },
+            primitive: wgpu::PrimitiveState {
+                polygon_mode,
+                ..Default::default()
             },
-            primitive: wgpu::PrimitiveState::default(),
             depth_stencil: Some(wgpu::DepthStencilState {
-                format: super::DEPTH_FORMAT,
-                depth_write_enabled: true,
+                format: depth_format,
+                depth_write_enabled: !transparent,
                 depth_compare: wgpu::CompareFunction::Less,
                 stencil: Default::default(),
                 bias: Default::default(),
@@ -390,13 +1586,172 @@ impl ModelRenderPipeline {
                 module: fs_module,
                 entry_point: "main",
                 targets: &[wgpu::ColorTargetState {
-                    format: super::COLOR_FORMAT,
-                    alpha_blend: wgpu::BlendState::REPLACE, // For now
-                    color_blend: wgpu::BlendState::REPLACE,
+                    format: color_format,
+                    alpha_blend: blend.clone(),
+                    color_blend: blend,
                     write_mask: wgpu::ColorWrite::ALL,
                 }],
             }),
-            multisample: wgpu::MultisampleState::default(),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
         })
     }
+
+    /// Depth only, the bias keeps surfaces from shadowing themselves
+    fn compile_shadow_pipeline(
+        device: &wgpu::Device,
+        pipeline_layout: &wgpu::PipelineLayout,
+        vs_module: &wgpu::ShaderModule,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Shadow Pipeline"),
+            layout: Some(pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: vs_module,
+                entry_point: "main",
+                buffers: &[VERTEX_BUFFER_LAYOUT],
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: SHADOW_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: Default::default(),
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+                clamp_depth: false,
+            }),
+            fragment: None,
+            multisample: Default::default(),
+        })
+    }
+}
+
+/// Every device supports these sample counts, wgpu can't tell us which others it does
+pub const GUARANTEED_SAMPLE_COUNTS: [u32; 2] = [1, 4];
+
+/// The sample count to draw with when `requested` is asked for, counts that not every device
+/// supports fall back to no multisampling
+pub fn supported_sample_count(requested: u32) -> u32 {
+    match requested {
+        0 | 1 => 1,
+        _ if GUARANTEED_SAMPLE_COUNTS.contains(&requested) => requested,
+        _ => {
+            eprintln!(
+                "[graphics] (warning): {}x multisampling may not be supported, using 1x",
+                requested
+            );
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_sample_counts_fall_back_to_one() {
+        assert_eq!(supported_sample_count(0), 1);
+        assert_eq!(supported_sample_count(1), 1);
+        assert_eq!(supported_sample_count(4), 4);
+        assert_eq!(supported_sample_count(3), 1);
+        assert_eq!(supported_sample_count(16), 1);
+    }
+
+    #[test]
+    fn sorting_by_layer_is_stable() {
+        let mut entries = vec![
+            ("rug", RenderLayer(0)),
+            ("ring", RenderLayer(2)),
+            ("decal", RenderLayer(0)),
+            ("unit", RenderLayer(1)),
+            ("marker", RenderLayer(2)),
+            ("wall", RenderLayer(0)),
+        ];
+
+        sort_by_layer(&mut entries, |(_, layer)| *layer);
+
+        let names = entries.iter().map(|(name, _)| *name).collect_vec();
+        assert_eq!(names, ["rug", "decal", "wall", "unit", "ring", "marker"]);
+    }
+
+    #[test]
+    fn distance_sorting_stays_within_layers() {
+        let at = |x: f32| cgmath::Vector3::new(x, 0.0, 0.0);
+        let mut entries = vec![
+            ("far", RenderLayer(0), at(9.0)),
+            ("overlay", RenderLayer(1), at(5.0)),
+            ("near", RenderLayer(0), at(1.0)),
+            ("middle", RenderLayer(0), at(-4.0)),
+        ];
+        let names = |entries: &[(&'static str, RenderLayer, cgmath::Vector3<f32>)]| {
+            entries.iter().map(|(name, _, _)| *name).collect_vec()
+        };
+
+        sort_by_distance(&mut entries, at(0.0), false, |(_, layer, pos)| {
+            (*layer, *pos)
+        });
+        assert_eq!(names(&entries), ["near", "middle", "far", "overlay"]);
+
+        sort_by_distance(&mut entries, at(0.0), true, |(_, layer, pos)| {
+            (*layer, *pos)
+        });
+        assert_eq!(names(&entries), ["far", "middle", "near", "overlay"]);
+    }
+
+    #[test]
+    fn models_sharing_a_key_are_grouped_in_draw_order() {
+        let entries = vec![
+            ("crate", Some("crate")),
+            ("barrel", Some("barrel")),
+            ("posed", None),
+            ("crate", Some("crate")),
+            ("posed", None),
+            ("barrel", Some("barrel")),
+            ("crate", Some("crate")),
+        ];
+
+        let groups = group_by_key(entries.iter().enumerate(), |(_, (_, key))| *key);
+
+        let indices = groups
+            .iter()
+            .map(|group| group.iter().map(|(i, _)| *i).collect_vec())
+            .collect_vec();
+        assert_eq!(indices, vec![vec![0, 3, 6], vec![1, 5], vec![2], vec![4]]);
+    }
+
+    #[test]
+    fn instance_attributes_match_local_uniforms() {
+        let uniforms = LocalUniforms::init();
+        let base = &uniforms as *const _ as usize;
+        let offset_of = |field: usize| (field - base) as u64;
+
+        let offsets = INSTANCE_ATTRIBUTES.iter().map(|attribute| attribute.offset);
+        let expected = [
+            offset_of(&uniforms.model_matrix[0] as *const _ as usize),
+            offset_of(&uniforms.model_matrix[1] as *const _ as usize),
+            offset_of(&uniforms.model_matrix[2] as *const _ as usize),
+            offset_of(&uniforms.model_matrix[3] as *const _ as usize),
+            offset_of(&uniforms.material.albedo as *const _ as usize),
+            offset_of(&uniforms.tint as *const _ as usize),
+        ];
+        assert!(offsets.eq(expected.iter().copied()));
+
+        // Right after the vertex attributes, which end at 5
+        let locations = INSTANCE_ATTRIBUTES
+            .iter()
+            .map(|attribute| attribute.shader_location)
+            .collect_vec();
+        assert_eq!(locations, (6..12).collect_vec());
+        assert_eq!(
+            INSTANCE_BUFFER_LAYOUT.array_stride,
+            wgpu::BIND_BUFFER_ALIGNMENT
+        );
+    }
 }
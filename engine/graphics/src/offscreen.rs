@@ -0,0 +1,179 @@
+use winit::dpi::PhysicalSize;
+
+use crate::{GraphicsResources, RenderContext, DEPTH_FORMAT};
+
+/// Color and depth textures to render into instead of the frame, e.g. for post-processing,
+/// minimaps or portals. Anything drawn into it can be sampled in a later pass, see
+/// `OffscreenTarget::bind_group` and `fullscreen_vertex_state`.
+pub struct OffscreenTarget {
+    color: wgpu::Texture,
+    color_view: wgpu::TextureView,
+    _depth: wgpu::Texture,
+    depth_view: wgpu::TextureView,
+    color_format: wgpu::TextureFormat,
+    size: PhysicalSize<u32>,
+}
+
+impl OffscreenTarget {
+    /// The pipelines drawing into it have to be made for `color_format`,
+    /// e.g. through `ModelRenderPipeline::reconfigure`
+    pub fn new(
+        device: &wgpu::Device,
+        size: PhysicalSize<u32>,
+        color_format: wgpu::TextureFormat,
+    ) -> Self {
+        let (color, depth) = Self::create_textures(device, size, color_format);
+
+        Self {
+            color_view: color.create_view(&Default::default()),
+            color,
+            depth_view: depth.create_view(&Default::default()),
+            _depth: depth,
+            color_format,
+            size,
+        }
+    }
+
+    /// To copy what was drawn out of it
+    pub(crate) fn color_texture(&self) -> &wgpu::Texture { &self.color }
+
+    pub fn color_view(&self) -> &wgpu::TextureView { &self.color_view }
+
+    pub fn depth_view(&self) -> &wgpu::TextureView { &self.depth_view }
+
+    pub fn color_format(&self) -> wgpu::TextureFormat { self.color_format }
+
+    pub fn size(&self) -> PhysicalSize<u32> { self.size }
+
+    /// Recreates the textures at the new size, what was drawn into them is lost.
+    /// Targets covering the window should follow `GraphicsContext::resize`, so the same
+    /// zero size of a minimized window is ignored here.
+    pub fn resize(&mut self, device: &wgpu::Device, size: PhysicalSize<u32>) {
+        if size.width == 0 || size.height == 0 || size == self.size {
+            return;
+        }
+
+        *self = Self::new(device, size, self.color_format);
+    }
+
+    /// Texture at binding 0 and sampler at binding 1, visible to fragment shaders
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Offscreen Target Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        filtering: true,
+                        comparison: false,
+                    },
+                    count: None,
+                },
+            ],
+        })
+    }
+
+    /// Samples the color texture through `bind_group_layout`.
+    /// Bind groups hold on to the textures, so make a new one after `resize`.
+    pub fn bind_group(
+        &self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Offscreen Target Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&self.color_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+        })
+    }
+
+    fn create_textures(
+        device: &wgpu::Device,
+        size: PhysicalSize<u32>,
+        color_format: wgpu::TextureFormat,
+    ) -> (wgpu::Texture, wgpu::Texture) {
+        let descriptor = |label, format, usage| wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size.width.max(1),
+                height: size.height.max(1),
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage,
+        };
+
+        let color = device.create_texture(&descriptor(
+            "Offscreen Color",
+            color_format,
+            wgpu::TextureUsage::RENDER_ATTACHMENT
+                | wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::COPY_SRC,
+        ));
+        let depth = device.create_texture(&descriptor(
+            "Offscreen Depth",
+            DEPTH_FORMAT,
+            wgpu::TextureUsage::RENDER_ATTACHMENT,
+        ));
+
+        (color, depth)
+    }
+}
+
+/// Where a pass draws to
+#[derive(Clone, Copy)]
+pub enum RenderTarget<'a> {
+    /// The swap chain frame of the `RenderContext`
+    Frame,
+    Offscreen(&'a OffscreenTarget),
+}
+
+impl<'a> RenderTarget<'a> {
+    pub fn color_view(self, render_context: &'a RenderContext) -> &'a wgpu::TextureView {
+        match self {
+            RenderTarget::Frame => render_context.color_view(),
+            RenderTarget::Offscreen(target) => target.color_view(),
+        }
+    }
+
+    pub fn size(self, render_context: &RenderContext) -> PhysicalSize<u32> {
+        match self {
+            RenderTarget::Frame => render_context.window_size,
+            RenderTarget::Offscreen(target) => target.size(),
+        }
+    }
+}
+
+/// A triangle covering the whole target, drawn with 3 vertices and no vertex buffer.
+/// Hands the fragment shader `v_TexCoord` at location 0, (0, 0) being the top left.
+pub fn fullscreen_vertex_state(graphics_resources: &GraphicsResources) -> wgpu::VertexState<'_> {
+    wgpu::VertexState {
+        module: &graphics_resources.shaders["fullscreen.vert"],
+        entry_point: "main",
+        buffers: &[],
+    }
+}
@@ -0,0 +1,355 @@
+use cgmath::Vector2;
+use legion::{Entity, EntityStore, IntoQuery};
+use wgpu::util::DeviceExt;
+use winit::dpi::PhysicalSize;
+
+use crate::components::{DynamicModel, StaticModel};
+use crate::models::{ModelRenderPipeline, VERTEX_ATTRIBUTES};
+use crate::offscreen::OffscreenTarget;
+use crate::{GraphicsContext, GraphicsResources, DEPTH_FORMAT};
+
+/// Each texel holds the id of the entity drawn there
+pub const PICKING_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+
+/// Written where no model was drawn, entity ids start at 1
+const NO_ENTITY: u32 = 0;
+
+/// Every model gets its id at its own dynamic offset into one buffer
+const ID_STRIDE: wgpu::BufferAddress = wgpu::BIND_BUFFER_ALIGNMENT;
+
+/// Finds the entity whose model is under the cursor by drawing every model's entity id
+/// into an `OffscreenTarget` and reading back the texel under it. Unlike
+/// `GraphicsContext::screen_to_world` it handles elevated and overlapping models.
+/// The read back stalls until the GPU is done, so only pick on demand, e.g. on a click.
+pub struct Picking {
+    target: OffscreenTarget,
+    global_bind_group: wgpu::BindGroup,
+    id_bind_group_layout: wgpu::BindGroupLayout,
+    static_pipeline: wgpu::RenderPipeline,
+    dynamic_pipeline: wgpu::RenderPipeline,
+    readback_buf: wgpu::Buffer,
+}
+
+impl Picking {
+    /// Draws with the camera of `model_render_pipeline`
+    pub fn new(
+        context: &GraphicsContext,
+        graphics_resources: &GraphicsResources,
+        model_render_pipeline: &ModelRenderPipeline,
+    ) -> Self {
+        let device = &context.device;
+
+        let target = OffscreenTarget::new(device, context.window_size, PICKING_FORMAT);
+
+        // Only the camera is needed from the globals, like in the shadow pass
+        let global_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Global Bind Group Layout -- Picking"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let global_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Global Bind Group -- Picking"),
+            layout: &global_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &model_render_pipeline.global_uniform_buf,
+                    offset: 0,
+                    size: None,
+                },
+            }],
+        });
+
+        // Takes the place of the materials, which the ids don't need
+        let id_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Id Bind Group Layout -- Picking"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Picking Pipeline Layout"),
+            bind_group_layouts: &[
+                &global_bind_group_layout,
+                &model_render_pipeline.local_bind_group_layout,
+                &id_bind_group_layout,
+                &model_render_pipeline.joint_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let compile_pipeline = |vs_module| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Picking Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: vs_module,
+                    entry_point: "main",
+                    buffers: &[wgpu::VertexBufferLayout {
+                        array_stride: std::mem::size_of::<crate::data::Vertex>() as u64,
+                        step_mode: wgpu::InputStepMode::Vertex,
+                        attributes: &VERTEX_ATTRIBUTES,
+                    }],
+                },
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: Default::default(),
+                    bias: Default::default(),
+                    clamp_depth: false,
+                }),
+                fragment: Some(wgpu::FragmentState {
+                    module: &graphics_resources.shaders["pick.frag"],
+                    entry_point: "main",
+                    // Integer targets can't be blended
+                    targets: &[PICKING_FORMAT.into()],
+                }),
+                multisample: Default::default(),
+            })
+        };
+
+        let static_pipeline = compile_pipeline(&graphics_resources.shaders["static.vert"]);
+        let dynamic_pipeline = compile_pipeline(&graphics_resources.shaders["forward.vert"]);
+
+        let readback_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Picking Readback"),
+            size: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            target,
+            global_bind_group,
+            id_bind_group_layout,
+            static_pipeline,
+            dynamic_pipeline,
+            readback_buf,
+        }
+    }
+
+    /// The entity with a `StaticModel` or `DynamicModel` in `world` under `mouse_pos`,
+    /// as they were last rendered. None if there's only background there, or the mouse
+    /// is outside the window.
+    pub fn pick(
+        &mut self,
+        context: &GraphicsContext,
+        graphics_resources: &GraphicsResources,
+        world: &impl EntityStore,
+        mouse_pos: Vector2<f32>,
+    ) -> Option<Entity> {
+        let (x, y) = texel_under(mouse_pos, context.window_size)?;
+        self.target.resize(&context.device, context.window_size);
+
+        let static_models = <(Entity, &StaticModel)>::query()
+            .iter(world)
+            .collect::<Vec<_>>();
+        let dynamic_models = <(Entity, &DynamicModel)>::query()
+            .iter(world)
+            .collect::<Vec<_>>();
+        let entities = static_models
+            .iter()
+            .map(|(entity, _)| **entity)
+            .chain(dynamic_models.iter().map(|(entity, _)| **entity))
+            .collect::<Vec<_>>();
+
+        let ids = (0..entities.len())
+            .flat_map(|index| {
+                let mut id = [0; ID_STRIDE as usize];
+                id[..4].copy_from_slice(&id_of(index).to_ne_bytes());
+                id
+            })
+            .collect::<Vec<u8>>();
+        // Nothing to draw still clears the target, but the buffer can't be empty
+        let id_buf = context
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Picking Ids"),
+                contents: if ids.is_empty() {
+                    &[0; ID_STRIDE as usize]
+                } else {
+                    &ids
+                },
+                usage: wgpu::BufferUsage::UNIFORM,
+            });
+        let id_bind_group = context
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Id Bind Group -- Picking"),
+                layout: &self.id_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: &id_buf,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(16),
+                    },
+                }],
+            });
+        let id_offset = |index: usize| [(index as wgpu::BufferAddress * ID_STRIDE) as u32];
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Picking"),
+            });
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: self.target.color_view(),
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: self.target.depth_view(),
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: false,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        // Only the texel under the mouse is read, so that's the only one drawn
+        render_pass.set_scissor_rect(x, y, 1, 1);
+        render_pass.set_bind_group(0, &self.global_bind_group, &[]);
+
+        render_pass.set_pipeline(&self.static_pipeline);
+        for (index, (_, model)) in static_models.iter().enumerate() {
+            render_pass.set_bind_group(1, &model.bind_group, &[]);
+            render_pass.set_bind_group(2, &id_bind_group, &id_offset(index));
+            for mesh in &graphics_resources.models[model.idx].meshes {
+                mesh.draw(&mut render_pass);
+            }
+        }
+
+        // The joints are whatever was last rendered, the bind pose for models without an Animator
+        render_pass.set_pipeline(&self.dynamic_pipeline);
+        for (index, (_, model)) in dynamic_models.iter().enumerate() {
+            render_pass.set_bind_group(1, &model.bind_group, &[]);
+            render_pass.set_bind_group(2, &id_bind_group, &id_offset(static_models.len() + index));
+            render_pass.set_bind_group(3, &model.joint_bind_group, &[]);
+            for mesh in &graphics_resources.models[model.idx].meshes {
+                mesh.draw(&mut render_pass);
+            }
+        }
+
+        drop(render_pass);
+
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: self.target.color_texture(),
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+            },
+            wgpu::BufferCopyView {
+                buffer: &self.readback_buf,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT,
+                    rows_per_image: 1,
+                },
+            },
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+        );
+
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = self.readback_buf.slice(..4);
+        let mapped = slice.map_async(wgpu::MapMode::Read);
+        context.device.poll(wgpu::Maintain::Wait);
+        if let Err(err) = futures::executor::block_on(mapped) {
+            eprintln!(
+                "[graphics] (warning): Couldn't read back the picked entity: {:?}",
+                err
+            );
+            return None;
+        }
+
+        let id = {
+            let texel = slice.get_mapped_range();
+            u32::from_ne_bytes([texel[0], texel[1], texel[2], texel[3]])
+        };
+        self.readback_buf.unmap();
+
+        entity_of(id, &entities)
+    }
+}
+
+/// The texel `mouse_pos` is over, none if it's outside of a target of `size`
+fn texel_under(mouse_pos: Vector2<f32>, size: PhysicalSize<u32>) -> Option<(u32, u32)> {
+    let inside = |pos: f32, extent: u32| pos >= 0.0 && pos < extent as f32;
+    if inside(mouse_pos.x, size.width) && inside(mouse_pos.y, size.height) {
+        Some((mouse_pos.x as u32, mouse_pos.y as u32))
+    } else {
+        None
+    }
+}
+
+fn id_of(index: usize) -> u32 { index as u32 + 1 }
+
+fn entity_of(id: u32, entities: &[Entity]) -> Option<Entity> {
+    match id {
+        NO_ENTITY => None,
+        id => entities.get(id as usize - 1).copied(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use legion::World;
+
+    use super::*;
+
+    #[test]
+    fn ids_map_back_to_entities() {
+        let mut world = World::default();
+        let entities = world.extend(vec![(0u32,), (1u32,), (2u32,)]).to_vec();
+
+        for (index, &entity) in entities.iter().enumerate() {
+            assert_eq!(entity_of(id_of(index), &entities), Some(entity));
+        }
+        assert_eq!(entity_of(NO_ENTITY, &entities), None);
+        // Ids that don't belong to anything, e.g. from a stale target
+        assert_eq!(entity_of(id_of(entities.len()), &entities), None);
+    }
+
+    #[test]
+    fn only_texels_inside_the_target_are_picked() {
+        let size = PhysicalSize::new(64, 32);
+
+        assert_eq!(texel_under(Vector2::new(0.0, 0.0), size), Some((0, 0)));
+        assert_eq!(texel_under(Vector2::new(63.9, 31.5), size), Some((63, 31)));
+        assert_eq!(texel_under(Vector2::new(64.0, 10.0), size), None);
+        assert_eq!(texel_under(Vector2::new(10.0, -0.5), size), None);
+    }
+}
@@ -0,0 +1,222 @@
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+use winit::dpi::PhysicalSize;
+
+use crate::offscreen::{fullscreen_vertex_state, OffscreenTarget};
+use crate::{GraphicsContext, GraphicsResources, RenderContext, COLOR_FORMAT};
+
+/// What the scene is rendered in before it's tonemapped, so lights can go past 1
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// How HDR colors are squeezed into what the screen can show.
+/// The numbers have to agree with the defines in post.frag.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Tonemap {
+    /// Clips everything past 1
+    None = 0,
+    Reinhard = 1,
+    /// Narkowicz's fit of the ACES filmic curve, keeps more contrast than Reinhard
+    #[default]
+    Aces = 2,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct PostProcessUniforms {
+    exposure: f32,
+    tonemap: u32,
+    gamma: f32,
+    _pad: u32,
+}
+
+/// Takes the scene from an HDR offscreen target to the frame, applying exposure,
+/// tonemapping and gamma correction with a full-screen triangle.
+/// Models draw into `scene`, everything 2D goes on top of the frame afterwards.
+pub struct PostProcess {
+    scene: OffscreenTarget,
+    scene_bind_group_layout: wgpu::BindGroupLayout,
+    scene_bind_group: wgpu::BindGroup,
+    sampler: wgpu::Sampler,
+    uniform_buf: wgpu::Buffer,
+    uniform_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    /// Scales the scene's colors before tonemapping
+    pub exposure: f32,
+    pub tonemap: Tonemap,
+    pub gamma: f32,
+}
+
+impl PostProcess {
+    pub fn new(context: &GraphicsContext, graphics_resources: &GraphicsResources) -> Self {
+        let device = &context.device;
+
+        let scene = OffscreenTarget::new(device, context.window_size, HDR_FORMAT);
+        let scene_bind_group_layout = OffscreenTarget::bind_group_layout(device);
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Post Process Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let scene_bind_group = scene.bind_group(device, &scene_bind_group_layout, &sampler);
+
+        let uniform_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Post Process Uniforms Layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Post Process Uniforms"),
+            contents: bytemuck::bytes_of(&PostProcessUniforms::zeroed()),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Post Process Uniforms"),
+            layout: &uniform_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &uniform_buf,
+                    offset: 0,
+                    size: None,
+                },
+            }],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Post Process Pipeline Layout"),
+            bind_group_layouts: &[&scene_bind_group_layout, &uniform_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Post Process Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: fullscreen_vertex_state(graphics_resources),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            fragment: Some(wgpu::FragmentState {
+                module: &graphics_resources.shaders["post.frag"],
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: COLOR_FORMAT,
+                    alpha_blend: wgpu::BlendState::REPLACE,
+                    color_blend: wgpu::BlendState::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        Self {
+            scene,
+            scene_bind_group_layout,
+            scene_bind_group,
+            sampler,
+            uniform_buf,
+            uniform_bind_group,
+            pipeline,
+            exposure: 1.0,
+            tonemap: Tonemap::default(),
+            gamma: 2.2,
+        }
+    }
+
+    /// Where the scene is drawn, in `HDR_FORMAT`
+    pub fn scene(&self) -> &OffscreenTarget { &self.scene }
+
+    /// Follows the window, see `OffscreenTarget::resize`
+    pub fn resize(&mut self, device: &wgpu::Device, size: PhysicalSize<u32>) {
+        if size == self.scene.size() {
+            return;
+        }
+
+        self.scene.resize(device, size);
+        self.scene_bind_group =
+            self.scene
+                .bind_group(device, &self.scene_bind_group_layout, &self.sampler);
+    }
+
+    /// Covers the whole frame with the processed scene
+    pub fn render(&self, render_context: &RenderContext) {
+        render_context.queue.write_buffer(
+            &self.uniform_buf,
+            0,
+            bytemuck::bytes_of(&PostProcessUniforms {
+                exposure: self.exposure,
+                tonemap: self.tonemap as u32,
+                gamma: self.gamma,
+                _pad: 0,
+            }),
+        );
+
+        let mut encoder =
+            render_context
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Post Process"),
+                });
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: render_context.color_view(),
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.scene_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+
+        drop(render_pass);
+
+        render_context.submit(encoder.finish());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tonemaps_match_the_shader_defines() {
+        let shader = include_str!("../../../assets/Shaders/post.frag");
+
+        for (tonemap, name) in [
+            (Tonemap::None, "NONE"),
+            (Tonemap::Reinhard, "REINHARD"),
+            (Tonemap::Aces, "ACES"),
+        ] {
+            let define = format!("#define TONEMAP_{} {}", name, tonemap as u32);
+            assert!(
+                shader.contains(&define),
+                "post.frag is missing {:?}",
+                define
+            );
+        }
+    }
+}
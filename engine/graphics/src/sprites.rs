@@ -0,0 +1,465 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use bytemuck::{Pod, Zeroable};
+use cgmath::{Vector2, Vector4};
+use wgpu::util::DeviceExt;
+
+use crate::data::{GlobalUniforms, Texture};
+use crate::{GraphicsContext, GraphicsResources, RenderContext, TextureID};
+
+/// A textured quad on the screen
+#[derive(Debug, Clone, Copy)]
+pub struct Sprite {
+    /// Top left corner in pixels, with the origin in the top left of the window
+    pub position: Vector2<f32>,
+    /// Width and height in pixels
+    pub size: Vector2<f32>,
+    /// The part of the texture to show as (min u, min v, max u, max v), where (0, 0) is the top
+    /// left of the image
+    pub uv_rect: Vector4<f32>,
+    /// Multiplied with the texture color, alpha included
+    pub tint: Vector4<f32>,
+    /// Plain white when None, so only the tint shows
+    pub texture: Option<TextureID>,
+}
+
+impl Sprite {
+    /// The whole of a texture, untinted
+    pub fn textured(position: Vector2<f32>, size: Vector2<f32>, texture: TextureID) -> Self {
+        Self {
+            position,
+            size,
+            uv_rect: Vector4::new(0.0, 0.0, 1.0, 1.0),
+            tint: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            texture: Some(texture),
+        }
+    }
+
+    /// A flat colored rectangle
+    pub fn colored(position: Vector2<f32>, size: Vector2<f32>, color: Vector4<f32>) -> Self {
+        Self {
+            position,
+            size,
+            uv_rect: Vector4::new(0.0, 0.0, 1.0, 1.0),
+            tint: color,
+            texture: None,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+struct SpriteInstance {
+    position: [f32; 2],
+    size: [f32; 2],
+    uv_rect: [f32; 4],
+    tint: [f32; 4],
+}
+
+/// Consecutive sprites drawn with the same texture
+#[derive(Debug, PartialEq)]
+struct Batch {
+    texture: Option<TextureID>,
+    instances: Range<u32>,
+}
+
+/// The CPU side of a `SpriteBatch`, sprites in the order they were drawn
+#[derive(Default)]
+struct PendingSprites {
+    instances: Vec<SpriteInstance>,
+    batches: Vec<Batch>,
+}
+
+impl PendingSprites {
+    fn push(&mut self, sprite: Sprite) {
+        let index = self.instances.len() as u32;
+        self.instances.push(SpriteInstance {
+            position: sprite.position.into(),
+            size: sprite.size.into(),
+            uv_rect: sprite.uv_rect.into(),
+            tint: sprite.tint.into(),
+        });
+
+        match self.batches.last_mut() {
+            Some(batch) if batch.texture == sprite.texture => batch.instances.end = index + 1,
+            _ => self.batches.push(Batch {
+                texture: sprite.texture,
+                instances: index..index + 1,
+            }),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.instances.clear();
+        self.batches.clear();
+    }
+}
+
+/// Draws 2D quads on top of the frame with one instanced draw per texture switch.
+///
+/// Sprites are collected between `begin` and `end`, `end` draws them in the order they came in.
+pub struct SpriteBatch {
+    global_uniform_buf: wgpu::Buffer,
+    global_bind_group: wgpu::BindGroup,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    white_texture: Texture,
+    pipeline: wgpu::RenderPipeline,
+
+    quad_buf: wgpu::Buffer,
+    instance_buf: wgpu::Buffer,
+    /// How many instances fit in `instance_buf`, it grows when a frame has more
+    capacity: usize,
+
+    pending: PendingSprites,
+    /// Missing textures that have been warned about already, so the warning isn't every frame
+    missing_textures: HashSet<TextureID>,
+}
+
+impl SpriteBatch {
+    const INITIAL_CAPACITY: usize = 256;
+
+    #[rustfmt::skip]
+    const QUAD_CORNERS: [f32; 2 * 6] = [
+        0.0, 0.0, // TL
+        0.0, 1.0, // BL
+        1.0, 0.0, // TR
+        1.0, 0.0, // TR
+        0.0, 1.0, // BL
+        1.0, 1.0, // BR
+    ];
+
+    pub fn new(graphics_context: &GraphicsContext, graphics_resources: &GraphicsResources) -> Self {
+        let device = &graphics_context.device;
+
+        let global_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                            filtering: true,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let global_uniform_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sprite Uniforms"),
+            contents: bytemuck::bytes_of(&GlobalUniforms::default()),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let global_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &global_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: &global_uniform_buf,
+                    offset: 0,
+                    size: None,
+                },
+            }],
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: None,
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let white = image::ImageBuffer::from_pixel(1, 1, image::Rgba([255, 255, 255, 255]));
+        let white_texture = Texture::new(image::DynamicImage::ImageRgba8(white), graphics_context);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&global_bind_group_layout, &texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let blend = wgpu::BlendState {
+            src_factor: wgpu::BlendFactor::SrcAlpha,
+            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+            operation: wgpu::BlendOperation::Add,
+        };
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sprites"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &graphics_resources.shaders["sprite.vert"],
+                entry_point: "main",
+                buffers: &[
+                    wgpu::VertexBufferLayout {
+                        array_stride: 2 * std::mem::size_of::<f32>() as u64,
+                        step_mode: wgpu::InputStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array![0 => Float2],
+                    },
+                    wgpu::VertexBufferLayout {
+                        array_stride: std::mem::size_of::<SpriteInstance>() as u64,
+                        step_mode: wgpu::InputStepMode::Instance,
+                        attributes: &wgpu::vertex_attr_array![
+                            1 => Float2,
+                            2 => Float2,
+                            3 => Float4,
+                            4 => Float4
+                        ],
+                    },
+                ],
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            fragment: Some(wgpu::FragmentState {
+                module: &graphics_resources.shaders["sprite.frag"],
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: super::COLOR_FORMAT,
+                    alpha_blend: blend.clone(),
+                    color_blend: blend,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        let quad_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sprite Quad"),
+            contents: bytemuck::cast_slice(&Self::QUAD_CORNERS[..]),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+
+        Self {
+            global_uniform_buf,
+            global_bind_group,
+            texture_bind_group_layout,
+            sampler,
+            white_texture,
+            pipeline,
+            quad_buf,
+            instance_buf: Self::instance_buffer(device, Self::INITIAL_CAPACITY),
+            capacity: Self::INITIAL_CAPACITY,
+            pending: Default::default(),
+            missing_textures: HashSet::new(),
+        }
+    }
+
+    fn instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sprite Instances"),
+            size: (capacity * std::mem::size_of::<SpriteInstance>()) as u64,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Throws away any sprites that weren't drawn by an `end`
+    pub fn begin(&mut self) { self.pending.clear(); }
+
+    /// Sprites drawn later end up on top
+    pub fn draw(&mut self, sprite: Sprite) { self.pending.push(sprite); }
+
+    /// Draws the sprites since `begin` over what's already in the frame
+    pub fn end(&mut self, render_context: &RenderContext, graphics_resources: &GraphicsResources) {
+        if self.pending.instances.is_empty() {
+            return;
+        }
+
+        let instances = &self.pending.instances;
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().next_power_of_two();
+            self.instance_buf = Self::instance_buffer(render_context.device, self.capacity);
+        }
+        render_context
+            .queue
+            .write_buffer(&self.instance_buf, 0, bytemuck::cast_slice(instances));
+
+        render_context.queue.write_buffer(
+            &self.global_uniform_buf,
+            0,
+            bytemuck::bytes_of(&GlobalUniforms {
+                projection_view_matrix: super::util::generate_ortho_matrix(
+                    render_context.window_size.cast(),
+                )
+                .into(),
+                eye_position: [0.0, 0.0, 1.0, 0.0],
+                ..Default::default()
+            }),
+        );
+
+        for id in self
+            .pending
+            .batches
+            .iter()
+            .filter_map(|batch| batch.texture)
+        {
+            if !graphics_resources.textures.contains_key(id) && self.missing_textures.insert(id) {
+                eprintln!("[graphics] (warning): Sprite texture is not loaded");
+            }
+        }
+
+        // Textures get replaced in place when they're reloaded, so the bind groups only live
+        // as long as the frame does
+        let texture_bind_groups = self
+            .pending
+            .batches
+            .iter()
+            .map(|batch| {
+                let texture = match batch.texture {
+                    Some(id) => graphics_resources
+                        .textures
+                        .get(id)
+                        .unwrap_or(&self.white_texture),
+                    None => &self.white_texture,
+                };
+
+                render_context
+                    .device
+                    .create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some("Sprite Texture Bind Group"),
+                        layout: &self.texture_bind_group_layout,
+                        entries: &[
+                            wgpu::BindGroupEntry {
+                                binding: 0,
+                                resource: wgpu::BindingResource::TextureView(&texture.texture_view),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 1,
+                                resource: wgpu::BindingResource::Sampler(&self.sampler),
+                            },
+                        ],
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let mut encoder =
+            render_context
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Sprite Render"),
+                });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: render_context.color_view(),
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.global_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.quad_buf.slice(..));
+            render_pass.set_vertex_buffer(1, self.instance_buf.slice(..));
+
+            for (batch, bind_group) in self.pending.batches.iter().zip(&texture_bind_groups) {
+                render_pass.set_bind_group(1, bind_group, &[]);
+                render_pass.draw(0..6, batch.instances.clone());
+            }
+        }
+
+        render_context.submit(encoder.finish());
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slotmap::SlotMap;
+
+    use super::*;
+
+    #[test]
+    fn texture_switches_start_new_batches() {
+        let mut textures = SlotMap::<TextureID, ()>::new();
+        let (a, b) = (textures.insert(()), textures.insert(()));
+        let at = |x: f32| Vector2::new(x, 0.0);
+        let size = Vector2::new(8.0, 8.0);
+
+        let mut pending = PendingSprites::default();
+        pending.push(Sprite::textured(at(0.0), size, a));
+        pending.push(Sprite::textured(at(1.0), size, a));
+        pending.push(Sprite::textured(at(2.0), size, b));
+        pending.push(Sprite::colored(
+            at(3.0),
+            size,
+            Vector4::new(1.0, 0.0, 0.0, 1.0),
+        ));
+        pending.push(Sprite::colored(
+            at(4.0),
+            size,
+            Vector4::new(0.0, 1.0, 0.0, 1.0),
+        ));
+        pending.push(Sprite::textured(at(5.0), size, a));
+
+        assert_eq!(
+            pending.batches,
+            vec![
+                Batch {
+                    texture: Some(a),
+                    instances: 0..2,
+                },
+                Batch {
+                    texture: Some(b),
+                    instances: 2..3,
+                },
+                Batch {
+                    texture: None,
+                    instances: 3..5,
+                },
+                Batch {
+                    texture: Some(a),
+                    instances: 5..6,
+                },
+            ]
+        );
+        // Still in the order they were drawn
+        for (i, instance) in pending.instances.iter().enumerate() {
+            assert_eq!(instance.position, [i as f32, 0.0]);
+        }
+
+        pending.clear();
+        assert!(pending.instances.is_empty() && pending.batches.is_empty());
+    }
+}
@@ -1,15 +1,26 @@
-use debug::DebugTimer;
+use cgmath::Matrix4;
+use debug::{DebugTimer, FrameSection, FrameStats};
+use entity_smith::FrameTime;
 use legion::systems::Runnable;
 use legion::{IntoQuery, SystemBuilder};
 use transforms::{Position, Transform};
 use winit::window::Window;
 
+use crate::animation::JointUniforms;
 use crate::canvas::{CanvasQueue, CanvasRenderPipeline};
-use crate::components::{ActiveCamera, Camera, DynamicModel, StaticModel, Target};
+use crate::components::{
+    ActiveCamera, Animator, Billboard, Camera, ClearColor, DirectionalLight, DynamicModel,
+    PointLight, RenderLayer, SpotLight, StaticModel, Target, Tint, Transparent,
+};
 use crate::data::{LocalUniforms, Material};
+use crate::debug_lines::{DebugLineRenderPipeline, DebugLines};
 use crate::gui::GuiRenderPipeline;
 use crate::models::{ModelQueue, ModelRenderPipeline};
-use crate::{GraphicsContext, GraphicsResources};
+use crate::offscreen::RenderTarget;
+use crate::post_process::PostProcess;
+use crate::sprites::SpriteBatch;
+use crate::util::pack_lights;
+use crate::{util, GraphicsContext, GraphicsError, GraphicsResources};
 
 pub const DISPLAY_DEBUG_DEFAULT: bool = false;
 
@@ -20,8 +31,11 @@ pub trait RenderBuilderExtender {
 impl RenderBuilderExtender for legion::systems::Builder {
     fn add_render_systems(&mut self) -> &mut Self {
         self.add_thread_local(update_camera_system())
+            .add_thread_local(update_lights_system())
             .add_thread_local(render_draw_static_models_system())
+            .add_thread_local(animation_system())
             .add_thread_local(render_draw_models_system())
+            .add_thread_local(prepare_mesh_materials_system())
             .add_thread_local(render_system())
     }
 }
@@ -35,8 +49,12 @@ fn update_camera_system() -> impl Runnable {
         .read_resource::<ActiveCamera>()
         .read_resource::<GraphicsContext>()
         .write_resource::<ModelRenderPipeline>()
+        .read_resource::<DebugLineRenderPipeline>()
         .build(
-            move |_, world, (active_cam, graphics_context, model_render_pass), _| {
+            move |_,
+                  world,
+                  (active_cam, graphics_context, model_render_pass, debug_line_pipeline),
+                  _| {
                 if let Ok((cam, cam_pos, target)) =
                     <(&Camera, &Transform, &Target)>::query().get(world, active_cam.entity)
                 {
@@ -47,60 +65,222 @@ fn update_camera_system() -> impl Runnable {
                             cam_pos.world_position(),
                             target_pos.world_position(),
                         );
+                        debug_line_pipeline.set_camera(
+                            graphics_context,
+                            cam,
+                            cam_pos.world_position(),
+                            target_pos.world_position(),
+                        );
                     }
                 }
             },
         )
 }
 
+fn update_lights_system() -> impl Runnable {
+    SystemBuilder::new("update_lights")
+        .read_component::<PointLight>()
+        .read_component::<SpotLight>()
+        .read_component::<DirectionalLight>()
+        .read_component::<Transform>()
+        .read_resource::<GraphicsContext>()
+        .write_resource::<ModelRenderPipeline>()
+        .with_query(<(&PointLight, &Transform)>::query())
+        .with_query(<(&SpotLight, &Transform)>::query())
+        .with_query(<(&DirectionalLight, Option<&Transform>)>::query())
+        .build(
+            move |_,
+                  world,
+                  (graphics_context, model_render_pipeline),
+                  (points, spots, directionals)| {
+                let lights = pack_lights(
+                    points
+                        .iter(world)
+                        .map(|(light, transform)| (transform.world_position(), light)),
+                    spots.iter(world).map(|(light, transform)| {
+                        let direction = transform.world_transform() * light.direction.extend(0.0);
+                        (transform.world_position(), direction.truncate(), light)
+                    }),
+                    directionals.iter(world).map(|(light, transform)| {
+                        let direction = match transform {
+                            Some(transform) => (transform.world_transform()
+                                * light.direction.extend(0.0))
+                            .truncate(),
+                            None => light.direction,
+                        };
+                        (direction, light)
+                    }),
+                );
+                model_render_pipeline.set_lights(graphics_context, &lights);
+            },
+        )
+}
+
 fn render_draw_models_system() -> impl Runnable {
     SystemBuilder::new("render_draw_models")
         .read_component::<DynamicModel>()
         .read_component::<Transform>()
+        .read_component::<Tint>()
+        .read_component::<Billboard>()
+        .read_component::<RenderLayer>()
+        .read_component::<Animator>()
+        .read_component::<Transparent>()
+        .read_component::<Camera>()
+        .read_resource::<ActiveCamera>()
         .write_resource::<ModelQueue>()
-        .with_query(<(&DynamicModel, &Transform)>::query())
-        .build(move |_, world, model_queue, query| {
-            query.for_each_mut(world, |(model, transform)| {
-                draw_model(model, transform, model_queue);
-            });
+        .with_query(<(
+            &DynamicModel,
+            &Transform,
+            Option<&Tint>,
+            Option<&Billboard>,
+            Option<&RenderLayer>,
+            Option<&Animator>,
+            Option<&Transparent>,
+        )>::query())
+        .build(move |_, world, (active_cam, model_queue), query| {
+            let camera = <(&Camera, &Transform)>::query()
+                .get(world, active_cam.entity)
+                .map(|(cam, transform)| (transform.world_position(), cam.up))
+                .ok();
+
+            query.for_each(
+                world,
+                |(model, transform, tint, billboard, layer, animator, transparent)| {
+                    let model_matrix = match (billboard, camera) {
+                        (Some(&billboard), Some((cam_pos, cam_up))) => util::billboard_matrix(
+                            transform.world_transform(),
+                            cam_pos,
+                            cam_up,
+                            billboard,
+                        ),
+                        _ => transform.world_transform(),
+                    };
+                    draw_model(
+                        model,
+                        model_matrix,
+                        tint,
+                        layer,
+                        animator,
+                        transparent.is_some(),
+                        model_queue,
+                    );
+                },
+            );
         })
 }
 
-fn draw_model(model: &DynamicModel, transform: &Transform, model_queue: &mut ModelQueue) {
-    model_queue.push_model(
-        model.clone(),
-        LocalUniforms::new(transform.world_transform().into(), Material::default()),
-    )
+fn draw_model(
+    model: &DynamicModel,
+    model_matrix: Matrix4<f32>,
+    tint: Option<&Tint>,
+    layer: Option<&RenderLayer>,
+    animator: Option<&Animator>,
+    transparent: bool,
+    model_queue: &mut ModelQueue,
+) {
+    let uniforms = LocalUniforms::new(model_matrix.into(), Material::default());
+    let uniforms = match tint {
+        Some(tint) => uniforms.with_tint(tint.0),
+        None => uniforms,
+    };
+    let layer = layer.copied().unwrap_or_default();
+
+    let pose = animator.filter(|animator| !animator.pose.is_empty());
+
+    if transparent {
+        let pose = pose.map(|animator| JointUniforms::from_pose(&animator.pose));
+        model_queue.push_transparent_model(model.clone(), uniforms, layer, pose);
+        return;
+    }
+
+    match pose {
+        Some(animator) => model_queue.push_posed_model(
+            model.clone(),
+            uniforms,
+            layer,
+            JointUniforms::from_pose(&animator.pose),
+        ),
+        None => model_queue.push_model(model.clone(), uniforms, layer),
+    }
+}
+
+fn animation_system() -> impl Runnable {
+    SystemBuilder::new("animation")
+        .read_component::<DynamicModel>()
+        .write_component::<Animator>()
+        .read_resource::<FrameTime>()
+        .read_resource::<GraphicsResources>()
+        .with_query(<(&DynamicModel, &mut Animator)>::query())
+        .build(move |_, world, (frame_time, graphics_resources), query| {
+            query.for_each_mut(world, |(model, animator)| {
+                let skin = graphics_resources
+                    .models
+                    .get(model.idx)
+                    .and_then(|model| model.skin.as_ref());
+
+                match skin {
+                    Some(skin) => {
+                        let duration = skin.clips.get(animator.clip).map_or(0.0, |c| c.duration);
+                        animator.advance(frame_time.0, duration);
+                        animator.pose = skin.sample(animator.clip, animator.time);
+                    }
+                    None => animator.pose.clear(),
+                }
+            });
+        })
 }
 
 fn render_draw_static_models_system() -> impl Runnable {
     SystemBuilder::new("render_draw_static_models_system")
         .read_component::<StaticModel>()
+        .read_component::<RenderLayer>()
         .write_resource::<ModelQueue>()
-        .with_query(<&StaticModel>::query())
+        .with_query(<(&StaticModel, Option<&RenderLayer>)>::query())
         .build(move |_, world, model_queue, query| {
             let for_query = world;
-            query.for_each_mut(for_query, |components| {
-                render_draw_static_models(components, model_queue);
+            query.for_each_mut(for_query, |(model, layer)| {
+                render_draw_static_models(model, layer, model_queue);
             });
         })
 }
 
-fn render_draw_static_models(model: &StaticModel, model_queue: &mut ModelQueue) {
-    model_queue.push_static_model(model.clone());
+fn render_draw_static_models(
+    model: &StaticModel,
+    layer: Option<&RenderLayer>,
+    model_queue: &mut ModelQueue,
+) {
+    model_queue.push_static_model(model.clone(), layer.copied().unwrap_or_default());
+}
+
+fn prepare_mesh_materials_system() -> impl Runnable {
+    SystemBuilder::new("prepare_mesh_materials")
+        .read_resource::<GraphicsContext>()
+        .read_resource::<ModelRenderPipeline>()
+        .write_resource::<GraphicsResources>()
+        .build(
+            move |_, _, (graphics_context, model_render_pipeline, graphics_resources), _| {
+                model_render_pipeline.prepare_mesh_materials(graphics_context, graphics_resources);
+            },
+        )
 }
 
 fn render_system() -> impl Runnable {
     SystemBuilder::new("render_models_system")
         .read_resource::<Window>()
         .read_resource::<GraphicsResources>()
-        .read_resource::<GraphicsContext>()
-        .read_resource::<ModelRenderPipeline>()
+        .write_resource::<GraphicsContext>()
+        .write_resource::<ModelRenderPipeline>()
+        .read_resource::<PostProcess>()
+        .read_resource::<ClearColor>()
         .write_resource::<CanvasRenderPipeline>()
         .write_resource::<GuiRenderPipeline>()
         .write_resource::<ModelQueue>()
         .write_resource::<CanvasQueue>()
+        .write_resource::<DebugLineRenderPipeline>()
+        .write_resource::<DebugLines>()
+        .write_resource::<SpriteBatch>()
         .write_resource::<DebugTimer>()
+        .write_resource::<FrameStats>()
         .build(
             move |_,
                   _,
@@ -109,11 +289,17 @@ fn render_system() -> impl Runnable {
                 graphics_resources,
                 graphics_context,
                 model_render_pipeline,
+                post_process,
+                clear_color,
                 canvas_render_pipeline,
                 gui_render_pipeline,
                 model_queue,
                 canvas_queue,
+                debug_line_pipeline,
+                debug_lines,
+                sprite_batch,
                 debug_timer,
+                frame_stats,
             ),
                   _| {
                 render(
@@ -121,11 +307,17 @@ fn render_system() -> impl Runnable {
                     graphics_resources,
                     graphics_context,
                     model_render_pipeline,
+                    post_process,
+                    **clear_color,
                     canvas_render_pipeline,
                     gui_render_pipeline,
                     model_queue,
                     canvas_queue,
+                    debug_line_pipeline,
+                    debug_lines,
+                    sprite_batch,
                     debug_timer,
+                    frame_stats,
                 )
             },
         )
@@ -136,31 +328,82 @@ fn render_system() -> impl Runnable {
 fn render(
     window: &Window,
     graphics_resources: &GraphicsResources,
-    graphics_context: &GraphicsContext,
-    model_render_pipeline: &ModelRenderPipeline,
+    graphics_context: &mut GraphicsContext,
+    model_render_pipeline: &mut ModelRenderPipeline,
+    post_process: &PostProcess,
+    clear_color: ClearColor,
     canvas_render_pipeline: &mut CanvasRenderPipeline,
     gui_render_pipeline: &mut GuiRenderPipeline,
     model_queue: &mut ModelQueue,
     canvas_queue: &mut CanvasQueue,
+    debug_line_pipeline: &mut DebugLineRenderPipeline,
+    debug_lines: &mut DebugLines,
+    sprite_batch: &mut SpriteBatch,
     debug_timer: &mut DebugTimer,
+    frame_stats: &mut FrameStats,
 ) {
-    let render_context = graphics_context.begin_render();
+    model_queue.sort_by_layer();
+
+    let render_context = match graphics_context.begin_render() {
+        Ok(render_context) => render_context,
+        // The frame wasn't ready in time, the next one will be
+        Err(GraphicsError::Surface(wgpu::SwapChainError::Timeout)) => None,
+        Err(e) => {
+            eprintln!("[graphics] (warning): Skipping a frame: {}", e);
+            None
+        }
+    };
+
+    // Headless contexts have nothing to present to, but the queues still need draining
+    if let Some(render_context) = render_context {
+        model_render_pipeline.render(
+            &render_context,
+            RenderTarget::Offscreen(post_process.scene()),
+            graphics_resources,
+            model_queue,
+            clear_color,
+            debug_timer,
+        );
+
+        debug_timer.push("Post Process");
+
+        post_process.render(&render_context);
+
+        debug_timer.pop();
 
-    model_render_pipeline.render(
-        &render_context,
-        graphics_resources,
-        model_queue,
-        debug_timer,
-    );
+        // Over the scene, but under the 2D elements
+        debug_line_pipeline.render(&render_context, debug_lines);
 
-    debug_timer.push("Canvas Render");
+        debug_timer.push("Sprite Render");
 
-    canvas_render_pipeline.render(&render_context, canvas_queue);
+        sprite_batch.end(&render_context, graphics_resources);
 
-    debug_timer.pop();
+        debug_timer.pop();
 
-    gui_render_pipeline.debug_render(window, &render_context, Some(debug_timer.finish()));
+        debug_timer.push("Canvas Render");
+
+        canvas_render_pipeline.render(&render_context, canvas_queue);
+
+        debug_timer.pop();
+
+        if debug::FRAME_STATS {
+            GuiRenderPipeline::frame_stats_window(
+                frame_stats,
+                model_render_pipeline.culled_models(),
+            );
+        }
+
+        gui_render_pipeline.debug_render(window, &render_context, Some(debug_timer.finish()));
+
+        frame_stats.record(FrameSection::GpuSubmit, render_context.submit_time());
+
+        // Every pass has submitted by now
+        render_context.present();
+    }
 
     model_queue.clear();
     canvas_queue.clear();
+    debug_lines.clear();
+    // Starts collecting the next frame's sprites
+    sprite_batch.begin();
 }
@@ -5,16 +5,22 @@
 use cgmath::num_traits::Float;
 use cgmath::{BaseFloat, Deg, EuclideanSpace};
 
-use crate::data::{DirectionalLight, Lights, PointLight};
-use crate::{GraphicsContext, MAX_NR_OF_POINT_LIGHTS};
+use crate::data::{DirectionalLight, Lights, PointLight, LIGHT_KIND_POINT, LIGHT_KIND_SPOT};
+use crate::{components, GraphicsContext, MAX_NR_OF_DIRECTIONAL_LIGHTS, MAX_NR_OF_POINT_LIGHTS};
 
-pub fn sc_desc_from_size(size: winit::dpi::PhysicalSize<u32>) -> wgpu::SwapChainDescriptor {
+/// How much of a spot light's cone is fully lit, the rest fades out towards the cutoff
+const SPOT_INNER_CONE_RATIO: f32 = 0.8;
+
+pub fn sc_desc_from_size(
+    size: winit::dpi::PhysicalSize<u32>,
+    present_mode: wgpu::PresentMode,
+) -> wgpu::SwapChainDescriptor {
     wgpu::SwapChainDescriptor {
         usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
         format: crate::COLOR_FORMAT,
         width: size.width,
         height: size.height,
-        present_mode: wgpu::PresentMode::Mailbox,
+        present_mode,
     }
 }
 
@@ -59,23 +65,40 @@ pub fn project_screen_to_world(
     }
 }
 
+/// The ray through a point on screen, as a point on it and its normalized direction away from
+/// the camera. Unlike casting from the camera's position, this also works for orthographic
+/// projections where every ray starts somewhere else.
+pub fn screen_ray(
+    screen: cgmath::Vector2<f32>,
+    view_projection: cgmath::Matrix4<f32>,
+    viewport: cgmath::Vector4<f32>,
+) -> Option<(cgmath::Vector3<f32>, cgmath::Vector3<f32>)> {
+    use cgmath::InnerSpace;
+
+    let near = project_screen_to_world(screen.extend(0.5), view_projection, viewport)?;
+    let far = project_screen_to_world(screen.extend(1.0), view_projection, viewport)?;
+
+    Some((near, (far - near).normalize()))
+}
+
 // Function by Vallentin
 // https://vallentin.dev/2019/08/12/screen-to-world-cgmath
+/// The inverse of `project_screen_to_world`. Returns `None` for points behind the camera.
 pub fn project_world_to_screen(
     world: cgmath::Vector3<f32>,
     view_projection: cgmath::Matrix4<f32>,
-    viewport: cgmath::Vector4<i32>,
+    viewport: cgmath::Vector4<f32>,
 ) -> Option<cgmath::Vector3<f32>> {
     let screen = view_projection * world.extend(1.0);
 
-    if screen.w != 0.0 {
+    if screen.w > 0.0 {
         let mut screen = screen.truncate() * (1.0 / screen.w);
 
-        screen.x = (screen.x + 1.0) * 0.5 * (viewport.z as f32) + (viewport.x as f32);
+        screen.x = (screen.x + 1.0) * 0.5 * viewport.z + viewport.x;
         // Screen Origin is Top Left    (Mouse Origin is Top Left)
-        // screen.y = (screen.y + 1.0) * 0.5 * (viewport.w as f32) + (viewport.y as f32);
+        // screen.y = (screen.y + 1.0) * 0.5 * viewport.w + viewport.y;
         // Screen Origin is Bottom Left (Mouse Origin is Top Left)
-        screen.y = (1.0 - screen.y) * 0.5 * (viewport.w as f32) + (viewport.y as f32);
+        screen.y = (1.0 - screen.y) * 0.5 * viewport.w + viewport.y;
 
         // This is only correct when glDepthRangef(0.0f, 1.0f)
         screen.z = (screen.z + 1.0) * 0.5;
@@ -86,6 +109,20 @@ pub fn project_world_to_screen(
     }
 }
 
+/// Whether a point in the world lands inside a screen space box, given as its lowest and
+/// highest corners. Points behind the camera never do.
+pub fn projects_into_rect(
+    world: cgmath::Vector3<f32>,
+    view_projection: cgmath::Matrix4<f32>,
+    viewport: cgmath::Vector4<f32>,
+    (min, max): (cgmath::Vector2<f32>, cgmath::Vector2<f32>),
+) -> bool {
+    matches!(
+        project_world_to_screen(world, view_projection, viewport),
+        Some(screen) if screen.x >= min.x && screen.x <= max.x && screen.y >= min.y && screen.y <= max.y
+    )
+}
+
 pub fn generate_view_matrix(
     cam: &crate::components::Camera,
     cam_pos: cgmath::Vector3<f32>,
@@ -98,9 +135,7 @@ pub fn generate_view_matrix(
         cgmath::Vector3::unit_z(),
     );
 
-    let mx_perspective = cgmath::perspective(cgmath::Deg(cam.fov), aspect_ratio, 1.0, 1000.0);
-
-    correction_matrix() * mx_perspective * mx_view
+    correction_matrix() * cam.projection.matrix(aspect_ratio) * mx_view
 }
 
 pub fn generate_ortho_matrix(size: winit::dpi::PhysicalSize<f32>) -> cgmath::Matrix4<f32> {
@@ -108,6 +143,94 @@ pub fn generate_ortho_matrix(size: winit::dpi::PhysicalSize<f32>) -> cgmath::Mat
     correction_matrix() * mx_ortho
 }
 
+/// Looks along a directional light at `focus`, seeing everything within `extent` of it.
+/// Used to render the shadow map, so the light has no position of its own.
+pub fn generate_light_matrix(
+    direction: cgmath::Vector3<f32>,
+    focus: cgmath::Vector3<f32>,
+    extent: f32,
+) -> cgmath::Matrix4<f32> {
+    use cgmath::InnerSpace;
+
+    let direction = direction.normalize();
+    // Straight down lights can't use z as their up
+    let up = if direction.z.abs() > 0.99 {
+        cgmath::Vector3::unit_y()
+    } else {
+        cgmath::Vector3::unit_z()
+    };
+
+    let mx_view = cgmath::Matrix4::look_at_rh(
+        cgmath::Point3::from_vec(focus - direction * extent),
+        cgmath::Point3::from_vec(focus),
+        up,
+    );
+    let mx_ortho = cgmath::ortho(-extent, extent, -extent, extent, 0.0, 2.0 * extent);
+
+    correction_matrix() * mx_ortho * mx_view
+}
+
+/// Packs point and spot lights, given with their world positions (and directions), into the
+/// light uniform. Both kinds share the same `MAX_NR_OF_POINT_LIGHTS` slots, lights that don't
+/// fit are dropped. Directional lights, given with their world directions, get their own
+/// `MAX_NR_OF_DIRECTIONAL_LIGHTS` slots.
+pub fn pack_lights<'a>(
+    point_lights: impl IntoIterator<Item = (cgmath::Vector3<f32>, &'a components::PointLight)>,
+    spot_lights: impl IntoIterator<
+        Item = (
+            cgmath::Vector3<f32>,
+            cgmath::Vector3<f32>,
+            &'a components::SpotLight,
+        ),
+    >,
+    directional_lights: impl IntoIterator<
+        Item = (cgmath::Vector3<f32>, &'a components::DirectionalLight),
+    >,
+) -> Lights {
+    use cgmath::{Angle, InnerSpace};
+
+    let points = point_lights
+        .into_iter()
+        .map(|(position, light)| PointLight {
+            radius: light.range,
+            kind: LIGHT_KIND_POINT,
+            position: position.extend(1.0).into(),
+            color: (light.color * light.intensity).extend(1.0).into(),
+            ..Default::default()
+        });
+
+    let spots = spot_lights
+        .into_iter()
+        .map(|(position, direction, light)| PointLight {
+            radius: light.range,
+            kind: LIGHT_KIND_SPOT,
+            inner_cutoff: (light.cutoff_angle * SPOT_INNER_CONE_RATIO).cos(),
+            outer_cutoff: light.cutoff_angle.cos(),
+            position: position.extend(1.0).into(),
+            color: (light.color * light.intensity).extend(1.0).into(),
+            direction: direction.normalize().extend(0.0).into(),
+        });
+
+    let mut lights = Lights::default();
+    for (slot, light) in lights.point_lights.iter_mut().zip(points.chain(spots)) {
+        *slot = light;
+        lights.nr_of_point_lights += 1;
+    }
+
+    let directionals = directional_lights
+        .into_iter()
+        .take(MAX_NR_OF_DIRECTIONAL_LIGHTS)
+        .map(|(direction, light)| DirectionalLight {
+            direction: direction.normalize().extend(0.0).into(),
+            color: (light.color * light.intensity).extend(1.0).into(),
+        });
+    for (slot, light) in lights.directional_lights.iter_mut().zip(directionals) {
+        *slot = light;
+        lights.nr_of_directional_lights += 1;
+    }
+    lights
+}
+
 #[rustfmt::skip]
 pub fn correction_matrix() -> cgmath::Matrix4<f32> {
     cgmath::Matrix4::new(
@@ -117,3 +240,383 @@ pub fn correction_matrix() -> cgmath::Matrix4<f32> {
         0.0, 0.0, 0.5, 1.0,
     )
 }
+
+/// Rebuilds a model matrix so the model faces the camera, keeping its position and scale.
+/// Falls back to the original matrix when there is no sensible direction to face.
+pub fn billboard_matrix(
+    model_matrix: cgmath::Matrix4<f32>,
+    camera_position: cgmath::Vector3<f32>,
+    camera_up: cgmath::Vector3<f32>,
+    mode: components::Billboard,
+) -> cgmath::Matrix4<f32> {
+    use cgmath::{InnerSpace, Vector3};
+
+    let position = model_matrix.w.truncate();
+    let scale = model_matrix.x.truncate().magnitude();
+
+    let mut to_camera = camera_position - position;
+    let up = match mode {
+        components::Billboard::Cylindrical => {
+            to_camera.z = 0.0;
+            Vector3::unit_z()
+        }
+        components::Billboard::Spherical => camera_up,
+    };
+
+    if to_camera.magnitude2() < f32::EPSILON {
+        return model_matrix;
+    }
+    let facing = to_camera.normalize();
+
+    let up = up - facing * up.dot(facing);
+    if up.magnitude2() < f32::EPSILON {
+        return model_matrix;
+    }
+    let up = up.normalize();
+
+    // The model faces down -Y, so -Y has to point at the camera
+    let y = -facing;
+    let x = y.cross(up);
+
+    cgmath::Matrix4::from_cols(
+        (x * scale).extend(0.0),
+        (y * scale).extend(0.0),
+        (up * scale).extend(0.0),
+        position.extend(1.0),
+    )
+}
+
+/// The part of the world a projection view matrix sees, as six inward facing planes
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    planes: [cgmath::Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from a matrix that has gone through `correction_matrix`,
+    /// so visible depths are 0 to w in clip space
+    pub fn from_matrix(projection_view: cgmath::Matrix4<f32>) -> Self {
+        use cgmath::{InnerSpace, Matrix};
+
+        let m = projection_view.transpose();
+        let (x, y, z, w) = (m.x, m.y, m.z, m.w);
+        let planes = [w + x, w - x, w + y, w - y, z, w - z]
+            .map(|plane| plane / plane.truncate().magnitude());
+
+        Self { planes }
+    }
+
+    /// Whether any part of the sphere is on the inside of every plane
+    pub fn intersects(&self, sphere: &crate::data::BoundingSphere) -> bool {
+        use cgmath::InnerSpace;
+
+        self.planes
+            .iter()
+            .all(|plane| plane.truncate().dot(sphere.center) + plane.w >= -sphere.radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, Vector3};
+
+    use super::*;
+
+    fn point() -> components::PointLight {
+        components::PointLight {
+            color: Vector3::new(1.0, 0.5, 0.0),
+            intensity: 2.0,
+            range: 4.0,
+        }
+    }
+
+    fn spot() -> components::SpotLight {
+        components::SpotLight {
+            direction: Vector3::new(0.0, 0.0, -2.0),
+            cutoff_angle: Deg(30.0),
+            color: Vector3::new(1.0, 1.0, 1.0),
+            intensity: 1.0,
+            range: 6.0,
+        }
+    }
+
+    #[test]
+    fn points_and_spots_share_capacity() {
+        let point = point();
+        let spot = spot();
+        let lights = pack_lights(
+            std::iter::repeat((Vector3::unit_x(), &point)).take(MAX_NR_OF_POINT_LIGHTS - 2),
+            std::iter::repeat((Vector3::unit_y(), spot.direction, &spot)).take(5),
+            vec![],
+        );
+
+        assert_eq!(lights.nr_of_point_lights as usize, MAX_NR_OF_POINT_LIGHTS);
+        let spots = lights
+            .point_lights
+            .iter()
+            .filter(|light| light.kind == LIGHT_KIND_SPOT)
+            .count();
+        assert_eq!(spots, 2);
+
+        let packed_point = lights.point_lights[0];
+        assert_eq!(packed_point.color, [2.0, 1.0, 0.0, 1.0]);
+        assert_eq!(packed_point.radius, 4.0);
+
+        let packed_spot = lights.point_lights[MAX_NR_OF_POINT_LIGHTS - 1];
+        assert_eq!(packed_spot.direction, [0.0, 0.0, -1.0, 0.0]);
+        assert!(packed_spot.inner_cutoff > packed_spot.outer_cutoff);
+    }
+
+    #[test]
+    fn world_to_screen_round_trips_on_the_ground() {
+        use cgmath::{InnerSpace, Matrix4, Point3, Vector2, Vector4};
+
+        let camera = Vector3::new(-6.0, -4.0, 9.0);
+        let target = Vector3::new(1.0, 2.0, 0.0);
+        let viewport = Vector4::new(0.0, 0.0, 1024.0, 768.0);
+
+        let view = Matrix4::look_at_rh(
+            Point3::from_vec(camera),
+            Point3::from_vec(target),
+            Vector3::unit_z(),
+        );
+        let projection = cgmath::perspective(Deg(30.0), 1024.0 / 768.0, 1.0, 1000.0);
+        let view_projection = correction_matrix() * projection * view;
+
+        for &(x, y) in &[(1.0, 2.0), (0.0, 0.0), (3.5, -1.0), (-2.0, 4.0)] {
+            let ground = Vector3::new(x, y, 0.0);
+
+            let screen = project_world_to_screen(ground, view_projection, viewport)
+                .unwrap()
+                .truncate();
+            assert!(screen.x >= 0.0 && screen.x <= 1024.0);
+            assert!(screen.y >= 0.0 && screen.y <= 768.0);
+
+            // Cast the screen point back out and see where it hits the ground,
+            // the same way the player picks a destination
+            let far =
+                project_screen_to_world(screen.extend(1.0), view_projection, viewport).unwrap();
+            let ray = far - camera;
+            let hit = camera - ray * (camera.z / ray.z);
+
+            assert!(
+                (hit - ground).magnitude() < 1e-2,
+                "{:?} != {:?}",
+                hit,
+                ground
+            );
+        }
+
+        let target_screen = project_world_to_screen(target, view_projection, viewport)
+            .unwrap()
+            .truncate();
+        assert!((target_screen - Vector2::new(512.0, 384.0)).magnitude() < 1e-2);
+    }
+
+    #[test]
+    fn world_to_screen_rejects_points_behind_the_camera() {
+        use cgmath::{Matrix4, Point3, Vector4};
+
+        let view = Matrix4::look_at_rh(
+            Point3::new(0.0, 0.0, 10.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::unit_y(),
+        );
+        let projection = cgmath::perspective(Deg(30.0), 1.0, 1.0, 1000.0);
+        let view_projection = correction_matrix() * projection * view;
+
+        assert!(project_world_to_screen(
+            Vector3::new(0.0, 0.0, 20.0),
+            view_projection,
+            Vector4::new(0.0, 0.0, 100.0, 100.0),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn cylindrical_billboard_stays_upright() {
+        use cgmath::{InnerSpace, Matrix4};
+
+        let model = Matrix4::from_translation(Vector3::new(1.0, 1.0, 0.0))
+            * Matrix4::from_angle_z(Deg(33.0))
+            * Matrix4::from_scale(2.0);
+        let camera = Vector3::new(4.0, 5.0, 10.0);
+
+        let billboard = billboard_matrix(
+            model,
+            camera,
+            Vector3::unit_z(),
+            components::Billboard::Cylindrical,
+        );
+
+        let front = (billboard * -Vector3::unit_y().extend(0.0)).truncate();
+        let to_camera = Vector3::new(3.0, 4.0, 0.0).normalize();
+
+        assert!((front.normalize() - to_camera).magnitude() < 1e-5);
+        assert!((front.magnitude() - 2.0).abs() < 1e-5);
+        assert_eq!(billboard.z.truncate(), Vector3::new(0.0, 0.0, 2.0));
+        assert_eq!(billboard.w, model.w);
+    }
+
+    #[test]
+    fn spherical_billboard_faces_the_camera() {
+        use cgmath::{InnerSpace, Matrix4};
+
+        let model = Matrix4::from_translation(Vector3::new(0.0, 0.0, 1.0));
+        let camera = Vector3::new(0.0, -3.0, 5.0);
+
+        let billboard = billboard_matrix(
+            model,
+            camera,
+            Vector3::unit_z(),
+            components::Billboard::Spherical,
+        );
+
+        let front = (billboard * -Vector3::unit_y().extend(0.0)).truncate();
+        let to_camera = (camera - Vector3::new(0.0, 0.0, 1.0)).normalize();
+
+        assert!((front - to_camera).magnitude() < 1e-5);
+
+        // Straight above leaves a cylindrical billboard nothing to turn towards
+        let above = billboard_matrix(
+            model,
+            Vector3::new(0.0, 0.0, 9.0),
+            Vector3::unit_z(),
+            components::Billboard::Cylindrical,
+        );
+        assert_eq!(above, model);
+    }
+
+    #[test]
+    fn no_spots_leaves_point_lights_alone() {
+        let point = point();
+        let lights = pack_lights(vec![(Vector3::unit_z(), &point)], vec![], vec![]);
+
+        assert_eq!(lights.nr_of_point_lights, 1);
+        assert_eq!(lights.point_lights[0].kind, LIGHT_KIND_POINT);
+        assert_eq!(lights.point_lights[0].position, [0.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn directional_lights_have_their_own_slots() {
+        let point = point();
+        let sun = components::DirectionalLight {
+            direction: Vector3::new(0.0, 0.0, -2.0),
+            color: Vector3::new(1.0, 0.5, 0.0),
+            intensity: 2.0,
+        };
+        let lights = pack_lights(
+            std::iter::repeat((Vector3::unit_x(), &point)).take(MAX_NR_OF_POINT_LIGHTS),
+            vec![],
+            std::iter::repeat((sun.direction, &sun)).take(MAX_NR_OF_DIRECTIONAL_LIGHTS + 1),
+        );
+
+        assert_eq!(lights.nr_of_point_lights as usize, MAX_NR_OF_POINT_LIGHTS);
+        assert_eq!(
+            lights.nr_of_directional_lights as usize,
+            MAX_NR_OF_DIRECTIONAL_LIGHTS
+        );
+        assert_eq!(
+            lights.directional_lights[0].direction,
+            [0.0, 0.0, -1.0, 0.0]
+        );
+        assert_eq!(lights.directional_lights[0].color, [2.0, 1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn light_matrix_centers_the_focus() {
+        let focus = Vector3::new(3.0, -2.0, 1.0);
+        let light = generate_light_matrix(Vector3::new(0.0, 0.0, -1.0), focus, 10.0);
+
+        // The focus lands in the middle of the map, halfway into its depth range
+        let center = light * focus.extend(1.0);
+        assert!(center.x.abs() < 1e-5 && center.y.abs() < 1e-5);
+        assert!((center.z - 0.5).abs() < 1e-5);
+
+        // Things closer to the light are shallower
+        let above = light * (focus + Vector3::unit_z()).extend(1.0);
+        assert!(above.z < center.z);
+
+        let slanted = generate_light_matrix(Vector3::new(1.0, 1.0, -1.0), focus, 10.0);
+        let center = slanted * focus.extend(1.0);
+        assert!(center.x.abs() < 1e-5 && center.y.abs() < 1e-5);
+    }
+
+    #[test]
+    fn frustum_keeps_what_the_camera_sees() {
+        use crate::data::BoundingSphere;
+
+        let view = cgmath::Matrix4::look_at_rh(
+            cgmath::Point3::new(0.0, 0.0, 0.0),
+            cgmath::Point3::new(0.0, 0.0, -1.0),
+            Vector3::unit_y(),
+        );
+        let frustum = Frustum::from_matrix(
+            correction_matrix() * cgmath::perspective(Deg(90.0), 1.0, 1.0, 100.0) * view,
+        );
+        let sphere = |x, z, radius| BoundingSphere {
+            center: Vector3::new(x, 0.0, z),
+            radius,
+        };
+
+        assert!(frustum.intersects(&sphere(0.0, -10.0, 1.0)));
+        // Straddling the right edge of the view, which is at x = 10 this far away
+        assert!(frustum.intersects(&sphere(11.0, -10.0, 2.0)));
+        assert!(!frustum.intersects(&sphere(50.0, -10.0, 1.0)));
+        assert!(!frustum.intersects(&sphere(0.0, 10.0, 1.0)));
+        assert!(!frustum.intersects(&sphere(0.0, -200.0, 1.0)));
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        use cgmath::{InnerSpace, Point3, Vector2, Vector4};
+
+        let camera = components::Camera {
+            projection: components::Projection::Orthographic { height: 10.0 },
+            up: Vector3::unit_z(),
+            roaming: false,
+        };
+        let viewport = Vector4::new(0.0, 0.0, 800.0, 600.0);
+        let view_projection = generate_view_matrix(
+            &camera,
+            Vector3::new(0.0, -0.01, 20.0),
+            Vector3::new(0.0, 0.0, 0.0),
+            800.0 / 600.0,
+        );
+
+        let (center, center_dir) =
+            screen_ray(Vector2::new(400.0, 300.0), view_projection, viewport).unwrap();
+        let (top, top_dir) =
+            screen_ray(Vector2::new(400.0, 0.0), view_projection, viewport).unwrap();
+
+        assert!((center_dir - top_dir).magnitude() < 1e-4);
+        assert!(center_dir.z < 0.0);
+
+        // Straight down onto the ground, the top of the screen is half the height away
+        let ground =
+            |(origin, dir): (Vector3<f32>, Vector3<f32>)| origin - dir * (origin.z / dir.z);
+        assert!(ground((center, center_dir)).truncate().magnitude() < 1e-2);
+        assert!((ground((top, top_dir)).y - 5.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn box_selection_skips_points_behind_the_camera() {
+        use cgmath::{Matrix4, Point3, Vector2, Vector4};
+
+        let view = Matrix4::look_at_rh(
+            Point3::new(0.0, -10.0, 10.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::unit_z(),
+        );
+        let projection = cgmath::perspective(Deg(30.0), 1.0, 1.0, 1000.0);
+        let view_projection = correction_matrix() * projection * view;
+        let viewport = Vector4::new(0.0, 0.0, 100.0, 100.0);
+        let rect = (Vector2::new(40.0, 40.0), Vector2::new(60.0, 60.0));
+
+        let selected = |world| projects_into_rect(world, view_projection, viewport, rect);
+        assert!(selected(Vector3::new(0.0, 0.0, 0.0)));
+        assert!(!selected(Vector3::new(5.0, 0.0, 0.0)));
+        // Straight behind the camera would land in the middle of the screen if it wasn't skipped
+        assert!(!selected(Vector3::new(0.0, -20.0, 20.0)));
+    }
+}
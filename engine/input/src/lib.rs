@@ -1,12 +1,17 @@
 #![allow(unused)]
 
+mod replay;
 mod systems;
 
-use cgmath::Vector2;
+use std::time::Instant;
+
+use cgmath::{InnerSpace, Vector2};
+pub use replay::InputSource;
+use serde::{Deserialize, Serialize};
 pub use systems::InputUnit;
 use winit::event::{ElementState, Event, MouseScrollDelta, VirtualKeyCode};
 
-#[derive(Default)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ButtonState {
     pub pressed: bool,
     pub down: bool,
@@ -30,7 +35,7 @@ impl ButtonState {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ButtonStatus {
     Down,
     Up,
@@ -40,6 +45,25 @@ pub enum ButtonStatus {
 
 type MouseButton = winit::event::MouseButton;
 
+/// How close together presses have to be to count as clicks
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClickThresholds {
+    /// In pixels, moving further than this while held is a drag instead
+    pub max_click_distance: f32,
+    /// In seconds, between the releases of the two clicks of a double click
+    pub double_click_time: f32,
+}
+
+impl Default for ClickThresholds {
+    fn default() -> Self {
+        Self {
+            max_click_distance: 5.0,
+            double_click_time: 0.3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MouseState {
     pub left: ButtonState,
     pub right: ButtonState,
@@ -47,6 +71,16 @@ pub struct MouseState {
     pub pos: Vector2<f32>,
     pub last_pos: Vector2<f32>,
     pub scroll: f32,
+    pub click_thresholds: ClickThresholds,
+    /// Where the left button went down, while it is held
+    press_pos: Option<Vector2<f32>>,
+    /// Whether the held left button has moved too far to be a click
+    dragged: bool,
+    clicked: bool,
+    double_clicked: bool,
+    /// Replays only need the results above, so the timing isn't recorded
+    #[serde(skip)]
+    last_click: Option<(Instant, Vector2<f32>)>,
 }
 
 impl Default for MouseState {
@@ -58,6 +92,12 @@ impl Default for MouseState {
             pos: Vector2::new(0.0, 0.0),
             last_pos: Vector2::new(0.0, 0.0),
             scroll: 0.0,
+            click_thresholds: Default::default(),
+            press_pos: None,
+            dragged: false,
+            clicked: false,
+            double_clicked: false,
+            last_click: None,
         }
     }
 }
@@ -67,12 +107,60 @@ impl MouseState {
 
     pub fn delta(&self) -> Vector2<f32> { self.pos - self.last_pos }
 
+    /// Whether the left button was released this frame without dragging
+    pub fn clicked(&self) -> bool { self.clicked }
+
+    /// Whether this frame's click is the second of a double click
+    pub fn double_clicked(&self) -> bool { self.double_clicked }
+
+    /// The corners of the box the left button is dragging out, lowest coordinates first
+    pub fn drag_rect(&self) -> Option<(Vector2<f32>, Vector2<f32>)> {
+        let start = self.press_pos.filter(|_| self.dragged)?;
+        Some((
+            Vector2::new(start.x.min(self.pos.x), start.y.min(self.pos.y)),
+            Vector2::new(start.x.max(self.pos.x), start.y.max(self.pos.y)),
+        ))
+    }
+
+    fn move_to(&mut self, pos: Vector2<f32>) {
+        self.pos = pos;
+        if let Some(start) = self.press_pos {
+            self.dragged |= (pos - start).magnitude() > self.click_thresholds.max_click_distance;
+        }
+    }
+
+    fn press_left(&mut self) {
+        self.press_pos = Some(self.pos);
+        self.dragged = false;
+    }
+
+    fn release_left(&mut self, now: Instant) {
+        if self.press_pos.take().is_none() || self.dragged {
+            self.dragged = false;
+            return;
+        }
+
+        self.clicked = true;
+        let thresholds = self.click_thresholds;
+        let follows_a_click = self.last_click.take().filter(|(time, pos)| {
+            now.duration_since(*time).as_secs_f32() <= thresholds.double_click_time
+                && (self.pos - pos).magnitude() <= thresholds.max_click_distance
+        });
+
+        // A third click starts over rather than making another double click
+        match follows_a_click {
+            Some(_) => self.double_clicked = true,
+            None => self.last_click = Some((now, self.pos)),
+        }
+    }
+
     pub fn update_from_mouse_button(&mut self, mouse_button: &MouseButton, state: &ElementState) {
         match state {
             ElementState::Pressed => match mouse_button {
                 MouseButton::Left => {
                     if !self.left.down {
                         self.left.pressed = true;
+                        self.press_left();
                     }
                     self.left.down = true;
                 }
@@ -92,6 +180,9 @@ impl MouseState {
             },
             ElementState::Released => match mouse_button {
                 MouseButton::Left => {
+                    if self.left.down {
+                        self.release_left(Instant::now());
+                    }
                     self.left.down = false;
                     self.left.pressed = false;
                 }
@@ -111,7 +202,7 @@ impl MouseState {
 
 pub type Key = VirtualKeyCode;
 
-#[derive(Default)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct InputState {
     pub mouse: MouseState,
     pub keyboard: std::collections::HashMap<Key, ButtonState>,
@@ -156,6 +247,9 @@ impl InputState {
 
         self.mouse.scroll = 0.0;
 
+        self.mouse.clicked = false;
+        self.mouse.double_clicked = false;
+
         self.keyboard
             .values_mut()
             .map(|f| f.pressed = false)
@@ -201,20 +295,23 @@ impl InputState {
                 self.mouse.update_from_mouse_button(button, state);
             }
             CursorMoved { position, .. } => {
-                self.mouse.pos = Vector2::new(position.x as f32, position.y as f32);
+                self.mouse
+                    .move_to(Vector2::new(position.x as f32, position.y as f32));
             }
             _ => (),
         }
     }
 }
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy)]
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum Command {
     DebugToggleInfo,
     DebugToggleLogic,
     DebugStepLogic,
     DebugToggleSnake,
+    DebugToggleColliders,
+    DebugToggleWireframe,
 
     SnakeMoveUp,
     SnakeMoveDown,
@@ -224,6 +321,9 @@ pub enum Command {
     DevToggleHotLoading,
     DevHotLoadModels,
 
+    ToggleFullscreen,
+    ToggleMinimap,
+
     PlayerCameraMoveUp,
     PlayerCameraMoveDown,
     PlayerCameraMoveLeft,
@@ -231,37 +331,65 @@ pub enum Command {
 
     PlayerClickToMove,
     PlayerOrbitCamera,
+    PlayerFrameCamera,
+    PlayerAttack,
 }
 
 pub type KeyBinding = dyn Fn(&InputState, bool) -> bool + Send + Sync;
 
+/// Maps keys to what they do, see `CommandManager::bind`
+pub type KeyBindings = std::collections::HashMap<Key, Command>;
+
+enum Trigger {
+    /// Whatever keys are bound to the command in the `KeyBindings`, flipping the state
+    /// instead of holding it when `toggle`
+    Keys {
+        status: ButtonStatus,
+        toggle: bool,
+    },
+    Custom(Box<KeyBinding>),
+}
+
 struct CommandState {
-    logic: Box<KeyBinding>,
+    trigger: Trigger,
     state: bool,
 }
 
 impl CommandState {
-    fn new(logic: Box<KeyBinding>) -> Self {
+    fn new(trigger: Trigger) -> Self {
         Self {
-            logic,
+            trigger,
             state: false,
         }
     }
 
-    fn update(&mut self, input_state: &InputState) {
-        self.state = (self.logic)(input_state, self.state);
+    fn update(&mut self, command: Command, key_bindings: &KeyBindings, input_state: &InputState) {
+        self.state = match &self.trigger {
+            Trigger::Keys { status, toggle } => {
+                let active = key_bindings
+                    .iter()
+                    .any(|(&key, &bound)| bound == command && input_state.key_state(key, *status));
+                match toggle {
+                    true => active ^ self.state,
+                    false => active,
+                }
+            }
+            Trigger::Custom(logic) => logic(input_state, self.state),
+        };
     }
 }
 
 #[derive(Default)]
 pub struct CommandManager {
     commands: std::collections::HashMap<Command, CommandState>,
+    key_bindings: KeyBindings,
 }
 
 impl CommandManager {
     pub fn new() -> Self {
         Self {
             commands: std::collections::HashMap::new(),
+            key_bindings: KeyBindings::new(),
         }
     }
 
@@ -299,6 +427,9 @@ impl CommandManager {
 
         ret.simple_key_bind(Command::DevHotLoadModels, Key::L, ButtonStatus::Pressed);
 
+        ret.simple_key_bind(Command::ToggleFullscreen, Key::F9, ButtonStatus::Pressed);
+        ret.key_toggle(Command::ToggleMinimap, Key::M, ButtonStatus::Pressed, true);
+
         ret.simple_key_bind(Command::PlayerCameraMoveUp, Key::E, ButtonStatus::Pressed);
         ret.simple_key_bind(Command::PlayerCameraMoveDown, Key::D, ButtonStatus::Pressed);
         ret.simple_key_bind(Command::PlayerCameraMoveLeft, Key::S, ButtonStatus::Pressed);
@@ -318,6 +449,21 @@ impl CommandManager {
             MouseButton::Right,
             ButtonStatus::Down,
         );
+        ret.simple_key_bind(Command::PlayerFrameCamera, Key::Z, ButtonStatus::Pressed);
+        ret.simple_key_bind(Command::PlayerAttack, Key::Space, ButtonStatus::Pressed);
+
+        ret.key_toggle(
+            Command::DebugToggleColliders,
+            Key::F8,
+            ButtonStatus::Pressed,
+            false,
+        );
+
+        ret.simple_key_bind(
+            Command::DebugToggleWireframe,
+            Key::F7,
+            ButtonStatus::Pressed,
+        );
 
         ret.key_toggle(
             Command::DebugToggleSnake,
@@ -345,10 +491,12 @@ impl CommandManager {
     pub fn simple_key_bind(&mut self, command: Command, key: Key, button_status: ButtonStatus) {
         self.commands.insert(
             command,
-            CommandState::new(Box::new(move |input_state: &InputState, _| {
-                input_state.key_state(key, button_status)
-            })),
+            CommandState::new(Trigger::Keys {
+                status: button_status,
+                toggle: false,
+            }),
         );
+        self.bind(command, key);
     }
 
     pub fn key_toggle(
@@ -358,13 +506,15 @@ impl CommandManager {
         button_status: ButtonStatus,
         default: bool,
     ) {
-        let mut state = CommandState::new(Box::new(move |input_state, prev_state| {
-            input_state.key_state(key, button_status) ^ prev_state
-        }));
+        let mut state = CommandState::new(Trigger::Keys {
+            status: button_status,
+            toggle: true,
+        });
 
         state.state = default;
 
         self.commands.insert(command, state);
+        self.bind(command, key);
     }
 
     pub fn simple_mouse_bind(
@@ -375,21 +525,210 @@ impl CommandManager {
     ) {
         self.commands.insert(
             command,
-            CommandState::new(Box::new(move |input_state: &InputState, _| {
-                input_state.mouse_button_state(mouse_button, button_status)
-            })),
+            CommandState::new(Trigger::Custom(Box::new(
+                move |input_state: &InputState, _| {
+                    input_state.mouse_button_state(mouse_button, button_status)
+                },
+            ))),
         );
     }
 
+    /// Bindings with their own logic can't be remapped with `bind`
     pub fn advanced_bind(&mut self, command: Command, logic: Box<KeyBinding>) {
-        self.commands.insert(command, CommandState::new(logic));
+        self.commands
+            .insert(command, CommandState::new(Trigger::Custom(logic)));
     }
 
     pub fn has_binding(&self, command: Command) -> bool { self.commands.contains_key(&command) }
 
+    /// Makes `key` trigger `command`, on top of any other keys bound to it. Commands that
+    /// weren't bound to keys before, or had their own logic, are triggered when it's pressed.
+    /// Returns the command the key was bound to before, which loses it.
+    pub fn bind(&mut self, command: Command, key: Key) -> Option<Command> {
+        let bound_to_keys = matches!(
+            self.commands.get(&command),
+            Some(CommandState {
+                trigger: Trigger::Keys { .. },
+                ..
+            })
+        );
+        if !bound_to_keys {
+            self.commands.insert(
+                command,
+                CommandState::new(Trigger::Keys {
+                    status: ButtonStatus::Pressed,
+                    toggle: false,
+                }),
+            );
+        }
+
+        self.key_bindings
+            .insert(key, command)
+            .filter(|&previous| previous != command)
+    }
+
+    /// Returns the command the key was bound to
+    pub fn unbind(&mut self, key: Key) -> Option<Command> { self.key_bindings.remove(&key) }
+
+    pub fn key_bindings(&self) -> &KeyBindings { &self.key_bindings }
+
+    /// Every key bound to `command`
+    pub fn keys_for(&self, command: Command) -> Vec<Key> {
+        self.key_bindings
+            .iter()
+            .filter(|(_, &bound)| bound == command)
+            .map(|(&key, _)| key)
+            .collect()
+    }
+
+    /// The key bindings as RON, e.g. to save them from a controls menu
+    pub fn key_bindings_to_ron(&self) -> Result<String, ron::Error> {
+        ron::ser::to_string_pretty(&self.key_bindings, Default::default())
+    }
+
+    /// Replaces the key bindings with ones saved by `key_bindings_to_ron`
+    pub fn load_key_bindings(&mut self, src: &str) -> Result<(), ron::Error> {
+        let key_bindings: KeyBindings = ron::de::from_str(src)?;
+
+        self.key_bindings.clear();
+        for (key, command) in key_bindings {
+            self.bind(command, key);
+        }
+
+        Ok(())
+    }
+
     pub fn update(&mut self, input_state: &InputState) {
-        for state in self.commands.values_mut() {
-            state.update(input_state);
+        let key_bindings = &self.key_bindings;
+        for (&command, state) in self.commands.iter_mut() {
+            state.update(command, key_bindings, input_state);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn click(mouse: &mut MouseState, at: Instant) {
+        mouse.press_left();
+        mouse.release_left(at);
+    }
+
+    fn seconds(start: Instant, seconds: f32) -> Instant { start + Duration::from_secs_f32(seconds) }
+
+    #[test]
+    fn double_clicks_need_to_be_quick_and_close() {
+        let start = Instant::now();
+        let mut mouse = MouseState::new();
+
+        click(&mut mouse, start);
+        assert!(mouse.clicked() && !mouse.double_clicked());
+
+        // Exactly at the threshold still counts
+        click(&mut mouse, seconds(start, 0.3));
+        assert!(mouse.double_clicked());
+
+        // A third click starts a new pair
+        mouse.double_clicked = false;
+        click(&mut mouse, seconds(start, 0.4));
+        assert!(!mouse.double_clicked());
+
+        // Too slow
+        click(&mut mouse, seconds(start, 0.75));
+        assert!(!mouse.double_clicked());
+
+        // Too far away, the first click of this pair was at the origin
+        mouse.move_to(Vector2::new(20.0, 0.0));
+        click(&mut mouse, seconds(start, 0.8));
+        assert!(!mouse.double_clicked());
+    }
+
+    fn press(key: Key) -> InputState {
+        let mut input_state = InputState::new();
+        input_state.keyboard.insert(
+            key,
+            ButtonState {
+                pressed: true,
+                down: true,
+            },
+        );
+        input_state
+    }
+
+    #[test]
+    fn keys_can_be_rebound() {
+        let mut command_manager = CommandManager::default_bindings();
+
+        // W was free, F moved the camera right
+        assert_eq!(
+            command_manager.bind(Command::PlayerCameraMoveRight, Key::W),
+            None
+        );
+        assert_eq!(
+            command_manager.bind(Command::PlayerCameraMoveUp, Key::F),
+            Some(Command::PlayerCameraMoveRight)
+        );
+
+        command_manager.update(&press(Key::F));
+        assert!(command_manager.get(Command::PlayerCameraMoveUp));
+        assert!(!command_manager.get(Command::PlayerCameraMoveRight));
+
+        command_manager.update(&press(Key::W));
+        assert!(command_manager.get(Command::PlayerCameraMoveRight));
+
+        assert_eq!(
+            command_manager.unbind(Key::W),
+            Some(Command::PlayerCameraMoveRight)
+        );
+        command_manager.update(&press(Key::W));
+        assert!(!command_manager.get(Command::PlayerCameraMoveRight));
+
+        // Toggles stay toggles
+        command_manager.bind(Command::DebugToggleSnake, Key::O);
+        command_manager.update(&press(Key::O));
+        command_manager.update(&InputState::new());
+        assert!(command_manager.get(Command::DebugToggleSnake));
+    }
+
+    #[test]
+    fn key_bindings_survive_ron() {
+        let mut command_manager = CommandManager::default_bindings();
+        command_manager.bind(Command::PlayerFrameCamera, Key::Q);
+        let saved = command_manager.key_bindings_to_ron().unwrap();
+
+        let mut loaded = CommandManager::default_bindings();
+        loaded.load_key_bindings(&saved).unwrap();
+        assert_eq!(loaded.key_bindings(), command_manager.key_bindings());
+        assert_eq!(loaded.keys_for(Command::PlayerFrameCamera).len(), 2);
+    }
+
+    #[test]
+    fn dragging_is_not_clicking() {
+        let start = Instant::now();
+        let mut mouse = MouseState::new();
+        mouse.move_to(Vector2::new(10.0, 10.0));
+
+        mouse.press_left();
+        mouse.move_to(Vector2::new(13.0, 12.0));
+        assert_eq!(mouse.drag_rect(), None);
+
+        mouse.move_to(Vector2::new(0.0, 30.0));
+        assert_eq!(
+            mouse.drag_rect(),
+            Some((Vector2::new(0.0, 10.0), Vector2::new(10.0, 30.0)))
+        );
+
+        // Coming back to where it started is still a drag
+        mouse.move_to(Vector2::new(10.0, 10.0));
+        mouse.release_left(start);
+        assert!(!mouse.clicked());
+        assert_eq!(mouse.drag_rect(), None);
+
+        // And doesn't leave a half finished double click behind
+        click(&mut mouse, seconds(start, 0.1));
+        assert!(mouse.clicked() && !mouse.double_clicked());
+    }
+}
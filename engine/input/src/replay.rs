@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::iter::Enumerate;
+use std::path::Path;
+
+use crate::InputState;
+
+/// Where the `InputState` comes from each frame.
+/// Recordings hold one RON serialized `InputState` per frame, one per line.
+#[derive(Default)]
+pub enum InputSource {
+    /// Window events, the default
+    #[default]
+    Hardware,
+    /// Window events, with every frame also written to a recording
+    Record(BufWriter<File>),
+    /// Frames from a recording, numbered from 0, window events are ignored until it runs out
+    Replay(Enumerate<Lines<BufReader<File>>>),
+}
+
+impl InputSource {
+    pub fn record_input(path: &Path) -> io::Result<Self> {
+        Ok(InputSource::Record(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn replay_input(path: &Path) -> io::Result<Self> {
+        Ok(InputSource::Replay(
+            BufReader::new(File::open(path)?).lines().enumerate(),
+        ))
+    }
+
+    /// Whether window events should update the `InputState`
+    pub fn uses_hardware(&self) -> bool { !matches!(self, InputSource::Replay(_)) }
+
+    /// Records the frame's input or replaces it with the next recorded one.
+    /// Falls back to the hardware when a recording fails, or a replay ends or can't be read.
+    pub fn next_frame(&mut self, input_state: &mut InputState) {
+        let result = match self {
+            InputSource::Hardware => return,
+            InputSource::Record(writer) => ron::ser::to_string(input_state)
+                .map_err(|e| e.to_string())
+                // Flushed every frame, the event loop exits without dropping resources
+                .and_then(|line| {
+                    writeln!(writer, "{}", line)
+                        .and_then(|_| writer.flush())
+                        .map_err(|e| e.to_string())
+                })
+                .map_err(|e| format!("Input recording failed: {}", e)),
+            InputSource::Replay(lines) => match lines.next() {
+                Some((number, line)) => line
+                    .map_err(|e| e.to_string())
+                    .and_then(|line| ron::de::from_str(&line).map_err(|e| e.to_string()))
+                    .map(|frame| *input_state = frame)
+                    .map_err(|e| format!("Replay frame {} is malformed: {}", number, e)),
+                None => {
+                    eprintln!("[input]: Replay finished, back to the keyboard and mouse");
+                    *self = InputSource::Hardware;
+                    return;
+                }
+            },
+        };
+
+        if let Err(e) = result {
+            eprintln!("[input] (warning): {}", e);
+            *self = InputSource::Hardware;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ButtonStatus, Key};
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let path = std::env::temp_dir().join("deeper_input_replay_test.ron");
+
+        let mut frames = vec![InputState::new(), InputState::new()];
+        frames[0].mouse.pos = cgmath::Vector2::new(12.0, 34.0);
+        frames[0].mouse.left.pressed = true;
+        frames[1].mouse.scroll = -1.0;
+        frames[1].keyboard.insert(
+            Key::Space,
+            crate::ButtonState {
+                pressed: true,
+                down: true,
+            },
+        );
+
+        let mut recorder = InputSource::record_input(&path).unwrap();
+        for frame in &mut frames {
+            recorder.next_frame(frame);
+        }
+        drop(recorder);
+
+        let mut replay = InputSource::replay_input(&path).unwrap();
+        let mut input_state = InputState::new();
+        for frame in &frames {
+            assert!(!replay.uses_hardware());
+            replay.next_frame(&mut input_state);
+            assert_eq!(&input_state, frame);
+        }
+        assert!(input_state.key_state(Key::Space, ButtonStatus::Pressed));
+
+        // Running out leaves the last frame alone and hands input back
+        replay.next_frame(&mut input_state);
+        assert!(replay.uses_hardware());
+        assert_eq!(&input_state, frames.last().unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn malformed_replays_hand_input_back() {
+        let path = std::env::temp_dir().join("deeper_input_malformed_replay_test.ron");
+
+        let mut recorded = InputState::new();
+        recorded.mouse.scroll = 2.0;
+        let mut recorder = InputSource::record_input(&path).unwrap();
+        recorder.next_frame(&mut recorded);
+        drop(recorder);
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+        contents.push_str("(mouse: oops\n");
+        std::fs::write(&path, contents).unwrap();
+
+        let mut replay = InputSource::replay_input(&path).unwrap();
+        let mut input_state = InputState::new();
+        replay.next_frame(&mut input_state);
+        assert!(!replay.uses_hardware());
+        assert_eq!(input_state, recorded);
+
+        // The bad frame leaves the last good one alone
+        replay.next_frame(&mut input_state);
+        assert!(replay.uses_hardware());
+        assert_eq!(input_state, recorded);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -2,19 +2,22 @@ use application::UnitStage;
 use legion::systems::{Builder, ParallelRunnable};
 use legion::{Resources, SystemBuilder, World};
 
-use crate::{CommandManager, InputState};
+use crate::{CommandManager, InputSource, InputState};
 
 pub struct InputUnit;
 
 impl application::Unit for InputUnit {
     fn load_resources(&self, _: &mut World, resources: &mut Resources) {
         resources.insert(InputState::new());
+        resources.insert(InputSource::Hardware);
         resources.insert(CommandManager::default_bindings());
     }
     fn add_systems(&self, stage: UnitStage, builder: &mut Builder) {
         match stage {
             UnitStage::StartFrame => {
-                builder.add_system(update_command_manager_system());
+                builder
+                    .add_system(input_source_system())
+                    .add_system(update_command_manager_system());
             }
             UnitStage::EndFrame => {
                 builder.add_system(input_state_new_frame_system());
@@ -24,6 +27,15 @@ impl application::Unit for InputUnit {
     }
 }
 
+fn input_source_system() -> impl ParallelRunnable {
+    SystemBuilder::new("input_source_system")
+        .write_resource::<InputSource>()
+        .write_resource::<InputState>()
+        .build(move |_, _, (input_source, input_state), _| {
+            input_source.next_frame(input_state);
+        })
+}
+
 fn update_command_manager_system() -> impl ParallelRunnable {
     SystemBuilder::new("update_input_state_system")
         .write_resource::<CommandManager>()
@@ -1,24 +1,165 @@
 use cgmath::Zero;
+use legion::Entity;
 use nphysics2d::object::{DefaultBodyHandle, DefaultColliderHandle};
 
 #[derive(Debug)]
 pub struct Velocity(pub cgmath::Vector2<f32>);
 
 impl Default for Velocity {
-    fn default() -> Self { Velocity(cgmath::Vector2::zero()) }
+    fn default() -> Self { Self::zero() }
 }
 
-pub struct Force(pub nphysics2d::algebra::Force2<f32>);
+impl Velocity {
+    pub fn new(x: f32, y: f32) -> Self { Self(cgmath::Vector2::new(x, y)) }
+    pub fn zero() -> Self { Self(cgmath::Vector2::zero()) }
+}
+
+impl From<cgmath::Vector2<f32>> for Velocity {
+    fn from(vel: cgmath::Vector2<f32>) -> Self { Self(vel) }
+}
+
+/// Drops the z component, physics happens in the ground plane
+impl From<cgmath::Vector3<f32>> for Velocity {
+    fn from(vel: cgmath::Vector3<f32>) -> Self { Self(vel.truncate()) }
+}
+
+/// Bounds on how far `movement` lets an entity travel.
+///
+/// `max_speed` is in units per second and scales with `FrameTime`, so distance covered doesn't
+/// depend on the framerate. `max_step` caps a single frame's displacement regardless of speed,
+/// as a last guard against tunneling when a frame runs long.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MovementLimits {
+    pub max_speed: f32,
+    pub max_step: f32,
+}
+
+impl Default for MovementLimits {
+    fn default() -> Self {
+        Self {
+            max_speed: 30.0,
+            max_step: 0.5,
+        }
+    }
+}
+
+/// Controls how the physics world is stepped each frame.
+/// Paused physics still syncs positions both ways, so moving entities by hand keeps working.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PhysicsControl {
+    pub paused: bool,
+    /// Steps the world once while paused, cleared once it has
+    pub single_step: bool,
+    /// How many smaller steps a frame is split into, at least one is always taken
+    pub substeps: u32,
+}
+
+impl Default for PhysicsControl {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            single_step: false,
+            substeps: 1,
+        }
+    }
+}
+
+/// How to deal with physics components that don't make sense together, like a static body
+/// with a `Velocity`. They are fixed with a warning, unless `strict` makes them panic.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PhysicsValidation {
+    pub strict: bool,
+}
+
+/// Two entities' colliders touching or letting go of each other
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CollisionEvent {
+    Started(Entity, Entity),
+    Stopped(Entity, Entity),
+}
+
+/// The collision events of the last physics step, a resource.
+/// Cleared when the next step begins, so systems can read or drain it in between.
+#[derive(Default, Debug)]
+pub struct CollisionEvents(pub Vec<CollisionEvent>);
+
+/// Something entering or leaving the collider of an entity with a `Sensor`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TriggerEvent {
+    /// The sensor first, then what entered it
+    Enter(Entity, Entity),
+    Exit(Entity, Entity),
+}
+
+/// The trigger events of the last physics step, a resource. Cleared like `CollisionEvents`.
+#[derive(Default, Debug)]
+pub struct TriggerEvents(pub Vec<TriggerEvent>);
+
+/// Makes an entity's collider report what overlaps it as `TriggerEvents` instead of blocking
+/// it, e.g. for pickups and pressure plates
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Sensor;
+
+/// Continuous collision detection, for things fast enough to pass through a wall between two
+/// steps, like arrows. Each step then sweeps the collider along its path, which costs a lot
+/// more than a plain step, so only put it on the few bodies that need it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Ccd;
+
+/// Keeps pushing a dynamic body for as long as it's on it, e.g. wind or a current
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Force(pub cgmath::Vector2<f32>);
 
 impl Default for Force {
-    fn default() -> Self { Force(nphysics2d::algebra::Force2::zero()) }
+    fn default() -> Self { Force(cgmath::Vector2::zero()) }
 }
 
+/// Knocks a dynamic body once, changing its velocity by the impulse over its mass.
+/// Removed once it has been applied.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Impulse(pub cgmath::Vector2<f32>);
+
 pub enum Collider {
     Circle { radius: f32 },
     Square { side_length: f32 },
 }
 
+/// Which layers a collider is on and which layers it collides with, as bit masks.
+/// Two colliders only collide if each is on a layer the other's filter lets through.
+/// Colliders without this collide with everything, rays are on every layer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CollisionGroups {
+    pub membership: u32,
+    pub filter: u32,
+}
+
+impl CollisionGroups {
+    /// Every layer there is, the physics world has 30 of them
+    pub const ALL: u32 = (1 << 30) - 1;
+}
+
+impl Default for CollisionGroups {
+    fn default() -> Self {
+        Self {
+            membership: Self::ALL,
+            filter: Self::ALL,
+        }
+    }
+}
+
+/// Layers past the 30th are ignored
+impl From<&CollisionGroups> for ncollide2d::pipeline::CollisionGroups {
+    fn from(groups: &CollisionGroups) -> Self {
+        ncollide2d::pipeline::CollisionGroups::new()
+            .with_membership_by_mask(groups.membership & CollisionGroups::ALL)
+            .with_whitelist_by_mask(groups.filter & CollisionGroups::ALL)
+    }
+}
+
+/// Shifts an entity's collider away from its `Position`, in the body's local space.
+/// Colliders without one are centered on the entity.
+pub struct ColliderOffset(pub cgmath::Vector2<f32>);
+
 #[allow(dead_code)]
 pub enum PhysicsBody {
     Disabled,
@@ -26,6 +167,10 @@ pub enum PhysicsBody {
     Dynamic { mass: f32 },
 }
 
+/// Whether the world's gravity pulls on a dynamic body, bodies without this ignore it
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct GravityEnabled(pub bool);
+
 pub struct BodyHandle(pub DefaultBodyHandle);
 
 pub struct ColliderHandle(pub DefaultColliderHandle);
@@ -2,7 +2,7 @@ use cgmath::Vector2;
 use entity_smith::EntitySmith;
 use transforms::Position;
 
-use crate::{Collider, PhysicsBody, Velocity};
+use crate::{Collider, ColliderOffset, PhysicsBody, Velocity};
 
 pub trait PhysicsEntitySmith {
     fn velocity(&mut self, vel: Vector2<f32>) -> &mut Self;
@@ -13,6 +13,7 @@ pub trait PhysicsEntitySmith {
     fn static_body(&mut self) -> &mut Self;
     fn circle_collider(&mut self, radius: f32) -> &mut Self;
     fn square_collider(&mut self, side_length: f32) -> &mut Self;
+    fn collider_offset(&mut self, offset: Vector2<f32>) -> &mut Self;
     fn static_square_body(&mut self, side_length: f32) -> &mut Self;
 }
 
@@ -33,6 +34,9 @@ impl<'a> PhysicsEntitySmith for EntitySmith<'a> {
     fn square_collider(&mut self, side_length: f32) -> &mut Self {
         self.add_component(Collider::Square { side_length })
     }
+    fn collider_offset(&mut self, offset: Vector2<f32>) -> &mut Self {
+        self.add_component(ColliderOffset(offset))
+    }
     fn static_square_body(&mut self, side_length: f32) -> &mut Self {
         self.add_component(PhysicsBody::Static)
             .add_component(Collider::Square { side_length })
@@ -1,5 +1,5 @@
 pub use components::*;
-pub use systems::PhysicsBuilderExtender;
+pub use systems::{PhysicsBuilderExtender, PhysicsResource};
 
 pub use crate::entity_smith::PhysicsEntitySmith;
 
@@ -1,23 +1,37 @@
 #![allow(dead_code)]
 
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Once;
+
 use cgmath::{InnerSpace, Rotation3};
 use crossbeam_channel::Receiver;
-use entity_smith::FrameTime;
+use entity_smith::{Acceleration, FrameTime, Speed};
 use legion::storage::Component;
 use legion::systems::{Builder, ParallelRunnable};
 use legion::world::Event;
-use legion::{component, Entity, EntityStore, IntoQuery, Resources, SystemBuilder, World};
+use legion::{
+    component, maybe_changed, Entity, EntityStore, IntoQuery, Resources, SystemBuilder, World,
+};
+use ncollide2d::pipeline::ContactEvent;
+use ncollide2d::query::{Proximity, Ray};
 use ncollide2d::shape::ShapeHandle;
+use nphysics2d::algebra::{Force2, ForceType};
 use nphysics2d::force_generator::DefaultForceGeneratorSet;
 use nphysics2d::joint::DefaultJointConstraintSet;
 use nphysics2d::ncollide2d::shape::{Ball, Cuboid};
 use nphysics2d::object::{
-    BodyPartHandle, BodyStatus, ColliderDesc, DefaultBodySet, DefaultColliderSet, RigidBodyDesc,
+    Body, BodyPartHandle, BodyStatus, ColliderDesc, DefaultBodyHandle, DefaultBodySet,
+    DefaultColliderHandle, DefaultColliderSet, RigidBody, RigidBodyDesc,
 };
 use nphysics2d::world::{DefaultGeometricalWorld, DefaultMechanicalWorld};
 use transforms::{Position, Rotation};
 
-use crate::{BodyHandle, Collider, ColliderHandle, PhysicsBody, Velocity};
+use crate::{
+    BodyHandle, Ccd, Collider, ColliderHandle, ColliderOffset, CollisionEvent, CollisionEvents,
+    CollisionGroups, Force, GravityEnabled, Impulse, MovementLimits, PhysicsBody, PhysicsControl,
+    PhysicsValidation, Sensor, TriggerEvent, TriggerEvents, Velocity,
+};
 
 pub trait PhysicsBuilderExtender {
     fn add_physics_systems(&mut self, world: &mut World, resources: &mut Resources) -> &mut Self;
@@ -26,18 +40,30 @@ pub trait PhysicsBuilderExtender {
 impl PhysicsBuilderExtender for Builder {
     fn add_physics_systems(&mut self, world: &mut World, resources: &mut Resources) -> &mut Self {
         resources.insert(PhysicsResource::default());
+        if !resources.contains::<MovementLimits>() {
+            resources.insert(MovementLimits::default());
+        }
+        if !resources.contains::<PhysicsControl>() {
+            resources.insert(PhysicsControl::default());
+        }
+        if !resources.contains::<PhysicsValidation>() {
+            resources.insert(PhysicsValidation::default());
+        }
+        resources.insert(CollisionEvents::default());
+        resources.insert(TriggerEvents::default());
         let (sender_body, _receiver_body) = crossbeam_channel::unbounded::<Event>();
         let (sender_collider, _receiver_collider) = crossbeam_channel::unbounded::<Event>();
         world.subscribe(sender_body, component::<BodyHandle>());
         world.subscribe(sender_collider, component::<ColliderHandle>());
         return self
-            // TODO: reimplement .add_system(validate_physics_entities_system())
+            .add_system(validate_physics_entities_system())
             .add_system(make_body_handles())
             .add_system(remove_body_handles())
             .flush()
             .add_system(make_collider_handles())
             .add_system(remove_collider_handles())
             .flush()
+            .add_system(sync_body_status())
             .add_system(entity_world_to_physics_world())
             .add_system(step_physics_world())
             .add_system(physics_world_to_entity_world());
@@ -45,13 +71,45 @@ impl PhysicsBuilderExtender for Builder {
     }
 }
 
-struct PhysicsResource {
+pub struct PhysicsResource {
     mechanical_world: DefaultMechanicalWorld<f32>,
     geometrical_world: DefaultGeometricalWorld<f32>,
     bodies: DefaultBodySet<f32>,
     colliders: DefaultColliderSet<f32>,
     joint_constraints: DefaultJointConstraintSet<f32>,
     force_generators: DefaultForceGeneratorSet<f32>,
+    bodies_of: HandleMap<DefaultBodyHandle>,
+    colliders_of: HandleMap<DefaultColliderHandle>,
+}
+
+/// Which entity each handle belongs to and the other way around
+struct HandleMap<H> {
+    entities: HashMap<H, Entity>,
+    handles: HashMap<Entity, H>,
+}
+
+impl<H: Copy + Eq + Hash> HandleMap<H> {
+    fn new() -> Self {
+        HandleMap {
+            entities: HashMap::new(),
+            handles: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, handle: H, entity: Entity) {
+        self.entities.insert(handle, entity);
+        self.handles.insert(entity, handle);
+    }
+
+    fn remove(&mut self, handle: H) {
+        if let Some(entity) = self.entities.remove(&handle) {
+            self.handles.remove(&entity);
+        }
+    }
+
+    fn entity(&self, handle: H) -> Option<Entity> { self.entities.get(&handle).copied() }
+
+    fn handle(&self, entity: Entity) -> Option<H> { self.handles.get(&entity).copied() }
 }
 
 impl PhysicsResource {
@@ -64,6 +122,125 @@ impl PhysicsResource {
             &mut self.force_generators,
         )
     }
+
+    /// Pulls every body with `GravityEnabled(true)` this way from the next step on.
+    /// Sleeping bodies are woken so they notice.
+    pub fn set_gravity(&mut self, gravity: cgmath::Vector2<f32>) {
+        self.mechanical_world.gravity = c2n(gravity);
+        for (_, body) in self.bodies.iter_mut() {
+            body.activate();
+        }
+    }
+
+    pub fn gravity(&self) -> cgmath::Vector2<f32> { n2c(&self.mechanical_world.gravity) }
+
+    /// The entity a rigid body was made for
+    pub fn body_entity(&self, handle: DefaultBodyHandle) -> Option<Entity> {
+        self.bodies_of.entity(handle)
+    }
+
+    pub fn body_handle(&self, entity: Entity) -> Option<DefaultBodyHandle> {
+        self.bodies_of.handle(entity)
+    }
+
+    /// The entity a collider was made for
+    pub fn collider_entity(&self, handle: DefaultColliderHandle) -> Option<Entity> {
+        self.colliders_of.entity(handle)
+    }
+
+    pub fn collider_handle(&self, entity: Entity) -> Option<DefaultColliderHandle> {
+        self.colliders_of.handle(entity)
+    }
+
+    /// The contacts that began or ended in the last step between colliders of entities
+    fn collision_events(&self) -> impl Iterator<Item = CollisionEvent> + '_ {
+        let entity = move |handle| self.colliders_of.entity(handle);
+        self.geometrical_world
+            .contact_events()
+            .iter()
+            .filter_map(move |event| match *event {
+                ContactEvent::Started(a, b) => {
+                    Some(CollisionEvent::Started(entity(a)?, entity(b)?))
+                }
+                ContactEvent::Stopped(a, b) => {
+                    Some(CollisionEvent::Stopped(entity(a)?, entity(b)?))
+                }
+            })
+    }
+
+    /// Colliders that began or stopped overlapping a sensor in the last step
+    fn trigger_events(&self) -> impl Iterator<Item = TriggerEvent> + '_ {
+        let entity = move |handle| self.colliders_of.entity(handle);
+        let is_sensor = move |handle| matches!(self.colliders.get(handle), Some(collider) if collider.is_sensor());
+        self.geometrical_world
+            .proximity_events()
+            .iter()
+            .filter_map(move |event| {
+                let (sensor, other) = if is_sensor(event.collider1) {
+                    (event.collider1, event.collider2)
+                } else {
+                    (event.collider2, event.collider1)
+                };
+                let (sensor, other) = (entity(sensor)?, entity(other)?);
+
+                let was_inside = event.prev_status == Proximity::Intersecting;
+                let is_inside = event.new_status == Proximity::Intersecting;
+                match (was_inside, is_inside) {
+                    (false, true) => Some(TriggerEvent::Enter(sensor, other)),
+                    (true, false) => Some(TriggerEvent::Exit(sensor, other)),
+                    _ => None,
+                }
+            })
+    }
+
+    /// The nearest entity whose collider the ray hits and how far along `dir` it is.
+    /// Colliders only become hittable once the physics world has stepped with them.
+    pub fn raycast(
+        &self,
+        origin: cgmath::Vector2<f32>,
+        dir: cgmath::Vector2<f32>,
+    ) -> Option<(Entity, f32)> {
+        if dir.magnitude2() == 0.0 {
+            return None;
+        }
+
+        // Normalized so the time of impact is the distance
+        let ray = Ray::new(c2n(origin).into(), c2n(dir.normalize()));
+        self.geometrical_world
+            .interferences_with_ray(
+                &self.colliders,
+                &ray,
+                f32::MAX,
+                &ncollide2d::pipeline::CollisionGroups::new(),
+            )
+            .filter_map(|(handle, _, intersection)| {
+                Some((self.colliders_of.entity(handle)?, intersection.toi))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    /// Whether no static collider, like a wall, lies between `from` and `to`.
+    /// Dynamic bodies don't block the line, so it works for seeing past other agents.
+    pub fn line_of_sight(&self, from: cgmath::Vector2<f32>, to: cgmath::Vector2<f32>) -> bool {
+        let along = to - from;
+        let distance = along.magnitude();
+        if distance == 0.0 {
+            return true;
+        }
+
+        let ray = Ray::new(c2n(from).into(), c2n(along / distance));
+        !self
+            .geometrical_world
+            .interferences_with_ray(
+                &self.colliders,
+                &ray,
+                distance,
+                &ncollide2d::pipeline::CollisionGroups::new(),
+            )
+            .any(|(_, collider, _)| {
+                matches!(self.bodies.get(collider.body()), Some(body) if body.is_static())
+            })
+    }
 }
 
 impl Default for PhysicsResource {
@@ -77,6 +254,8 @@ impl Default for PhysicsResource {
             colliders: DefaultColliderSet::new(),
             joint_constraints: DefaultJointConstraintSet::new(),
             force_generators: DefaultForceGeneratorSet::new(),
+            bodies_of: HandleMap::new(),
+            colliders_of: HandleMap::new(),
         }
     }
 }
@@ -99,33 +278,174 @@ fn removal_subscriber<T: Component>(receiver: Receiver<Event>) -> impl ParallelR
         })
 }
 
+/// Fixes up bodies that are about to be made: static bodies drop anything that would move them,
+/// dynamic ones get the `Velocity` and `Rotation` they can't do without
+fn validate_physics_entities_system() -> impl ParallelRunnable {
+    SystemBuilder::new("validate_physics_entities")
+        .read_component::<PhysicsBody>()
+        .read_component::<Velocity>()
+        .read_component::<Speed>()
+        .read_component::<Acceleration>()
+        .read_component::<Force>()
+        .read_component::<Rotation>()
+        .read_resource::<PhysicsValidation>()
+        .with_query(
+            <(
+                Entity,
+                &PhysicsBody,
+                Option<&Velocity>,
+                Option<&Speed>,
+                Option<&Acceleration>,
+                Option<&Force>,
+                Option<&Rotation>,
+            )>::query()
+            .filter(!component::<BodyHandle>()),
+        )
+        .build(move |commands, world, validation, query| {
+            let complain = |entity: &Entity, problem: &str| {
+                if validation.strict {
+                    panic!("[physics] {:?} {}", entity, problem);
+                }
+                eprintln!("[physics] (warning): {:?} {}, fixing it", entity, problem);
+            };
+
+            for (entity, body, vel, speed, accel, force, ori) in query.iter(world) {
+                match body {
+                    PhysicsBody::Static => {
+                        if vel.is_some() {
+                            complain(entity, "is a static body with a Velocity");
+                            commands.remove_component::<Velocity>(*entity);
+                        }
+                        if speed.is_some() {
+                            complain(entity, "is a static body with a Speed");
+                            commands.remove_component::<Speed>(*entity);
+                        }
+                        if accel.is_some() {
+                            complain(entity, "is a static body with an Acceleration");
+                            commands.remove_component::<Acceleration>(*entity);
+                        }
+                        if force.is_some() {
+                            complain(entity, "is a static body with a Force");
+                            commands.remove_component::<Force>(*entity);
+                        }
+                    }
+                    PhysicsBody::Dynamic { .. } => {
+                        if vel.is_none() {
+                            complain(entity, "is a dynamic body without a Velocity");
+                            commands.add_component(*entity, Velocity::zero());
+                        }
+                        if ori.is_none() {
+                            complain(entity, "is a dynamic body without a Rotation");
+                            commands.add_component(*entity, Rotation::zero());
+                        }
+                    }
+                    PhysicsBody::Disabled => {}
+                }
+            }
+        })
+}
+
 fn make_body_handles() -> impl ParallelRunnable {
     SystemBuilder::new("make_body_handles")
         .read_component::<PhysicsBody>()
         .read_component::<Position>()
+        .read_component::<GravityEnabled>()
         .write_resource::<PhysicsResource>()
-        .with_query(<(Entity, &PhysicsBody, &Position)>::query().filter(!component::<BodyHandle>()))
+        .with_query(
+            <(Entity, &PhysicsBody, &Position, Option<&GravityEnabled>)>::query()
+                .filter(!component::<BodyHandle>()),
+        )
         .build(move |commands, world, resources, query| {
             let physics: &mut PhysicsResource = &mut *resources;
-            for (entity, physics_body, position) in query.iter_mut(world) {
+            for (entity, physics_body, position, gravity) in query.iter_mut(world) {
                 let body = match physics_body {
                     PhysicsBody::Disabled => {
                         RigidBodyDesc::<f32>::new().status(BodyStatus::Disabled)
                     }
                     PhysicsBody::Static => RigidBodyDesc::<f32>::new()
                         .status(BodyStatus::Static)
-                        .position(nalgebra::Isometry2::new(c2n(position.0.truncate()), 0.)),
+                        .position(nalgebra::Isometry2::new(c2n(position.xy()), 0.)),
                     PhysicsBody::Dynamic { mass } => RigidBodyDesc::<f32>::new()
                         .status(BodyStatus::Dynamic)
-                        .gravity_enabled(false)
+                        .gravity_enabled(gravity_enabled(gravity))
                         .mass(*mass),
                 };
-                let handle = BodyHandle(physics.bodies.insert(body.build()));
-                commands.add_component(*entity, handle);
+                let handle = physics.bodies.insert(body.build());
+                physics.bodies_of.insert(handle, *entity);
+                commands.add_component(*entity, BodyHandle(handle));
             }
         })
 }
 
+fn gravity_enabled(gravity: Option<&GravityEnabled>) -> bool {
+    gravity.copied().unwrap_or_default().0
+}
+
+fn body_status(physics_body: &PhysicsBody) -> BodyStatus {
+    match physics_body {
+        PhysicsBody::Disabled => BodyStatus::Disabled,
+        PhysicsBody::Static => BodyStatus::Static,
+        PhysicsBody::Dynamic { .. } => BodyStatus::Dynamic,
+    }
+}
+
+/// Brings existing rigid bodies in line with a `PhysicsBody` or `GravityEnabled` that was
+/// changed after they were made
+fn sync_body_status() -> impl ParallelRunnable {
+    SystemBuilder::new("sync_body_status")
+        .read_component::<BodyHandle>()
+        .read_component::<PhysicsBody>()
+        .read_component::<Position>()
+        .read_component::<GravityEnabled>()
+        .write_resource::<PhysicsResource>()
+        .with_query(
+            <(
+                &BodyHandle,
+                &PhysicsBody,
+                &Position,
+                Option<&GravityEnabled>,
+            )>::query()
+            .filter(maybe_changed::<PhysicsBody>() | maybe_changed::<GravityEnabled>()),
+        )
+        .build(move |_, world, physics, query| {
+            for (handle, physics_body, position, gravity) in query.iter(world) {
+                if let Some(body) = physics.bodies.rigid_body_mut(handle.0) {
+                    sync_status(body, physics_body, position, gravity_enabled(gravity));
+                }
+            }
+        })
+}
+
+fn sync_status(
+    body: &mut RigidBody<f32>,
+    physics_body: &PhysicsBody,
+    position: &Position,
+    gravity: bool,
+) {
+    if body.gravity_enabled() != gravity {
+        body.enable_gravity(gravity);
+        body.activate();
+    }
+
+    // maybe_changed also fires for untouched neighbours in the same chunk
+    let status = body_status(physics_body);
+    if body.status() == status {
+        return;
+    }
+
+    body.set_status(status);
+    match physics_body {
+        PhysicsBody::Disabled => {}
+        PhysicsBody::Static => {
+            body.set_position(nalgebra::Isometry2::new(c2n(position.xy()), 0.));
+        }
+        PhysicsBody::Dynamic { mass } => {
+            body.set_mass(*mass);
+            body.activate();
+        }
+    }
+}
+
 fn remove_body_handles() -> impl ParallelRunnable {
     SystemBuilder::new("remove_body_handles")
         .read_component::<PhysicsBody>()
@@ -134,6 +454,7 @@ fn remove_body_handles() -> impl ParallelRunnable {
         .build(move |commands, world, physics, query| {
             query.for_each_mut(world, |(entity, handle): (&Entity, &BodyHandle)| {
                 physics.bodies.remove(handle.0);
+                physics.bodies_of.remove(handle.0);
                 commands.remove_component::<BodyHandle>(*entity);
             });
         })
@@ -143,9 +464,22 @@ fn make_collider_handles() -> impl ParallelRunnable {
     SystemBuilder::new("make_collider_handles")
         .read_component::<BodyHandle>()
         .read_component::<Collider>()
+        .read_component::<ColliderOffset>()
+        .read_component::<CollisionGroups>()
+        .read_component::<Sensor>()
+        .read_component::<Ccd>()
         .write_resource::<PhysicsResource>()
         .with_query(
-            <(Entity, &BodyHandle, &Collider)>::query().filter(!component::<ColliderHandle>()),
+            <(
+                Entity,
+                &BodyHandle,
+                &Collider,
+                Option<&ColliderOffset>,
+                Option<&CollisionGroups>,
+                Option<&Sensor>,
+                Option<&Ccd>,
+            )>::query()
+            .filter(!component::<ColliderHandle>()),
         )
         .build(move |commands, world, resources, query| {
             // TODO: figure out if this split does anything
@@ -153,7 +487,7 @@ fn make_collider_handles() -> impl ParallelRunnable {
             let (mut for_query, _) = world.split_for_query(query);
             let physics: &mut PhysicsResource = &mut *resources;
             for components in query.iter_mut(&mut for_query) {
-                let (entity, body_handle, collider): (&Entity, &BodyHandle, &Collider) = components;
+                let (entity, body_handle, collider, offset, groups, sensor, ccd) = components;
                 let shape_handle = match collider {
                     Collider::Circle { radius } => ShapeHandle::new(Ball::new(*radius)),
                     Collider::Square { side_length } => {
@@ -162,13 +496,20 @@ fn make_collider_handles() -> impl ParallelRunnable {
                         ShapeHandle::new(Cuboid::new(sides_vec))
                     }
                 };
-                let collider = ColliderDesc::<f32>::new(shape_handle);
-                let handle = ColliderHandle(
-                    physics
-                        .colliders
-                        .insert(collider.build(BodyPartHandle(body_handle.0, 0))),
-                );
-                commands.add_component(*entity, handle);
+                let mut collider = ColliderDesc::<f32>::new(shape_handle);
+                if let Some(offset) = offset {
+                    collider.set_translation(c2n(offset.0));
+                }
+                if let Some(groups) = groups {
+                    collider.set_collision_groups(groups.into());
+                }
+                collider.set_is_sensor(sensor.is_some());
+                collider.set_ccd_enabled(ccd.is_some());
+                let handle = physics
+                    .colliders
+                    .insert(collider.build(BodyPartHandle(body_handle.0, 0)));
+                physics.colliders_of.insert(handle, *entity);
+                commands.add_component(*entity, ColliderHandle(handle));
             }
         })
 }
@@ -181,11 +522,15 @@ fn remove_collider_handles() -> impl ParallelRunnable {
             let for_query = world;
             query.for_each_mut(for_query, |(entity, collider_handle)| {
                 physics.colliders.remove(collider_handle.0);
+                physics.colliders_of.remove(collider_handle.0);
                 commands.remove_component::<ColliderHandle>(*entity);
             });
         })
 }
 
+/// Rotations closer than this are the same one read back from the physics world
+const ANGLE_EPSILON: f32 = 1e-5;
+
 fn entity_world_to_physics_world() -> impl ParallelRunnable {
     SystemBuilder::new("entity_world_to_physics_world")
         .read_component::<BodyHandle>()
@@ -193,21 +538,60 @@ fn entity_world_to_physics_world() -> impl ParallelRunnable {
         .read_component::<Velocity>()
         .read_component::<Rotation>()
         .read_component::<PhysicsBody>()
+        .read_component::<Force>()
+        .read_component::<Impulse>()
         .write_resource::<PhysicsResource>()
-        .with_query(<(&BodyHandle, &PhysicsBody, &Position, &Velocity, &Rotation)>::query())
-        .build(move |_, world, physics, query| {
+        .with_query(<(
+            Entity,
+            &BodyHandle,
+            &PhysicsBody,
+            &Position,
+            &Velocity,
+            &Rotation,
+            Option<&Force>,
+            Option<&Impulse>,
+        )>::query())
+        .build(move |commands, world, physics, query| {
             let physics: &mut PhysicsResource = &mut *physics;
-            for (han, bod, pos, vel, ori) in query.iter(world) {
+            for (entity, han, bod, pos, vel, ori, force, impulse) in query.iter(world) {
                 if let PhysicsBody::Dynamic { .. } = bod {
                     if let Some(body) = physics.bodies.rigid_body_mut(han.0) {
-                        body.set_position(nalgebra::Isometry2::new(
-                            c2n(pos.0.truncate()),
-                            ori.to_rad().0,
-                        ));
-                        body.set_linear_velocity(c2n(vel.0));
-                        // and force?
+                        // Only what changed since physics wrote it back, so resting bodies can
+                        // fall asleep while teleported or steered ones still wake up
+                        let position = nalgebra::Isometry2::new(c2n(pos.xy()), ori.to_rad().0);
+                        let turned = (body.position().rotation.angle() - position.rotation.angle())
+                            .abs()
+                            > ANGLE_EPSILON;
+                        if body.position().translation != position.translation || turned {
+                            body.set_position(position);
+                            body.activate();
+                        }
+                        if body.velocity().linear != c2n(vel.0) {
+                            body.set_linear_velocity(c2n(vel.0));
+                            body.activate();
+                        }
+                        // Forces are forgotten after every step, so they're applied every frame
+                        if let Some(force) = force {
+                            body.apply_force(
+                                0,
+                                &Force2::linear(c2n(force.0)),
+                                ForceType::Force,
+                                true,
+                            );
+                        }
+                        if let Some(impulse) = impulse {
+                            body.apply_force(
+                                0,
+                                &Force2::linear(c2n(impulse.0)),
+                                ForceType::Impulse,
+                                true,
+                            );
+                        }
                     }
                 }
+                if impulse.is_some() {
+                    commands.remove_component::<Impulse>(*entity);
+                }
             }
         })
 }
@@ -215,12 +599,33 @@ fn entity_world_to_physics_world() -> impl ParallelRunnable {
 fn step_physics_world() -> impl ParallelRunnable {
     SystemBuilder::new("step_physics_world")
         .read_resource::<FrameTime>()
+        .write_resource::<PhysicsControl>()
         .write_resource::<PhysicsResource>()
-        .build(move |_, _, (frame_time, physics), _| {
-            let physics: &mut PhysicsResource = &mut *physics;
-            physics.mechanical_world.set_timestep(frame_time.0);
-            physics.step();
-        })
+        .write_resource::<CollisionEvents>()
+        .write_resource::<TriggerEvents>()
+        .build(
+            move |_, _, (frame_time, control, physics, events, triggers), _| {
+                events.0.clear();
+                triggers.0.clear();
+
+                if control.paused && !control.single_step {
+                    return;
+                }
+                control.single_step = false;
+
+                let physics: &mut PhysicsResource = &mut *physics;
+                let substeps = control.substeps.max(1);
+                physics
+                    .mechanical_world
+                    .set_timestep(frame_time.0 / substeps as f32);
+                // Every step starts by forgetting the events of the one before
+                for _ in 0..substeps {
+                    physics.step();
+                    events.0.extend(physics.collision_events());
+                    triggers.0.extend(physics.trigger_events());
+                }
+            },
+        )
 }
 
 fn physics_world_to_entity_world() -> impl ParallelRunnable {
@@ -251,7 +656,7 @@ fn physics_world_to_entity_world() -> impl ParallelRunnable {
                 )| {
                     if let PhysicsBody::Dynamic { .. } = body {
                         if let Some(bod) = physics.bodies.rigid_body(handle.0) {
-                            pos.0 = n2c(&bod.position().translation.vector).extend(0.);
+                            pos.set_xy(n2c(&bod.position().translation.vector));
                             if let Some(v) = vel {
                                 v.0 = n2c(&bod.velocity().linear);
                             }
@@ -270,27 +675,45 @@ fn physics_world_to_entity_world() -> impl ParallelRunnable {
 fn movement_system() -> impl ParallelRunnable {
     SystemBuilder::new("movement")
         .read_resource::<FrameTime>()
+        .read_resource::<MovementLimits>()
         .with_query(<(&mut Position, &mut Velocity)>::query())
-        .build(move |_cmd, world, resources, query| {
+        .build(move |_cmd, world, (frame_time, limits), query| {
             let for_query = world;
-            query.for_each_mut(for_query, |components| {
-                movement(&*resources, components.0, components.1);
+            query.for_each_mut(for_query, |(pos, vel)| {
+                movement(frame_time, limits, pos, vel);
             });
         })
 }
 
-fn movement(frame_time: &FrameTime, pos: &mut Position, vel: &mut Velocity) {
+fn movement(
+    frame_time: &FrameTime,
+    limits: &MovementLimits,
+    pos: &mut Position,
+    vel: &mut Velocity,
+) {
     if vel.0.x.is_finite() && vel.0.y.is_finite() {
-        let v = if (vel.0 * frame_time.0).magnitude() < 0.5 {
-            vel.0 * frame_time.0
-        } else {
-            (vel.0 * frame_time.0).normalize() * 0.5
-        };
-        pos.0 += v.extend(0.);
+        pos.set_xy(pos.xy() + clamped_step(vel.0, frame_time.0, limits));
     } else {
-        // TODO: We need to deal with this somehow
+        // Stands still rather than spreading NaNs, it happening every frame is warned about once
         vel.0 = cgmath::Vector2::new(0.0, 0.0);
-        println!("Velocity Hickup");
+        static WARNED: Once = Once::new();
+        WARNED.call_once(|| {
+            eprintln!("[physics] (warning): non-finite velocity, resetting it to zero");
+        });
+    }
+}
+
+fn clamped_step(
+    velocity: cgmath::Vector2<f32>,
+    delta: f32,
+    limits: &MovementLimits,
+) -> cgmath::Vector2<f32> {
+    let step = velocity * delta;
+    let max_length = (limits.max_speed * delta).min(limits.max_step);
+    if step.magnitude() <= max_length {
+        step
+    } else {
+        step.normalize() * max_length
     }
 }
 
@@ -299,3 +722,606 @@ fn n2c(input: &nalgebra::Vector2<f32>) -> cgmath::Vector2<f32> {
 }
 
 fn c2n(input: cgmath::Vector2<f32>) -> nalgebra::Vector2<f32> { [input.x, input.y].into() }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector2;
+    use legion::Schedule;
+
+    use super::*;
+
+    /// A world at 60 fps with only the physics systems scheduled
+    fn physics_schedule() -> (World, Resources, Schedule) {
+        let mut world = World::default();
+        let mut resources = Resources::default();
+        resources.insert(FrameTime(1.0 / 60.0));
+
+        let mut builder = Schedule::builder();
+        builder.add_physics_systems(&mut world, &mut resources);
+        let schedule = builder.build();
+        (world, resources, schedule)
+    }
+
+    fn distance_after_one_second(fps: u32, velocity: Vector2<f32>, limits: &MovementLimits) -> f32 {
+        let frame_time = FrameTime(1.0 / fps as f32);
+        let mut pos = Position::zero();
+        let mut vel = Velocity(velocity);
+        for _ in 0..fps {
+            movement(&frame_time, limits, &mut pos, &mut vel);
+        }
+        pos.0.magnitude()
+    }
+
+    #[test]
+    fn capped_speed_covers_the_same_distance_at_any_framerate() {
+        let limits = MovementLimits {
+            max_speed: 4.0,
+            max_step: 0.5,
+        };
+        let velocity = Vector2::new(6.0, 8.0);
+
+        let at_30 = distance_after_one_second(30, velocity, &limits);
+        let at_144 = distance_after_one_second(144, velocity, &limits);
+
+        assert!((at_30 - 4.0).abs() < 1e-3, "{}", at_30);
+        assert!((at_144 - 4.0).abs() < 1e-3, "{}", at_144);
+    }
+
+    #[test]
+    fn swapping_the_body_kind_updates_the_rigid_body() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let entity = world.push((
+            Position::zero(),
+            Rotation::zero(),
+            Velocity::new(1.0, 0.0),
+            PhysicsBody::Disabled,
+        ));
+
+        let body_x = |world: &World, resources: &Resources| {
+            let handle = <&BodyHandle>::query().get(world, entity).unwrap().0;
+            let physics = resources.get::<PhysicsResource>().unwrap();
+            let body = physics.bodies.rigid_body(handle).unwrap();
+            (body.status(), body.position().translation.vector.x)
+        };
+
+        for _ in 0..3 {
+            schedule.execute(&mut world, &mut resources);
+        }
+        assert_eq!(body_x(&world, &resources), (BodyStatus::Disabled, 0.0));
+
+        world
+            .entry(entity)
+            .unwrap()
+            .add_component(PhysicsBody::Dynamic { mass: 1.0 });
+        for _ in 0..10 {
+            schedule.execute(&mut world, &mut resources);
+        }
+        let (status, moved_to) = body_x(&world, &resources);
+        assert_eq!(status, BodyStatus::Dynamic);
+        assert!(moved_to > 0.1, "{}", moved_to);
+
+        world
+            .entry(entity)
+            .unwrap()
+            .add_component(PhysicsBody::Disabled);
+        for _ in 0..10 {
+            schedule.execute(&mut world, &mut resources);
+        }
+        assert_eq!(body_x(&world, &resources), (BodyStatus::Disabled, moved_to));
+    }
+
+    #[test]
+    fn pausing_freezes_bodies_until_resumed() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let entity = world.push((
+            Position::zero(),
+            Rotation::zero(),
+            Velocity::new(1.0, 0.0),
+            PhysicsBody::Dynamic { mass: 1.0 },
+        ));
+        let x = |world: &World| <&Position>::query().get(world, entity).unwrap().0.x;
+        let mut run = |world: &mut World, resources: &mut Resources, frames: usize| {
+            for _ in 0..frames {
+                schedule.execute(world, resources);
+            }
+        };
+
+        run(&mut world, &mut resources, 5);
+        let moving = x(&world);
+        assert!(moving > 0.0);
+
+        resources.get_mut::<PhysicsControl>().unwrap().paused = true;
+        run(&mut world, &mut resources, 5);
+        let paused = x(&world);
+        assert_eq!(paused, moving);
+
+        // A single step moves one frame's worth and pauses again
+        resources.get_mut::<PhysicsControl>().unwrap().single_step = true;
+        run(&mut world, &mut resources, 3);
+        let stepped = x(&world);
+        assert!(
+            (stepped - paused - 1.0 / 60.0).abs() < 1e-4,
+            "{}",
+            stepped - paused
+        );
+
+        resources.get_mut::<PhysicsControl>().unwrap().paused = false;
+        run(&mut world, &mut resources, 5);
+        assert!(x(&world) - stepped > 4.0 / 60.0);
+    }
+
+    fn touches_probe_at(probe_position: Position) -> bool {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let prop = world.push((
+            Position::zero(),
+            PhysicsBody::Static,
+            Collider::Circle { radius: 0.5 },
+            ColliderOffset(Vector2::new(2.0, 0.0)),
+        ));
+        let probe = world.push((
+            probe_position,
+            Rotation::zero(),
+            Velocity::zero(),
+            PhysicsBody::Dynamic { mass: 1.0 },
+            Collider::Circle { radius: 0.25 },
+        ));
+
+        schedule.execute(&mut world, &mut resources);
+
+        let collider = |entity| <&ColliderHandle>::query().get(&world, entity).unwrap().0;
+        let physics = resources.get::<PhysicsResource>().unwrap();
+        physics
+            .geometrical_world
+            .contact_pair(&physics.colliders, collider(prop), collider(probe), true)
+            .is_some()
+    }
+
+    #[test]
+    fn collider_offsets_move_the_contact() {
+        assert!(touches_probe_at(Position::new(2.0, 0.5, 0.0)));
+        assert!(!touches_probe_at(Position::zero()));
+    }
+
+    #[test]
+    fn raycasts_hit_the_nearest_collider() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let near = world.push((
+            Position::new(3.0, 0.0, 0.0),
+            PhysicsBody::Static,
+            Collider::Circle { radius: 0.5 },
+        ));
+        world.push((
+            Position::new(6.0, 0.0, 0.0),
+            PhysicsBody::Static,
+            Collider::Square { side_length: 1.0 },
+        ));
+
+        schedule.execute(&mut world, &mut resources);
+
+        let physics = resources.get::<PhysicsResource>().unwrap();
+        let (hit, distance) = physics
+            .raycast(Vector2::new(0.0, 0.0), Vector2::new(2.0, 0.0))
+            .unwrap();
+        assert_eq!(hit, near);
+        assert!((distance - 2.5).abs() < 1e-4, "{}", distance);
+
+        assert_eq!(
+            physics.raycast(Vector2::new(0.0, 0.0), Vector2::new(-1.0, 0.0)),
+            None
+        );
+        assert_eq!(
+            physics.raycast(Vector2::new(0.0, 0.0), Vector2::new(0.0, 0.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn touching_and_parting_emit_collision_events() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let player = world.push((
+            Position::zero(),
+            PhysicsBody::Static,
+            Collider::Circle { radius: 0.5 },
+        ));
+        let enemy = world.push((
+            Position::new(5.0, 0.0, 0.0),
+            Rotation::zero(),
+            Velocity::zero(),
+            PhysicsBody::Dynamic { mass: 1.0 },
+            Collider::Circle { radius: 0.5 },
+        ));
+        let mut move_enemy_to = |world: &mut World, resources: &mut Resources, x: f32| {
+            <&mut Position>::query().get_mut(world, enemy).unwrap().0.x = x;
+            schedule.execute(world, resources);
+            resources.get::<CollisionEvents>().unwrap().0.clone()
+        };
+        let involves_both = |event: &CollisionEvent| match *event {
+            CollisionEvent::Started(a, b) | CollisionEvent::Stopped(a, b) => {
+                (a, b) == (player, enemy) || (a, b) == (enemy, player)
+            }
+        };
+
+        assert!(move_enemy_to(&mut world, &mut resources, 5.0).is_empty());
+
+        let events = move_enemy_to(&mut world, &mut resources, 0.8);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], CollisionEvent::Started(..)));
+        assert!(involves_both(&events[0]));
+
+        // Still touching, the start isn't repeated
+        assert!(move_enemy_to(&mut world, &mut resources, 0.8).is_empty());
+
+        let events = move_enemy_to(&mut world, &mut resources, 5.0);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], CollisionEvent::Stopped(..)));
+        assert!(involves_both(&events[0]));
+    }
+
+    #[test]
+    fn only_bodies_with_gravity_enabled_fall() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let body = |x: f32| {
+            (
+                Position::new(x, 0.0, 0.0),
+                Rotation::zero(),
+                Velocity::zero(),
+                PhysicsBody::Dynamic { mass: 1.0 },
+            )
+        };
+        let falling = world.push(body(0.0));
+        world
+            .entry(falling)
+            .unwrap()
+            .add_component(GravityEnabled(true));
+        let floating = world.push(body(5.0));
+        let y = |world: &World, entity| <&Position>::query().get(world, entity).unwrap().0.y;
+        let mut run = |world: &mut World, resources: &mut Resources, frames: usize| {
+            for _ in 0..frames {
+                schedule.execute(world, resources);
+            }
+        };
+
+        // No gravity until it's set
+        run(&mut world, &mut resources, 10);
+        assert_eq!(y(&world, falling), 0.0);
+
+        resources
+            .get_mut::<PhysicsResource>()
+            .unwrap()
+            .set_gravity(Vector2::new(0.0, -10.0));
+        run(&mut world, &mut resources, 10);
+        assert!(y(&world, falling) < -0.1, "{}", y(&world, falling));
+        assert_eq!(y(&world, floating), 0.0);
+
+        world
+            .entry(floating)
+            .unwrap()
+            .add_component(GravityEnabled(true));
+        run(&mut world, &mut resources, 10);
+        assert!(y(&world, floating) < -0.1, "{}", y(&world, floating));
+    }
+
+    #[test]
+    fn colliders_only_meet_the_layers_they_filter_for() {
+        const WALLS: u32 = 1 << 0;
+        const ENEMIES: u32 = 1 << 1;
+
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let wall = world.push((
+            Position::zero(),
+            PhysicsBody::Static,
+            Collider::Square { side_length: 1.0 },
+            CollisionGroups {
+                membership: WALLS,
+                filter: CollisionGroups::ALL,
+            },
+        ));
+        let enemy = |y: f32| {
+            (
+                Position::new(0.5, y, 0.0),
+                Rotation::zero(),
+                Velocity::zero(),
+                PhysicsBody::Dynamic { mass: 1.0 },
+                Collider::Circle { radius: 0.5 },
+            )
+        };
+        let solid = world.push(enemy(0.0));
+        let ghost = world.push(enemy(0.5));
+        world.entry(ghost).unwrap().add_component(CollisionGroups {
+            membership: ENEMIES,
+            filter: CollisionGroups::ALL & !WALLS,
+        });
+
+        schedule.execute(&mut world, &mut resources);
+
+        let events = &resources.get::<CollisionEvents>().unwrap().0;
+        let touched = |a, b| {
+            events.iter().any(|event| {
+                *event == CollisionEvent::Started(a, b) || *event == CollisionEvent::Started(b, a)
+            })
+        };
+        assert!(touched(wall, solid));
+        assert!(!touched(wall, ghost));
+        // Enemies still bump into each other
+        assert!(touched(solid, ghost));
+
+        // Rays are on every layer, so the ghost can still be clicked
+        let physics = resources.get::<PhysicsResource>().unwrap();
+        let (hit, _) = physics
+            .raycast(Vector2::new(5.0, 0.5), Vector2::new(-1.0, 0.0))
+            .unwrap();
+        assert_eq!(hit, ghost);
+    }
+
+    #[test]
+    fn only_static_colliders_block_line_of_sight() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        world.push((
+            Position::new(3.0, 0.0, 0.0),
+            PhysicsBody::Static,
+            Collider::Square { side_length: 1.0 },
+        ));
+        world.push((
+            Position::new(0.0, 3.0, 0.0),
+            Rotation::zero(),
+            Velocity::zero(),
+            PhysicsBody::Dynamic { mass: 1.0 },
+            Collider::Circle { radius: 0.5 },
+        ));
+
+        schedule.execute(&mut world, &mut resources);
+
+        let physics = resources.get::<PhysicsResource>().unwrap();
+        let origin = Vector2::new(0.0, 0.0);
+        assert!(!physics.line_of_sight(origin, Vector2::new(6.0, 0.0)));
+        assert!(physics.line_of_sight(origin, Vector2::new(2.0, 0.0)));
+        assert!(physics.line_of_sight(origin, Vector2::new(0.0, 6.0)));
+    }
+
+    #[test]
+    fn impulses_knock_once_and_forces_keep_pushing() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let body = |y: f32| {
+            (
+                Position::new(0.0, y, 0.0),
+                Rotation::zero(),
+                Velocity::zero(),
+                PhysicsBody::Dynamic { mass: 2.0 },
+            )
+        };
+        let knocked = world.push(body(0.0));
+        let pushed = world.push(body(5.0));
+        schedule.execute(&mut world, &mut resources);
+
+        world
+            .entry(knocked)
+            .unwrap()
+            .add_component(Impulse(Vector2::new(4.0, 0.0)));
+        world
+            .entry(pushed)
+            .unwrap()
+            .add_component(Force(Vector2::new(60.0, 0.0)));
+
+        let velocity = |world: &World, entity| <&Velocity>::query().get(world, entity).unwrap().0;
+        schedule.execute(&mut world, &mut resources);
+        assert!((velocity(&world, knocked).x - 2.0).abs() < 1e-4);
+        assert!((velocity(&world, pushed).x - 0.5).abs() < 1e-4);
+        assert!(<&Impulse>::query().get(&world, knocked).is_err());
+
+        schedule.execute(&mut world, &mut resources);
+        assert!((velocity(&world, knocked).x - 2.0).abs() < 1e-4);
+        assert!((velocity(&world, pushed).x - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sensors_report_overlaps_without_blocking() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let plate = world.push((
+            Position::zero(),
+            PhysicsBody::Static,
+            Collider::Square { side_length: 1.0 },
+            Sensor,
+        ));
+        let walker = world.push((
+            Position::new(5.0, 0.0, 0.0),
+            Rotation::zero(),
+            Velocity::zero(),
+            PhysicsBody::Dynamic { mass: 1.0 },
+            Collider::Circle { radius: 0.25 },
+        ));
+        let mut move_walker_to = |world: &mut World, resources: &mut Resources, x: f32| {
+            <&mut Position>::query().get_mut(world, walker).unwrap().0.x = x;
+            schedule.execute(world, resources);
+            assert!(resources.get::<CollisionEvents>().unwrap().0.is_empty());
+            resources.get::<TriggerEvents>().unwrap().0.clone()
+        };
+
+        assert!(move_walker_to(&mut world, &mut resources, 5.0).is_empty());
+        assert_eq!(
+            move_walker_to(&mut world, &mut resources, 0.0),
+            vec![TriggerEvent::Enter(plate, walker)]
+        );
+        // Standing right on it isn't pushed away
+        assert!(move_walker_to(&mut world, &mut resources, 0.0).is_empty());
+        assert_eq!(<&Position>::query().get(&world, walker).unwrap().0.x, 0.0);
+        assert_eq!(
+            move_walker_to(&mut world, &mut resources, 5.0),
+            vec![TriggerEvent::Exit(plate, walker)]
+        );
+    }
+
+    fn ball_after_hitting_a_thin_wall(ccd: bool) -> f32 {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        world.push((
+            Position::new(3.0, 0.0, 0.0),
+            PhysicsBody::Static,
+            Collider::Square { side_length: 0.2 },
+        ));
+        let ball = world.push((
+            Position::zero(),
+            Rotation::zero(),
+            Velocity::new(300.0, 0.0),
+            PhysicsBody::Dynamic { mass: 1.0 },
+            Collider::Circle { radius: 0.1 },
+        ));
+        if ccd {
+            world.entry(ball).unwrap().add_component(Ccd);
+        }
+
+        for _ in 0..3 {
+            schedule.execute(&mut world, &mut resources);
+        }
+        <&Position>::query().get(&world, ball).unwrap().0.x
+    }
+
+    #[test]
+    fn ccd_stops_fast_bodies_tunneling() {
+        assert!(ball_after_hitting_a_thin_wall(false) > 3.0);
+        assert!(ball_after_hitting_a_thin_wall(true) < 3.0);
+    }
+
+    fn validated(entity: impl legion::storage::IntoComponentSource, strict: bool) -> World {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+        resources.insert(PhysicsValidation { strict });
+
+        world.extend(entity);
+        schedule.execute(&mut world, &mut resources);
+        world
+    }
+
+    #[test]
+    fn invalid_bodies_are_fixed() {
+        let world = validated(
+            vec![(
+                Position::zero(),
+                PhysicsBody::Static,
+                Velocity::new(1.0, 0.0),
+                Speed(2.0),
+            )],
+            false,
+        );
+        let (entity, _) = <(Entity, &PhysicsBody)>::query()
+            .iter(&world)
+            .next()
+            .unwrap();
+        let entry = world.entry_ref(*entity).unwrap();
+        assert!(entry.get_component::<Velocity>().is_err());
+        assert!(entry.get_component::<Speed>().is_err());
+
+        let world = validated(
+            vec![(Position::zero(), PhysicsBody::Dynamic { mass: 1.0 })],
+            false,
+        );
+        let (entity, _) = <(Entity, &PhysicsBody)>::query()
+            .iter(&world)
+            .next()
+            .unwrap();
+        let entry = world.entry_ref(*entity).unwrap();
+        assert!(entry.get_component::<Velocity>().is_ok());
+        assert!(entry.get_component::<Rotation>().is_ok());
+    }
+
+    #[test]
+    #[should_panic]
+    fn strict_validation_panics() {
+        validated(
+            vec![(Position::zero(), PhysicsBody::Static, Velocity::zero())],
+            true,
+        );
+    }
+
+    #[test]
+    fn handles_map_back_to_their_entities() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let entity = world.push((
+            Position::zero(),
+            PhysicsBody::Static,
+            Collider::Circle { radius: 0.5 },
+        ));
+        schedule.execute(&mut world, &mut resources);
+
+        {
+            let physics = resources.get::<PhysicsResource>().unwrap();
+            let body = <&BodyHandle>::query().get(&world, entity).unwrap().0;
+            let collider = <&ColliderHandle>::query().get(&world, entity).unwrap().0;
+            assert_eq!(physics.body_entity(body), Some(entity));
+            assert_eq!(physics.body_handle(entity), Some(body));
+            assert_eq!(physics.collider_entity(collider), Some(entity));
+            assert_eq!(physics.collider_handle(entity), Some(collider));
+        }
+
+        let mut entry = world.entry(entity).unwrap();
+        entry.remove_component::<PhysicsBody>();
+        entry.remove_component::<Collider>();
+        schedule.execute(&mut world, &mut resources);
+
+        let physics = resources.get::<PhysicsResource>().unwrap();
+        assert_eq!(physics.body_handle(entity), None);
+        assert_eq!(physics.collider_handle(entity), None);
+    }
+
+    #[test]
+    fn resting_bodies_sleep_until_moved() {
+        let (mut world, mut resources, mut schedule) = physics_schedule();
+
+        let entity = world.push((
+            Position::zero(),
+            Rotation::zero(),
+            Velocity::zero(),
+            PhysicsBody::Dynamic { mass: 1.0 },
+            Collider::Circle { radius: 0.5 },
+        ));
+        let active = |world: &World, resources: &Resources| {
+            let handle = <&BodyHandle>::query().get(world, entity).unwrap().0;
+            let physics = resources.get::<PhysicsResource>().unwrap();
+            physics.bodies.rigid_body(handle).unwrap().is_active()
+        };
+
+        for _ in 0..300 {
+            schedule.execute(&mut world, &mut resources);
+        }
+        assert!(!active(&world, &resources));
+
+        <&mut Position>::query()
+            .get_mut(&mut world, entity)
+            .unwrap()
+            .0
+            .x = 3.0;
+        schedule.execute(&mut world, &mut resources);
+        assert!(active(&world, &resources));
+        assert_eq!(<&Position>::query().get(&world, entity).unwrap().0.x, 3.0);
+    }
+
+    #[test]
+    fn non_finite_velocities_stop_instead_of_moving() {
+        let mut pos = Position::new(1.0, 2.0, 0.0);
+        let mut vel = Velocity::new(f32::NAN, 1.0);
+        movement(
+            &FrameTime(1.0 / 60.0),
+            &MovementLimits::default(),
+            &mut pos,
+            &mut vel,
+        );
+        assert_eq!(pos.xy(), Vector2::new(1.0, 2.0));
+        assert_eq!(vel.0, Vector2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn long_frames_are_capped_by_max_step() {
+        let limits = MovementLimits::default();
+        let step = clamped_step(Vector2::new(20.0, 0.0), 1.0, &limits);
+        assert_eq!(step, Vector2::new(limits.max_step, 0.0));
+    }
+}
@@ -1,6 +1,8 @@
 use std::collections::HashSet;
 
-use cgmath::{Deg, Euler, Matrix4, Quaternion, Rad, Rotation3, SquareMatrix, Vector3, Zero};
+use cgmath::{
+    Deg, Euler, Matrix4, One, Quaternion, Rad, Rotation3, SquareMatrix, Vector2, Vector3, Zero,
+};
 use legion::Entity;
 
 pub struct Parent(pub Entity);
@@ -11,7 +13,8 @@ pub struct Position(pub Vector3<f32>);
 
 pub struct Rotation(pub Quaternion<f32>);
 
-pub struct Scale(pub f32);
+/// How much an entity is stretched along each of its own axes
+pub struct Scale(pub Vector3<f32>);
 
 impl From<&Position> for Matrix4<f32> {
     fn from(pos: &Position) -> Self { Matrix4::from_translation(pos.0) }
@@ -22,10 +25,55 @@ impl From<&Rotation> for Matrix4<f32> {
 }
 
 impl From<&Scale> for Matrix4<f32> {
-    fn from(scale: &Scale) -> Self { Matrix4::from_scale(scale.0) }
+    fn from(scale: &Scale) -> Self {
+        Matrix4::from_nonuniform_scale(scale.0.x, scale.0.y, scale.0.z)
+    }
+}
+
+impl Position {
+    pub fn new(x: f32, y: f32, z: f32) -> Self { Self(Vector3::new(x, y, z)) }
+    pub fn zero() -> Self { Self(Vector3::zero()) }
+
+    /// Where the entity stands on the ground plane, which is all that movement and physics see
+    pub fn xy(&self) -> Vector2<f32> { self.0.truncate() }
+
+    /// Moves the entity on the ground plane, keeping its height
+    pub fn set_xy(&mut self, xy: Vector2<f32>) { self.0 = xy.extend(self.0.z); }
+}
+
+impl From<Vector3<f32>> for Position {
+    fn from(pos: Vector3<f32>) -> Self { Self(pos) }
+}
+
+/// Places the position on the ground plane
+impl From<Vector2<f32>> for Position {
+    fn from(pos: Vector2<f32>) -> Self { Self(pos.extend(0.)) }
+}
+
+impl Default for Rotation {
+    fn default() -> Self { Self::zero() }
+}
+
+impl Default for Scale {
+    fn default() -> Self { Self::uniform(1.0) }
+}
+
+impl Scale {
+    /// The same scale along every axis
+    pub fn uniform(scale: f32) -> Self { Self(Vector3::new(scale, scale, scale)) }
+}
+
+impl From<f32> for Scale {
+    fn from(scale: f32) -> Self { Self::uniform(scale) }
+}
+
+impl From<Vector3<f32>> for Scale {
+    fn from(scale: Vector3<f32>) -> Self { Self(scale) }
 }
 
 impl Rotation {
+    /// No rotation at all
+    pub fn zero() -> Self { Self(Quaternion::one()) }
     pub fn to_rad(&self) -> Rad<f32> { Euler::from(self.0).z }
     pub fn to_deg(&self) -> Deg<f32> { Euler::from(self.0).z.into() }
     pub fn from_deg(deg: f32) -> Self { Self(Quaternion::from_angle_z(Deg(deg))) }
@@ -54,6 +102,11 @@ impl Transform {
         }
     }
 
+    /// Scales, then rotates, then translates, the order every model matrix is built in
+    pub fn compose(position: &Position, rotation: &Rotation, scale: &Scale) -> Matrix4<f32> {
+        Matrix4::from(position) * Matrix4::from(rotation) * Matrix4::from(scale)
+    }
+
     pub fn world_transform(&self) -> Matrix4<f32> { self.absolute }
     pub fn local_transform(&self) -> Matrix4<f32> { self.relative }
 
@@ -75,6 +128,8 @@ pub struct SphericalOffset {
     pub theta_delta: f32,
     pub phi_delta: f32,
     pub radius_delta: f32,
+    pub min_phi: f32,
+    pub max_phi: f32,
 }
 
 impl SphericalOffset {
@@ -88,6 +143,126 @@ impl SphericalOffset {
             theta_delta: -0.005,
             phi_delta: 0.0025,
             radius_delta: 0.3,
+            min_phi: 0.1 * PI,
+            max_phi: 0.3 * PI,
+        }
+    }
+
+    /// Rotates the offset around the z axis, keeping theta within [0, 2π)
+    /// so that it doesn't lose precision over long sessions.
+    pub fn orbit(&mut self, angle: f32) {
+        self.theta = (self.theta + angle).rem_euclid(std::f32::consts::TAU);
+        // rem_euclid can round up to exactly TAU for tiny negative values
+        if self.theta >= std::f32::consts::TAU {
+            self.theta = 0.0;
         }
     }
+
+    pub fn to_cartesian(&self) -> Vector3<f32> {
+        Vector3::new(
+            self.radius * self.theta.cos() * self.phi.cos(),
+            self.radius * self.theta.sin() * self.phi.cos(),
+            self.radius * self.phi.sin(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use cgmath::InnerSpace;
+
+    use super::*;
+
+    #[test]
+    fn component_constructors_agree() {
+        assert_eq!(
+            Position::new(1.0, 2.0, 0.0).0,
+            Position::from(Vector2::new(1.0, 2.0)).0
+        );
+        assert_eq!(Position::zero().0, Position::from(Vector3::zero()).0);
+        assert_eq!(Rotation::default().0, Rotation::from_deg(0.0).0);
+        assert_eq!(Rotation::zero().to_deg(), Deg(0.0));
+        assert_eq!(Scale::default().0, Vector3::new(1.0, 1.0, 1.0));
+        assert_eq!(Scale::from(2.0).0, Scale::uniform(2.0).0);
+
+        let mut raised = Position::new(1.0, 2.0, 3.0);
+        assert_eq!(raised.xy(), Vector2::new(1.0, 2.0));
+        raised.set_xy(Vector2::new(-1.0, 0.5));
+        assert_eq!(raised.0, Vector3::new(-1.0, 0.5, 3.0));
+    }
+
+    #[test]
+    fn composing_scales_then_rotates_then_translates() {
+        let matrix = Transform::compose(
+            &Position::new(3.0, -2.0, 1.0),
+            &Rotation::from_deg(90.0),
+            &Scale::uniform(2.0),
+        );
+
+        // +X is doubled, turned onto +Y and moved
+        let tip = matrix * Vector3::unit_x().extend(1.0);
+        assert!((tip.truncate() - Vector3::new(3.0, 0.0, 1.0)).magnitude() < 1e-5);
+        // Scaling applies to Z too, but rotating around it doesn't
+        let top = matrix * Vector3::unit_z().extend(1.0);
+        assert!((top.truncate() - Vector3::new(3.0, -2.0, 3.0)).magnitude() < 1e-5);
+
+        // The quarter turns dungeon generation orients walls with
+        for (degrees, facing) in [
+            (0.0, -Vector3::unit_y()),
+            (90.0, Vector3::unit_x()),
+            (180.0, Vector3::unit_y()),
+            (-90.0, -Vector3::unit_x()),
+        ] {
+            let matrix = Transform::compose(
+                &Position::zero(),
+                &Rotation::from_deg(degrees),
+                &Scale::default(),
+            );
+            let turned = (matrix * (-Vector3::unit_y()).extend(0.0)).truncate();
+            assert!((turned - facing).magnitude() < 1e-5, "{} degrees", degrees);
+        }
+    }
+
+    #[test]
+    fn non_uniform_scale_stretches_local_axes() {
+        let matrix = Transform::compose(
+            &Position::zero(),
+            &Rotation::from_deg(90.0),
+            &Scale(Vector3::new(2.0, 1.0, 3.0)),
+        );
+
+        // Stretched before being turned, so the long side ends up along +Y
+        for (axis, expected) in [
+            (Vector3::unit_x(), Vector3::new(0.0, 2.0, 0.0)),
+            (Vector3::unit_y(), Vector3::new(-1.0, 0.0, 0.0)),
+            (Vector3::unit_z(), Vector3::new(0.0, 0.0, 3.0)),
+        ] {
+            let scaled = (matrix * axis.extend(0.0)).truncate();
+            assert!((scaled - expected).magnitude() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn orbiting_keeps_theta_bounded() {
+        let mut offset = SphericalOffset::camera_offset();
+        let mut reference = offset.theta as f64;
+        let step = -0.005 * 37.0;
+
+        // many full turns in steps that don't divide a turn evenly
+        for _ in 0..100_000 {
+            offset.orbit(step);
+            reference += step as f64;
+            assert!((0.0..TAU).contains(&offset.theta));
+        }
+
+        let mut expected = SphericalOffset::camera_offset();
+        expected.theta = reference.rem_euclid(std::f64::consts::TAU) as f32;
+
+        let cartesian = offset.to_cartesian();
+        assert!((cartesian.magnitude() - offset.radius).abs() < 1e-4);
+        // an unwrapped f32 theta drifts by more than a radian over this many steps
+        assert!((cartesian - expected.to_cartesian()).magnitude() < 0.1);
+    }
 }
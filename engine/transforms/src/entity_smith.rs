@@ -2,13 +2,15 @@ use cgmath::{Vector2, Vector3};
 use entity_smith::EntitySmith;
 use legion::Entity;
 
-use crate::{Children, Parent, Position, Rotation, Transform};
+use crate::{Children, Parent, Position, Rotation, Scale, SphericalOffset, Transform};
 
 pub trait TransformEntitySmith {
     fn transform_identity(&mut self) -> &mut Self;
     fn position(&mut self, pos: Vector3<f32>) -> &mut Self;
     fn pos(&mut self, pos: Vector2<f32>) -> &mut Self;
     fn orientation(&mut self, ori: f32) -> &mut Self;
+    fn scale(&mut self, scale: f32) -> &mut Self;
+    fn spherical_offset(&mut self, offset: SphericalOffset) -> &mut Self;
 
     fn adopt_child(&mut self, child: Entity) -> &mut Self;
     fn child_of(&mut self, parent: Entity) -> &mut Self;
@@ -16,11 +18,15 @@ pub trait TransformEntitySmith {
 
 impl<'a> TransformEntitySmith for EntitySmith<'a> {
     fn transform_identity(&mut self) -> &mut Self { self.add_component(Transform::identity()) }
-    fn position(&mut self, pos: Vector3<f32>) -> &mut Self { self.add_component(Position(pos)) }
-    fn pos(&mut self, pos: Vector2<f32>) -> &mut Self {
-        self.add_component(Position(pos.extend(0.)))
+    fn position(&mut self, pos: Vector3<f32>) -> &mut Self {
+        self.add_component(Position::from(pos))
     }
+    fn pos(&mut self, pos: Vector2<f32>) -> &mut Self { self.add_component(Position::from(pos)) }
     fn orientation(&mut self, ori: f32) -> &mut Self { self.add_component(Rotation::from_deg(ori)) }
+    fn scale(&mut self, scale: f32) -> &mut Self { self.add_component(Scale::uniform(scale)) }
+    fn spherical_offset(&mut self, offset: SphericalOffset) -> &mut Self {
+        self.add_component(offset)
+    }
 
     fn adopt_child(&mut self, child: Entity) -> &mut Self {
         let me = self.entity;
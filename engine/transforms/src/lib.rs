@@ -1,10 +1,12 @@
 pub use components::*;
+pub use spatial::SpatialIndex;
 pub use systems::TransformBuilderExtender;
 
 pub use crate::entity_smith::TransformEntitySmith;
 
 pub mod components;
 mod entity_smith;
+mod spatial;
 mod systems;
 
 // #[derive(Default)]
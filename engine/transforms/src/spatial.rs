@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector2};
+use legion::Entity;
+
+type Cell = Vec<(Entity, Vector2<f32>)>;
+
+/// A uniform grid over the xy plane for finding entities near a point without
+/// scanning every `Position`. Rebuilt from scratch every frame by `spatial_index_system`.
+pub struct SpatialIndex {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Cell>,
+}
+
+impl Default for SpatialIndex {
+    fn default() -> Self { Self::new(2.0) }
+}
+
+impl SpatialIndex {
+    pub fn new(cell_size: f32) -> Self {
+        assert!(cell_size > 0.0, "SpatialIndex cell size must be positive");
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    pub fn cell_size(&self) -> f32 { self.cell_size }
+
+    pub fn len(&self) -> usize { self.cells.values().map(Vec::len).sum() }
+
+    pub fn is_empty(&self) -> bool { self.cells.values().all(Vec::is_empty) }
+
+    /// Empties the cells while keeping their allocations around for the next rebuild
+    pub fn clear(&mut self) { self.cells.values_mut().for_each(Vec::clear); }
+
+    pub fn insert(&mut self, entity: Entity, position: Vector2<f32>) {
+        self.cells
+            .entry(self.cell_of(position))
+            .or_default()
+            .push((entity, position));
+    }
+
+    /// All entities within `radius` of `center`, in no particular order
+    pub fn query_radius(
+        &self,
+        center: Vector2<f32>,
+        radius: f32,
+    ) -> impl Iterator<Item = Entity> + '_ {
+        self.neighbours(center, radius).map(|(entity, _)| entity)
+    }
+
+    /// Like `query_radius`, along with where each entity was when it was inserted
+    pub fn neighbours(
+        &self,
+        center: Vector2<f32>,
+        radius: f32,
+    ) -> impl Iterator<Item = (Entity, Vector2<f32>)> + '_ {
+        let radius = radius.max(0.0);
+        let reach = Vector2::new(radius, radius);
+
+        self.cells_between(center - reach, center + reach)
+            .filter(move |(_, position)| (position - center).magnitude2() <= radius * radius)
+            .copied()
+    }
+
+    /// All entities inside the box between the corners `min` and `max`, in no particular order
+    pub fn query_rect(
+        &self,
+        min: Vector2<f32>,
+        max: Vector2<f32>,
+    ) -> impl Iterator<Item = Entity> + '_ {
+        self.cells_between(min, max)
+            .filter(move |(_, p)| p.x >= min.x && p.x <= max.x && p.y >= min.y && p.y <= max.y)
+            .map(|(entity, _)| *entity)
+    }
+
+    fn cells_between(
+        &self,
+        min: Vector2<f32>,
+        max: Vector2<f32>,
+    ) -> impl Iterator<Item = &(Entity, Vector2<f32>)> + '_ {
+        let (min_x, min_y) = self.cell_of(min);
+        let (max_x, max_y) = self.cell_of(max);
+
+        (min_x..=max_x)
+            .flat_map(move |x| (min_y..=max_y).map(move |y| (x, y)))
+            .filter_map(move |cell| self.cells.get(&cell))
+            .flatten()
+    }
+
+    fn cell_of(&self, position: Vector2<f32>) -> (i32, i32) {
+        (
+            (position.x / self.cell_size).floor() as i32,
+            (position.y / self.cell_size).floor() as i32,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use legion::World;
+
+    use super::*;
+
+    fn scatter(world: &mut World, index: &mut SpatialIndex, points: &[[f32; 2]]) -> Vec<Entity> {
+        points
+            .iter()
+            .map(|&p| {
+                let entity = world.push((0u8,));
+                index.insert(entity, p.into());
+                entity
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_radius_finds_exactly_the_nearby_entities() {
+        let mut world = World::default();
+        let mut index = SpatialIndex::new(1.0);
+
+        let points = [
+            [0.0, 0.0],
+            [0.5, 0.5],
+            [-1.5, 0.0],
+            [2.1, 0.0],
+            [0.0, -1.99],
+            [10.0, 10.0],
+        ];
+        let entities = scatter(&mut world, &mut index, &points);
+
+        let center = Vector2::new(0.0, 0.0);
+        let radius = 2.0;
+
+        let found: HashSet<Entity> = index.query_radius(center, radius).collect();
+        let expected: HashSet<Entity> = entities
+            .iter()
+            .zip(points.iter())
+            .filter(|(_, &p)| (Vector2::from(p) - center).magnitude() <= radius)
+            .map(|(e, _)| *e)
+            .collect();
+
+        assert_eq!(found, expected);
+        assert_eq!(found.len(), 4);
+    }
+
+    #[test]
+    fn query_rect_finds_entities_inside_the_box() {
+        let mut world = World::default();
+        let mut index = SpatialIndex::new(1.0);
+
+        let entities = scatter(
+            &mut world,
+            &mut index,
+            &[[0.5, 0.5], [2.0, 1.0], [3.5, 0.0], [-0.1, 0.5]],
+        );
+
+        let found: HashSet<Entity> = index
+            .query_rect(Vector2::new(0.0, 0.0), Vector2::new(3.0, 1.0))
+            .collect();
+        assert_eq!(found, entities[..2].iter().copied().collect());
+    }
+
+    #[test]
+    fn clear_empties_the_index() {
+        let mut world = World::default();
+        let mut index = SpatialIndex::default();
+
+        scatter(&mut world, &mut index, &[[0.0, 0.0], [3.0, -3.0]]);
+        assert_eq!(index.len(), 2);
+
+        index.clear();
+        assert!(index.is_empty());
+        assert_eq!(index.query_radius(Vector2::new(0.0, 0.0), 100.0).count(), 0);
+    }
+}
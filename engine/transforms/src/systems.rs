@@ -6,7 +6,9 @@ use legion::world::EntityAccessError;
 use legion::{component, maybe_changed, Entity, IntoQuery, SystemBuilder};
 
 use crate::components::{Children, Parent};
-use crate::{Position, Rotation, Scale, SphericalOffset, Transform, TransformEntitySmith};
+use crate::{
+    Position, Rotation, Scale, SpatialIndex, SphericalOffset, Transform, TransformEntitySmith,
+};
 //use crate::graphics::gui::GuiContext;
 
 // note(Jökull): This belongs here if anywhere
@@ -23,9 +25,20 @@ pub fn spherical_offset_system() -> impl ParallelRunnable {
 
 #[allow(dead_code)]
 pub fn spherical_offset(pos: &mut Position, follow: &SphericalOffset) {
-    pos.0.x = follow.radius * follow.theta.cos() * follow.phi.cos();
-    pos.0.y = follow.radius * follow.theta.sin() * follow.phi.cos();
-    pos.0.z = follow.radius * follow.phi.sin();
+    pos.0 = follow.to_cartesian();
+}
+
+/// Rebuilds the SpatialIndex from the world positions of everything with a Position
+pub fn spatial_index_system() -> impl ParallelRunnable {
+    SystemBuilder::new("spatial_index")
+        .write_resource::<SpatialIndex>()
+        .with_query(<(Entity, &Transform)>::query().filter(component::<Position>()))
+        .build(move |_cmd, world, index, query| {
+            index.clear();
+            for (entity, transform) in query.iter(world) {
+                index.insert(*entity, transform.world_position().truncate());
+            }
+        })
 }
 
 pub trait TransformBuilderExtender {
@@ -50,6 +63,7 @@ impl TransformBuilderExtender for Builder {
             .add_system(rotation_scale())
             .add_system(position_rotation_scale())
             .add_system(inherit_transforms())
+            .add_system(spatial_index_system())
         //.add_thread_local(player_transform_shower())
     }
 }
@@ -263,11 +277,9 @@ fn position_rotation_scale() -> impl ParallelRunnable {
                 .filter(maybe_changed::<Transform>()),
         )
         .build(move |_, world, _, query| {
-            query.for_each_mut(world, |(transform, val1, val2, val3)| {
-                transform.relative = transform.relative
-                    * Matrix4::from(val1)
-                    * Matrix4::from(val2)
-                    * Matrix4::from(val3);
+            query.for_each_mut(world, |(transform, position, rotation, scale)| {
+                transform.relative =
+                    transform.relative * Transform::compose(position, rotation, scale);
             });
         })
 }
@@ -275,6 +287,7 @@ fn position_rotation_scale() -> impl ParallelRunnable {
 fn inherit_transforms() -> impl ParallelRunnable {
     SystemBuilder::new("inherit_transforms")
         .read_component::<Children>()
+        .read_component::<Parent>()
         .write_component::<Transform>()
         .with_query(<&mut Transform>::query().filter(!component::<Parent>()))
         .with_query(
@@ -296,9 +309,16 @@ fn inherit_transforms() -> impl ParallelRunnable {
             while let Some((parent, parent_transform)) = stack.pop() {
                 if let Ok(children) = <&Children>::query().get(&children_only, parent) {
                     for &child in &children.0 {
-                        if let Ok::<&mut Transform, EntityAccessError>(child_transform) =
-                            <&mut Transform>::query().get_mut(&mut rest, child)
+                        // Children are never removed, so a child that was moved to another
+                        // parent or orphaned is still listed here
+                        if let Ok::<(&Parent, &mut Transform), EntityAccessError>((
+                            Parent(current_parent),
+                            child_transform,
+                        )) = <(&Parent, &mut Transform)>::query().get_mut(&mut rest, child)
                         {
+                            if *current_parent != parent {
+                                continue;
+                            }
                             child_transform.absolute =
                                 parent_transform.absolute * child_transform.relative;
                             stack.push((child, *child_transform));
@@ -308,3 +328,79 @@ fn inherit_transforms() -> impl ParallelRunnable {
             }
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{InnerSpace, Vector3};
+    use legion::{Resources, Schedule, World};
+
+    use super::*;
+
+    #[test]
+    fn models_get_their_full_transform() {
+        let mut world = World::default();
+        let mut resources = Resources::default();
+        resources.insert(SpatialIndex::default());
+        let mut schedule = Schedule::builder().add_transform_systems().build();
+
+        let (position, rotation, scale) = (
+            Position::new(4.0, 5.0, 0.5),
+            Rotation::from_deg(-90.0),
+            Scale::uniform(1.5),
+        );
+        let expected = Transform::compose(&position, &rotation, &scale);
+        let entity = world.push((position, rotation, scale));
+
+        // One frame to attach the Transform, one to fill it in
+        schedule.execute(&mut world, &mut resources);
+        schedule.execute(&mut world, &mut resources);
+
+        let transform = <&Transform>::query().get(&world, entity).unwrap();
+        for corner in [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()] {
+            let point = corner.extend(1.0);
+            let difference = transform.world_transform() * point - expected * point;
+            assert!(difference.magnitude() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn children_follow_their_current_parent() {
+        let mut world = World::default();
+        let mut resources = Resources::default();
+        resources.insert(SpatialIndex::default());
+        let mut schedule = Schedule::builder().add_transform_systems().build();
+
+        let player = world.push((Position::new(1.0, 0.0, 0.0), Rotation::from_deg(90.0)));
+        let chest = world.push((Position::new(-10.0, 0.0, 0.0),));
+        let weapon = world.push((Position::new(0.0, -2.0, 0.0), Parent(player)));
+
+        let world_position = |world: &World| {
+            <&Transform>::query()
+                .get(world, weapon)
+                .unwrap()
+                .world_position()
+        };
+
+        schedule.execute(&mut world, &mut resources);
+        // Turned along with the player, then moved to it
+        assert!((world_position(&world) - Vector3::new(3.0, 0.0, 0.0)).magnitude() < 1e-5);
+
+        <&mut Position>::query()
+            .get_mut(&mut world, player)
+            .unwrap()
+            .0 = Vector3::new(5.0, 1.0, 0.0);
+        schedule.execute(&mut world, &mut resources);
+        assert!((world_position(&world) - Vector3::new(7.0, 1.0, 0.0)).magnitude() < 1e-5);
+
+        // The player still lists the weapon among its children
+        world.entry(weapon).unwrap().add_component(Parent(chest));
+        for _ in 0..2 {
+            schedule.execute(&mut world, &mut resources);
+            assert!((world_position(&world) - Vector3::new(-10.0, -2.0, 0.0)).magnitude() < 1e-5);
+        }
+
+        world.entry(weapon).unwrap().remove_component::<Parent>();
+        schedule.execute(&mut world, &mut resources);
+        assert!((world_position(&world) - Vector3::new(0.0, -2.0, 0.0)).magnitude() < 1e-5);
+    }
+}
@@ -0,0 +1,90 @@
+use cgmath::Vector2;
+use entity_smith::EntitySmith;
+
+use crate::components::{AIFollow, Destination, HitPoints, Separation};
+use crate::world_gen::components::Faction;
+
+pub trait GameEntitySmith {
+    #[allow(dead_code)]
+    fn hit_points(&mut self, max: f32) -> &mut Self;
+    fn regenerating_hit_points(&mut self, max: f32, rate: f32, delay: f32) -> &mut Self;
+    fn faction(&mut self, faction: Faction) -> &mut Self;
+    fn destination(&mut self, goal: Vector2<f32>) -> &mut Self;
+    fn ai_follow(&mut self, follow: AIFollow) -> &mut Self;
+    fn separation(&mut self, radius: f32, strength: f32) -> &mut Self;
+}
+
+impl<'a> GameEntitySmith for EntitySmith<'a> {
+    fn hit_points(&mut self, max: f32) -> &mut Self { self.add_component(HitPoints::new(max)) }
+    fn regenerating_hit_points(&mut self, max: f32, rate: f32, delay: f32) -> &mut Self {
+        self.add_component(HitPoints::new(max).with_regen(rate, delay))
+    }
+    fn faction(&mut self, faction: Faction) -> &mut Self { self.add_component(faction) }
+    fn destination(&mut self, goal: Vector2<f32>) -> &mut Self {
+        self.add_component(Destination::simple(goal))
+    }
+    fn ai_follow(&mut self, follow: AIFollow) -> &mut Self { self.add_component(follow) }
+    fn separation(&mut self, radius: f32, strength: f32) -> &mut Self {
+        self.add_component(Separation { radius, strength })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use entity_smith::Smith;
+    use legion::systems::CommandBuffer;
+    use legion::{IntoQuery, Resources, World};
+    use physics::{Collider, PhysicsBody, PhysicsEntitySmith};
+    use transforms::{Position, Scale, TransformEntitySmith};
+
+    use super::*;
+
+    #[test]
+    fn an_enemy_is_one_chain() {
+        let mut world = World::default();
+        let target = world.push(());
+
+        let mut command_buffer = CommandBuffer::new(&world);
+        let enemy = command_buffer
+            .smith()
+            .pos(Vector2::new(2.0, 3.0))
+            .dynamic_body(0.5)
+            .circle_collider(0.5)
+            .scale(0.8)
+            .faction(Faction::Enemies)
+            .regenerating_hit_points(10.0, 1.0, 2.0)
+            .separation(1.5, 10.0)
+            .destination(Vector2::new(5.0, 5.0))
+            .ai_follow(AIFollow {
+                target: Some(target),
+                minimum_distance: 1.0,
+                maximum_distance: 8.0,
+                home: None,
+                memory: 4.0,
+                last_seen: None,
+            })
+            .get_entity();
+        command_buffer.flush(&mut world, &mut Resources::default());
+
+        let mut query = <(
+            &Position,
+            &PhysicsBody,
+            &Collider,
+            &Scale,
+            &Faction,
+            &HitPoints,
+            &Separation,
+            &Destination,
+            &AIFollow,
+        )>::query();
+        let (position, _, _, _, faction, hit_points, _, destination, follow) =
+            query.get(&world, enemy).unwrap();
+
+        assert_eq!(position.xy(), Vector2::new(2.0, 3.0));
+        assert!(*faction == Faction::Enemies);
+        assert_eq!(hit_points.max, 10.0);
+        assert_eq!(hit_points.regen_rate, 1.0);
+        assert_eq!(destination.goal, Vector2::new(5.0, 5.0));
+        assert_eq!(follow.target, Some(target));
+    }
+}
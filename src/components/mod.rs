@@ -1,6 +1,10 @@
-use cgmath::Vector2;
+use cgmath::{Deg, InnerSpace, Rad, Vector2};
 use legion::Entity;
 
+pub use self::entity_smith::GameEntitySmith;
+
+mod entity_smith;
+
 /*
    Welcome to Ms. Deeper's home for orphan components.
 
@@ -19,26 +23,219 @@ pub struct PlayerCamera {
 
 // end entity pointers
 
+/// On the player camera while it eases its `SphericalOffset` radius toward `radius`,
+/// see `systems::player::frame_bounds`
+pub struct CameraFraming {
+    pub radius: f32,
+}
+
+/// Where the minimap goes and how big it is, `Command::ToggleMinimap` shows and hides it
+pub struct Minimap {
+    /// From the top right corner of the window to the top right corner of the map, in pixels
+    pub margin: Vector2<f32>,
+    /// How many pixels across a tile is on the map
+    pub tile_size: f32,
+}
+
+impl Default for Minimap {
+    fn default() -> Self {
+        Self {
+            margin: Vector2::new(8.0, 8.0),
+            tile_size: 3.0,
+        }
+    }
+}
+
+/// How the player camera pans around when roaming, in world units per second
+pub struct CameraControl {
+    pub speed: f32,
+    /// How fast it gets up to `speed` while a direction is held
+    pub acceleration: f32,
+    /// How fast it comes to a stop once let go
+    pub damping: f32,
+    /// Pans while the cursor is at the edge of the window, like in RTS games.
+    /// Easy to set off by accident in a window, so it's off by default.
+    pub edge_scroll: bool,
+    /// How close to the edge in pixels the cursor has to be
+    pub edge_thickness: f32,
+    pub edge_speed: f32,
+}
+
+impl Default for CameraControl {
+    fn default() -> Self {
+        Self {
+            speed: 5.0,
+            acceleration: 25.0,
+            damping: 15.0,
+            edge_scroll: false,
+            edge_thickness: 8.0,
+            edge_speed: 5.0,
+        }
+    }
+}
+
+/// Sent when an entity's `HitPoints` run out
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Died(pub Entity);
+
+/// The deaths of this frame, a resource. Cleared when hit points are checked again next frame.
+#[derive(Default, Debug)]
+pub struct DeathEvents(pub Vec<Died>);
+
+/// What happens to the dead, a resource
+pub struct DeathSettings {
+    /// Leaves a darkened body behind that nothing bumps into, otherwise they vanish
+    pub leave_corpse: bool,
+    /// Seconds a corpse lies around before it's removed
+    pub despawn_delay: f32,
+}
+
+impl Default for DeathSettings {
+    fn default() -> Self {
+        Self {
+            leave_corpse: true,
+            despawn_delay: 10.0,
+        }
+    }
+}
+
+/// The player's swing, a resource
+pub struct MeleeAttack {
+    pub damage: f32,
+    pub range: f32,
+    /// How far to either side of straight ahead a swing reaches
+    pub arc: Deg<f32>,
+    /// The impulse the struck get pushed away with
+    pub knockback: f32,
+    /// Seconds between swings
+    pub cooldown: f32,
+    /// Seconds until the next swing
+    pub cooldown_left: f32,
+}
+
+impl Default for MeleeAttack {
+    fn default() -> Self {
+        Self {
+            damage: 1.0,
+            range: 2.0,
+            arc: Deg(60.0),
+            knockback: 1.5,
+            cooldown: 0.4,
+            cooldown_left: 0.0,
+        }
+    }
+}
+
+impl MeleeAttack {
+    /// Which way `target` gets knocked if a swing from `attacker`, facing `forward`, hits it
+    pub fn knockback_direction(
+        &self,
+        attacker: Vector2<f32>,
+        forward: Vector2<f32>,
+        target: Vector2<f32>,
+    ) -> Option<Vector2<f32>> {
+        let away = target - attacker;
+        if away.magnitude2() == 0.0 || away.magnitude() > self.range {
+            return None;
+        }
+        let away = away.normalize();
+        if away.angle(forward.normalize()) > Rad::from(self.arc) {
+            return None;
+        }
+        Some(away)
+    }
+}
+
+/// Removed along with its entity once `remaining` seconds have passed
+pub struct Lifetime {
+    pub remaining: f32,
+}
+
+/// Hurts the first thing it runs into and falls apart, walls included
+#[derive(Copy, Clone)]
+pub struct Projectile {
+    pub damage: f32,
+}
+
+/// On dead entities until they are removed
+pub struct Corpse {
+    pub despawn_in: f32,
+}
+
+/// Steers a moving agent away from other agents with `Separation` within `radius`,
+/// so a crowd heading for the same place doesn't pile up
+pub struct Separation {
+    pub radius: f32,
+    /// How hard neighbours push at full speed, in units per second squared
+    pub strength: f32,
+}
+
 pub struct AIFollow {
-    pub target: Entity,
+    /// Picked by `ai_targeting_system` for followers with a `Faction`, set by hand otherwise
+    pub target: Option<Entity>,
     pub minimum_distance: f32,
+    /// How close the target has to be to be chased at all
+    pub maximum_distance: f32,
+    /// Where to go back to once the target is out of range, stays put if `None`
+    pub home: Option<Vector2<f32>>,
+    /// Seconds to keep heading for where the target was last seen after losing sight of it
+    pub memory: f32,
+    /// Where the target was last seen and how many seconds are left to look for it there
+    pub last_seen: Option<(Vector2<f32>, f32)>,
 }
 
 pub struct Destination {
     pub goal: Vector2<f32>,
-    pub next: Vector2<f32>,
+    /// Where to head first when the goal is out of sight, kept up to date by
+    /// `intermediate_destination_system`
+    pub next: Option<Vector2<f32>>,
 }
 
 impl Destination {
-    pub fn simple(goal: Vector2<f32>) -> Destination {
-        Destination {
-            goal,
-            next: Vector2 { x: 0., y: 0. },
-        }
-    }
+    pub fn simple(goal: Vector2<f32>) -> Destination { Destination { goal, next: None } }
 }
 
 pub struct HitPoints {
     pub max: f32,
     pub health: f32,
+    /// Health healed every second, zero to not heal at all
+    pub regen_rate: f32,
+    /// How many seconds healing pauses for after taking damage
+    pub regen_delay: f32,
+    /// Seconds left until healing resumes
+    pub regen_cooldown: f32,
+}
+
+impl HitPoints {
+    /// Full health that doesn't heal by itself
+    pub fn new(max: f32) -> HitPoints {
+        HitPoints {
+            max,
+            health: max,
+            regen_rate: 0.0,
+            regen_delay: 0.0,
+            regen_cooldown: 0.0,
+        }
+    }
+
+    pub fn with_regen(self, regen_rate: f32, regen_delay: f32) -> HitPoints {
+        HitPoints {
+            regen_rate,
+            regen_delay,
+            ..self
+        }
+    }
+
+    /// Takes `amount` of health and pauses healing for `regen_delay`
+    pub fn damage(&mut self, amount: f32) {
+        self.health -= amount;
+        self.regen_cooldown = self.regen_delay;
+    }
+
+    /// Heals for `dt` seconds, once the cooldown from the last damage has run out
+    pub fn regenerate(&mut self, dt: f32) {
+        let healing_time = (dt - self.regen_cooldown).max(0.0);
+        self.regen_cooldown = (self.regen_cooldown - dt).max(0.0);
+        self.health = self.max.min(self.health + self.regen_rate * healing_time);
+    }
 }
@@ -2,33 +2,46 @@
 
 mod components;
 mod misc;
+mod prefab;
+mod schedule;
 mod systems;
 mod world_gen;
 
 use std::time::Instant;
 
-use application::UnitStage;
-use assman::components::DynamicModelRequest;
-use assman::data::AssetStorageInfo;
-use assman::systems::AssetManagerBuilderExtender;
-use assman::{AssetStore, GraphicsAssetManager};
-use cgmath::{InnerSpace, Vector2, Vector3, Zero};
-use components::{Player, PlayerCamera};
-use debug::DebugTimer;
-use entity_smith::{FrameTime, Smith};
+use assman::{AssetEntitySmith, AssetStore, GraphicsAssetManager};
+use cgmath::{Deg, InnerSpace, Vector2, Vector3, Zero};
+use components::{
+    CameraControl, DeathEvents, DeathSettings, MeleeAttack, Minimap, Player, PlayerCamera,
+};
+use debug::{DebugTimer, FrameStats};
+use entity_smith::{FrameClock, Smith};
 use graphics::canvas::{CanvasQueue, CanvasRenderPipeline};
-use graphics::components::{ActiveCamera, Camera, Target};
+use graphics::components::{
+    ActiveCamera, Camera, ClearColor, DirectionalLight, Fog, Projection, SpotLight,
+};
+use graphics::debug_lines::{DebugLineRenderPipeline, DebugLines};
+use graphics::fullscreen::Fullscreen;
+use graphics::gui::text::TextRenderer;
 use graphics::gui::GuiRenderPipeline;
 use graphics::models::{ModelQueue, ModelRenderPipeline};
-use graphics::systems::RenderBuilderExtender;
-use input::InputState;
-use physics::{PhysicsBuilderExtender, PhysicsEntitySmith};
-use transforms::{Parent, Scale, SphericalOffset, TransformBuilderExtender, TransformEntitySmith};
+use graphics::offscreen::OffscreenTarget;
+use graphics::picking::Picking;
+use graphics::post_process::{PostProcess, HDR_FORMAT};
+use graphics::sprites::SpriteBatch;
+use graphics::GraphicsEntitySmith;
+use input::{InputSource, InputState};
+use physics::PhysicsEntitySmith;
+use prefab::Prefabs;
+use transforms::{SpatialIndex, SphericalOffset, TransformEntitySmith};
 use winit::dpi::PhysicalSize;
 use winit::event::{Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 
-use crate::world_gen::components::{FloorNumber, MapTransition};
+use crate::systems::pathfinding::{Heuristic, WalkableTiles};
+use crate::world_gen::components::{
+    FactionRelations, FloorMap, FloorNumber, FogOfWar, MapTransition, SpawnPoints,
+};
 
 async fn run_async() {
     // world_gen::wfc::test();
@@ -53,7 +66,15 @@ async fn run_async() {
     let window = builder.build(&event_loop).unwrap();
 
     // Graphics Initialization
-    let mut graphics_context = graphics::GraphicsContext::new(&window).await;
+    let mut graphics_context =
+        match graphics::GraphicsContext::new(&window, Default::default()).await {
+            Ok(graphics_context) => graphics_context,
+            Err(e) => {
+                eprintln!("[deeper] (error): {}", e);
+                return;
+            }
+        };
+    display_settings.apply(&window, &mut graphics_context);
 
     let gui_context = graphics::gui::GuiRenderPipeline::new(&window, &graphics_context);
 
@@ -63,35 +84,53 @@ async fn run_async() {
         .load_assets_recursive(None);
 
     let color_texture_id = ass_man
-        .get_asset_storage_info("gradient_texture_extended.png")
-        .map(|f| match f {
-            AssetStorageInfo::Texture(storage_info) => storage_info.unwrap(),
-            _ => panic!(),
-        })
-        .unwrap()
-        .id;
-
-    let model_render_pipeline =
-        ModelRenderPipeline::new(&graphics_context, &graphics_resources, color_texture_id);
+        .get_texture_index("gradient_texture_extended.png")
+        .unwrap();
+    let font_atlas_id = ass_man.get_texture_index("font_atlas.png").unwrap();
+
+    let mut model_render_pipeline = ModelRenderPipeline::new(
+        &graphics_context,
+        &graphics_resources,
+        color_texture_id,
+        None,
+        display_settings.sample_count,
+        display_settings.shadow_map_size,
+    );
+    // The scene is drawn in HDR and tonemapped into the frame
+    let post_process = PostProcess::new(&graphics_context, &graphics_resources);
+    let depth_format = model_render_pipeline.depth_format();
+    model_render_pipeline.reconfigure(
+        &graphics_context.device,
+        HDR_FORMAT,
+        depth_format,
+        display_settings.sample_count,
+    );
+    // Far off corridors fade into the dark like the background
+    model_render_pipeline.set_fog(
+        &graphics_context,
+        Fog {
+            color: Vector3::new(0.0, 0.0, 0.0),
+            start: 18.0,
+            end: 40.0,
+            density: 0.9,
+        },
+    );
+    // Gameplay picks the entity under the mouse through it on demand
+    let picking = Picking::new(
+        &graphics_context,
+        &graphics_resources,
+        &model_render_pipeline,
+    );
 
     let canvas_render_pipeline = CanvasRenderPipeline::new(&graphics_context, &graphics_resources);
+    let debug_line_pipeline = DebugLineRenderPipeline::new(&graphics_context, &graphics_resources);
+    let sprite_batch = SpriteBatch::new(&graphics_context, &graphics_resources);
+    let text_renderer = TextRenderer::new(font_atlas_id, &graphics_resources).unwrap();
 
     // ECS Initialization
     let mut ecs = {
         let mut builder = application::Application::builder();
-
-        builder.schedule_builders[UnitStage::StartFrame].add_assman_systems();
-
-        builder.schedule_builders[UnitStage::Logic]
-            .add_system(systems::player::player_system())
-            .add_system(systems::player::camera_control_system())
-            .add_system(world_gen::systems::dung_gen_system())
-            .add_system(systems::go_to_destination_system())
-            .add_physics_systems(&mut builder.world, &mut builder.resources)
-            .add_transform_systems();
-
-        builder.schedule_builders[UnitStage::Render].add_render_systems();
-
+        schedule::build_schedule(&mut builder);
         builder
     }
     .with_unit(misc::SnakeUnit)
@@ -100,24 +139,19 @@ async fn run_async() {
 
     let mut command_buffer = legion::systems::CommandBuffer::new(&ecs.world);
 
-    let player = command_buffer
-        .smith()
-        .name("Player")
-        .position(Vector3::unit_x())
-        .orientation(0.0)
-        .agent(5., 30.)
-        .velocity(Vector2::zero())
-        .dynamic_body(1.)
-        .circle_collider(0.3)
-        .get_entity();
+    let prefabs = Prefabs::load(ass_man.entities_path()).unwrap_or_else(|e| panic!("{}", e));
+
+    let player = prefabs
+        .spawn_prefab(&mut command_buffer, "player", Vector2::unit_x())
+        .unwrap();
 
     let player_model = command_buffer
         .smith()
         .name("Player model")
-        .any(Parent(player))
+        .child_of(player)
         .orientation(1.0)
-        .any(DynamicModelRequest::new("arissa.obj"))
-        .any(Scale(0.75))
+        .dynamic_model("arissa.obj")
+        .scale(0.75)
         .get_entity();
 
     for &dir in &[
@@ -129,24 +163,46 @@ async fn run_async() {
         command_buffer
             .smith()
             .position(dir.normalize())
-            .any(DynamicModelRequest::new("arissa.obj"))
-            .any(Scale(0.2))
+            .dynamic_model("arissa.obj")
+            .scale(0.2)
             .child_of(player_model);
     }
 
+    // A lantern held up in front, the model faces down its local -x
+    command_buffer
+        .smith()
+        .name("Player lantern")
+        .position(Vector3::new(-0.5, 0.0, 1.5))
+        .any(SpotLight {
+            direction: Vector3::new(-1.0, 0.0, -0.5),
+            cutoff_angle: Deg(35.0),
+            color: Vector3::new(1.0, 0.8, 0.5),
+            intensity: 2.0,
+            range: 8.0,
+        })
+        .child_of(player_model);
+
+    // Light from above, so rooms the lantern doesn't reach can still be made out.
+    // It is the first directional light, so it also casts the shadows.
+    command_buffer.smith().name("Sun").any(DirectionalLight {
+        direction: Vector3::new(0.3, 0.5, -1.0),
+        color: Vector3::new(0.9, 0.9, 1.0),
+        intensity: 2.5,
+    });
+
     let player_camera = command_buffer
         .smith()
         .name("The camera")
-        .any(Parent(player))
-        .any(Target { entity: player })
+        .child_of(player)
+        .target(player)
         .position(Vector3::zero())
         .velocity(Vector2::zero())
-        .any(Camera {
+        .camera(Camera {
             up: Vector3::unit_z(),
-            fov: 30.0,
+            projection: Projection::Perspective { fov: 30.0 },
             roaming: false,
         })
-        .any(SphericalOffset::camera_offset())
+        .spherical_offset(SphericalOffset::camera_offset())
         .get_entity();
 
     command_buffer.flush(&mut ecs.world, &mut ecs.resources);
@@ -161,10 +217,28 @@ async fn run_async() {
     ecs.resources.insert(PlayerCamera {
         entity: player_camera,
     });
+    // The cursor can leave a window on its way elsewhere, so only fullscreen scrolls at the edges
+    ecs.resources.insert(CameraControl {
+        edge_scroll: display_settings.fullscreen != Fullscreen::Windowed,
+        ..Default::default()
+    });
+
+    ecs.resources.insert(DeathEvents::default());
+    ecs.resources.insert(DeathSettings::default());
+    ecs.resources.insert(FactionRelations::default());
+    ecs.resources.insert(MeleeAttack::default());
 
     ecs.resources.insert(Instant::now());
+    ecs.resources.insert(FrameClock::default());
+    ecs.resources.insert(SpatialIndex::default());
     ecs.resources.insert(MapTransition::Deeper);
     ecs.resources.insert(FloorNumber(1));
+    ecs.resources.insert(SpawnPoints::default());
+    ecs.resources.insert(FogOfWar::default());
+    ecs.resources.insert(FloorMap::default());
+    ecs.resources.insert(Minimap::default());
+    ecs.resources.insert(Heuristic::default());
+    ecs.resources.insert(WalkableTiles::default());
 
     ecs.resources.insert(ass_man);
 
@@ -172,10 +246,20 @@ async fn run_async() {
     ecs.resources.insert(graphics_resources);
     ecs.resources.insert(gui_context);
     ecs.resources.insert(window);
+    ecs.resources.insert(display_settings);
+    ecs.resources.insert(ClearColor::default());
+    ecs.resources.insert(FrameStats::new());
+    ecs.resources.insert(input_source_from_args());
     ecs.resources.insert(ModelQueue::new());
     ecs.resources.insert(CanvasQueue::new());
     ecs.resources.insert(canvas_render_pipeline);
+    ecs.resources.insert(DebugLines::new());
+    ecs.resources.insert(debug_line_pipeline);
+    ecs.resources.insert(sprite_batch);
+    ecs.resources.insert(text_renderer);
     ecs.resources.insert(model_render_pipeline);
+    ecs.resources.insert(post_process);
+    ecs.resources.insert(picking);
 
     event_loop.run(move |event, _, control_flow| {
         let imgui_wants_input = {
@@ -195,8 +279,13 @@ async fn run_async() {
         match event {
             Event::MainEventsCleared => {
                 let frame_time = ecs.resources.get::<Instant>().unwrap().elapsed();
+                let frame_time = ecs
+                    .resources
+                    .get_mut::<FrameClock>()
+                    .unwrap()
+                    .tick(frame_time.as_secs_f32());
 
-                ecs.resources.insert(FrameTime(frame_time.as_secs_f32()));
+                ecs.resources.insert(frame_time);
                 ecs.resources.insert(Instant::now());
 
                 let mut debug_timer = DebugTimer::new();
@@ -211,15 +300,31 @@ async fn run_async() {
                     .prep_frame(&ecs.resources.get::<winit::window::Window>().unwrap());
 
                 ecs.execute_schedules();
+
+                if debug::FRAME_STATS {
+                    let frame = ecs.resources.get::<Instant>().unwrap().elapsed();
+                    ecs.resources
+                        .get_mut::<FrameStats>()
+                        .unwrap()
+                        .finish_frame(frame);
+                }
             }
             Event::WindowEvent {
                 event: WindowEvent::Resized(size),
                 ..
             } => {
-                ecs.resources
+                let mut graphics_context = ecs
+                    .resources
                     .get_mut::<graphics::GraphicsContext>()
-                    .unwrap()
-                    .resize(size);
+                    .unwrap();
+                graphics_context.resize(size);
+                // Offscreen targets covering the window follow along
+                if let Some(mut target) = ecs.resources.get_mut::<OffscreenTarget>() {
+                    target.resize(&graphics_context.device, size);
+                }
+                if let Some(mut post_process) = ecs.resources.get_mut::<PostProcess>() {
+                    post_process.resize(&graphics_context.device, size);
+                }
             }
             // note(Jökull): Can we make this more readable somehow?
             // It is not clear that these two events result in Exit
@@ -240,7 +345,8 @@ async fn run_async() {
                 ..
             } => *control_flow = ControlFlow::Exit,
             Event::WindowEvent { ref event, .. } => {
-                if !imgui_wants_input {
+                let uses_hardware = ecs.resources.get::<InputSource>().unwrap().uses_hardware();
+                if !imgui_wants_input && uses_hardware {
                     ecs.resources
                         .get_mut::<InputState>()
                         .unwrap()
@@ -254,4 +360,28 @@ async fn run_async() {
     });
 }
 
+/// `--record <path>` saves the input of every frame and `--replay <path>` plays it back
+fn input_source_from_args() -> InputSource {
+    let args = std::env::args().collect::<Vec<_>>();
+    let source = match args
+        .iter()
+        .position(|arg| arg == "--record" || arg == "--replay")
+    {
+        None => return InputSource::Hardware,
+        Some(i) => match (args[i].as_str(), args.get(i + 1)) {
+            ("--record", Some(path)) => InputSource::record_input(path.as_ref()),
+            ("--replay", Some(path)) => InputSource::replay_input(path.as_ref()),
+            (flag, _) => {
+                eprintln!("[deeper] (warning): {} needs a path", flag);
+                return InputSource::Hardware;
+            }
+        },
+    };
+
+    source.unwrap_or_else(|e| {
+        eprintln!("[deeper] (warning): Can't open the input recording: {}", e);
+        InputSource::Hardware
+    })
+}
+
 fn main() { futures::executor::block_on(run_async()); }
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use assman::AssetEntitySmith;
+use cgmath::{Vector2, Vector3};
+use entity_smith::Smith;
+use legion::systems::CommandBuffer;
+use legion::Entity;
+use physics::PhysicsEntitySmith;
+use serde::Deserialize;
+use transforms::TransformEntitySmith;
+
+use crate::components::GameEntitySmith;
+use crate::world_gen::components::Faction;
+
+/// One line of a prefab file, each one maps to a smith method
+#[derive(Deserialize)]
+pub enum PrefabComponent {
+    Name(String),
+    /// Offset from where the prefab is spawned
+    Position(f32, f32, f32),
+    Orientation(f32),
+    Scale(f32),
+    Agent {
+        speed: f32,
+        acceleration: f32,
+    },
+    Velocity,
+    DynamicBody(f32),
+    StaticBody,
+    CircleCollider(f32),
+    SquareCollider(f32),
+    /// A model by its asset name, loaded once the asset manager gets to it
+    Model(String),
+    HitPoints(f32),
+    RegeneratingHitPoints {
+        max: f32,
+        rate: f32,
+        delay: f32,
+    },
+    Faction(Faction),
+}
+
+/// Entities described in RON files instead of code, named after the file they came from
+#[derive(Default)]
+pub struct Prefabs {
+    prefabs: HashMap<String, Vec<PrefabComponent>>,
+}
+
+impl Prefabs {
+    /// Reads every `.ron` file in `dir`, a single bad file fails the whole lot
+    pub fn load(dir: &Path) -> Result<Prefabs, String> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| format!("Prefab folder {} could not be read: {}", dir.display(), e))?;
+
+        let mut prefabs = Prefabs::default();
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.extension() != Some("ron".as_ref()) {
+                continue;
+            }
+            let components = assman::read_ron::<Vec<PrefabComponent>>(&path)
+                .map_err(|e| format!("Prefab {} could not be read: {}", path.display(), e))?;
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            prefabs.prefabs.insert(name, components);
+        }
+        Ok(prefabs)
+    }
+
+    pub fn spawn_prefab(
+        &self,
+        command_buffer: &mut CommandBuffer,
+        name: &str,
+        position: Vector2<f32>,
+    ) -> Result<Entity, String> {
+        let components = self
+            .prefabs
+            .get(name)
+            .ok_or_else(|| format!("There is no prefab called {}", name))?;
+
+        let offset = components
+            .iter()
+            .filter_map(|component| match *component {
+                PrefabComponent::Position(x, y, z) => Some(Vector3::new(x, y, z)),
+                _ => None,
+            })
+            .sum::<Vector3<f32>>();
+
+        let mut smith = command_buffer.smith();
+        smith.position(position.extend(0.0) + offset);
+        for component in components {
+            match component {
+                PrefabComponent::Name(name) => smith.name(name),
+                PrefabComponent::Position(..) => &mut smith,
+                PrefabComponent::Orientation(degrees) => smith.orientation(*degrees),
+                PrefabComponent::Scale(scale) => smith.scale(*scale),
+                PrefabComponent::Agent {
+                    speed,
+                    acceleration,
+                } => smith.agent(*speed, *acceleration),
+                PrefabComponent::Velocity => smith.velocity_zero(),
+                PrefabComponent::DynamicBody(mass) => smith.dynamic_body(*mass),
+                PrefabComponent::StaticBody => smith.static_body(),
+                PrefabComponent::CircleCollider(radius) => smith.circle_collider(*radius),
+                PrefabComponent::SquareCollider(side) => smith.square_collider(*side),
+                PrefabComponent::Model(label) => smith.dynamic_model(label),
+                PrefabComponent::HitPoints(max) => smith.hit_points(*max),
+                PrefabComponent::RegeneratingHitPoints { max, rate, delay } => {
+                    smith.regenerating_hit_points(*max, *rate, *delay)
+                }
+                PrefabComponent::Faction(faction) => smith.faction(*faction),
+            };
+        }
+        Ok(smith.done())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assman::components::DynamicModelRequest;
+    use entity_smith::Name;
+    use legion::{EntityStore, Resources, World};
+    use physics::{Collider, Velocity};
+    use transforms::Position;
+
+    use super::*;
+    use crate::components::HitPoints;
+
+    fn prefab_dir(test: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("deeper_prefabs_{}", test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn prefabs_spawn_what_their_file_lists() {
+        let dir = prefab_dir(
+            "spawn",
+            &[
+                (
+                    "slime.ron",
+                    r#"[
+                        Name("Slime"),
+                        Position(0.0, 0.0, 0.5),
+                        Model("monstroman.obj"),
+                        HitPoints(12.0),
+                        Velocity,
+                        DynamicBody(1.0),
+                        CircleCollider(0.4),
+                        Faction(Enemies),
+                    ]"#,
+                ),
+                ("notes.txt", "not a prefab"),
+            ],
+        );
+        let prefabs = Prefabs::load(&dir).unwrap();
+
+        let mut world = World::default();
+        let mut command_buffer = CommandBuffer::new(&world);
+        let slime = prefabs
+            .spawn_prefab(&mut command_buffer, "slime", Vector2::new(3.0, 4.0))
+            .unwrap();
+        assert!(prefabs
+            .spawn_prefab(&mut command_buffer, "notes", Vector2::new(0.0, 0.0))
+            .is_err());
+        command_buffer.flush(&mut world, &mut Resources::default());
+
+        let entry = world.entry_ref(slime).unwrap();
+        assert_eq!(entry.get_component::<Name>().unwrap().to_string(), "Slime");
+        assert_eq!(
+            entry.get_component::<Position>().unwrap().0,
+            Vector3::new(3.0, 4.0, 0.5)
+        );
+        assert_eq!(entry.get_component::<HitPoints>().unwrap().max, 12.0);
+        assert!(*entry.get_component::<Faction>().unwrap() == Faction::Enemies);
+        assert!(entry.get_component::<DynamicModelRequest>().is_ok());
+        assert!(entry.get_component::<Velocity>().is_ok());
+        assert!(entry.get_component::<Collider>().is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_components_are_an_error() {
+        let dir = prefab_dir(
+            "unknown",
+            &[("wizard.ron", "[Name(\"Wizard\"), Fireball(3.0)]")],
+        );
+
+        let error = Prefabs::load(&dir).err().unwrap();
+        assert!(error.contains("wizard.ron"), "{}", error);
+        assert!(error.contains("Fireball"), "{}", error);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shipped_prefabs_load() {
+        let prefabs = Prefabs::load("entities".as_ref()).unwrap();
+        assert!(prefabs.prefabs.contains_key("player"));
+    }
+}
@@ -0,0 +1,380 @@
+// The order the game's systems run in each frame, kept in one place so it can be audited.
+//
+// Legion runs systems in the order they are added whenever their component and
+// resource accesses conflict, and defers every CommandBuffer until the next flush.
+// Each flush below is a barrier, named after what has to be visible past it.
+// The stages themselves run one after the other, so each one sees everything the
+// previous one did.
+
+use application::{ApplicationBuilder, UnitStage};
+use assman::systems::AssetManagerBuilderExtender;
+use debug::{FrameSection, FrameStats};
+use graphics::systems::RenderBuilderExtender;
+use legion::systems::Builder;
+use legion::{Resources, World};
+use physics::PhysicsBuilderExtender;
+use transforms::TransformBuilderExtender;
+
+use crate::world_gen::dung_gen::Rooms;
+use crate::{systems, world_gen};
+
+/// Adds the game's systems to every stage. Units added to the builder afterwards run their
+/// systems after these in each stage.
+pub fn build_schedule(builder: &mut ApplicationBuilder) {
+    // Models loaded since the last frame are uploaded before anything goes looking for them
+    builder.schedule_builders[UnitStage::StartFrame].add_assman_systems();
+
+    add_logic_systems(
+        &mut builder.schedule_builders[UnitStage::Logic],
+        &mut builder.world,
+        &mut builder.resources,
+    );
+
+    timed(
+        &mut builder.schedule_builders[UnitStage::Render],
+        FrameSection::Render,
+        |builder| {
+            // The camera follows where transforms put its target this frame, then lights are
+            // uploaded, models queued and everything drawn, all on the main thread
+            builder.add_render_systems();
+        },
+    );
+}
+
+/// Everything that runs in `UnitStage::Logic`
+fn add_logic_systems(builder: &mut Builder, world: &mut World, resources: &mut Resources) {
+    builder
+        // Player input, needs the GraphicsContext to pick things under the mouse
+        .add_system(systems::player::player_system())
+        .add_system(systems::player::player_attack_system())
+        .add_system(systems::player::camera_control_system())
+        .add_system(systems::player::frame_player_system())
+        .add_system(systems::physics_control_system())
+        // Barrier "input": destinations issued by clicks are attached
+        .flush()
+        // Touches the window, which some platforms only allow from the main thread
+        .add_thread_local(systems::display::toggle_fullscreen_system())
+        .add_system(systems::display::toggle_wireframe_system());
+
+    add_simulation_systems(builder, world, resources);
+
+    // Draws where everything ended up this frame
+    builder
+        .add_system(systems::debug_draw::debug_draw_system())
+        .add_system(systems::display::title_overlay_system())
+        .add_system(systems::minimap::minimap_system());
+}
+
+/// The part of the Logic stage that doesn't depend on a window, graphics or input
+fn add_simulation_systems(builder: &mut Builder, world: &mut World, resources: &mut Resources) {
+    builder
+        .add_system(world_gen::systems::level_transition_system())
+        .add_system(world_gen::systems::dung_gen_system(Box::new(Rooms)))
+        .add_system(world_gen::systems::spawn_enemies_system())
+        // Barrier "level": the old floor is gone, the new one, its enemies and the moved
+        // player exist before physics goes looking for bodies to create
+        .flush()
+        .add_system(world_gen::systems::fog_of_war_system());
+
+    timed(builder, FrameSection::Ai, |builder| {
+        builder
+            .add_system(systems::ai_targeting_system())
+            .add_system(systems::ai_follow_system())
+            // Barrier "ai": enemies have a Destination towards whoever they hunt
+            .flush();
+    });
+
+    builder
+        // Projectiles hurt whatever they ran into during the last step
+        .add_system(systems::projectile::projectile_hit_system())
+        .add_system(systems::projectile::lifetime_system())
+        // The dead stop hunting and lose their bodies, so physics removes their handles this
+        // frame and the entity can go by the next
+        .add_system(systems::hit_point_regen_system())
+        .add_system(systems::despawn_corpses_system())
+        // Walls in the way get walked around
+        .add_system(systems::pathfinding::intermediate_destination_system())
+        .add_system(systems::go_to_destination_system())
+        // Crowds spread out on top of the velocity towards their goal
+        .add_system(systems::separation_system())
+        // Barrier "movement": arrivals have dropped their Destination
+        .flush();
+
+    timed(builder, FrameSection::Physics, |builder| {
+        builder
+            // Creates bodies and colliders behind its own barriers, then steps the world
+            .add_physics_systems(world, resources)
+            // Barrier "physics": new body and collider handles are attached
+            .flush();
+    });
+
+    // Transforms last so rendering sees this frame's positions
+    builder.add_transform_systems();
+}
+
+/// Times the systems `add` puts in the schedule into the `FrameStats` resource.
+/// Each end of the section is a barrier, so with frame stats compiled out the systems
+/// are added as they are.
+fn timed(builder: &mut Builder, section: FrameSection, add: impl FnOnce(&mut Builder)) {
+    if !debug::FRAME_STATS {
+        add(builder);
+        return;
+    }
+
+    builder.add_thread_local_fn(move |_, resources| {
+        if let Some(mut stats) = resources.get_mut::<FrameStats>() {
+            stats.begin(section);
+        }
+    });
+    add(builder);
+    builder.add_thread_local_fn(move |_, resources| {
+        if let Some(mut stats) = resources.get_mut::<FrameStats>() {
+            stats.end(section);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{InnerSpace, Vector2, Vector3};
+    use entity_smith::{FrameTime, Smith};
+    use legion::{Entity, IntoQuery, Schedule};
+    use physics::{PhysicsEntitySmith, Velocity};
+    use transforms::{Position, SpatialIndex, TransformEntitySmith};
+
+    use super::*;
+    use crate::components::{
+        DeathEvents, DeathSettings, Destination, Died, GameEntitySmith, HitPoints, Player,
+    };
+    use crate::systems::pathfinding::{Heuristic, WalkableTiles};
+    use crate::systems::projectile::spawn_projectile;
+    use crate::world_gen::components::{
+        Faction, FactionRelations, FloorMap, FloorNumber, FogOfWar, MapTransition, SpawnPoints,
+        TileType,
+    };
+
+    struct TinyGame {
+        world: World,
+        resources: Resources,
+        schedule: Schedule,
+        player: Entity,
+    }
+
+    fn tiny_game() -> TinyGame {
+        let mut world = World::default();
+        let mut resources = Resources::default();
+
+        let mut builder = Schedule::builder();
+        add_simulation_systems(&mut builder, &mut world, &mut resources);
+        let schedule = builder.build();
+
+        let mut command_buffer = legion::systems::CommandBuffer::new(&world);
+        let player = command_buffer
+            .smith()
+            .position(Vector3::unit_x())
+            .orientation(0.0)
+            .agent(5., 30.)
+            .velocity(Vector2::new(0.0, 0.0))
+            .dynamic_body(1.)
+            .circle_collider(0.3)
+            .faction(Faction::Friends)
+            .get_entity();
+        command_buffer.flush(&mut world, &mut resources);
+
+        resources.insert(Player {
+            player,
+            model: player,
+        });
+        resources.insert(FrameTime(1.0 / 60.0));
+        resources.insert(MapTransition::None);
+        resources.insert(FloorNumber(1));
+        resources.insert(SpawnPoints::default());
+        resources.insert(FogOfWar::default());
+        resources.insert(FloorMap::default());
+        resources.insert(SpatialIndex::default());
+        resources.insert(Heuristic::default());
+        resources.insert(WalkableTiles::default());
+        resources.insert(DeathEvents::default());
+        resources.insert(DeathSettings::default());
+        resources.insert(FactionRelations::default());
+
+        TinyGame {
+            world,
+            resources,
+            schedule,
+            player,
+        }
+    }
+
+    impl TinyGame {
+        fn step(&mut self, frames: usize) {
+            for _ in 0..frames {
+                self.schedule.execute(&mut self.world, &mut self.resources);
+            }
+        }
+
+        fn player_position(&self) -> Vector3<f32> {
+            <&Position>::query()
+                .get(&self.world, self.player)
+                .unwrap()
+                .0
+        }
+    }
+
+    #[test]
+    fn descending_builds_a_floor_and_moves_the_player() {
+        let mut game = tiny_game();
+        game.resources.insert(MapTransition::Deeper);
+
+        game.step(3);
+
+        assert_eq!(game.resources.get::<FloorNumber>().unwrap().0, 2);
+        assert!(matches!(
+            *game.resources.get::<MapTransition>().unwrap(),
+            MapTransition::None
+        ));
+        assert!(<&TileType>::query().iter(&game.world).count() > 0);
+        assert_ne!(game.player_position(), Vector3::unit_x());
+        // The player starts on the floor agents path over
+        let walkable = game.resources.get::<WalkableTiles>().unwrap();
+        assert!(walkable.is_walkable(game.player_position().truncate()));
+    }
+
+    #[test]
+    fn agents_walk_towards_their_destination() {
+        let mut game = tiny_game();
+        let goal = Vector2::new(4.0, 0.0);
+
+        let mut command_buffer = legion::systems::CommandBuffer::new(&game.world);
+        command_buffer.forge(game.player).destination(goal);
+        command_buffer.flush(&mut game.world, &mut game.resources);
+
+        let start_distance = (game.player_position().truncate() - goal).magnitude();
+        game.step(30);
+        let end_distance = (game.player_position().truncate() - goal).magnitude();
+
+        assert!(end_distance < start_distance);
+        assert!(
+            <&Velocity>::query()
+                .get(&game.world, game.player)
+                .unwrap()
+                .0
+                .magnitude()
+                > 0.0
+        );
+    }
+
+    #[test]
+    fn the_dead_are_announced_and_removed() {
+        let mut game = tiny_game();
+        game.resources.insert(DeathSettings {
+            leave_corpse: false,
+            ..Default::default()
+        });
+
+        let mut command_buffer = legion::systems::CommandBuffer::new(&game.world);
+        let victim = command_buffer
+            .smith()
+            .position(Vector3::new(-3.0, 0.0, 0.0))
+            .orientation(0.0)
+            .velocity_zero()
+            .dynamic_body(1.)
+            .circle_collider(0.3)
+            .hit_points(0.0)
+            .get_entity();
+        command_buffer.flush(&mut game.world, &mut game.resources);
+
+        game.step(1);
+        assert_eq!(
+            game.resources.get::<DeathEvents>().unwrap().0,
+            vec![Died(victim)]
+        );
+        assert!(game.world.entry(victim).is_some());
+
+        game.step(1);
+        assert!(game.resources.get::<DeathEvents>().unwrap().0.is_empty());
+        assert!(game.world.entry(victim).is_none());
+    }
+
+    #[test]
+    fn projectiles_hurt_what_they_hit_and_fall_apart() {
+        let mut game = tiny_game();
+
+        let mut command_buffer = legion::systems::CommandBuffer::new(&game.world);
+        let target = command_buffer
+            .smith()
+            .position(Vector3::new(-3.0, 0.0, 0.0))
+            .orientation(0.0)
+            .velocity_zero()
+            .dynamic_body(1.)
+            .circle_collider(0.3)
+            .hit_points(10.0)
+            .get_entity();
+        let shot = spawn_projectile(
+            &mut command_buffer,
+            Vector2::new(-3.0, 3.0),
+            Vector2::new(0.0, -1.0),
+            10.0,
+            4.0,
+        );
+        let miss = spawn_projectile(
+            &mut command_buffer,
+            Vector2::new(3.0, 3.0),
+            Vector2::new(0.0, 1.0),
+            10.0,
+            4.0,
+        );
+        command_buffer.flush(&mut game.world, &mut game.resources);
+
+        game.step(60);
+        assert_eq!(
+            <&HitPoints>::query()
+                .get(&game.world, target)
+                .unwrap()
+                .health,
+            6.0
+        );
+        assert!(game.world.entry(shot).is_none());
+        assert!(game.world.entry(miss).is_some());
+
+        // Out of time, having hit nothing
+        game.step(150);
+        assert!(game.world.entry(miss).is_none());
+        assert!(game.world.entry(target).is_some());
+    }
+
+    #[test]
+    fn heavy_and_light_bodies_stop_at_their_goal() {
+        let mut game = tiny_game();
+
+        let mut command_buffer = legion::systems::CommandBuffer::new(&game.world);
+        let mut walker = |y: f32, mass: f32| {
+            command_buffer
+                .smith()
+                .position(Vector3::new(0.0, y, 0.0))
+                .orientation(0.0)
+                .agent(5., 30.)
+                .velocity_zero()
+                .dynamic_body(mass)
+                .circle_collider(0.3)
+                .destination(Vector2::new(4.0, y))
+                .get_entity()
+        };
+        let walkers = [(walker(5.0, 1.0), 5.0), (walker(-5.0, 10.0), -5.0)];
+        command_buffer.flush(&mut game.world, &mut game.resources);
+        // Long frames are where overshooting would show
+        game.resources.insert(FrameTime(1.0 / 15.0));
+
+        game.step(180);
+
+        for &(walker, y) in walkers.iter() {
+            let position = <&Position>::query().get(&game.world, walker).unwrap().0;
+            assert!(
+                (position.truncate() - Vector2::new(4.0, y)).magnitude() < 0.05,
+                "{:?}",
+                position
+            );
+            assert!(<&Destination>::query().get(&game.world, walker).is_err());
+        }
+    }
+}
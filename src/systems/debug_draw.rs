@@ -0,0 +1,60 @@
+use cgmath::Vector3;
+use graphics::debug_lines::DebugLines;
+use input::{Command, CommandManager};
+use legion::systems::ParallelRunnable;
+use legion::{IntoQuery, SystemBuilder};
+use physics::{Collider, ColliderOffset};
+use transforms::Position;
+
+use crate::components::Destination;
+
+const COLLIDER_COLOR: Vector3<f32> = Vector3::new(0.0, 1.0, 0.0);
+const NEXT_HOP_COLOR: Vector3<f32> = Vector3::new(1.0, 1.0, 0.0);
+const GOAL_COLOR: Vector3<f32> = Vector3::new(1.0, 0.0, 0.0);
+
+/// Outlines colliders and where agents are headed while the colliders are toggled on.
+/// Square colliders are drawn unrotated, like the static bodies they're used for.
+pub fn debug_draw_system() -> impl ParallelRunnable {
+    SystemBuilder::new("debug_draw")
+        .read_component::<Position>()
+        .read_component::<Collider>()
+        .read_component::<ColliderOffset>()
+        .read_component::<Destination>()
+        .read_resource::<CommandManager>()
+        .write_resource::<DebugLines>()
+        .with_query(<(&Position, &Collider, Option<&ColliderOffset>)>::query())
+        .with_query(<(&Position, &Destination)>::query())
+        .build(
+            move |_, world, (command_manager, debug_lines), (colliders, destinations)| {
+                if !command_manager.get(Command::DebugToggleColliders) {
+                    return;
+                }
+
+                for (position, collider, offset) in colliders.iter(world) {
+                    let center = position.0
+                        + offset.map_or(Vector3::new(0.0, 0.0, 0.0), |offset| offset.0.extend(0.0));
+                    match *collider {
+                        Collider::Circle { radius } => {
+                            debug_lines.push_circle(center, radius, COLLIDER_COLOR)
+                        }
+                        Collider::Square { side_length } => {
+                            let half = Vector3::new(side_length, side_length, 0.0) / 2.0;
+                            debug_lines.push_aabb(center - half, center + half, COLLIDER_COLOR)
+                        }
+                    }
+                }
+
+                for (position, destination) in destinations.iter(world) {
+                    let goal = destination.goal.extend(position.0.z);
+                    match destination.next {
+                        Some(next) => {
+                            let next = next.extend(position.0.z);
+                            debug_lines.push_line(position.0, next, NEXT_HOP_COLOR);
+                            debug_lines.push_line(next, goal, GOAL_COLOR);
+                        }
+                        None => debug_lines.push_line(position.0, goal, GOAL_COLOR),
+                    }
+                }
+            },
+        )
+}
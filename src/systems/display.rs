@@ -0,0 +1,101 @@
+use assman::data::DisplaySettings;
+use assman::AssetStore;
+use cgmath::{Vector2, Vector4};
+use entity_smith::FrameClock;
+use graphics::fullscreen::Fullscreen;
+use graphics::gui::text::TextRenderer;
+use graphics::models::ModelRenderPipeline;
+use graphics::sprites::SpriteBatch;
+use graphics::GraphicsContext;
+use input::{Command, CommandManager};
+use legion::systems::Runnable;
+use legion::SystemBuilder;
+use winit::window::Window;
+
+/// Switches between windowed and the fullscreen mode from the display settings,
+/// saving the mode it switched to
+pub fn toggle_fullscreen_system() -> impl Runnable {
+    SystemBuilder::new("toggle_fullscreen")
+        .read_resource::<CommandManager>()
+        .read_resource::<AssetStore>()
+        .write_resource::<DisplaySettings>()
+        .write_resource::<GraphicsContext>()
+        .read_resource::<Window>()
+        .build(
+            move |_,
+                  _,
+                  (command_manager, ass_man, display_settings, graphics_context, window),
+                  _| {
+                if command_manager.get(Command::ToggleFullscreen) {
+                    display_settings.fullscreen =
+                        toggled(window.fullscreen().is_some(), display_settings.fullscreen);
+                    display_settings.apply(window, graphics_context);
+                    ass_man.save_display_settings(display_settings);
+                }
+            },
+        )
+}
+
+/// Flips the models between filled in and wireframe
+pub fn toggle_wireframe_system() -> impl Runnable {
+    SystemBuilder::new("toggle_wireframe")
+        .read_resource::<CommandManager>()
+        .read_resource::<GraphicsContext>()
+        .write_resource::<ModelRenderPipeline>()
+        .build(
+            move |_, _, (command_manager, graphics_context, model_render_pipeline), _| {
+                if command_manager.get(Command::DebugToggleWireframe) {
+                    let wireframe = !model_render_pipeline.wireframe();
+                    model_render_pipeline.set_wireframe(&graphics_context.device, wireframe);
+                }
+            },
+        )
+}
+
+/// The game's name and frame rate in the top left corner
+pub fn title_overlay_system() -> impl Runnable {
+    SystemBuilder::new("title_overlay")
+        .read_resource::<TextRenderer>()
+        .read_resource::<FrameClock>()
+        .write_resource::<SpriteBatch>()
+        .build(move |_, _, (text_renderer, frame_clock, sprite_batch), _| {
+            let fps = match frame_clock.smoothed() {
+                delta if delta > 0.0 => 1.0 / delta,
+                _ => 0.0,
+            };
+
+            text_renderer.draw(
+                sprite_batch,
+                &format!("deeper\n{:.0} fps", fps),
+                Vector2::new(8.0, 8.0),
+                20.0,
+                Vector4::new(1.0, 1.0, 1.0, 1.0),
+            );
+        })
+}
+
+/// Settings that ask for a window still get to go borderless when toggled
+fn toggled(is_fullscreen: bool, preferred: Fullscreen) -> Fullscreen {
+    match preferred {
+        _ if is_fullscreen => Fullscreen::Windowed,
+        Fullscreen::Windowed => Fullscreen::Borderless,
+        preferred => preferred,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toggling_goes_back_and_forth() {
+        let exclusive = Fullscreen::Exclusive {
+            resolution: (1920, 1080),
+        };
+
+        assert_eq!(toggled(false, exclusive), exclusive);
+        assert_eq!(toggled(true, exclusive), Fullscreen::Windowed);
+        assert_eq!(toggled(false, Fullscreen::Windowed), Fullscreen::Borderless);
+        assert_eq!(toggled(true, Fullscreen::Windowed), Fullscreen::Windowed);
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use cgmath::{Vector2, Vector4};
+use graphics::sprites::{Sprite, SpriteBatch};
+use graphics::GraphicsContext;
+use input::{Command, CommandManager};
+use itertools::Itertools;
+use legion::systems::Runnable;
+use legion::{EntityStore, SystemBuilder};
+use transforms::Position;
+
+use crate::components::{Minimap, Player};
+use crate::world_gen::components::{FloorMap, FogOfWar, TileType, Visibility};
+
+const BACKGROUND: [f32; 4] = [0.0, 0.0, 0.0, 0.6];
+const FLOOR_COLOR: [f32; 4] = [0.45, 0.45, 0.5, 1.0];
+const WALL_COLOR: [f32; 4] = [0.85, 0.85, 0.85, 1.0];
+const LADDER_COLOR: [f32; 4] = [0.35, 0.75, 0.35, 1.0];
+const PLAYER_COLOR: [f32; 4] = [0.95, 0.8, 0.2, 1.0];
+/// Tiles the player has seen but can't see right now are drawn this much darker
+const SEEN_BRIGHTNESS: f32 = 0.5;
+/// How many tiles across the player marker is
+const PLAYER_MARKER_SIZE: f32 = 1.5;
+
+/// The explored part of the floor in the top right corner of the window
+pub fn minimap_system() -> impl Runnable {
+    SystemBuilder::new("minimap")
+        .read_component::<Position>()
+        .read_resource::<CommandManager>()
+        .read_resource::<Minimap>()
+        .read_resource::<FloorMap>()
+        .read_resource::<FogOfWar>()
+        .read_resource::<GraphicsContext>()
+        .read_resource::<Player>()
+        .write_resource::<SpriteBatch>()
+        .build(
+            move |_,
+                  world,
+                  (
+                command_manager,
+                minimap,
+                floor_map,
+                fog,
+                graphics_context,
+                player,
+                sprite_batch,
+            ),
+                  _| {
+                if !command_manager.get(Command::ToggleMinimap) {
+                    return;
+                }
+
+                let player_pos = world
+                    .entry_ref(player.player)
+                    .ok()
+                    .and_then(|e| e.into_component::<Position>().ok())
+                    .map(|pos| pos.xy());
+                let window_size = Vector2::new(
+                    graphics_context.window_size.width as f32,
+                    graphics_context.window_size.height as f32,
+                );
+
+                for sprite in minimap_sprites(minimap, &floor_map.0, fog, player_pos, window_size) {
+                    sprite_batch.draw(sprite);
+                }
+            },
+        )
+}
+
+/// A background for the whole floor, a square for every tile the player has seen
+/// and a marker where the player is
+fn minimap_sprites(
+    minimap: &Minimap,
+    tiles: &HashMap<(i32, i32), TileType>,
+    fog: &FogOfWar,
+    player_pos: Option<Vector2<f32>>,
+    window_size: Vector2<f32>,
+) -> Vec<Sprite> {
+    let (min_x, max_x) = match tiles.keys().map(|&(x, _)| x).minmax().into_option() {
+        Some(range) => range,
+        None => return vec![],
+    };
+    let (min_y, max_y) = tiles
+        .keys()
+        .map(|&(_, y)| y)
+        .minmax()
+        .into_option()
+        .unwrap();
+
+    let tile_size = minimap.tile_size;
+    let size = Vector2::new(
+        (max_x - min_x + 1) as f32 * tile_size,
+        (max_y - min_y + 1) as f32 * tile_size,
+    );
+    let top_left = Vector2::new(window_size.x - minimap.margin.x - size.x, minimap.margin.y);
+    // North is up on the map, tiles are centered on their coordinates
+    let on_map = |point: Vector2<f32>| {
+        top_left
+            + Vector2::new(point.x - min_x as f32 + 0.5, max_y as f32 - point.y + 0.5) * tile_size
+    };
+
+    let mut sprites = vec![Sprite::colored(top_left, size, BACKGROUND.into())];
+
+    for (&(x, y), &tile) in tiles {
+        let color = match tile {
+            TileType::Floor | TileType::Path => FLOOR_COLOR,
+            TileType::Wall(_)
+            | TileType::CornerIn(_)
+            | TileType::CornerOut(_)
+            | TileType::UndirectedWall => WALL_COLOR,
+            TileType::LadderDown => LADDER_COLOR,
+            TileType::Nothing | TileType::Unknown => continue,
+        };
+        let brightness = match fog.get((x, y)) {
+            Visibility::Unseen => continue,
+            Visibility::Seen => SEEN_BRIGHTNESS,
+            Visibility::Visible => 1.0,
+        };
+
+        let [r, g, b, a] = color;
+        sprites.push(Sprite::colored(
+            on_map(Vector2::new(x as f32, y as f32)) - Vector2::new(0.5, 0.5) * tile_size,
+            Vector2::new(tile_size, tile_size),
+            Vector4::new(r * brightness, g * brightness, b * brightness, a),
+        ));
+    }
+
+    if let Some(pos) = player_pos {
+        let marker = (PLAYER_MARKER_SIZE * tile_size).max(3.0);
+        sprites.push(Sprite::colored(
+            on_map(pos) - Vector2::new(marker, marker) / 2.0,
+            Vector2::new(marker, marker),
+            PLAYER_COLOR.into(),
+        ));
+    }
+
+    sprites
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_seen_tiles_make_it_onto_the_minimap() {
+        let mut tiles = HashMap::new();
+        for x in 0..30 {
+            for y in 0..10 {
+                let tile = match (x, y) {
+                    (_, 0) | (_, 9) => TileType::UndirectedWall,
+                    (29, 5) => TileType::LadderDown,
+                    _ => TileType::Floor,
+                };
+                tiles.insert((x, y), tile);
+            }
+        }
+        tiles.insert((0, 5), TileType::Nothing);
+
+        let mut fog = FogOfWar::default();
+        fog.reset(tiles.keys().copied());
+        fog.reveal(Vector2::new(3.0, 5.0));
+        fog.reveal(Vector2::new(12.0, 5.0));
+
+        let minimap = Minimap {
+            margin: Vector2::new(10.0, 20.0),
+            tile_size: 2.0,
+        };
+        let window_size = Vector2::new(800.0, 600.0);
+        let sprites = minimap_sprites(
+            &minimap,
+            &tiles,
+            &fog,
+            Some(Vector2::new(12.0, 5.0)),
+            window_size,
+        );
+
+        // The background covers the floor in the top right corner
+        let background = sprites[0];
+        assert_eq!(background.size, Vector2::new(60.0, 20.0));
+        assert_eq!(background.position, Vector2::new(730.0, 20.0));
+
+        let seen = tiles
+            .iter()
+            .filter(|&(&tile, &tile_type)| {
+                tile_type != TileType::Nothing && fog.get(tile) != Visibility::Unseen
+            })
+            .count();
+        assert!(seen < tiles.len());
+        assert_eq!(sprites.len(), 1 + seen + 1);
+
+        // Tiles left behind are darker than the ones in sight
+        let color_at = |tile: Vector2<f32>| {
+            let corner = background.position + Vector2::new(tile.x, 9.0 - tile.y) * 2.0;
+            sprites[1..]
+                .iter()
+                .find(|sprite| sprite.position == corner)
+                .map(|sprite| sprite.tint)
+        };
+        assert!(color_at(Vector2::new(3.0, 5.0)).unwrap().x < FLOOR_COLOR[0]);
+        assert_eq!(color_at(Vector2::new(12.0, 4.0)).unwrap().x, FLOOR_COLOR[0]);
+        assert!(color_at(Vector2::new(29.0, 5.0)).is_none());
+
+        let player = sprites.last().unwrap();
+        assert_eq!(player.tint, PLAYER_COLOR.into());
+        assert!(sprites
+            .iter()
+            .all(|sprite| sprite.position.x >= background.position.x));
+    }
+}
@@ -1,16 +1,30 @@
+use std::collections::HashSet;
 use std::f32::consts::FRAC_PI_2;
 
 use cgmath::{InnerSpace, Vector2, Vector3};
 use entity_smith::{Acceleration, FrameTime, Speed};
+use graphics::components::{DynamicModel, Tint};
+use input::{Command, CommandManager};
+use legion::query::component;
 use legion::systems::{CommandBuffer, ParallelRunnable};
 use legion::world::SubWorld;
 use legion::{Entity, EntityStore, IntoQuery, SystemBuilder, TryWrite};
-use physics::Velocity;
-use transforms::{Position, Rotation};
+use physics::{Collider, PhysicsBody, PhysicsControl, PhysicsResource, Velocity};
+use transforms::{Position, Rotation, SpatialIndex};
 
-use crate::components::{AIFollow, Destination, HitPoints};
+use crate::components::{
+    AIFollow, Corpse, DeathEvents, DeathSettings, Destination, Died, HitPoints, Separation,
+};
+use crate::world_gen::components::{Faction, FactionRelations};
 
+const CORPSE_TINT: [f32; 4] = [0.3, 0.25, 0.25, 1.0];
+
+pub mod debug_draw;
+pub mod display;
+pub mod minimap;
+pub mod pathfinding;
 pub mod player;
+pub mod projectile;
 
 #[allow(dead_code)]
 pub(crate) fn order_tester(message: &'static str) -> impl ParallelRunnable {
@@ -19,66 +33,176 @@ pub(crate) fn order_tester(message: &'static str) -> impl ParallelRunnable {
     })
 }
 
-#[allow(dead_code)]
+/// Toggling logic off pauses the physics, stepping it then advances a frame at a time
+pub fn physics_control_system() -> impl ParallelRunnable {
+    SystemBuilder::new("physics_control")
+        .read_resource::<CommandManager>()
+        .write_resource::<PhysicsControl>()
+        .build(move |_, _, (command_manager, control), _| {
+            control.paused = !command_manager.get(Command::DebugToggleLogic);
+            control.single_step |= command_manager.get(Command::DebugStepLogic);
+        })
+}
+
 pub fn hit_point_regen_system() -> impl ParallelRunnable {
     SystemBuilder::new("hit_point_regen")
         .read_resource::<FrameTime>()
-        .with_query(<(::legion::Entity, ::legion::Write<HitPoints>)>::query())
-        .build(move |cmd, world, resources, query| {
-            let (mut for_query, mut world) = world.split_for_query(query);
-            let for_query = &mut for_query;
-            query.for_each_mut(for_query, |components| {
-                hit_point_regen(&mut world, cmd, &*resources, components.0, components.1);
+        .read_resource::<DeathSettings>()
+        .write_resource::<DeathEvents>()
+        .with_query(<(Entity, &mut HitPoints)>::query().filter(!component::<Corpse>()))
+        .build(move |cmd, world, (frame_time, settings, deaths), query| {
+            deaths.0.clear();
+            query.for_each_mut(world, |(ent, hp)| {
+                hit_point_regen(cmd, frame_time, settings, deaths, ent, hp);
             });
         })
 }
-#[allow(dead_code)]
+
 pub fn hit_point_regen(
-    _world: &mut SubWorld,
     commands: &mut CommandBuffer,
     frame_time: &FrameTime,
+    settings: &DeathSettings,
+    deaths: &mut DeathEvents,
     ent: &Entity,
     hp: &mut HitPoints,
 ) {
     if hp.health <= 0.0 {
-        commands.remove_component::<AIFollow>(*ent);
-        commands.remove_component::<Destination>(*ent);
+        die(commands, settings, *ent);
+        deaths.0.push(Died(*ent));
     } else {
-        hp.health += 0.7654321 * frame_time.0;
-        hp.health = hp.max.min(hp.health);
+        hp.regenerate(frame_time.0);
     }
 }
 
-#[allow(unused)]
-fn ai_follow_system() -> impl ParallelRunnable {
+fn die(commands: &mut CommandBuffer, settings: &DeathSettings, ent: Entity) {
+    commands.remove_component::<AIFollow>(ent);
+    commands.remove_component::<Destination>(ent);
+    // Physics cleans up the body and collider handles before the entity is removed
+    commands.remove_component::<PhysicsBody>(ent);
+    commands.remove_component::<Collider>(ent);
+
+    let despawn_in = if settings.leave_corpse {
+        commands.add_component(ent, Tint(CORPSE_TINT));
+        settings.despawn_delay
+    } else {
+        commands.remove_component::<DynamicModel>(ent);
+        0.0
+    };
+    commands.add_component(ent, Corpse { despawn_in });
+}
+
+/// Gets rid of an entity without leaving a corpse. Physics cleans up the body and collider
+/// handles first, then `despawn_corpses_system` removes the entity.
+fn despawn(commands: &mut CommandBuffer, ent: Entity) {
+    commands.remove_component::<PhysicsBody>(ent);
+    commands.remove_component::<Collider>(ent);
+    commands.remove_component::<DynamicModel>(ent);
+    commands.add_component(ent, Corpse { despawn_in: 0.0 });
+}
+
+/// Removes corpses once they have lain around for long enough
+pub fn despawn_corpses_system() -> impl ParallelRunnable {
+    SystemBuilder::new("despawn_corpses")
+        .read_resource::<FrameTime>()
+        .with_query(<(Entity, &mut Corpse)>::query())
+        .build(move |cmd, world, frame_time, query| {
+            query.for_each_mut(world, |(entity, corpse)| {
+                if corpse.despawn_in <= 0.0 {
+                    cmd.remove(*entity);
+                } else {
+                    corpse.despawn_in -= frame_time.0;
+                }
+            });
+        })
+}
+
+/// Followers with a faction hunt the nearest living entity their faction is hostile towards,
+/// and nobody once there is no one left to be hostile towards
+pub fn ai_targeting_system() -> impl ParallelRunnable {
+    SystemBuilder::new("ai_targeting")
+        .read_resource::<FactionRelations>()
+        .with_query(<(Entity, &Position, &Faction)>::query().filter(!component::<Corpse>()))
+        .with_query(
+            <(Entity, &Position, &Faction, &mut AIFollow)>::query().filter(!component::<Corpse>()),
+        )
+        .build(move |_, world, relations, (targets, followers)| {
+            let targets = targets
+                .iter(world)
+                .map(|(entity, pos, faction)| (*entity, pos.xy(), *faction))
+                .collect::<Vec<_>>();
+            followers.for_each_mut(world, |(ent, pos, faction, follow)| {
+                follow.target = targets
+                    .iter()
+                    .filter(|&&(target, _, other)| {
+                        target != *ent && relations.hostile(*faction, other)
+                    })
+                    .map(|&(target, target_pos, _)| (target, (target_pos - pos.xy()).magnitude2()))
+                    // Something physics flung to NaN can't be the nearest
+                    .filter(|(_, distance)| distance.is_finite())
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(target, _)| target);
+            });
+        })
+}
+
+pub fn ai_follow_system() -> impl ParallelRunnable {
     SystemBuilder::new("ai_follow")
-        .read_component::<AIFollow>()
+        .write_component::<AIFollow>()
         .read_component::<Position>()
         .write_component::<Destination>()
         .write_component::<Rotation>()
-        .build(move |cmd, world, _resources, _query| {
-            ai_follow(world, cmd);
+        .read_resource::<FrameTime>()
+        .read_resource::<PhysicsResource>()
+        .build(move |cmd, world, (frame_time, physics), _query| {
+            ai_follow(world, cmd, frame_time, physics);
         })
 }
 
-#[allow(dead_code)]
-fn ai_follow(world: &mut SubWorld, command: &mut CommandBuffer) {
-    let mut query = <(Entity, TryWrite<Rotation>, &AIFollow, &Position)>::query();
+/// Followers chase targets they can see within range, and where they last saw them for a
+/// while after losing sight of them. Walls block the view, other agents don't.
+fn ai_follow(
+    world: &mut SubWorld,
+    command: &mut CommandBuffer,
+    frame_time: &FrameTime,
+    physics: &PhysicsResource,
+) {
+    let mut query = <(Entity, TryWrite<Rotation>, &mut AIFollow, &Position)>::query();
     let (mut hunter_world, hunted_world) = world.split_for_query(&query);
     for (ent, orient, follow, hunter) in query.iter_mut(&mut hunter_world) {
-        if let Some(hunted) = hunted_world
-            .entry_ref(follow.target)
-            .ok()
-            .map(|e| e.into_component::<Position>().ok())
-            .flatten()
+        if let Some(hunted) = follow
+            .target
+            .and_then(|target| hunted_world.entry_ref(target).ok())
+            .and_then(|e| e.into_component::<Position>().ok())
         {
             let difference: Vector3<f32> = hunted.0 - hunter.0;
             let distance = difference.magnitude();
-            if distance > follow.minimum_distance {
-                command.add_component(*ent, Destination::simple(hunted.0.truncate()));
+            let goal = if distance > follow.maximum_distance {
+                follow.last_seen = None;
+                follow.home
+            } else if physics.line_of_sight(hunter.xy(), hunted.xy()) {
+                follow.last_seen = Some((hunted.xy(), follow.memory));
                 if let Some(orientation) = orient {
                     *orientation = Rotation::from(difference.angle(Vector3::unit_y()));
                 }
+                Some(hunted.xy())
+            } else {
+                match follow.last_seen {
+                    Some((last_seen, time_left)) if time_left > 0.0 => {
+                        follow.last_seen = Some((last_seen, time_left - frame_time.0));
+                        Some(last_seen)
+                    }
+                    _ => {
+                        follow.last_seen = None;
+                        follow.home
+                    }
+                }
+            };
+
+            // Unless it's close enough already
+            if let Some(goal) = goal {
+                if (goal - hunter.xy()).magnitude() > follow.minimum_distance {
+                    command.add_component(*ent, Destination::simple(goal));
+                }
             }
         }
     }
@@ -112,12 +236,18 @@ pub fn go_to_destination(
         &Acceleration,
     )>::query();
     for (ent, dest, hunter, vel, speed, accel) in query.iter_mut(world) {
-        let to_dest: Vector2<f32> = dest.goal - hunter.0.truncate();
+        let to_dest: Vector2<f32> = dest.goal - hunter.xy();
         if to_dest.magnitude() < EPSILON {
             commands.remove_component::<Destination>(*ent);
             vel.0 = Vector2::new(0.0, 0.0);
         } else {
-            let direction = to_dest.normalize();
+            // Slows down for the goal only, not for the turns on the way there
+            let direction = match dest.next {
+                Some(next) if next != hunter.xy() => (next - hunter.xy()).normalize(),
+                _ => to_dest.normalize(),
+            };
+            // Physics takes the velocity as is rather than as a force, so mass doesn't change
+            // how far a body goes while braking
             let time_to_stop = speed.0 / accel.0;
             let slowdown = FRAC_PI_2
                 .min(to_dest.magnitude() / time_to_stop * 0.5)
@@ -131,3 +261,291 @@ pub fn go_to_destination(
         }
     }
 }
+
+/// Pushes agents that are on their way somewhere away from their neighbours. The push fades
+/// as they slow down to arrive, so it doesn't keep them from reaching their goal.
+pub fn separation_system() -> impl ParallelRunnable {
+    SystemBuilder::new("separation")
+        .read_component::<Separation>()
+        .read_component::<Position>()
+        .read_component::<Speed>()
+        .read_component::<Destination>()
+        .write_component::<Velocity>()
+        .read_resource::<SpatialIndex>()
+        .read_resource::<FrameTime>()
+        .build(move |_, world, (index, frame_time), _| {
+            separation(world, index, frame_time);
+        })
+}
+
+fn separation(world: &mut SubWorld, index: &SpatialIndex, frame_time: &FrameTime) {
+    let separated = <(Entity, &Separation)>::query()
+        .iter(world)
+        .map(|(entity, _)| *entity)
+        .collect::<HashSet<_>>();
+
+    let mut query = <(Entity, &Separation, &Position, &Speed, &mut Velocity)>::query()
+        .filter(component::<Destination>());
+    for (ent, separation, position, speed, vel) in query.iter_mut(world) {
+        let position = position.xy();
+        let push: Vector2<f32> = index
+            .neighbours(position, separation.radius)
+            .filter(|(other, _)| other != ent && separated.contains(other))
+            .map(|(_, other)| position - other)
+            // Agents right on top of each other have no way to go
+            .filter(|away| away.magnitude2() > 0.0)
+            .map(|away| away.normalize() * (1.0 - away.magnitude() / separation.radius))
+            .sum();
+
+        let pace = (vel.0.magnitude() / speed.0).min(1.0);
+        vel.0 += push * separation.strength * pace * frame_time.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Zero;
+    use physics::PhysicsBuilderExtender;
+
+    use super::*;
+    use crate::world_gen::components::Relation;
+
+    struct Chase {
+        hunter: Vector3<f32>,
+        home: Option<Vector2<f32>>,
+        last_seen: Option<(Vector2<f32>, f32)>,
+        wall: Option<Vector2<f32>>,
+    }
+
+    impl Chase {
+        fn at(x: f32) -> Chase {
+            Chase {
+                hunter: Vector3::new(x, 0.0, 0.0),
+                home: None,
+                last_seen: None,
+                wall: None,
+            }
+        }
+
+        /// Where the hunter heads after a frame of chasing a target at the origin
+        fn goal(&self) -> Option<Vector2<f32>> {
+            let mut world = legion::World::default();
+            let mut resources = legion::Resources::default();
+            resources.insert(FrameTime(1.0 / 60.0));
+
+            let target = world.push((Position(Vector3::zero()),));
+            let hunter = world.push((
+                Position(self.hunter),
+                AIFollow {
+                    target: Some(target),
+                    minimum_distance: 1.0,
+                    maximum_distance: 5.0,
+                    home: self.home,
+                    memory: 2.0,
+                    last_seen: self.last_seen,
+                },
+            ));
+            if let Some(wall) = self.wall {
+                world.push((
+                    Position(wall.extend(0.0)),
+                    PhysicsBody::Static,
+                    Collider::Square { side_length: 1.0 },
+                ));
+            }
+
+            let mut builder = legion::Schedule::builder();
+            builder
+                .add_physics_systems(&mut world, &mut resources)
+                .flush()
+                .add_system(ai_follow_system());
+            builder.build().execute(&mut world, &mut resources);
+
+            <&Destination>::query()
+                .get(&world, hunter)
+                .ok()
+                .map(|destination| destination.goal)
+        }
+    }
+
+    #[test]
+    fn followers_only_chase_within_range() {
+        let home = Some(Vector2::new(10.0, 0.0));
+        assert_eq!(Chase::at(3.0).goal(), Some(Vector2::zero()));
+        assert_eq!(
+            Chase {
+                home,
+                ..Chase::at(8.0)
+            }
+            .goal(),
+            home
+        );
+        assert_eq!(Chase::at(8.0).goal(), None);
+        // Already home
+        assert_eq!(
+            Chase {
+                home,
+                ..Chase::at(10.0)
+            }
+            .goal(),
+            None
+        );
+    }
+
+    #[test]
+    fn walls_hide_the_target_for_a_while() {
+        let wall = Some(Vector2::new(2.0, 0.0));
+        let spotted = Vector2::new(0.0, 1.5);
+        assert_eq!(
+            Chase {
+                wall,
+                ..Chase::at(4.0)
+            }
+            .goal(),
+            None
+        );
+        assert_eq!(
+            Chase {
+                wall,
+                last_seen: Some((spotted, 1.0)),
+                ..Chase::at(4.0)
+            }
+            .goal(),
+            Some(spotted)
+        );
+
+        let home = Some(Vector2::new(4.0, 3.0));
+        assert_eq!(
+            Chase {
+                wall,
+                home,
+                last_seen: Some((spotted, 0.0)),
+                ..Chase::at(4.0)
+            }
+            .goal(),
+            home
+        );
+    }
+
+    #[test]
+    fn followers_hunt_the_nearest_hostile() {
+        let mut world = legion::World::default();
+        let mut resources = legion::Resources::default();
+        resources.insert(FactionRelations::default());
+
+        let follow = || AIFollow {
+            target: None,
+            minimum_distance: 1.0,
+            maximum_distance: 5.0,
+            home: None,
+            memory: 2.0,
+            last_seen: None,
+        };
+        let at = |x: f32| Position(Vector3::new(x, 0.0, 0.0));
+        let hunter = world.push((at(0.0), Faction::Enemies, follow()));
+        world.push((at(1.0), Faction::Enemies, follow()));
+        let frenemy = world.push((at(2.0), Faction::Frenemies));
+        let far = world.push((at(-6.0), Faction::Friends));
+        let near = world.push((at(4.0), Faction::Friends));
+        world.push((at(3.0), Faction::Friends, Corpse { despawn_in: 1.0 }));
+
+        let mut schedule = legion::Schedule::builder()
+            .add_system(ai_targeting_system())
+            .build();
+        let target =
+            |world: &legion::World| <&AIFollow>::query().get(world, hunter).unwrap().target;
+
+        schedule.execute(&mut world, &mut resources);
+        assert_eq!(target(&world), Some(near));
+
+        world.remove(near);
+        schedule.execute(&mut world, &mut resources);
+        assert_eq!(target(&world), Some(far));
+
+        // Old grudges settle and new ones start
+        resources.get_mut::<FactionRelations>().unwrap().set(
+            Faction::Enemies,
+            Faction::Friends,
+            Relation::Neutral,
+        );
+        schedule.execute(&mut world, &mut resources);
+        assert_eq!(target(&world), None);
+
+        resources.get_mut::<FactionRelations>().unwrap().set(
+            Faction::Frenemies,
+            Faction::Enemies,
+            Relation::Hostile,
+        );
+        schedule.execute(&mut world, &mut resources);
+        assert_eq!(target(&world), Some(frenemy));
+
+        world.push((at(f32::NAN), Faction::Frenemies));
+        schedule.execute(&mut world, &mut resources);
+        assert_eq!(target(&world), Some(frenemy));
+    }
+
+    #[test]
+    fn separation_spreads_out_moving_agents() {
+        let mut world = legion::World::default();
+        let mut resources = legion::Resources::default();
+        resources.insert(FrameTime(1.0 / 60.0));
+
+        let mut index = SpatialIndex::default();
+        let mut agent = |world: &mut legion::World, x: f32, destination: Option<Destination>| {
+            let entity = world.push((
+                Position(Vector3::new(x, 0.0, 0.0)),
+                Velocity(Vector2::new(0.0, 2.0)),
+                Speed(2.0),
+                Separation {
+                    radius: 1.0,
+                    strength: 10.0,
+                },
+            ));
+            if let Some(destination) = destination {
+                world.entry(entity).unwrap().add_component(destination);
+            }
+            index.insert(entity, Vector2::new(x, 0.0));
+            entity
+        };
+        let heading_up = || Some(Destination::simple(Vector2::new(0.0, 10.0)));
+        let left = agent(&mut world, -0.25, heading_up());
+        let right = agent(&mut world, 0.25, heading_up());
+        let idle = agent(&mut world, 0.5, None);
+        resources.insert(index);
+
+        let mut schedule = legion::Schedule::builder()
+            .add_system(separation_system())
+            .build();
+        schedule.execute(&mut world, &mut resources);
+
+        let velocity = |entity| <&Velocity>::query().get(&world, entity).unwrap().0;
+        assert!(velocity(left).x < 0.0);
+        assert!(velocity(right).x < 0.0);
+        assert!(velocity(left).x < velocity(right).x);
+        // Only agents on their way somewhere get pushed
+        assert_eq!(velocity(idle), Vector2::new(0.0, 2.0));
+    }
+
+    #[test]
+    fn damage_pauses_regeneration() {
+        let mut hp = HitPoints::new(10.0).with_regen(2.0, 1.0);
+        hp.damage(5.0);
+
+        hp.regenerate(0.5);
+        assert_eq!(hp.health, 5.0);
+
+        // Half of this second is still cooling down
+        hp.regenerate(1.0);
+        assert_eq!(hp.health, 6.0);
+
+        hp.regenerate(10.0);
+        assert_eq!(hp.health, 10.0);
+    }
+
+    #[test]
+    fn zero_rate_never_heals() {
+        let mut hp = HitPoints::new(10.0);
+        hp.damage(3.0);
+        hp.regenerate(100.0);
+        assert_eq!(hp.health, 7.0);
+    }
+}
@@ -0,0 +1,338 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use cgmath::{InnerSpace, Vector2};
+use legion::systems::ParallelRunnable;
+use legion::{Entity, IntoQuery, SystemBuilder};
+use transforms::Position;
+
+use crate::components::Destination;
+use crate::world_gen::components::TileType;
+
+type Tile = (i32, i32);
+
+/// How far from walls agents keep when walking in a straight line
+const CLEARANCE: f32 = 0.3;
+
+/// A* gives up after expanding this many tiles, the agent then walks straight at its goal
+const MAX_EXPANDED: usize = 4096;
+
+/// How A* estimates the distance left to the goal, a resource
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Heuristic {
+    /// Exact on the tile grid when nothing is in the way, so it expands the fewest tiles
+    #[default]
+    Manhattan,
+    /// The straight line distance, expands more tiles before finding the same path
+    #[allow(dead_code)]
+    Euclidean,
+}
+
+impl Heuristic {
+    fn estimate(self, from: Tile, to: Tile) -> f32 {
+        let (dx, dy) = ((to.0 - from.0) as f32, (to.1 - from.1) as f32);
+        match self {
+            Heuristic::Manhattan => dx.abs() + dy.abs(),
+            Heuristic::Euclidean => (dx * dx + dy * dy).sqrt(),
+        }
+    }
+}
+
+/// The floor of the dungeon, a resource filled in when a floor is generated.
+/// Tiles are a unit wide and centered on whole coordinates.
+#[derive(Default)]
+pub struct WalkableTiles(HashSet<Tile>);
+
+impl WalkableTiles {
+    pub fn new(tiles: impl IntoIterator<Item = (TileType, Vector2<f32>)>) -> Self {
+        WalkableTiles(
+            tiles
+                .into_iter()
+                .filter(|(tile_type, _)| {
+                    matches!(
+                        tile_type,
+                        TileType::Floor | TileType::Path | TileType::LadderDown
+                    )
+                })
+                .map(|(_, pos)| tile_at(pos))
+                .collect(),
+        )
+    }
+
+    pub fn is_walkable(&self, point: Vector2<f32>) -> bool { self.0.contains(&tile_at(point)) }
+
+    /// Whether something `CLEARANCE` wide can go straight from `from` to `to` on walkable tiles
+    pub fn line_of_sight(&self, from: Vector2<f32>, to: Vector2<f32>) -> bool {
+        const STEP: f32 = 0.1;
+
+        let along = to - from;
+        let length = along.magnitude();
+        if length < STEP {
+            return self.is_walkable(to);
+        }
+
+        let side = Vector2::new(-along.y, along.x) / length * CLEARANCE;
+        let steps = (length / STEP).ceil() as usize;
+        (0..=steps).all(|i| {
+            let point = from + along * (i as f32 / steps as f32);
+            self.is_walkable(point)
+                && self.is_walkable(point + side)
+                && self.is_walkable(point - side)
+        })
+    }
+
+    /// The shortest way over walkable tiles from the tile `from` is on to the one `to` is on,
+    /// both included. None if either isn't walkable or the goal is too far away.
+    pub fn find_path(
+        &self,
+        from: Vector2<f32>,
+        to: Vector2<f32>,
+        heuristic: Heuristic,
+    ) -> Option<Vec<Tile>> {
+        let (start, goal) = (tile_at(from), tile_at(to));
+        if !self.0.contains(&start) || !self.0.contains(&goal) {
+            return None;
+        }
+
+        let mut open = BinaryHeap::new();
+        let mut came_from = HashMap::new();
+        let mut costs = HashMap::new();
+
+        costs.insert(start, 0.0);
+        open.push(Open {
+            estimate: heuristic.estimate(start, goal),
+            cost: 0.0,
+            tile: start,
+        });
+
+        let mut expanded = 0;
+        while let Some(Open { cost, tile, .. }) = open.pop() {
+            if tile == goal {
+                let mut path = vec![goal];
+                while let Some(&previous) = came_from.get(path.last().unwrap()) {
+                    path.push(previous);
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            // A cheaper way to this tile was found after this one was queued
+            if cost > costs[&tile] {
+                continue;
+            }
+
+            expanded += 1;
+            if expanded > MAX_EXPANDED {
+                return None;
+            }
+
+            for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                let neighbour = (tile.0 + dx, tile.1 + dy);
+                if !self.0.contains(&neighbour) {
+                    continue;
+                }
+
+                let neighbour_cost = cost + 1.0;
+                if costs
+                    .get(&neighbour)
+                    .is_none_or(|&known| neighbour_cost < known)
+                {
+                    costs.insert(neighbour, neighbour_cost);
+                    came_from.insert(neighbour, tile);
+                    open.push(Open {
+                        estimate: neighbour_cost + heuristic.estimate(neighbour, goal),
+                        cost: neighbour_cost,
+                        tile: neighbour,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// The furthest point along the path that can be walked to in a straight line,
+    /// the center of the first tile if not even the next one can
+    pub fn next_hop(&self, from: Vector2<f32>, path: &[Tile]) -> Vector2<f32> {
+        let mut next = tile_center(path[0]);
+        for &tile in &path[1..] {
+            let center = tile_center(tile);
+            if !self.line_of_sight(from, center) {
+                break;
+            }
+            next = center;
+        }
+        next
+    }
+}
+
+fn tile_at(point: Vector2<f32>) -> Tile { (point.x.round() as i32, point.y.round() as i32) }
+
+fn tile_center(tile: Tile) -> Vector2<f32> { Vector2::new(tile.0 as f32, tile.1 as f32) }
+
+/// A tile waiting to be expanded, the heap pops the lowest estimate first
+struct Open {
+    estimate: f32,
+    cost: f32,
+    tile: Tile,
+}
+
+impl PartialEq for Open {
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
+}
+
+impl Eq for Open {}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .estimate
+            .partial_cmp(&self.estimate)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// The A* path an agent last searched for, only searched again once the agent is on another
+/// tile or its goal has moved
+pub struct Route {
+    from: Tile,
+    goal: Vector2<f32>,
+    path: Option<Vec<Tile>>,
+}
+
+impl Route {
+    fn plan(
+        walkable: &WalkableTiles,
+        position: Vector2<f32>,
+        goal: Vector2<f32>,
+        heuristic: Heuristic,
+    ) -> Route {
+        Route {
+            from: tile_at(position),
+            goal,
+            path: walkable.find_path(position, goal, heuristic),
+        }
+    }
+
+    fn leads(&self, position: Vector2<f32>, goal: Vector2<f32>) -> bool {
+        self.from == tile_at(position) && self.goal == goal
+    }
+
+    fn next_hop(&self, walkable: &WalkableTiles, position: Vector2<f32>) -> Option<Vector2<f32>> {
+        self.path
+            .as_ref()
+            .map(|path| walkable.next_hop(position, path))
+    }
+}
+
+/// Heads agents whose goal is out of sight for the furthest point along an A* path they can
+/// walk straight to, and straight at the goal again once it comes into sight
+pub fn intermediate_destination_system() -> impl ParallelRunnable {
+    SystemBuilder::new("intermediate_destination")
+        .read_component::<Position>()
+        .write_component::<Destination>()
+        .write_component::<Route>()
+        .read_resource::<WalkableTiles>()
+        .read_resource::<Heuristic>()
+        .with_query(<(Entity, &Position, &mut Destination, Option<&mut Route>)>::query())
+        .build(move |cmd, world, (walkable, heuristic), agents| {
+            if walkable.0.is_empty() {
+                return;
+            }
+
+            for (entity, position, destination, route) in agents.iter_mut(world) {
+                let position = position.xy();
+                if walkable.line_of_sight(position, destination.goal) {
+                    destination.next = None;
+                    continue;
+                }
+
+                match route {
+                    Some(route) => {
+                        if !route.leads(position, destination.goal) {
+                            *route = Route::plan(walkable, position, destination.goal, **heuristic);
+                        }
+                        destination.next = route.next_hop(walkable, position);
+                    }
+                    None => {
+                        let route = Route::plan(walkable, position, destination.goal, **heuristic);
+                        destination.next = route.next_hop(walkable, position);
+                        cmd.add_component(*entity, route);
+                    }
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `#` is a wall, anything else is floor. The first row is y = 0.
+    fn tiles(map: &str) -> WalkableTiles {
+        WalkableTiles::new(map.lines().enumerate().flat_map(|(y, row)| {
+            row.chars().enumerate().map(move |(x, c)| {
+                let tile_type = match c {
+                    '#' => TileType::UndirectedWall,
+                    _ => TileType::Floor,
+                };
+                (tile_type, Vector2::new(x as f32, y as f32))
+            })
+        }))
+    }
+
+    const ROOMS: &str = "\
+.....
+####.
+.....";
+
+    #[test]
+    fn paths_go_around_walls() {
+        let walkable = tiles(ROOMS);
+        let (from, to) = (Vector2::new(0.0, 0.0), Vector2::new(0.0, 2.0));
+
+        assert!(!walkable.line_of_sight(from, to));
+        for heuristic in [Heuristic::Manhattan, Heuristic::Euclidean] {
+            let path = walkable.find_path(from, to, heuristic).unwrap();
+            assert_eq!(path.len(), 11);
+            assert_eq!(path[0], (0, 0));
+            assert!(path.contains(&(4, 1)));
+            assert_eq!(*path.last().unwrap(), (0, 2));
+        }
+
+        assert_eq!(
+            walkable.find_path(from, Vector2::new(1.0, 1.0), Heuristic::Manhattan),
+            None
+        );
+    }
+
+    #[test]
+    fn routes_are_searched_again_on_another_tile_or_goal() {
+        let walkable = tiles(ROOMS);
+        let (from, to) = (Vector2::new(0.0, 0.0), Vector2::new(0.0, 2.0));
+        let route = Route::plan(&walkable, from, to, Heuristic::Manhattan);
+
+        assert!(route.leads(Vector2::new(0.3, -0.2), to));
+        assert!(!route.leads(Vector2::new(1.0, 0.0), to));
+        assert!(!route.leads(from, Vector2::new(1.0, 2.0)));
+        assert_eq!(
+            route.next_hop(&walkable, from),
+            Some(Vector2::new(4.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn next_hop_is_the_furthest_visible_tile() {
+        let walkable = tiles(ROOMS);
+        let from = Vector2::new(0.0, 0.0);
+        let path = walkable
+            .find_path(from, Vector2::new(0.0, 2.0), Heuristic::Manhattan)
+            .unwrap();
+
+        assert_eq!(walkable.next_hop(from, &path), Vector2::new(4.0, 0.0));
+        assert!(walkable.line_of_sight(from, Vector2::new(4.0, 0.0)));
+    }
+}
@@ -1,18 +1,29 @@
 use std::f32::consts::PI;
 
 use cgmath::num_traits::clamp;
-use cgmath::{Deg, InnerSpace, Vector2, Vector3};
-use entity_smith::Smith;
-use graphics::components::{Camera, Target};
+use cgmath::{Deg, InnerSpace, Rad, Vector2, Vector3, Zero};
+use entity_smith::{FrameTime, Smith};
+use graphics::components::{Camera, DynamicModel, Projection, Target};
+use graphics::data::Aabb;
 use input::{Command, CommandManager, InputState};
+use legion::query::component;
 use legion::systems::ParallelRunnable;
 use legion::world::SubWorld;
-use legion::{EntityStore, IntoQuery, SystemBuilder};
-use physics::Velocity;
+use legion::{Entity, EntityStore, IntoQuery, SystemBuilder};
+use physics::{Impulse, Velocity};
 use transforms::{Position, Rotation, SphericalOffset, Transform};
+use winit::dpi::PhysicalSize;
 
-use crate::components::{Destination, HitPoints, Player, PlayerCamera};
-use crate::world_gen::components::Faction;
+use crate::components::{
+    CameraControl, CameraFraming, Corpse, Destination, GameEntitySmith, HitPoints, MeleeAttack,
+    Player, PlayerCamera,
+};
+use crate::world_gen::components::{Faction, FactionRelations};
+
+const MINIMUM_RADIUS: f32 = 5.0;
+const MAXIMUM_RADIUS: f32 = 20.0;
+/// How much of the way to the framing radius the camera zooms each frame
+const FRAMING_EASE: f32 = 0.2;
 
 pub fn camera_control_system() -> impl ParallelRunnable {
     SystemBuilder::new("camera_control_system")
@@ -23,28 +34,39 @@ pub fn camera_control_system() -> impl ParallelRunnable {
         .read_component::<Position>()
         .read_component::<Target>()
         .read_component::<Transform>()
+        .read_component::<CameraFraming>()
         .read_resource::<CommandManager>()
         .read_resource::<InputState>()
         .read_resource::<PlayerCamera>()
+        .read_resource::<CameraControl>()
+        .read_resource::<FrameTime>()
+        .read_resource::<graphics::GraphicsContext>()
         .build(move |cmd, world, resources, _| {
-            camera_control(world, cmd, &resources.0, &resources.1, &resources.2);
+            let (command_manager, input, player_cam, control, frame_time, context) = resources;
+            camera_control(
+                world,
+                cmd,
+                command_manager,
+                input,
+                player_cam,
+                control,
+                frame_time,
+                context.window_size,
+            );
         })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn camera_control(
     world: &mut SubWorld,
-    _: &mut legion::systems::CommandBuffer,
+    commands: &mut legion::systems::CommandBuffer,
     command_manager: &CommandManager,
     input: &InputState,
     player_cam: &PlayerCamera,
+    control: &CameraControl,
+    frame_time: &FrameTime,
+    window_size: PhysicalSize<u32>,
 ) {
-    // Should these be a feature of the spherical offset?
-    const MINIMUM_PHI: f32 = 0.1 * PI;
-    const MAXIMUM_PHI: f32 = 0.3 * PI;
-
-    const MINIMUM_RADIUS: f32 = 5.0;
-    const MAXIMUM_RADIUS: f32 = 20.0;
-
     let (mut camera_world, mut world) = world.split::<&mut Camera>();
     let (mut offset_world, mut world) = world.split::<&mut SphericalOffset>();
     let (mut velocity_world, world) = world.split::<&mut Velocity>();
@@ -57,18 +79,30 @@ pub fn camera_control(
         .get_mut(&mut offset_world, player_cam.entity)
         .unwrap();
 
-    // Zoom controls
+    // Zoom controls, scrolling takes over from any framing in progress
+    if let Ok(framing) = <&CameraFraming>::query().get(&world, player_cam.entity) {
+        if input.mouse.scroll != 0.0 {
+            commands.remove_component::<CameraFraming>(player_cam.entity);
+        } else {
+            cam_offset.radius += (framing.radius - cam_offset.radius) * FRAMING_EASE;
+            if (framing.radius - cam_offset.radius).abs() < 0.01 {
+                cam_offset.radius = framing.radius;
+                commands.remove_component::<CameraFraming>(player_cam.entity);
+            }
+        }
+    }
     cam_offset.radius += -input.mouse.scroll * cam_offset.radius_delta;
     cam_offset.radius = clamp(cam_offset.radius, MINIMUM_RADIUS, MAXIMUM_RADIUS);
 
     cam_offset.phi = (cam_offset.radius - MINIMUM_RADIUS) / (MAXIMUM_RADIUS - MINIMUM_RADIUS)
-        * (MAXIMUM_PHI - MINIMUM_PHI)
-        + MINIMUM_PHI;
+        * (cam_offset.max_phi - cam_offset.min_phi)
+        + cam_offset.min_phi;
 
     // camera orbiting system enabled for now
     if command_manager.get(Command::PlayerOrbitCamera) {
         let mouse_delta = input.mouse.delta();
-        cam_offset.theta += cam_offset.theta_delta * mouse_delta.x;
+        let angle = cam_offset.theta_delta * mouse_delta.x;
+        cam_offset.orbit(angle);
     }
 
     if let Ok(cam_target_pos) = <&transforms::Transform>::query()
@@ -90,42 +124,187 @@ pub fn camera_control(
             //     .unwrap();
             // let cam_pos = cam_pos.0.extend(height.0.x);
 
-            let to_center: Vector3<f32> = (cam_target_pos - cam_pos).normalize() * 5.0;
-            let cam_front = to_center.truncate();
-            let cam_right = Vector2::new(to_center.y, -to_center.x);
+            let cam_front = (cam_target_pos - cam_pos).truncate().normalize();
+            let cam_right = Vector2::new(cam_front.y, -cam_front.x);
 
-            let mut new_velocity = Vector2::new(0.0, 0.0);
+            // x is rightwards and y is forwards, relative to the camera
+            let mut move_input = Vector2::new(0.0, 0.0);
 
             if command_manager.get(Command::PlayerCameraMoveUp) {
-                new_velocity += cam_front;
+                move_input.y += 1.0;
                 camera.roaming = true;
             }
             if command_manager.get(Command::PlayerCameraMoveLeft) {
-                new_velocity -= cam_right;
+                move_input.x -= 1.0;
                 camera.roaming = true;
             }
             if command_manager.get(Command::PlayerCameraMoveDown) {
-                new_velocity -= cam_front;
+                move_input.y -= 1.0;
                 camera.roaming = true;
             }
             if command_manager.get(Command::PlayerCameraMoveRight) {
-                new_velocity += cam_right;
+                move_input.x += 1.0;
+                camera.roaming = true;
+            }
+
+            let edge_input = match control.edge_scroll {
+                true => edge_scroll_input(input.mouse.pos, window_size, control.edge_thickness),
+                false => Vector2::zero(),
+            };
+            if !edge_input.is_zero() {
                 camera.roaming = true;
             }
 
+            let new_velocity = roaming_velocity(cam_front, cam_right, move_input, control.speed)
+                + roaming_velocity(cam_front, cam_right, edge_input, control.edge_speed);
+
             // Need to deal with removing the destination also
             if camera.roaming {
-                velocity_world
-                    .entry_mut(player_cam.entity)
-                    .unwrap()
-                    .get_component_mut::<Velocity>()
-                    .unwrap()
-                    .0 = new_velocity;
+                let mut entry = velocity_world.entry_mut(player_cam.entity).unwrap();
+                let velocity = &mut entry.get_component_mut::<Velocity>().unwrap().0;
+                *velocity = approach_velocity(*velocity, new_velocity, control, frame_time.0);
             }
         }
     }
 }
 
+/// Zooms the camera to fit the player's model when asked to
+pub fn frame_player_system() -> impl ParallelRunnable {
+    SystemBuilder::new("frame_player_system")
+        .read_component::<Camera>()
+        .read_component::<DynamicModel>()
+        .read_component::<Transform>()
+        .read_resource::<CommandManager>()
+        .read_resource::<graphics::GraphicsContext>()
+        .read_resource::<graphics::GraphicsResources>()
+        .read_resource::<Player>()
+        .read_resource::<PlayerCamera>()
+        .build(move |cmd, world, resources, _| {
+            let (command_manager, context, graphics_resources, player, player_cam) = resources;
+            if !command_manager.get(Command::PlayerFrameCamera) {
+                return;
+            }
+
+            let camera = <&Camera>::query().get(world, player_cam.entity);
+            let model = <(&DynamicModel, &Transform)>::query().get(world, player.model);
+            if let (Ok(camera), Ok((model, transform))) = (camera, model) {
+                if let Some(bounds) = graphics_resources.models.get(model.idx).map(|m| m.aabb) {
+                    let size = context.window_size;
+                    let aspect_ratio = size.width as f32 / size.height.max(1) as f32;
+                    frame_bounds(cmd, player_cam, camera, &bounds, transform, aspect_ratio);
+                }
+            }
+        })
+}
+
+/// How far the camera has to be from `bounds`, placed by `transform`, to fit all of it on
+/// screen, clamped to the zoom range. None for orthographic cameras, distance doesn't
+/// change what they see.
+pub fn framing_radius(
+    bounds: &Aabb,
+    transform: &Transform,
+    camera: &Camera,
+    aspect_ratio: f32,
+) -> Option<f32> {
+    let fov = match camera.projection {
+        Projection::Perspective { fov } => Rad::from(Deg(fov)).0,
+        Projection::Orthographic { .. } => return None,
+    };
+
+    // The narrower of the two directions is the one that has to fit
+    let half_vertical = fov / 2.0;
+    let half_horizontal = (half_vertical.tan() * aspect_ratio).atan();
+    let half_fov = half_vertical.min(half_horizontal);
+
+    let radius = bounds.transformed(transform.world_transform()).radius();
+    Some(clamp(
+        radius / half_fov.sin(),
+        MINIMUM_RADIUS,
+        MAXIMUM_RADIUS,
+    ))
+}
+
+/// Eases the player camera out or in over the next few frames so `bounds` fit on screen,
+/// see `framing_radius`
+pub fn frame_bounds(
+    commands: &mut legion::systems::CommandBuffer,
+    player_cam: &PlayerCamera,
+    camera: &Camera,
+    bounds: &Aabb,
+    transform: &Transform,
+    aspect_ratio: f32,
+) {
+    if let Some(radius) = framing_radius(bounds, transform, camera, aspect_ratio) {
+        commands.add_component(player_cam.entity, CameraFraming { radius });
+    }
+}
+
+/// Moves `velocity` toward `target` by at most what the camera can change in `dt`,
+/// accelerating while moving and damping once it's told to stop
+fn approach_velocity(
+    velocity: Vector2<f32>,
+    target: Vector2<f32>,
+    control: &CameraControl,
+    dt: f32,
+) -> Vector2<f32> {
+    let rate = match target.is_zero() {
+        true => control.damping,
+        false => control.acceleration,
+    };
+    let delta = target - velocity;
+    let step = rate * dt;
+
+    if delta.magnitude() <= step {
+        target
+    } else {
+        velocity + delta.normalize() * step
+    }
+}
+
+/// Which way the cursor pushes the camera when it's within `thickness` pixels of the window's
+/// edges, x being rightwards and y forwards like the movement keys. A cursor outside of the
+/// window doesn't push.
+fn edge_scroll_input(
+    mouse_pos: Vector2<f32>,
+    window_size: PhysicalSize<u32>,
+    thickness: f32,
+) -> Vector2<f32> {
+    let (width, height) = (window_size.width as f32, window_size.height as f32);
+    let inside = (0.0..width).contains(&mouse_pos.x) && (0.0..height).contains(&mouse_pos.y);
+    if !inside {
+        return Vector2::zero();
+    }
+
+    let push = |pos: f32, extent: f32| {
+        if pos < thickness {
+            -1.0
+        } else if pos >= extent - thickness {
+            1.0
+        } else {
+            0.0
+        }
+    };
+    // The screen's y goes down, away from what's in front of the camera
+    Vector2::new(push(mouse_pos.x, width), -push(mouse_pos.y, height))
+}
+
+/// Turns a camera relative movement input into a world velocity of at most `speed`.
+/// The input is clamped to unit length so that diagonals are not faster than cardinal
+/// directions, while partial (analog) inputs still give proportionally slower movement.
+fn roaming_velocity(
+    cam_front: Vector2<f32>,
+    cam_right: Vector2<f32>,
+    input: Vector2<f32>,
+    speed: f32,
+) -> Vector2<f32> {
+    let input = if input.magnitude2() > 1.0 {
+        input.normalize()
+    } else {
+        input
+    };
+    (cam_right * input.x + cam_front * input.y) * speed
+}
+
 pub fn player_system() -> impl ParallelRunnable {
     SystemBuilder::new("player_system")
         .write_component::<Rotation>()
@@ -134,8 +313,6 @@ pub fn player_system() -> impl ParallelRunnable {
         .read_component::<Position>()
         .read_component::<Transform>()
         .read_component::<Target>()
-        .read_component::<Faction>()
-        .read_component::<HitPoints>()
         .read_resource::<InputState>()
         .read_resource::<graphics::GraphicsContext>()
         .read_resource::<Player>()
@@ -192,16 +369,14 @@ pub fn player(
             .map(|trans| trans.world_position())
             .unwrap();
 
-        if let Some(mouse_world_pos) =
-            context.screen_to_world(mouse_pos, camera, camera_position, camera_target_pos)
+        if let Some((ray_origin, ray_direction)) =
+            context.screen_to_ray(mouse_pos, camera, camera_position, camera_target_pos)
         {
-            let ray_delta: Vector3<f32> = mouse_world_pos - camera_position;
-            let t: f32 = mouse_world_pos.z / ray_delta.z;
-            let ray_hit = (mouse_world_pos - ray_delta * t).truncate();
+            // Where the ray meets the ground
+            let t: f32 = ray_origin.z / ray_direction.z;
+            let ray_hit = (ray_origin - ray_direction * t).truncate();
 
-            commands
-                .forge(player.player)
-                .any(Destination::simple(ray_hit));
+            commands.forge(player.player).destination(ray_hit);
             camera.roaming = false;
 
             let difference: Vector2<f32> = {
@@ -224,15 +399,196 @@ pub fn player(
             }
         }
     }
+}
+
+/// Swings at everything hostile in front of the player model when the attack key is pressed,
+/// hurting it and knocking it back. Swings are `MeleeAttack::cooldown` apart at the least.
+pub fn player_attack_system() -> impl ParallelRunnable {
+    SystemBuilder::new("player_attack")
+        .read_component::<Position>()
+        .read_component::<Rotation>()
+        .read_component::<Faction>()
+        .write_component::<HitPoints>()
+        .read_resource::<CommandManager>()
+        .read_resource::<FactionRelations>()
+        .read_resource::<FrameTime>()
+        .read_resource::<Player>()
+        .write_resource::<MeleeAttack>()
+        .with_query(
+            <(Entity, &Position, &Faction, &mut HitPoints)>::query().filter(!component::<Corpse>()),
+        )
+        .build(
+            move |cmd, world, (command_manager, relations, frame_time, player, melee), query| {
+                melee.cooldown_left = (melee.cooldown_left - frame_time.0).max(0.0);
+                if !command_manager.get(Command::PlayerAttack) || melee.cooldown_left > 0.0 {
+                    return;
+                }
+
+                let attacker = world.entry_ref(player.player).ok().and_then(|entry| {
+                    let position = entry.get_component::<Position>().ok()?.xy();
+                    let faction = *entry.get_component::<Faction>().ok()?;
+                    Some((position, faction))
+                });
+                // The model faces down its local -x
+                let forward = world.entry_ref(player.model).ok().and_then(|entry| {
+                    let rotation = entry.get_component::<Rotation>().ok()?;
+                    Some((rotation.0 * -Vector3::unit_x()).truncate())
+                });
+                let ((position, faction), forward) = match (attacker, forward) {
+                    (Some(attacker), Some(forward)) => (attacker, forward),
+                    _ => return,
+                };
+
+                melee.cooldown_left = melee.cooldown;
+                for (ent, target, target_faction, hit_points) in query.iter_mut(world) {
+                    if *ent == player.player || !relations.hostile(faction, *target_faction) {
+                        continue;
+                    }
+                    if let Some(away) = melee.knockback_direction(position, forward, target.xy()) {
+                        hit_points.damage(melee.damage);
+                        cmd.add_component(*ent, Impulse(away * melee.knockback));
+                    }
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+
+    use super::*;
+
+    #[test]
+    fn roaming_velocity_is_direction_independent() {
+        let cam_front = Vector2::new(0.6, 0.8);
+        let cam_right = Vector2::new(cam_front.y, -cam_front.x);
+        let speed = CameraControl::default().speed;
+
+        for x in -1..=1 {
+            for y in -1..=1 {
+                let input = Vector2::new(x as f32, y as f32);
+                let velocity = roaming_velocity(cam_front, cam_right, input, speed);
+                if x == 0 && y == 0 {
+                    assert_eq!(velocity, Vector2::new(0.0, 0.0));
+                } else {
+                    assert!((velocity.magnitude() - speed).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roaming_velocity_keeps_partial_input() {
+        let speed = CameraControl::default().speed;
+        let velocity = roaming_velocity(
+            Vector2::unit_y(),
+            Vector2::unit_x(),
+            Vector2::new(0.0, 0.5),
+            speed,
+        );
+        assert!((velocity.magnitude() - 0.5 * speed).abs() < 1e-5);
+    }
+
+    #[test]
+    fn edges_push_the_camera_outwards() {
+        let size = PhysicalSize::new(800, 600);
+        let push = |x, y| edge_scroll_input(Vector2::new(x, y), size, 8.0);
+
+        assert_eq!(push(400.0, 300.0), Vector2::new(0.0, 0.0));
+        assert_eq!(push(2.0, 300.0), Vector2::new(-1.0, 0.0));
+        assert_eq!(push(795.0, 300.0), Vector2::new(1.0, 0.0));
+        assert_eq!(push(400.0, 0.0), Vector2::new(0.0, 1.0));
+        // Corners go diagonally, roaming_velocity keeps that from being faster
+        assert_eq!(push(799.0, 599.0), Vector2::new(1.0, -1.0));
+        assert_eq!(push(-5.0, 300.0), Vector2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn camera_speeds_up_and_slows_down_gradually() {
+        let control = CameraControl {
+            speed: 5.0,
+            acceleration: 10.0,
+            damping: 20.0,
+            ..Default::default()
+        };
+        let target = Vector2::new(control.speed, 0.0);
+        let dt = 0.1;
+
+        // 1 unit per second faster each frame until it's at full speed
+        let mut velocity = Vector2::zero();
+        for frame in 1..=5 {
+            velocity = approach_velocity(velocity, target, &control, dt);
+            assert!((velocity.x - frame as f32).abs() < 1e-5);
+        }
+        assert_eq!(approach_velocity(velocity, target, &control, dt), target);
+
+        // Stopping takes 2 units per second off each frame
+        velocity = approach_velocity(velocity, Vector2::zero(), &control, dt);
+        assert!((velocity.x - 3.0).abs() < 1e-5);
+        for _ in 0..2 {
+            velocity = approach_velocity(velocity, Vector2::zero(), &control, dt);
+        }
+        assert_eq!(velocity, Vector2::zero());
+    }
+
+    #[test]
+    fn framing_fits_the_narrower_field_of_view() {
+        let camera = Camera {
+            projection: Projection::Perspective { fov: 60.0 },
+            up: Vector3::unit_z(),
+            roaming: false,
+        };
+        let transform = Transform::identity();
+        let bounds = Aabb {
+            min: Vector3::new(-2.0, -2.0, -2.0),
+            max: Vector3::new(2.0, 2.0, 2.0),
+        };
+        let radius = 12f32.sqrt();
+
+        // Wide screens are limited by the 30 degrees above and below the center
+        let wide = framing_radius(&bounds, &transform, &camera, 2.0).unwrap();
+        assert!((wide - radius / 0.5).abs() < 1e-4);
+
+        // Tall ones by the sides
+        let tall = framing_radius(&bounds, &transform, &camera, 0.5).unwrap();
+        assert!(tall > wide);
+        assert!(tall <= MAXIMUM_RADIUS);
 
-    //if input.is_key_pressed(Key::Space) {
-    //    for (ent, pos, &HitPoints { max, health }, &faction, dynamic) in (&ents, &pos, &hp, &faction, &dynamic).join() {
-    //        let forward_vector = cgmath::Basis2::<f32>::from_angle(player_orient.0).rotate_vector(-Vector2::unit_x());
-    //        let in_front = (pos.0 - player_pos.0).normalize().dot(forward_vector.normalize()) > 0.5;
-    //        if faction == Faction::Enemies && pos.0.distance(player_pos.0) < 2.0 && in_front {
-    //            updater.insert(ent, HitPoints { max, health: (health - 1.0).max(0.0) });
-    //            updater.insert(ent, Velocity((pos.0 - player_pos.0).normalize() * 1.5 / dynamic.0));
-    //        }
-    //    }
-    //}
+        let tiny = Aabb {
+            min: Vector3::new(0.0, 0.0, 0.0),
+            max: Vector3::new(0.1, 0.1, 0.1),
+        };
+        assert_eq!(
+            framing_radius(&tiny, &transform, &camera, 1.0),
+            Some(MINIMUM_RADIUS)
+        );
+
+        let orthographic = Camera {
+            projection: Projection::Orthographic { height: 10.0 },
+            up: Vector3::unit_z(),
+            roaming: false,
+        };
+        assert_eq!(
+            framing_radius(&bounds, &transform, &orthographic, 1.0),
+            None
+        );
+    }
+
+    #[test]
+    fn swings_reach_what_is_close_and_in_front() {
+        let melee = MeleeAttack::default();
+        let attacker = Vector2::new(1.0, 1.0);
+        let forward = Vector2::new(-2.0, 0.0);
+        let hit = |x: f32, y: f32| melee.knockback_direction(attacker, forward, Vector2::new(x, y));
+
+        assert_eq!(hit(-0.5, 1.0), Some(Vector2::new(-1.0, 0.0)));
+        // Off to the side, but inside the arc
+        assert!(hit(0.0, 2.0).is_some());
+        // Too far, beside and behind
+        assert_eq!(hit(-1.5, 1.0), None);
+        assert_eq!(hit(1.0, 2.5), None);
+        assert_eq!(hit(2.0, 1.0), None);
+        assert_eq!(hit(1.0, 1.0), None);
+    }
 }
@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+
+use cgmath::{InnerSpace, Vector2};
+use entity_smith::{FrameTime, Smith};
+use legion::query::component;
+use legion::systems::{CommandBuffer, ParallelRunnable};
+use legion::{Entity, EntityStore, IntoQuery, SystemBuilder};
+use physics::{Ccd, CollisionEvent, CollisionEvents, PhysicsEntitySmith};
+use transforms::TransformEntitySmith;
+
+use super::despawn;
+use crate::components::{Corpse, HitPoints, Lifetime, Projectile};
+
+const PROJECTILE_RADIUS: f32 = 0.1;
+const PROJECTILE_MASS: f32 = 0.1;
+/// Seconds a projectile flies for before it falls apart without hitting anything
+const PROJECTILE_LIFETIME: f32 = 3.0;
+
+/// A small fast body flying from `origin` along `direction`. Start it clear of whoever fires
+/// it, otherwise it hits them first.
+#[allow(dead_code)]
+pub fn spawn_projectile(
+    command_buffer: &mut CommandBuffer,
+    origin: Vector2<f32>,
+    direction: Vector2<f32>,
+    speed: f32,
+    damage: f32,
+) -> Entity {
+    command_buffer
+        .smith()
+        .name("Projectile")
+        .pos(origin)
+        .orientation(0.0)
+        .velocity(direction.normalize() * speed)
+        .dynamic_body(PROJECTILE_MASS)
+        .circle_collider(PROJECTILE_RADIUS)
+        .any(Ccd)
+        .any(Projectile { damage })
+        .any(Lifetime {
+            remaining: PROJECTILE_LIFETIME,
+        })
+        .done()
+}
+
+/// Counts down every `Lifetime` and despawns what has run out
+pub fn lifetime_system() -> impl ParallelRunnable {
+    SystemBuilder::new("lifetime")
+        .read_resource::<FrameTime>()
+        .with_query(<(Entity, &mut Lifetime)>::query().filter(!component::<Corpse>()))
+        .build(move |cmd, world, frame_time, query| {
+            query.for_each_mut(world, |(entity, lifetime)| {
+                lifetime.remaining -= frame_time.0;
+                if lifetime.remaining <= 0.0 {
+                    despawn(cmd, *entity);
+                }
+            });
+        })
+}
+
+/// Projectiles that ran into something during the last physics step hurt it and despawn
+pub fn projectile_hit_system() -> impl ParallelRunnable {
+    SystemBuilder::new("projectile_hit")
+        .read_component::<Projectile>()
+        .write_component::<HitPoints>()
+        .read_resource::<CollisionEvents>()
+        .build(move |cmd, world, collisions, _| {
+            // Only the first thing a projectile runs into in a step gets hurt
+            let mut spent = HashSet::new();
+            for event in &collisions.0 {
+                let (a, b) = match *event {
+                    CollisionEvent::Started(a, b) => (a, b),
+                    CollisionEvent::Stopped(..) => continue,
+                };
+                for &(projectile, target) in &[(a, b), (b, a)] {
+                    let damage = match world
+                        .entry_ref(projectile)
+                        .ok()
+                        .and_then(|entry| entry.get_component::<Projectile>().ok().copied())
+                    {
+                        Some(Projectile { damage }) if spent.insert(projectile) => damage,
+                        _ => continue,
+                    };
+
+                    if let Ok(mut entry) = world.entry_mut(target) {
+                        if let Ok(hit_points) = entry.get_component_mut::<HitPoints>() {
+                            hit_points.damage(damage);
+                        }
+                    }
+                    despawn(cmd, projectile);
+                }
+            }
+        })
+}
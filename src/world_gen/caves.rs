@@ -0,0 +1,204 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::world_gen::components::TileType;
+use crate::world_gen::dung_gen::{
+    direct_walls, fill_with_nothing, spawn_points, DungGen, DungeonGenerator, DungeonMap,
+};
+
+/// Caves grown with a cellular automaton: the map starts out as random rock and
+/// gets smoothed until only blobby caverns are left.
+pub struct CaveGen {
+    // The chance each cell starts out as rock
+    pub fill: f64,
+    // How many times the automaton runs, more gives smoother caves
+    pub smoothing: usize,
+}
+
+impl Default for CaveGen {
+    fn default() -> Self {
+        CaveGen {
+            fill: 0.45,
+            smoothing: 5,
+        }
+    }
+}
+
+impl DungeonGenerator for CaveGen {
+    fn generate(&self, depth: i32, seed: u64) -> DungeonMap {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // Caves grow with depth the same way the rooms do
+        let size = DungGen::for_depth(depth);
+        let (width, height) = (size.width, size.height);
+        let inside = |x: i32, y: i32| x > 0 && y > 0 && x < width - 1 && y < height - 1;
+
+        // Step 1: Scatter rock at random, the edges are always rock
+        let mut open = HashSet::new();
+        for x in 0..width {
+            for y in 0..height {
+                if inside(x, y) && !rng.gen_bool(self.fill) {
+                    open.insert((x, y));
+                }
+            }
+        }
+
+        // Step 2: A cell becomes rock when most of the cells around it are
+        for _ in 0..self.smoothing {
+            open = (0..width)
+                .flat_map(|x| (0..height).map(move |y| (x, y)))
+                .filter(|&(x, y)| inside(x, y) && rock_around(&open, x, y) < 5)
+                .collect();
+        }
+        if open.is_empty() {
+            open.insert((width / 2, height / 2));
+        }
+
+        // Step 3: Keep the biggest cave so everything is reachable
+        let mut cells: Vec<_> = open.iter().copied().collect();
+        cells.sort_unstable();
+        let mut seen = HashSet::new();
+        let mut cave = vec![];
+        for cell in cells {
+            if seen.contains(&cell) {
+                continue;
+            }
+            let mut region: Vec<_> = distances(&open, cell).keys().copied().collect();
+            seen.extend(region.iter().copied());
+            if region.len() > cave.len() {
+                region.sort_unstable();
+                cave = region;
+            }
+        }
+        // A lone cell would put the ladder right under the player, so give it a neighbour
+        if cave.len() < 2 {
+            let (x, y) = cave[0];
+            let neighbour = [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)]
+                .iter()
+                .copied()
+                .find(|&(x, y)| inside(x, y))
+                .unwrap();
+            cave.push(neighbour);
+            cave.sort_unstable();
+        }
+
+        // Step 4: Paint the cave and wall it in
+        let mut world = HashMap::new();
+        for &loc in &cave {
+            world.insert(loc, TileType::Floor);
+        }
+        for &(x, y) in &cave {
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    world
+                        .entry((x + dx, y + dy))
+                        .or_insert(TileType::UndirectedWall);
+                }
+            }
+        }
+        direct_walls(&mut world);
+        fill_with_nothing(&mut world, width, height);
+
+        // Step 5: Start somewhere random and put the ladder as far away as it gets
+        let start = cave[rng.gen_range(0..cave.len())];
+        let exit = distances(&world_floor(&world), start)
+            .into_iter()
+            .max_by_key(|&(loc, distance)| (distance, Reverse(loc)))
+            .map(|(loc, _)| loc)
+            .unwrap_or(start);
+        world.insert(exit, TileType::LadderDown);
+
+        DungeonMap {
+            seed,
+            spawn_points: spawn_points(&world, Some(start)),
+            start,
+            exit,
+            world,
+        }
+    }
+}
+
+/// Rock in the 3x3 block around a cell, the cell included
+fn rock_around(open: &HashSet<(i32, i32)>, x: i32, y: i32) -> usize {
+    let mut rock = 0;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            if !open.contains(&(x + dx, y + dy)) {
+                rock += 1;
+            }
+        }
+    }
+    rock
+}
+
+fn world_floor(world: &HashMap<(i32, i32), TileType>) -> HashSet<(i32, i32)> {
+    world
+        .iter()
+        .filter(|(_, &tile)| tile == TileType::Floor)
+        .map(|(&loc, _)| loc)
+        .collect()
+}
+
+/// Steps it takes to walk from `from` to every open cell it can reach
+fn distances(open: &HashSet<(i32, i32)>, from: (i32, i32)) -> HashMap<(i32, i32), i32> {
+    let mut distances = HashMap::new();
+    let mut queue = VecDeque::new();
+    distances.insert(from, 0);
+    queue.push_back(from);
+
+    while let Some((x, y)) = queue.pop_front() {
+        let distance = distances[&(x, y)];
+        for &next in &[(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+            if open.contains(&next) && !distances.contains_key(&next) {
+                distances.insert(next, distance + 1);
+                queue.push_back(next);
+            }
+        }
+    }
+    distances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caves_are_connected_and_repeatable() {
+        for depth in 1..=6 {
+            let cave = CaveGen::default().generate(depth, 42 + depth as u64);
+
+            assert!(cave.world.get(&cave.exit) == Some(&TileType::LadderDown));
+            assert!(cave.world.get(&cave.start) == Some(&TileType::Floor));
+            assert_ne!(cave.start, cave.exit);
+            assert!(!cave.spawn_points.is_empty());
+
+            let mut walkable = world_floor(&cave.world);
+            walkable.insert(cave.exit);
+            assert_eq!(
+                distances(&walkable, cave.start).len(),
+                walkable.len(),
+                "every floor tile can be reached from the start"
+            );
+
+            let again = CaveGen::default().generate(depth, 42 + depth as u64);
+            assert!(cave.world == again.world);
+            assert_eq!(cave.spawn_points, again.spawn_points);
+        }
+    }
+
+    #[test]
+    fn solid_rock_still_leaves_room_to_walk_to_the_exit() {
+        let cave = CaveGen {
+            fill: 1.0,
+            ..Default::default()
+        }
+        .generate(1, 7);
+
+        assert!(cave.world.get(&cave.start) == Some(&TileType::Floor));
+        assert!(cave.world.get(&cave.exit) == Some(&TileType::LadderDown));
+        assert_ne!(cave.start, cave.exit);
+    }
+}
@@ -1,3 +1,9 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector2};
+use graphics::data::LocalUniforms;
+use serde::Deserialize;
+
 #[derive(Copy, Clone)]
 pub enum MapTransition {
     None,
@@ -6,10 +12,117 @@ pub enum MapTransition {
 
 pub struct MapSwitcher(pub MapTransition);
 
+/// Tiles of one chunk of the floor that look the same, merged into one model. Each such
+/// group is drawn by two of these, one for its `Visible` tiles and one for the `Seen`.
+/// Removed along with the floor.
+#[derive(Clone)]
+pub struct FloorMesh {
+    pub chunk: (i32, i32),
+    pub visibility: Visibility,
+    pub uniforms: LocalUniforms,
+    pub tiles: Vec<MeshTile>,
+}
+
+/// A tile, its model and the matrix putting the model in place
+pub type MeshTile = ((i32, i32), &'static str, [[f32; 4]; 4]);
+
+/// How much the player knows about a tile
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    Unseen,
+    Seen,
+    Visible,
+}
+
+/// How far the player can see
+const SIGHT_RADIUS: f32 = 7.0;
+
+/// What the player has found of the current floor, tile by tile. Tiles around the player
+/// are `Visible` and stay `Seen` once the player has moved away from them.
+pub struct FogOfWar {
+    pub radius: f32,
+    pub tiles: HashMap<(i32, i32), Visibility>,
+    // So revealing doesn't have to look at the whole floor
+    visible: Vec<(i32, i32)>,
+}
+
+impl Default for FogOfWar {
+    fn default() -> Self {
+        Self {
+            radius: SIGHT_RADIUS,
+            tiles: HashMap::new(),
+            visible: vec![],
+        }
+    }
+}
+
+impl FogOfWar {
+    /// Forgets everything for a new floor made of `tiles`
+    pub fn reset(&mut self, tiles: impl IntoIterator<Item = (i32, i32)>) {
+        self.tiles = tiles
+            .into_iter()
+            .map(|tile| (tile, Visibility::Unseen))
+            .collect();
+        self.visible.clear();
+    }
+
+    pub fn get(&self, tile: (i32, i32)) -> Visibility {
+        self.tiles.get(&tile).copied().unwrap_or(Visibility::Unseen)
+    }
+
+    /// Makes the tiles within `radius` of `center` visible and the ones left behind seen.
+    /// Returns every tile that changed.
+    pub fn reveal(&mut self, center: Vector2<f32>) -> Vec<(i32, i32)> {
+        let radius = self.radius;
+        let in_sight = |&(x, y): &(i32, i32)| {
+            (Vector2::new(x as f32, y as f32) - center).magnitude() <= radius
+        };
+
+        let (mut visible, left): (Vec<_>, Vec<_>) = self.visible.drain(..).partition(in_sight);
+        let mut changed = left;
+        for tile in &changed {
+            self.tiles.insert(*tile, Visibility::Seen);
+        }
+
+        let reach = radius.ceil() as i32;
+        let (cx, cy) = (center.x.round() as i32, center.y.round() as i32);
+        for x in cx - reach..=cx + reach {
+            for y in cy - reach..=cy + reach {
+                match self.tiles.get_mut(&(x, y)) {
+                    Some(visibility) if *visibility != Visibility::Visible && in_sight(&(x, y)) => {
+                        *visibility = Visibility::Visible;
+                        changed.push((x, y));
+                        visible.push((x, y));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.visible = visible;
+        changed
+    }
+}
+
+/// The tiles of the current floor, as the generator made them
+#[derive(Default)]
+pub struct FloorMap(pub HashMap<(i32, i32), TileType>);
+
+/// How deep the player currently is, generation scales with it
 pub struct FloorNumber(pub i32);
 
-#[derive(Eq, PartialEq)]
-#[derive(Copy, Clone)]
+/// Where enemies can appear on a freshly generated floor.
+/// `spawn_enemies_system` populates them once and empties the list.
+#[derive(Default)]
+pub struct SpawnPoints {
+    /// The dungeon's seed, so the same floor always gets the same enemies
+    pub seed: u64,
+    pub depth: i32,
+    pub points: Vec<(i32, i32)>,
+}
+
+#[derive(Eq, PartialEq, Hash, Debug)]
+#[derive(Copy, Clone, Deserialize)]
 #[allow(unused)]
 pub enum Faction {
     Enemies,
@@ -17,6 +130,47 @@ pub enum Faction {
     Frenemies,
 }
 
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Relation {
+    Friendly,
+    Neutral,
+    Hostile,
+}
+
+/// How the factions get along, a resource. Relations go both ways and can change mid game,
+/// followers go after whoever their faction is hostile towards.
+pub struct FactionRelations {
+    relations: HashMap<(Faction, Faction), Relation>,
+}
+
+impl FactionRelations {
+    /// Factions are friendly with themselves and neutral towards others unless told otherwise
+    pub fn get(&self, a: Faction, b: Faction) -> Relation {
+        match self.relations.get(&(a, b)) {
+            Some(&relation) => relation,
+            None if a == b => Relation::Friendly,
+            None => Relation::Neutral,
+        }
+    }
+
+    pub fn set(&mut self, a: Faction, b: Faction, relation: Relation) {
+        self.relations.insert((a, b), relation);
+        self.relations.insert((b, a), relation);
+    }
+
+    pub fn hostile(&self, a: Faction, b: Faction) -> bool { self.get(a, b) == Relation::Hostile }
+}
+
+impl Default for FactionRelations {
+    fn default() -> Self {
+        let mut relations = FactionRelations {
+            relations: HashMap::new(),
+        };
+        relations.set(Faction::Enemies, Faction::Friends, Relation::Hostile);
+        relations
+    }
+}
+
 #[derive(Eq, PartialEq, Copy, Clone)]
 #[allow(dead_code)]
 pub enum TileType {
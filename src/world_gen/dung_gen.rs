@@ -3,12 +3,50 @@ extern crate rand;
 
 use std::collections::HashMap;
 
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use self::ena::unify::{InPlace, UnificationTable, UnifyKey};
-use self::rand::thread_rng;
 use crate::world_gen::components::{Direction, TileType};
 
+/// Enemies don't spawn closer than this to where the player starts
+const START_CLEARANCE: f32 = 6.0;
+
+/// A generated floor, whichever algorithm made it
+pub struct DungeonMap {
+    pub seed: u64,
+    pub world: HashMap<(i32, i32), TileType>,
+    // Where the player is put
+    pub start: (i32, i32),
+    // Where the ladder down to the next floor was placed
+    #[allow(dead_code)]
+    pub exit: (i32, i32),
+    // Floor tiles enemies can be placed on, away from the start
+    pub spawn_points: Vec<(i32, i32)>,
+}
+
+/// Something that can lay out a floor, so `dung_gen_system` can swap algorithms.
+/// The same depth and seed should always give the same map.
+pub trait DungeonGenerator: Send + Sync {
+    fn generate(&self, depth: i32, seed: u64) -> DungeonMap;
+}
+
+/// Rooms connected by corridors, see `DungGen`
+pub struct Rooms;
+
+impl DungeonGenerator for Rooms {
+    fn generate(&self, depth: i32, seed: u64) -> DungeonMap {
+        let dungeon = DungGen::for_depth(depth).seed(seed).generate();
+        DungeonMap {
+            seed,
+            start: dungeon.start().unwrap_or(dungeon.exit),
+            exit: dungeon.exit,
+            spawn_points: dungeon.spawn_points,
+            world: dungeon.world,
+        }
+    }
+}
+
 /// usage:
 /// ```
 ///let dungeon = DungGen::new()
@@ -31,9 +69,17 @@ pub struct DungGen {
 
     pub n_rooms: usize,
 
+    // Everything random about the dungeon follows from this,
+    // so the same seed and settings give the same dungeon
+    pub seed: u64,
+
     // Used over the course of the algorithm,
     // made public to position player currently
     pub room_centers: Vec<(i32, i32)>,
+    // Where the ladder down to the next floor was placed
+    pub exit: (i32, i32),
+    // Floor tiles enemies can be placed on, sorted so they don't depend on HashMap order
+    pub spawn_points: Vec<(i32, i32)>,
     // The result of the algorithm is stored here
     pub world: HashMap<(i32, i32), TileType>,
 }
@@ -59,11 +105,31 @@ impl DungGen {
             room_min: 4,
             room_range: 11,
             n_rooms: 10,
+            seed: rand::thread_rng().gen(),
             room_centers: vec![],
+            exit: (0, 0),
+            spawn_points: vec![],
             world: HashMap::<(i32, i32), TileType>::new(),
         }
     }
 
+    /// Dungeons get wider and have more rooms the deeper you go
+    pub fn for_depth(depth: i32) -> DungGen {
+        let depth = depth.max(1);
+        DungGen::new()
+            .width((60 + 10 * depth).min(160))
+            .height((40 + 6 * depth).min(100))
+            .n_rooms((5 + depth as usize / 2).min(15))
+    }
+
+    /// A room center other than the exit, if there is one
+    pub fn start(&self) -> Option<(i32, i32)> {
+        self.room_centers
+            .iter()
+            .copied()
+            .find(|&center| center != self.exit)
+    }
+
     pub fn width(mut self, width: i32) -> DungGen {
         self.width = width;
         self
@@ -87,8 +153,13 @@ impl DungGen {
         self
     }
 
+    pub fn seed(mut self, seed: u64) -> DungGen {
+        self.seed = seed;
+        self
+    }
+
     pub fn generate(mut self) -> DungGen {
-        let mut rng = rand::thread_rng();
+        let mut rng = StdRng::seed_from_u64(self.seed);
 
         self.room_centers = Vec::<(i32, i32)>::new();
 
@@ -97,8 +168,14 @@ impl DungGen {
         // and are then surrounded afterwards by walls (might change).
         let world_edge_size = 1;
 
+        // Rooms are placed at random, so a crowded map could otherwise keep us here forever
+        const MAX_ATTEMPTS: usize = 10_000;
+        let mut attempts = 0;
+
         // n_rooms is 10 by default but should be set when constructing a room
-        while self.room_centers.len() < self.n_rooms {
+        while self.room_centers.len() < self.n_rooms && attempts < MAX_ATTEMPTS {
+            attempts += 1;
+
             // Step 1: Generate a random room in the world
 
             let x_min = rng.gen_range(
@@ -247,86 +324,17 @@ impl DungGen {
             comps.union(*keys.get(&r1).unwrap(), *keys.get(&r2).unwrap());
         }
 
-        // Determine the orientation of walls to assign the correct model and rotation
-        let mut directed_walls = vec![];
-
-        for (&(x, y), &wall_type) in self.world.iter() {
-            let loc = (x, y);
-            if let TileType::Wall(_) = wall_type {
-                let n = *self
-                    .world
-                    .get(&(x, y + 1))
-                    .unwrap_or(&TileType::UndirectedWall);
-                let w = *self
-                    .world
-                    .get(&(x - 1, y))
-                    .unwrap_or(&TileType::UndirectedWall);
-                let s = *self
-                    .world
-                    .get(&(x, y - 1))
-                    .unwrap_or(&TileType::UndirectedWall);
-                let e = *self
-                    .world
-                    .get(&(x + 1, y))
-                    .unwrap_or(&TileType::UndirectedWall);
-
-                let ne = *self
-                    .world
-                    .get(&(x + 1, y + 1))
-                    .unwrap_or(&TileType::UndirectedWall);
-                let nw = *self
-                    .world
-                    .get(&(x - 1, y + 1))
-                    .unwrap_or(&TileType::UndirectedWall);
-                let se = *self
-                    .world
-                    .get(&(x + 1, y - 1))
-                    .unwrap_or(&TileType::UndirectedWall);
-                let sw = *self
-                    .world
-                    .get(&(x - 1, y - 1))
-                    .unwrap_or(&TileType::UndirectedWall);
-
-                for &(a, b, c, d, e, f, typ) in [
-                    (s, e, n, w, ne, nw, TileType::Wall(Direction::North)),
-                    (e, n, w, s, sw, nw, TileType::Wall(Direction::West)),
-                    (n, w, s, e, se, sw, TileType::Wall(Direction::South)),
-                    (w, s, e, n, se, ne, TileType::Wall(Direction::East)),
-                ]
-                .iter()
-                {
-                    if (a == TileType::Floor || a == TileType::Path)
-                        && b == TileType::UndirectedWall
-                        && c == TileType::UndirectedWall
-                        && d == TileType::UndirectedWall
-                        && e == TileType::UndirectedWall
-                        && f == TileType::UndirectedWall
-                    {
-                        directed_walls.push((loc, typ));
-                    }
-                }
-            }
-        }
-
-        for (loc, typ) in directed_walls {
-            self.world.insert(loc, typ);
-        }
-
-        // Mark the rest of the world as consisting of nothing
-        for x in 0..self.width {
-            for y in 0..self.width {
-                if self.world.get(&(x, y)).is_none() {
-                    self.world.insert((x, y), TileType::Nothing);
-                }
-            }
-        }
+        direct_walls(&mut self.world);
+        fill_with_nothing(&mut self.world, self.width, self.height);
 
         // Step 4.5: make a thing
 
-        let mut rng = thread_rng();
         let ladder_loc = rng.gen_range(0..self.room_centers.len());
-        self.world
-            .insert(self.room_centers[ladder_loc], TileType::LadderDown);
+        self.exit = self.room_centers[ladder_loc];
+        self.world.insert(self.exit, TileType::LadderDown);
+
+        // Step 6: Find places for enemies that aren't right on top of the player
+        self.spawn_points = spawn_points(&self.world, self.start());
 
         self
     }
@@ -350,3 +358,148 @@ impl DungGen {
         self
     }
 }
+
+/// Determines the orientation of walls to assign the correct model and rotation
+pub fn direct_walls(world: &mut HashMap<(i32, i32), TileType>) {
+    let mut directed_walls = vec![];
+
+    for (&(x, y), &wall_type) in world.iter() {
+        let loc = (x, y);
+        if let TileType::Wall(_) = wall_type {
+            let n = *world.get(&(x, y + 1)).unwrap_or(&TileType::UndirectedWall);
+            let w = *world.get(&(x - 1, y)).unwrap_or(&TileType::UndirectedWall);
+            let s = *world.get(&(x, y - 1)).unwrap_or(&TileType::UndirectedWall);
+            let e = *world.get(&(x + 1, y)).unwrap_or(&TileType::UndirectedWall);
+
+            let ne = *world
+                .get(&(x + 1, y + 1))
+                .unwrap_or(&TileType::UndirectedWall);
+            let nw = *world
+                .get(&(x - 1, y + 1))
+                .unwrap_or(&TileType::UndirectedWall);
+            let se = *world
+                .get(&(x + 1, y - 1))
+                .unwrap_or(&TileType::UndirectedWall);
+            let sw = *world
+                .get(&(x - 1, y - 1))
+                .unwrap_or(&TileType::UndirectedWall);
+
+            for &(a, b, c, d, e, f, typ) in [
+                (s, e, n, w, ne, nw, TileType::Wall(Direction::North)),
+                (e, n, w, s, sw, nw, TileType::Wall(Direction::West)),
+                (n, w, s, e, se, sw, TileType::Wall(Direction::South)),
+                (w, s, e, n, se, ne, TileType::Wall(Direction::East)),
+            ]
+            .iter()
+            {
+                if (a == TileType::Floor || a == TileType::Path)
+                    && b == TileType::UndirectedWall
+                    && c == TileType::UndirectedWall
+                    && d == TileType::UndirectedWall
+                    && e == TileType::UndirectedWall
+                    && f == TileType::UndirectedWall
+                {
+                    directed_walls.push((loc, typ));
+                }
+            }
+        }
+    }
+
+    for (loc, typ) in directed_walls {
+        world.insert(loc, typ);
+    }
+}
+
+/// Marks the rest of the world as consisting of nothing
+pub fn fill_with_nothing(world: &mut HashMap<(i32, i32), TileType>, width: i32, height: i32) {
+    for x in 0..width {
+        for y in 0..height {
+            world.entry((x, y)).or_insert(TileType::Nothing);
+        }
+    }
+}
+
+/// Floor tiles enemies can be placed on that aren't right on top of the player,
+/// sorted so they don't depend on HashMap order
+pub fn spawn_points(
+    world: &HashMap<(i32, i32), TileType>,
+    start: Option<(i32, i32)>,
+) -> Vec<(i32, i32)> {
+    let far_from_start = |&(x, y): &(i32, i32)| match start {
+        Some((sx, sy)) => (((x - sx).pow(2) + (y - sy).pow(2)) as f32).sqrt() > START_CLEARANCE,
+        None => true,
+    };
+    let mut points: Vec<_> = world
+        .iter()
+        .filter(|(_, &tile)| tile == TileType::Floor)
+        .map(|(&loc, _)| loc)
+        .filter(far_from_start)
+        .collect();
+    points.sort_unstable();
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deeper_floors_are_bigger() {
+        let shallow = DungGen::for_depth(1);
+        let deep = DungGen::for_depth(10);
+
+        assert!(deep.width > shallow.width);
+        assert!(deep.height > shallow.height);
+        assert!(deep.n_rooms > shallow.n_rooms);
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_dungeon() {
+        let a = DungGen::for_depth(3).seed(1337).generate();
+        let b = DungGen::for_depth(3).seed(1337).generate();
+
+        assert!(a.world == b.world);
+        assert_eq!(a.room_centers, b.room_centers);
+        assert_eq!(a.exit, b.exit);
+        assert_eq!(a.spawn_points, b.spawn_points);
+        assert!(!a.spawn_points.is_empty());
+
+        let start = a.start().unwrap();
+        for &(x, y) in &a.spawn_points {
+            assert!(a.world.get(&(x, y)) == Some(&TileType::Floor));
+            let distance = (((x - start.0).pow(2) + (y - start.1).pow(2)) as f32).sqrt();
+            assert!(distance > START_CLEARANCE);
+        }
+    }
+
+    #[test]
+    fn rooms_are_repeatable_through_the_generator() {
+        let a = Rooms.generate(4, 2021);
+        let b = Rooms.generate(4, 2021);
+
+        assert_eq!(a.seed, 2021);
+        assert!(a.world == b.world);
+        assert_eq!((a.start, a.exit), (b.start, b.exit));
+
+        let other = Rooms.generate(4, 2022);
+        assert!(a.world != other.world);
+    }
+
+    #[test]
+    fn generated_floor_has_an_exit_apart_from_the_start() {
+        for depth in 1..=12 {
+            let dungeon = DungGen::for_depth(depth)
+                .seed(1000 + depth as u64)
+                .generate();
+
+            assert!(dungeon.world.get(&dungeon.exit) == Some(&TileType::LadderDown));
+
+            let start = dungeon.start().unwrap();
+            assert_ne!(start, dungeon.exit);
+            assert!(matches!(
+                dungeon.world.get(&start),
+                Some(TileType::Floor) | Some(TileType::Path)
+            ));
+        }
+    }
+}
@@ -1,5 +1,8 @@
+// Not in the schedule, swap it in for `Rooms` in schedule.rs to play in caves
+#[allow(dead_code)]
+pub mod caves;
 pub mod components;
-mod dung_gen;
+pub mod dung_gen;
 mod grid;
 pub mod systems;
 pub mod wfc;
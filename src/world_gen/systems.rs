@@ -1,27 +1,41 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use assman::components::{DynamicModelRequest, StaticModelRequest};
-use cgmath::{vec2, Vector2};
+use assman::components::MergedModelRequest;
+use assman::AssetEntitySmith;
+use cgmath::{vec2, InnerSpace, Matrix4, SquareMatrix, Vector2};
 use entity_smith::Smith;
-use graphics::data::LocalUniforms;
+use graphics::data::{LocalUniforms, NO_TINT};
 use legion::systems::{CommandBuffer, Runnable};
 use legion::world::SubWorld;
-use legion::{Entity, IntoQuery, SystemBuilder};
+use legion::{Entity, EntityStore, IntoQuery, SystemBuilder};
 use physics::PhysicsEntitySmith;
 use rand::prelude::*;
-use transforms::{Scale, TransformEntitySmith};
+use transforms::{Position, TransformEntitySmith};
 
-use crate::components::{HitPoints, Player};
+use crate::components::{AIFollow, GameEntitySmith, Player};
+use crate::systems::pathfinding::WalkableTiles;
 use crate::world_gen::components::{
-    Direction, Faction, FloorNumber, MapSwitcher, MapTransition, TileType,
+    Direction, Faction, FloorMap, FloorMesh, FloorNumber, FogOfWar, MapSwitcher, MapTransition,
+    SpawnPoints, TileType, Visibility,
 };
+use crate::world_gen::dung_gen::DungeonGenerator;
 
-pub fn dung_gen_system() -> impl Runnable {
+const LIGHT_GRAY: [f32; 4] = [0.78, 0.78, 0.78, 1.0];
+const DARK_GRAY: [f32; 4] = [0.31, 0.31, 0.31, 1.0];
+const LADDER_GREEN: [f32; 4] = [0.35, 0.75, 0.35, 1.0];
+
+/// Lays out a new floor with `generator` whenever the player goes deeper
+pub fn dung_gen_system(generator: Box<dyn DungeonGenerator>) -> impl Runnable {
     SystemBuilder::new("DungGen System")
         .read_component::<TileType>()
         .read_component::<Faction>()
+        .read_component::<FloorMesh>()
         .write_resource::<MapTransition>()
         .write_resource::<FloorNumber>()
+        .write_resource::<SpawnPoints>()
+        .write_resource::<FogOfWar>()
+        .write_resource::<FloorMap>()
+        .write_resource::<WalkableTiles>()
         .read_resource::<Player>()
         .build(move |command_buffer, world, resources, _| {
             dung_gen(
@@ -29,17 +43,58 @@ pub fn dung_gen_system() -> impl Runnable {
                 world,
                 &mut resources.0,
                 &mut resources.1,
-                &resources.2,
+                &mut resources.2,
+                &mut resources.3,
+                &mut resources.4,
+                &mut resources.5,
+                &resources.6,
+                &*generator,
             );
         })
 }
 
+/// How close the player has to get to a tile's center to use it
+const TRANSITION_RADIUS: f32 = 0.5;
+
+/// Starts a map transition once the player steps onto a tile with a MapSwitcher
+pub fn level_transition_system() -> impl Runnable {
+    SystemBuilder::new("level_transition")
+        .read_component::<Position>()
+        .read_component::<MapSwitcher>()
+        .write_resource::<MapTransition>()
+        .read_resource::<Player>()
+        .with_query(<(&MapSwitcher, &Position)>::query())
+        .build(move |_, world, (transition, player), query| {
+            let player_pos = match world
+                .entry_ref(player.player)
+                .ok()
+                .and_then(|e| e.into_component::<Position>().ok())
+            {
+                Some(pos) => pos.xy(),
+                None => return,
+            };
+
+            if let Some((switcher, _)) = query
+                .iter(world)
+                .find(|(_, pos)| (pos.xy() - player_pos).magnitude() < TRANSITION_RADIUS)
+            {
+                **transition = switcher.0;
+            }
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn dung_gen(
     command_buffer: &mut legion::systems::CommandBuffer,
     world: &mut SubWorld,
     transition: &mut MapTransition,
     floor: &mut FloorNumber,
+    spawn_points: &mut SpawnPoints,
+    fog: &mut FogOfWar,
+    floor_map: &mut FloorMap,
+    walkable: &mut WalkableTiles,
     player: &Player,
+    generator: &dyn DungeonGenerator,
 ) {
     #[allow(clippy::single_match)]
     match *transition {
@@ -48,6 +103,9 @@ pub fn dung_gen(
             for (entity, _) in <(Entity, &TileType)>::query().iter(world) {
                 command_buffer.remove(*entity);
             }
+            for (entity, _) in <(Entity, &FloorMesh)>::query().iter(world) {
+                command_buffer.remove(*entity);
+            }
 
             for (entity, faction) in <(Entity, &Faction)>::query().iter(world) {
                 if let Faction::Enemies = faction {
@@ -59,70 +117,14 @@ pub fn dung_gen(
 
             println!("You have reached floor {}", floor.0);
 
-            //let mut rng = thread_rng();
-
-            //let wfc_source = image::open("assets/Images/dungeon_5_separated.png").unwrap();
-            let wfc_source = image::open("maps/WFC.png").unwrap();
-
-            // use crate::world_gen::grid::Grid;
-            // use crate::world_gen::wfc::{wfc, SQUARE_NEIGHBOURHOOD};
-            // let wfc_result = wfc(
-            //     Grid::from(&wfc_source.into_rgb8()),
-            //     &SQUARE_NEIGHBOURHOOD,
-            //     Vector2::<usize>::from_value(64),
-            //     &[],
-            // );
-
-            // let wfc_result = image_patterns
-            //     .collapse_wave_retrying(
-            //         map_size,
-            //         wrap::WrapXY,
-            //         EmptyEdgesForbid {
-            //             empty_tile_id: top_left_corner_id,
-            //         },
-            //         retry::Forever,
-            //         &mut StdRng::from_entropy(),
-            //     )
-            //     .apply(|wave| image_patterns.image_from_wave(&wave));
-            let wfc_result = wfc_source;
-
-            let test_world = wfc_result
-                .into_bgr8()
-                .enumerate_pixels()
-                .map(|(x, y, pixel)| {
-                    ((x as i32, y as i32), {
-                        let [b, g, r] = pixel.0;
-                        let direction = match r {
-                            0 => Direction::North,
-                            64 => Direction::East,
-                            128 => Direction::South,
-                            192 => Direction::West,
-                            _ => Direction::North,
-                        };
-                        if b > 0 {
-                            match g {
-                                0 => TileType::Floor,
-                                64 => TileType::CornerIn(direction),
-                                128 => TileType::CornerOut(direction),
-                                192 => TileType::Wall(direction),
-                                _ => TileType::Unknown,
-                            }
-                        } else {
-                            TileType::Nothing
-                        }
-                    })
-                })
-                .collect::<HashMap<(i32, i32), TileType>>();
+            let dungeon = generator.generate(floor.0, rand::thread_rng().gen());
+            // So an interesting floor can be generated again
+            println!("Floor {} was generated from seed {}", floor.0, dungeon.seed);
 
-            populate_environment(command_buffer, &test_world);
+            populate_environment(command_buffer, &dungeon.world);
+            fog.reset(dungeon.world.keys().copied());
 
-            let player_start = test_world
-                .iter()
-                .filter(|&(_, &tile_type)| tile_type == TileType::Floor)
-                .nth(100)
-                //.choose(&mut rng)
-                .map(|((x, y), _)| vec2(*x as f32, *y as f32))
-                .unwrap();
+            let player_start = vec2(dungeon.start.0 as f32, dungeon.start.1 as f32);
 
             // Reset player position and stuff
             command_buffer
@@ -130,23 +132,47 @@ pub fn dung_gen(
                 .position(player_start.extend(0.))
                 .velocity_zero();
 
-            add_enemies(command_buffer, floor, &test_world);
+            *spawn_points = SpawnPoints {
+                seed: dungeon.seed,
+                depth: floor.0,
+                points: dungeon.spawn_points,
+            };
+            *walkable = WalkableTiles::new(
+                dungeon
+                    .world
+                    .iter()
+                    .map(|(&(x, y), &tile_type)| (tile_type, vec2(x as f32, y as f32))),
+            );
+            floor_map.0 = dungeon.world;
         }
         _ => {}
     }
     *transition = MapTransition::None;
 }
 
+/// How many tiles across the chunks are that a floor's models are merged in, so seeing
+/// more of the floor only rebuilds the chunks around the player
+const CHUNK_SIZE: i32 = 8;
+/// Tiles the player has seen but can't see right now are drawn this much darker
+const SEEN_BRIGHTNESS: f32 = 0.35;
+
+fn chunk_of((x, y): (i32, i32)) -> (i32, i32) {
+    (x.div_euclid(CHUNK_SIZE), y.div_euclid(CHUNK_SIZE))
+}
+
+/// Tiles that look the same are merged into one model per chunk, so a floor is drawn by a
+/// few hundred entities at most. They draw nothing until `fog_of_war_system` reveals tiles.
+/// Tiles other than Nothing still get an entity each for physics and pathfinding.
 fn populate_environment(
     command_buffer: &mut CommandBuffer,
     dungeon: &HashMap<(i32, i32), TileType>,
 ) {
+    let mut chunks: HashMap<(i32, i32), Vec<FloorMesh>> = HashMap::new();
+
     for (&(x, y), &tile_type) in dungeon.iter() {
         let pos = Vector2::new(x as f32, y as f32);
 
-        let mut smith = command_buffer.smith();
-
-        let thing = StaticModelRequest::new(
+        let (label, uniforms) = (
             match tile_type {
                 TileType::Nothing => "DevFloor.obj",
                 TileType::Wall(_) => "DevWall.obj",
@@ -186,22 +212,47 @@ fn populate_environment(
                     _ => 0.,
                 },
                 Default::default(),
-            ),
+            )
+            .with_tint(match tile_type {
+                TileType::Wall(_) | TileType::CornerIn(_) | TileType::CornerOut(_) => LIGHT_GRAY,
+                TileType::UndirectedWall => LIGHT_GRAY,
+                TileType::Floor | TileType::Path => DARK_GRAY,
+                TileType::LadderDown => LADDER_GREEN,
+                _ => NO_TINT,
+            }),
         );
 
-        smith.any(thing);
+        let chunk = chunk_of((x, y));
+        let looks = chunks.entry(chunk).or_default();
+        let tile = ((x, y), label, uniforms.model_matrix);
+        match looks
+            .iter_mut()
+            .find(|look| look.uniforms.similar_to(&uniforms))
+        {
+            Some(look) => look.tiles.push(tile),
+            None => looks.push(FloorMesh {
+                chunk,
+                visibility: Visibility::Visible,
+                uniforms: uniforms.with_model_matrix(Matrix4::identity().into()),
+                tiles: vec![tile],
+            }),
+        }
 
-        smith.any(tile_type);
-        match tile_type {
-            TileType::Nothing => {}
-            _ => {
-                smith.pos(pos);
-            }
+        // Nothing is only there to be looked at
+        if tile_type == TileType::Nothing {
+            continue;
         }
 
+        let mut smith = command_buffer.smith();
+        smith.any(tile_type);
+        smith.pos(pos);
+
         // tile specific behaviors
         match tile_type {
-            TileType::Wall(_) | TileType::CornerIn(_) | TileType::CornerOut(_) => {
+            TileType::Wall(_)
+            | TileType::CornerIn(_)
+            | TileType::CornerOut(_)
+            | TileType::UndirectedWall => {
                 smith.static_square_body(1.0);
             }
             TileType::LadderDown => {
@@ -210,48 +261,287 @@ fn populate_environment(
             _ => {}
         }
     }
+
+    for look in chunks.into_values().flatten() {
+        let seen = FloorMesh {
+            visibility: Visibility::Seen,
+            ..look.clone()
+        };
+        command_buffer.push((look,));
+        command_buffer.push((seen,));
+    }
 }
 
-fn add_enemies(
-    command_buffer: &mut CommandBuffer,
-    floor: &mut FloorNumber,
-    dungeon: &HashMap<(i32, i32), TileType>,
-) {
-    let mut rng = thread_rng();
+/// What a floor mesh should draw, given what the player has seen
+fn fog_request(mesh: &FloorMesh, fog: &FogOfWar) -> MergedModelRequest {
+    let uniforms = match mesh.visibility {
+        Visibility::Seen => {
+            let [r, g, b, a] = mesh.uniforms.tint;
+            let dim = SEEN_BRIGHTNESS;
+            mesh.uniforms.with_tint([r * dim, g * dim, b * dim, a])
+        }
+        _ => mesh.uniforms,
+    };
+
+    let mut request = MergedModelRequest::new(uniforms);
+    for &(tile, label, model_matrix) in &mesh.tiles {
+        if fog.get(tile) == mesh.visibility {
+            request.push(label, model_matrix);
+        }
+    }
+    request
+}
 
-    // Add enemies to floor
+/// Reveals the floor around the player and rebuilds the models of the chunks that changed
+pub fn fog_of_war_system() -> impl Runnable {
+    SystemBuilder::new("fog_of_war")
+        .read_component::<Position>()
+        .read_component::<FloorMesh>()
+        .write_resource::<FogOfWar>()
+        .read_resource::<Player>()
+        .with_query(<(Entity, &FloorMesh)>::query())
+        .build(move |command_buffer, world, (fog, player), query| {
+            let player_pos = match world
+                .entry_ref(player.player)
+                .ok()
+                .and_then(|e| e.into_component::<Position>().ok())
+            {
+                Some(pos) => pos.xy(),
+                None => return,
+            };
 
-    for (&(x, y), &tile_type) in dungeon.iter() {
-        let pos = Vector2::new(x as f32, y as f32);
+            let changed = fog
+                .reveal(player_pos)
+                .into_iter()
+                .map(chunk_of)
+                .collect::<HashSet<_>>();
+            if changed.is_empty() {
+                return;
+            }
 
-        if TileType::Floor == tile_type
-            && rng.gen_bool(((floor.0 - 1) as f64 * 0.05 + 1.).log2().min(1.) as f64)
-        {
-            let rad = rng.gen_range(0.1..0.4) + rng.gen_range(0.0..0.1);
-            let mut smith = command_buffer.smith();
-            smith
-                .position(
-                    (pos + Vector2::new(rng.gen_range(-0.3..0.3), rng.gen_range(-0.3..0.3)))
-                        .extend(0.),
-                )
-                .agent(
-                    rng.gen_range(1.0..4.0) - 1.6 * rad,
-                    rng.gen_range(3.0..9.0) + 2.0 * rad,
-                )
-                .orientation(0.0)
-                .velocity_zero()
-                .dynamic_body(rad)
-                .circle_collider(rad)
-                .any(Faction::Enemies)
-                .any(HitPoints {
-                    max: rng.gen_range(0.0..2.0) + 8. * rad,
-                    health: rng.gen_range(0.0..2.0) + 8. * rad,
-                })
-                .any(DynamicModelRequest {
-                    label: "monstroman.obj".to_string(),
-                })
-                .any(Scale(rad * 1.7))
-                .done();
+            for (entity, mesh) in query.iter(world) {
+                if changed.contains(&mesh.chunk) {
+                    command_buffer.add_component(*entity, fog_request(mesh, fog));
+                }
+            }
+        })
+}
+
+/// Enemies on a floor of depth 1, each floor deeper adds `ENEMIES_PER_FLOOR` more
+const BASE_ENEMIES: i32 = 3;
+const ENEMIES_PER_FLOOR: i32 = 2;
+/// Health enemies heal every second, once they haven't been hit for `ENEMY_REGEN_DELAY` seconds
+const ENEMY_REGEN_RATE: f32 = 0.75;
+const ENEMY_REGEN_DELAY: f32 = 3.0;
+/// How close the player has to get before an enemy starts chasing
+const ENEMY_AGGRO_RANGE: f32 = 8.0;
+/// How many seconds enemies look for the player after losing sight of them
+const ENEMY_MEMORY: f32 = 4.0;
+/// How hard enemies keep out of each other's way, see `Separation`
+const ENEMY_SEPARATION: f32 = 10.0;
+
+/// What a single enemy will be spawned with, rolled up front so it can be checked
+#[derive(Debug, PartialEq)]
+struct EnemySpawn {
+    position: Vector2<f32>,
+    radius: f32,
+    speed: f32,
+    acceleration: f32,
+    hit_points: f32,
+}
+
+/// Picks which spawn points get an enemy and how strong they are, deeper floors get
+/// more and tougher enemies. Only depends on the seed, depth and points.
+fn plan_enemy_spawns(spawn_points: &SpawnPoints) -> Vec<EnemySpawn> {
+    let mut rng = StdRng::seed_from_u64(spawn_points.seed);
+    let depth = spawn_points.depth.max(1);
+
+    let count = (BASE_ENEMIES + ENEMIES_PER_FLOOR * (depth - 1)) as usize;
+    let strength = 1.0 + 0.25 * (depth - 1) as f32;
+
+    let chosen = spawn_points
+        .points
+        .choose_multiple(&mut rng, count)
+        .copied()
+        .collect::<Vec<_>>();
+
+    chosen
+        .into_iter()
+        .map(|(x, y)| {
+            let radius = rng.gen_range(0.1..0.4) + rng.gen_range(0.0..0.1);
+            EnemySpawn {
+                position: Vector2::new(x as f32, y as f32)
+                    + Vector2::new(rng.gen_range(-0.3..0.3), rng.gen_range(-0.3..0.3)),
+                radius,
+                speed: rng.gen_range(1.0..4.0) - 1.6 * radius,
+                acceleration: rng.gen_range(3.0..9.0) + 2.0 * radius,
+                hit_points: (rng.gen_range(0.0..2.0) + 8. * radius) * strength,
+            }
+        })
+        .collect()
+}
+
+/// Populates a new floor with enemies that hunt the player, once per floor
+pub fn spawn_enemies_system() -> impl Runnable {
+    SystemBuilder::new("spawn_enemies")
+        .write_resource::<SpawnPoints>()
+        .build(move |command_buffer, _, spawn_points, _| {
+            if spawn_points.points.is_empty() {
+                return;
+            }
+
+            for enemy in plan_enemy_spawns(spawn_points) {
+                command_buffer
+                    .smith()
+                    .pos(enemy.position)
+                    .agent(enemy.speed, enemy.acceleration)
+                    .orientation(0.0)
+                    .velocity_zero()
+                    .dynamic_body(enemy.radius)
+                    .circle_collider(enemy.radius)
+                    .faction(Faction::Enemies)
+                    .regenerating_hit_points(enemy.hit_points, ENEMY_REGEN_RATE, ENEMY_REGEN_DELAY)
+                    .separation(enemy.radius * 3.0, ENEMY_SEPARATION)
+                    .ai_follow(AIFollow {
+                        target: None,
+                        minimum_distance: 1.0,
+                        maximum_distance: ENEMY_AGGRO_RANGE,
+                        home: Some(enemy.position),
+                        memory: ENEMY_MEMORY,
+                        last_seen: None,
+                    })
+                    .dynamic_model("monstroman.obj")
+                    .scale(enemy.radius * 1.7)
+                    .done();
+            }
+
+            spawn_points.points.clear();
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn floor(depth: i32) -> SpawnPoints {
+        SpawnPoints {
+            seed: 42,
+            depth,
+            points: (0..100).map(|i| (i % 10, i / 10)).collect(),
         }
     }
+
+    fn small_floor() -> HashMap<(i32, i32), TileType> {
+        let mut tiles = HashMap::new();
+        for x in 0..20 {
+            for y in 0..20 {
+                let tile = match (x, y) {
+                    (0, _) | (_, 0) | (19, _) | (_, 19) => TileType::UndirectedWall,
+                    (1..=18, 1..=18) if x > 10 => TileType::Floor,
+                    _ => TileType::Nothing,
+                };
+                tiles.insert((x, y), tile);
+            }
+        }
+        tiles.insert((15, 15), TileType::LadderDown);
+        tiles.insert((1, 5), TileType::Wall(Direction::West));
+        tiles
+    }
+
+    #[test]
+    fn tiles_are_drawn_by_a_few_merged_models_per_chunk() {
+        let tiles = small_floor();
+
+        let mut world = legion::World::default();
+        let mut command_buffer = CommandBuffer::new(&world);
+        populate_environment(&mut command_buffer, &tiles);
+        command_buffer.flush(&mut world, &mut legion::Resources::default());
+
+        let meshes = <&FloorMesh>::query().iter(&world).collect::<Vec<_>>();
+        // 3 by 3 chunks with at most nothing, floors, walls and the ladder, seen and visible
+        assert!(meshes.len() <= 9 * 4 * 2);
+        for visibility in [Visibility::Visible, Visibility::Seen] {
+            let mut drawn = meshes
+                .iter()
+                .filter(|mesh| mesh.visibility == visibility)
+                .flat_map(|mesh| mesh.tiles.iter().map(|&(tile, _, _)| tile))
+                .collect::<Vec<_>>();
+            drawn.sort_unstable();
+            drawn.dedup();
+            assert_eq!(drawn.len(), tiles.len());
+        }
+        for mesh in &meshes {
+            assert!(mesh
+                .tiles
+                .iter()
+                .all(|&(tile, _, _)| chunk_of(tile) == mesh.chunk));
+        }
+        // Nothing is drawn before the player has seen it
+        assert_eq!(<&MergedModelRequest>::query().iter(&world).count(), 0);
+
+        let nothing = tiles.values().filter(|&&t| t == TileType::Nothing).count();
+        assert_eq!(
+            <&TileType>::query().iter(&world).count(),
+            tiles.len() - nothing
+        );
+    }
+
+    #[test]
+    fn the_fog_lifts_around_the_player_and_stays_lifted() {
+        let mut fog = FogOfWar::default();
+        fog.reset(small_floor().keys().copied());
+
+        let changed = fog.reveal(vec2(15.0, 15.0));
+        assert!(changed.contains(&(15, 15)));
+        assert_eq!(fog.get((15, 15)), Visibility::Visible);
+        assert_eq!(fog.get((15, 15 - fog.radius as i32)), Visibility::Visible);
+        assert_eq!(fog.get((3, 3)), Visibility::Unseen);
+        // Standing still changes nothing
+        assert!(fog.reveal(vec2(15.0, 15.0)).is_empty());
+
+        let changed = fog.reveal(vec2(3.0, 3.0));
+        assert!(changed.contains(&(15, 15)) && changed.contains(&(3, 3)));
+        assert_eq!(fog.get((15, 15)), Visibility::Seen);
+        assert_eq!(fog.get((3, 3)), Visibility::Visible);
+
+        let mesh = FloorMesh {
+            chunk: (1, 1),
+            visibility: Visibility::Seen,
+            uniforms: LocalUniforms::init().with_tint(LIGHT_GRAY),
+            tiles: vec![
+                ((15, 15), "DevFloor.obj", LocalUniforms::init().model_matrix),
+                ((1, 18), "DevFloor.obj", LocalUniforms::init().model_matrix),
+            ],
+        };
+        let request = fog_request(&mesh, &fog);
+        assert_eq!(request.parts.len(), 1);
+        assert!(request.uniforms.tint[0] < LIGHT_GRAY[0]);
+    }
+
+    #[test]
+    fn spawns_are_reproducible() {
+        assert_eq!(plan_enemy_spawns(&floor(2)), plan_enemy_spawns(&floor(2)));
+    }
+
+    #[test]
+    fn deeper_floors_have_more_and_tougher_enemies() {
+        let shallow = plan_enemy_spawns(&floor(1));
+        let deep = plan_enemy_spawns(&floor(6));
+
+        assert_eq!(shallow.len(), BASE_ENEMIES as usize);
+        assert!(deep.len() > shallow.len());
+
+        let toughest =
+            |spawns: &[EnemySpawn]| spawns.iter().map(|e| e.hit_points).fold(0.0, f32::max);
+        assert!(toughest(&deep) > toughest(&shallow));
+    }
+
+    #[test]
+    fn never_more_enemies_than_spawn_points() {
+        let cramped = SpawnPoints {
+            points: vec![(0, 0), (5, 5)],
+            ..floor(10)
+        };
+        assert_eq!(plan_enemy_spawns(&cramped).len(), 2);
+    }
 }